@@ -1,7 +1,7 @@
 use anyhow::{ensure, Result};
 use std::str::FromStr;
 
-use super::{LayerId, LayerSetName, ProjectId};
+use super::{Layer, LayerId, LayerSetName, ProjectId};
 
 pub struct DomainName(trust_dns_proto::rr::Name);
 
@@ -40,10 +40,76 @@ pub struct Domain {
     pub kind: DomainKind,
 }
 
+impl Domain {
+    /// Domain that serves only this layer, so that it can be previewed.
+    pub fn layer_preview(layer: &Layer) -> Domain {
+        Domain {
+            project_id: layer.project_id,
+            fqdn: Domain::layer_preview_fqdn(layer.id),
+            kind: DomainKind::Layer {
+                layer_set_name: layer.layer_set_name.clone(),
+                layer_id: layer.id,
+            },
+        }
+    }
+
+    pub fn layer_preview_fqdn(layer_id: LayerId) -> String {
+        format!("{}.localhost", layer_id.into_uuid().as_hyphenated())
+    }
+
+    /// Domain that serves the live layer of a layer set created from a ref pattern.
+    pub fn layer_set_preview(project_id: ProjectId, layer_set_name: &LayerSetName) -> Domain {
+        Domain {
+            project_id,
+            fqdn: Domain::layer_set_preview_fqdn(project_id, layer_set_name),
+            kind: DomainKind::LayerSet {
+                layer_set_name: layer_set_name.clone(),
+            },
+        }
+    }
+
+    /// Layer set names are only unique within a project, so the project is part of the domain.
+    pub fn layer_set_preview_fqdn(project_id: ProjectId, layer_set_name: &LayerSetName) -> String {
+        format!(
+            "{}.{}.localhost",
+            layer_set_name.as_str(),
+            project_id.into_uuid().as_hyphenated()
+        )
+    }
+
+    /// Whether this is the domain that [Domain::layer_set_preview] creates for the layer set.
+    pub fn is_layer_set_preview(
+        &self,
+        project_id: ProjectId,
+        layer_set_name: &LayerSetName,
+    ) -> bool {
+        match self.kind {
+            DomainKind::LayerSet {
+                layer_set_name: ref domain_layer_set_name,
+            } => {
+                self.project_id == project_id
+                    && domain_layer_set_name == layer_set_name
+                    && self.fqdn == Domain::layer_set_preview_fqdn(project_id, layer_set_name)
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum DomainKind {
-    Layer,
-    WildCard { kind: WildCardKind },
+    /// Serves a single layer, e.g. the preview domain of a build.
+    Layer {
+        layer_set_name: LayerSetName,
+        layer_id: LayerId,
+    },
+    /// Serves the pinned layer of a layer set, or its last ready layer if it is not pinned.
+    LayerSet {
+        layer_set_name: LayerSetName,
+    },
+    WildCard {
+        kind: WildCardKind,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -1,15 +1,18 @@
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Result};
 use blake2::{digest::Mac, Blake2bMac};
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305};
+use chrono::{DateTime, Utc};
+use std::fmt;
 
 use super::ProjectId;
 
-#[derive(Copy, Clone, Debug, PartialEq, bincode::Encode, bincode::Decode)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, bincode::Encode, bincode::Decode)]
 pub enum FileChecksum {
     Deleted,
     Blake2b(FileEncoding, [u8; 32]),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, bincode::Encode, bincode::Decode)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, bincode::Encode, bincode::Decode)]
 pub enum FileEncoding {
     Identity,
     Gzip,
@@ -29,6 +32,8 @@ impl FileChecksum {
         Ok(checksum)
     }
 
+    /// Checksums are BLAKE2b-256 MACs without a key, salted with the 16 bytes of the project id
+    /// and personalized with `file`. Clients that upload files compute them the same way.
     pub fn blake2b_hasher(project_id: ProjectId) -> Blake2bHasher {
         let hasher = blake2::Blake2bMac::new_with_salt_and_personal(
             project_id.into_uuid().as_bytes(),
@@ -39,6 +44,29 @@ impl FileChecksum {
         Blake2bHasher(hasher)
     }
 
+    /// Checksum of unencoded data from the 32 bytes of a BLAKE2b-256 MAC.
+    pub fn from_blake2b(bytes: &[u8]) -> Result<FileChecksum> {
+        ensure!(bytes.len() == 32, "file checksums must be 32 bytes");
+
+        let mut checksum = [0u8; 32];
+        checksum.copy_from_slice(bytes);
+        Ok(FileChecksum::Blake2b(FileEncoding::Identity, checksum))
+    }
+
+    pub fn as_blake2b(&self) -> Option<&[u8; 32]> {
+        match self {
+            FileChecksum::Deleted => None,
+            FileChecksum::Blake2b(_, checksum) => Some(checksum),
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        match self {
+            FileChecksum::Deleted => "deleted".into(),
+            FileChecksum::Blake2b(_, checksum) => hex::encode(checksum),
+        }
+    }
+
     pub fn with_encoding(self, encoding: FileEncoding) -> FileChecksum {
         match self {
             FileChecksum::Deleted => FileChecksum::Deleted,
@@ -67,6 +95,8 @@ pub struct File {
     pub project_id: ProjectId,
     pub checksum: FileChecksum,
     pub length: u64,
+    /// When the file was last stored, storing an existing file again moves this forward.
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Clone, Debug)]
@@ -75,3 +105,293 @@ pub struct FileChunk {
     pub offset: u64,
     pub data: Vec<u8>,
 }
+
+/// A blob referenced by a file, starting at `offset` within the file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FileBlob {
+    pub offset: u64,
+    pub checksum: BlobChecksum,
+    pub length: u64,
+}
+
+/// Content addressed chunk of data that can be shared between several files in a project.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlobChecksum([u8; 32]);
+
+impl BlobChecksum {
+    pub fn blake2b(project_id: ProjectId, data: &[u8]) -> BlobChecksum {
+        let mut hasher =
+            blake2::Blake2bMac::<blake2::digest::consts::U32>::new_with_salt_and_personal(
+                project_id.into_uuid().as_bytes(),
+                &[],
+                b"blob",
+            )
+            .unwrap();
+        hasher.update(data);
+
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hasher.finalize().into_bytes());
+        BlobChecksum(bytes)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<BlobChecksum> {
+        ensure!(bytes.len() == 32, "blob checksums must be 32 bytes");
+
+        let mut checksum = [0u8; 32];
+        checksum.copy_from_slice(bytes);
+        Ok(BlobChecksum(checksum))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlobCompression {
+    Identity,
+    Zstd,
+}
+
+impl BlobCompression {
+    const ZSTD_LEVEL: i32 = 3;
+
+    /// Compresses the data with zstd, unless it doesn't make the data noticeably smaller.
+    pub fn compress(data: &[u8]) -> Result<(BlobCompression, Vec<u8>)> {
+        let compressed = zstd::encode_all(data, Self::ZSTD_LEVEL)?;
+
+        if compressed.len() < data.len() - data.len() / 10 {
+            Ok((BlobCompression::Zstd, compressed))
+        } else {
+            Ok((BlobCompression::Identity, data.to_vec()))
+        }
+    }
+
+    pub fn decompress(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            BlobCompression::Identity => Ok(data),
+            BlobCompression::Zstd => Ok(zstd::decode_all(&data[..])?),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub project_id: ProjectId,
+    pub checksum: BlobChecksum,
+    pub length: u64,
+    pub compression: BlobCompression,
+    /// When the blob was last stored, storing an existing blob again moves this forward.
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CreateBlob {
+    pub checksum: BlobChecksum,
+    pub length: u64,
+    pub compression: BlobCompression,
+    pub data: Vec<u8>,
+}
+
+/// Key used to encrypt file chunks at rest.
+///
+/// Every project has one or more keys, new chunks are always encrypted with the key with the
+/// highest version. Older keys are kept until every chunk has been re-encrypted.
+#[derive(Clone)]
+pub struct FileEncryptionKey {
+    pub version: u32,
+    key: [u8; 32],
+}
+
+impl FileEncryptionKey {
+    const NONCE_LENGTH: usize = 24;
+
+    pub fn generate(version: u32) -> Result<FileEncryptionKey> {
+        let mut key = [0u8; 32];
+        getrandom::getrandom(&mut key)?;
+
+        Ok(FileEncryptionKey { version, key })
+    }
+
+    pub fn from_row(version: u32, key: &[u8]) -> Result<FileEncryptionKey> {
+        ensure!(key.len() == 32, "file encryption keys must be 32 bytes");
+
+        let mut key_bytes = [0u8; 32];
+        key_bytes.copy_from_slice(key);
+
+        Ok(FileEncryptionKey {
+            version,
+            key: key_bytes,
+        })
+    }
+
+    pub fn key_to_slice(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Encrypts `data` with XChaCha20-Poly1305 and a random nonce, the nonce is prepended to the
+    /// returned ciphertext. `associated_data` is authenticated but not encrypted, use
+    /// [FileEncryptionKey::blob_associated_data] to bind a blob to its project and checksum.
+    pub fn encrypt(&self, associated_data: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::Payload;
+
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+
+        let mut nonce = [0u8; Self::NONCE_LENGTH];
+        getrandom::getrandom(&mut nonce)?;
+
+        let payload = Payload {
+            msg: data,
+            aad: associated_data,
+        };
+
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), payload)
+            .map_err(|_| anyhow!("could not encrypt blob"))?;
+
+        let mut output = Vec::with_capacity(nonce.len() + ciphertext.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+
+        Ok(output)
+    }
+
+    pub fn decrypt(&self, associated_data: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::Payload;
+
+        ensure!(
+            data.len() >= Self::NONCE_LENGTH,
+            "encrypted blob is too short"
+        );
+
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+
+        let (nonce, ciphertext) = data.split_at(Self::NONCE_LENGTH);
+        let mut nonce_bytes = [0u8; Self::NONCE_LENGTH];
+        nonce_bytes.copy_from_slice(nonce);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: associated_data,
+        };
+
+        cipher
+            .decrypt(&nonce_bytes.into(), payload)
+            .map_err(|_| anyhow!("could not decrypt blob"))
+    }
+
+    /// Associated data for a blob, so that blobs can't be swapped with each other.
+    pub fn blob_associated_data(project_id: ProjectId, checksum: &BlobChecksum) -> Vec<u8> {
+        let mut associated_data = Vec::with_capacity(48);
+        associated_data.extend_from_slice(project_id.into_uuid().as_bytes());
+        associated_data.extend_from_slice(&checksum.0);
+        associated_data
+    }
+}
+
+impl fmt::Debug for FileEncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileEncryptionKey")
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn blob_associated_data(data: &[u8]) -> Vec<u8> {
+        let project_id = ProjectId::from(Uuid::from_u128(1));
+        let checksum = BlobChecksum::blake2b(project_id, data);
+        FileEncryptionKey::blob_associated_data(project_id, &checksum)
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_blob() {
+        let key = FileEncryptionKey::generate(1).unwrap();
+        let associated_data = blob_associated_data(b"hello world");
+
+        let ciphertext = key.encrypt(&associated_data, b"hello world").unwrap();
+        assert_ne!(
+            &ciphertext[FileEncryptionKey::NONCE_LENGTH..],
+            b"hello world"
+        );
+
+        let plaintext = key.decrypt(&associated_data, &ciphertext).unwrap();
+        assert_eq!(plaintext, b"hello world");
+    }
+
+    #[test]
+    fn encrypt_uses_unique_nonces() {
+        let key = FileEncryptionKey::generate(1).unwrap();
+        let associated_data = blob_associated_data(b"hello world");
+
+        let first = key.encrypt(&associated_data, b"hello world").unwrap();
+        let second = key.encrypt(&associated_data, b"hello world").unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn decrypt_blob_with_another_checksum() {
+        let key = FileEncryptionKey::generate(1).unwrap();
+
+        let ciphertext = key
+            .encrypt(&blob_associated_data(b"hello world"), b"hello world")
+            .unwrap();
+        assert!(key
+            .decrypt(&blob_associated_data(b"goodbye world"), &ciphertext)
+            .is_err());
+    }
+
+    #[test]
+    fn decrypt_blob_with_another_key() {
+        let key = FileEncryptionKey::generate(1).unwrap();
+        let other_key = FileEncryptionKey::generate(2).unwrap();
+        let associated_data = blob_associated_data(b"hello world");
+
+        let ciphertext = key.encrypt(&associated_data, b"hello world").unwrap();
+        assert!(other_key.decrypt(&associated_data, &ciphertext).is_err());
+        assert!(key.decrypt(&associated_data, &ciphertext[..8]).is_err());
+    }
+
+    #[test]
+    fn blob_checksums_depend_on_the_project() {
+        let project_id = ProjectId::from(Uuid::from_u128(1));
+        let other_project_id = ProjectId::from(Uuid::from_u128(2));
+
+        assert_eq!(
+            BlobChecksum::blake2b(project_id, b"hello world"),
+            BlobChecksum::blake2b(project_id, b"hello world")
+        );
+        assert_ne!(
+            BlobChecksum::blake2b(project_id, b"hello world"),
+            BlobChecksum::blake2b(other_project_id, b"hello world")
+        );
+    }
+
+    #[test]
+    fn compress_and_decompress_blob() {
+        let data = b"hello world ".repeat(1024);
+
+        let (compression, compressed) = BlobCompression::compress(&data).unwrap();
+        assert_eq!(compression, BlobCompression::Zstd);
+        assert!(compressed.len() < data.len());
+        assert_eq!(compression.decompress(compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible_blobs_are_stored_as_is() {
+        let mut data = vec![0u8; 4096];
+        getrandom::getrandom(&mut data).unwrap();
+
+        let (compression, stored) = BlobCompression::compress(&data).unwrap();
+        assert_eq!(compression, BlobCompression::Identity);
+        assert_eq!(stored, data);
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use std::{collections::BTreeMap, str::FromStr};
 use uuid::Uuid;
 
@@ -11,8 +12,37 @@ impl LayerSetName {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Name of the layer set that is created from `template` for a ref matching `ref_pattern`,
+    /// e.g. `preview-feature-login` for `refs/heads/feature/login` and `refs/heads/*`. The name
+    /// is also used as the label of its preview hostname.
+    pub fn for_ref(template: &LayerSetName, ref_pattern: &str, ref_: &str) -> LayerSetName {
+        let prefix = ref_pattern_prefix(ref_pattern);
+        let prefix = match prefix.rfind('/') {
+            Some(index) => &prefix[..=index],
+            None => "",
+        };
+        let ref_name = ref_.strip_prefix(prefix).unwrap_or(ref_);
+
+        let mut name = String::new();
+
+        for c in format!("{}-{ref_name}", template.as_str()).chars() {
+            if c.is_ascii_alphanumeric() {
+                name.push(c.to_ascii_lowercase());
+            } else if !name.is_empty() && !name.ends_with('-') {
+                name.push('-');
+            }
+        }
+
+        name.truncate(MAX_DOMAIN_LABEL_LENGTH);
+
+        LayerSetName(name.trim_end_matches('-').to_owned())
+    }
 }
 
+/// Longest label that hostnames can contain.
+const MAX_DOMAIN_LABEL_LENGTH: usize = 63;
+
 impl FromStr for LayerSetName {
     type Err = anyhow::Error;
 
@@ -31,6 +61,27 @@ pub struct LayerSet {
     pub source: Option<LayerSetSource>,
 
     pub build_status: LayerSetBuildStatus,
+
+    /// While set, this layer is served instead of the last ready layer. Layers keep being built
+    /// but don't go live until the layer set is unpinned.
+    pub pinned_layer_id: Option<LayerId>,
+
+    /// Layer set with a ref pattern that this layer set was created from, for one of the refs
+    /// matching it.
+    pub template_name: Option<LayerSetName>,
+
+    /// Archived layer sets aren't built or previewed anymore, layer sets created from a ref
+    /// pattern are archived when their ref is deleted.
+    pub archived: bool,
+}
+
+impl LayerSet {
+    /// The layer that domains of this layer set serve. Layers that are still being built,
+    /// cancelled or failed are never live, the last layer of the build status is only set once a
+    /// layer is ready.
+    pub fn live_layer_id(&self) -> Option<LayerId> {
+        self.pinned_layer_id.or(self.build_status.last_layer_id)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -47,15 +98,125 @@ pub struct LayerSetSource {
 
 #[derive(Clone, Debug)]
 pub enum LayerSetSourceKind {
-    Git { ref_: String },
+    /// Builds `root` of the repository at `ref_`, the whole repository if `root` is empty. If
+    /// there are any `paths`, only the files under `root` that are in one of them are published.
+    ///
+    /// A `ref_` containing `*` is a ref pattern, the layer set isn't built itself but is the
+    /// template of the layer sets that are created for each ref matching it.
+    Git {
+        ref_: String,
+        root: String,
+        paths: Vec<String>,
+    },
+}
+
+impl LayerSetSourceKind {
+    /// Paths of the repository that layers are built from, empty if it is all of it. Commits
+    /// that leave these paths unchanged don't need a new layer.
+    pub fn tracked_paths(&self) -> Vec<String> {
+        match self {
+            LayerSetSourceKind::Git { root, paths, .. } if paths.is_empty() => {
+                if root.is_empty() {
+                    vec![]
+                } else {
+                    vec![root.clone()]
+                }
+            }
+            LayerSetSourceKind::Git { root, paths, .. } => paths
+                .iter()
+                .map(|path| {
+                    if root.is_empty() {
+                        path.clone()
+                    } else {
+                        format!("{root}/{path}")
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Whether `ref_` is a pattern, where `*` matches any number of characters.
+pub fn is_ref_pattern(ref_: &str) -> bool {
+    ref_.contains('*')
+}
+
+/// The part of a ref pattern before its first `*`, all refs matching it start with this prefix.
+pub fn ref_pattern_prefix(ref_pattern: &str) -> &str {
+    match ref_pattern.split_once('*') {
+        Some((prefix, _)) => prefix,
+        None => ref_pattern,
+    }
+}
+
+pub fn ref_pattern_matches(ref_pattern: &str, ref_: &str) -> bool {
+    match ref_pattern.split_once('*') {
+        Some((prefix, rest)) => match ref_.strip_prefix(prefix) {
+            Some(ref_) => (0..=ref_.len())
+                .filter(|&index| ref_.is_char_boundary(index))
+                .any(|index| ref_pattern_matches(rest, &ref_[index..])),
+            None => false,
+        },
+        None => ref_pattern == ref_,
+    }
+}
+
+/// Normalizes a path of a repository to its components joined by `/`, the empty path is the
+/// root of the repository.
+pub fn normalize_source_path(path: &str) -> Result<String> {
+    let mut components = vec![];
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return Err(anyhow!("source paths can't contain `..`")),
+            component => components.push(component),
+        }
+    }
+
+    Ok(components.join("/"))
 }
 
 #[derive(Clone, Debug)]
 pub struct LayerSetBuildStatus {
+    /// Layer that is being built, other layers of the layer set wait until it is done.
     pub current_layer_id: Option<LayerId>,
+    /// Last layer that became ready. Unlike [LayerRepository::set_last_layer_id], which tracks the
+    /// last layer that was created, this is only set when a layer is finalized.
+    ///
+    /// [LayerRepository::set_last_layer_id]: crate::repositories::LayerRepository::set_last_layer_id
     pub last_layer_id: Option<LayerId>,
 }
 
+/// Which layers of a layer set are kept when garbage is collected. Layers that are still being
+/// built or that a domain routes to are always kept.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LayerSetRetention {
+    /// Number of the most recent ready layers to keep.
+    pub keep_ready_layers: u32,
+}
+
+impl Default for LayerSetRetention {
+    fn default() -> LayerSetRetention {
+        LayerSetRetention {
+            keep_ready_layers: 10,
+        }
+    }
+}
+
+/// How many layer sets created from ref patterns a project can have that aren't archived. Refs
+/// matching a pattern once the limit is reached get their layer set when another is archived.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RefLayerSetLimit {
+    pub max_layer_sets: u32,
+}
+
+impl Default for RefLayerSetLimit {
+    fn default() -> RefLayerSetLimit {
+        RefLayerSetLimit { max_layer_sets: 20 }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CreateLayerSet {
     pub name: LayerSetName,
@@ -71,7 +232,11 @@ pub struct CreateLayerSetSource {
 
 #[derive(Clone, Debug)]
 pub enum CreateLayerSetSourceKind {
-    Git { ref_: String },
+    Git {
+        ref_: String,
+        root: String,
+        paths: Vec<String>,
+    },
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
@@ -86,6 +251,12 @@ impl LayerId {
         let LayerId(uuid) = self;
         uuid
     }
+
+    /// Layer ids are UUIDv7, which start with the time they were generated at.
+    pub fn created_at(&self) -> DateTime<Utc> {
+        let timestamp_ms = (self.0.as_u128() >> 80) as i64;
+        Utc.timestamp_millis_opt(timestamp_ms).unwrap()
+    }
 }
 
 impl From<Uuid> for LayerId {
@@ -94,6 +265,14 @@ impl From<Uuid> for LayerId {
     }
 }
 
+impl FromStr for LayerId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LayerId> {
+        Ok(LayerId(s.parse()?))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Layer {
     pub project_id: ProjectId,
@@ -113,7 +292,14 @@ pub enum LayerStatus {
 
 #[derive(Clone, Debug)]
 pub enum LayerSource {
-    Git { commit: String },
+    Git {
+        commit: String,
+    },
+    /// Shares the members of a ready layer of another layer set.
+    Promotion {
+        layer_set_name: LayerSetName,
+        layer_id: LayerId,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -140,6 +326,20 @@ pub struct LayerChange {
     pub headers: BTreeMap<String, String>,
 }
 
+/// Files of a layer that is uploaded by a client instead of being built from a source.
+#[derive(Clone, Debug)]
+pub struct UploadManifest {
+    pub files: Vec<UploadManifestFile>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UploadManifestFile {
+    pub path: String,
+    /// Computed by the client with `FileChecksum::blake2b_hasher`.
+    pub checksum: FileChecksum,
+    pub length: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct LayerMember {
     pub project_id: ProjectId,
@@ -160,6 +360,37 @@ pub struct LayerMemberSummary {
     pub headers: BTreeMap<String, String>,
 }
 
+#[derive(Clone, Debug)]
+pub struct LayerMemberFile {
+    pub path: String,
+    pub checksum: FileChecksum,
+    pub length: u64,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ListLayerMembers {
+    pub prefix: String,
+    /// Path of the last member of the previous page.
+    pub page_token: Option<String>,
+    pub page_size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct LayerDiffEntry {
+    pub path: String,
+    pub kind: LayerDiffKind,
+    pub old_length: Option<u64>,
+    pub new_length: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LayerDiffKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
 #[derive(Copy, Clone, Debug, bincode::Encode, bincode::Decode)]
 pub enum ContentEncodingHint {
     Relative {
@@ -254,3 +485,52 @@ impl ContentEncodingHint {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_ref_patterns() {
+        assert!(ref_pattern_matches("refs/heads/*", "refs/heads/main"));
+        assert!(ref_pattern_matches(
+            "refs/heads/*",
+            "refs/heads/feature/login"
+        ));
+        assert!(!ref_pattern_matches("refs/heads/*", "refs/tags/v1"));
+        assert!(ref_pattern_matches("refs/tags/v*", "refs/tags/v1.2.0"));
+        assert!(!ref_pattern_matches("refs/tags/v*", "refs/tags/release"));
+        assert!(ref_pattern_matches(
+            "refs/heads/*/preview",
+            "refs/heads/a/b/preview"
+        ));
+        assert!(!ref_pattern_matches(
+            "refs/heads/*/preview",
+            "refs/heads/a/b"
+        ));
+        assert!(ref_pattern_matches("refs/heads/main", "refs/heads/main"));
+        assert!(!ref_pattern_matches("refs/heads/main", "refs/heads/main2"));
+
+        assert_eq!(ref_pattern_prefix("refs/tags/v*"), "refs/tags/v");
+        assert_eq!(ref_pattern_prefix("refs/heads/main"), "refs/heads/main");
+    }
+
+    #[test]
+    fn layer_set_names_for_refs() {
+        let template: LayerSetName = "Preview".parse().unwrap();
+
+        assert_eq!(
+            LayerSetName::for_ref(&template, "refs/heads/*", "refs/heads/feature/Login_Page")
+                .as_str(),
+            "preview-feature-login-page"
+        );
+        assert_eq!(
+            LayerSetName::for_ref(&template, "refs/tags/v*", "refs/tags/v1.2.0").as_str(),
+            "preview-v1-2-0"
+        );
+
+        let ref_ = format!("refs/heads/{}", "a".repeat(100));
+        let name = LayerSetName::for_ref(&template, "refs/heads/*", &ref_);
+        assert_eq!(name.as_str().len(), MAX_DOMAIN_LABEL_LENGTH);
+    }
+}
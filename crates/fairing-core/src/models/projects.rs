@@ -1,5 +1,9 @@
+use anyhow::Result;
+use std::str::FromStr;
 use uuid::Uuid;
 
+use super::FileEncryptionKey;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct ProjectId(Uuid);
 
@@ -16,6 +20,14 @@ impl From<Uuid> for ProjectId {
     }
 }
 
+impl FromStr for ProjectId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ProjectId> {
+        Ok(ProjectId(s.parse()?))
+    }
+}
+
 impl Into<Uuid> for ProjectId {
     fn into(self) -> Uuid {
         let ProjectId(uuid) = self;
@@ -53,7 +65,22 @@ impl<'de> bincode::BorrowDecode<'de> for ProjectId {
 pub struct Project {
     pub id: ProjectId,
     pub acme_dns_challenge_label: String,
-    pub file_encryption_key: Vec<u8>,
+    pub file_encryption_keys: Vec<FileEncryptionKey>,
+}
+
+impl Project {
+    /// The key that new file chunks should be encrypted with.
+    pub fn current_file_encryption_key(&self) -> Option<&FileEncryptionKey> {
+        self.file_encryption_keys
+            .iter()
+            .max_by_key(|key| key.version)
+    }
+
+    pub fn file_encryption_key(&self, version: u32) -> Option<&FileEncryptionKey> {
+        self.file_encryption_keys
+            .iter()
+            .find(|key| key.version == version)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
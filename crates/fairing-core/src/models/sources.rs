@@ -25,6 +25,7 @@ pub struct Source {
     pub project_id: ProjectId,
     pub name: SourceName,
     pub kind: SourceKind,
+    pub status: SourceStatus,
 }
 
 impl Source {
@@ -42,6 +43,20 @@ pub enum SourceKind {
     Git {
         repository_url: GitRepository,
         id_ed25519: Ed25519,
+        /// Host keys the repository must present, pinned on the first refresh if empty.
+        known_host_keys: Vec<GitHostKey>,
+        /// Token for repositories reached over http, public repositories don't need one.
+        http_token: Option<GitHttpToken>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum SourceStatus {
+    Ok,
+    /// The repository presented a host key that isn't trusted, nothing is fetched from it until
+    /// the host key is pinned.
+    HostKeyMismatch {
+        host_key: GitHostKey,
     },
 }
 
@@ -51,7 +66,10 @@ pub struct CreateSource {
 }
 
 pub enum CreateSourceKind {
-    Git { repository_url: GitRepository },
+    Git {
+        repository_url: GitRepository,
+        http_token: Option<GitHttpToken>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -65,6 +83,22 @@ pub struct SourceWithKind<Kind> {
 pub struct GitSource {
     pub repository_url: GitRepository,
     pub id_ed25519: Ed25519,
+    pub known_host_keys: Vec<GitHostKey>,
+    pub http_token: Option<GitHttpToken>,
+}
+
+impl GitSource {
+    /// Host keys the repository is trusted with, the known host keys of the source or the
+    /// built-in host keys of the forge. Empty if the host key should be trusted on first use.
+    pub fn trusted_host_keys(&self) -> Result<Vec<GitHostKey>> {
+        if !self.known_host_keys.is_empty() {
+            return Ok(self.known_host_keys.clone());
+        }
+
+        let repository = self.repository_url.parts()?;
+
+        Ok(GitHostKey::builtin(&repository.host))
+    }
 }
 
 impl SourceKindData for GitSource {}
@@ -77,9 +111,13 @@ impl TryFrom<SourceKind> for GitSource {
             SourceKind::Git {
                 repository_url,
                 id_ed25519,
+                known_host_keys,
+                http_token,
             } => Ok(GitSource {
                 repository_url,
                 id_ed25519,
+                known_host_keys,
+                http_token,
             }),
         }
     }
@@ -93,6 +131,106 @@ pub struct GitSourceRefAndCommit {
     pub commit: String,
 }
 
+#[derive(Clone, Debug)]
+pub struct GitSourceLatest {
+    /// Host key the repository presented, if it was reached over ssh.
+    pub host_key: Option<GitHostKey>,
+    pub refs_and_commits: Vec<GitSourceRefAndCommit>,
+}
+
+/// Host keys of the major forges, trusted unless a source has known host keys of its own.
+const BUILTIN_HOST_KEYS: &[(&str, &str)] = &[
+    (
+        "github.com",
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl",
+    ),
+    (
+        "gitlab.com",
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAfuCHKVTjquxvt6CM6tdG4SLp1Btn/nOeHHE5UOzRdf",
+    ),
+];
+
+/// Public key of an ssh server, in the `ssh-ed25519 AAAA...` format of known hosts files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GitHostKey(String);
+
+impl GitHostKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn from_public_key(public_key: &thrussh_keys::key::PublicKey) -> GitHostKey {
+        use thrussh_keys::PublicKeyBase64;
+
+        GitHostKey(format!(
+            "{} {}",
+            public_key.name(),
+            public_key.public_key_base64()
+        ))
+    }
+
+    pub fn builtin(host: &str) -> Vec<GitHostKey> {
+        BUILTIN_HOST_KEYS
+            .iter()
+            .filter(|(builtin_host, _)| builtin_host.eq_ignore_ascii_case(host))
+            .map(|(_, host_key)| GitHostKey(host_key.to_string()))
+            .collect()
+    }
+
+    /// Only the key itself is compared, rsa keys are named after the signature hash in use.
+    pub fn matches(&self, public_key: &thrussh_keys::key::PublicKey) -> bool {
+        use thrussh_keys::PublicKeyBase64;
+
+        self.0.split(' ').nth(1) == Some(public_key.public_key_base64().as_str())
+    }
+}
+
+impl FromStr for GitHostKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<GitHostKey> {
+        let mut parts = s.split_whitespace();
+
+        let (name, key) = match (parts.next(), parts.next()) {
+            (Some(name), Some(key)) => (name, key),
+            _ => {
+                return Err(anyhow!(
+                    "host key must be formatted like `ssh-ed25519 AAAA...`"
+                ))
+            }
+        };
+
+        thrussh_keys::parse_public_key_base64(key).map_err(|_| anyhow!("invalid host key"))?;
+
+        Ok(GitHostKey(format!("{name} {key}")))
+    }
+}
+
+impl fmt::Display for GitHostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Returned by git source repositories when the repository presents a host key that isn't one
+/// of the trusted host keys of the source.
+#[derive(Debug)]
+pub struct GitHostKeyMismatch {
+    pub host_key: GitHostKey,
+}
+
+impl fmt::Display for GitHostKeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "host key mismatch, the repository presented {} which isn't a known host key",
+            self.host_key
+        )
+    }
+}
+
+impl std::error::Error for GitHostKeyMismatch {}
+
 #[derive(Clone, Debug)]
 pub struct GitRepository(String);
 
@@ -114,14 +252,40 @@ impl FromStr for GitRepository {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GitTransport {
+    Ssh,
+    /// Smart http, plain http is only meant for repositories on the local network.
+    Http,
+    Https,
+}
+
 #[derive(Clone, Debug)]
 pub struct GitRepositoryParts {
+    pub transport: GitTransport,
     pub user: String,
     pub host: String,
     pub port: u16,
     pub path: String,
 }
 
+impl GitRepositoryParts {
+    /// Base url of a repository reached over http, without credentials or a trailing slash.
+    pub fn http_url(&self) -> String {
+        let scheme = match self.transport {
+            GitTransport::Http => "http",
+            GitTransport::Https | GitTransport::Ssh => "https",
+        };
+
+        format!(
+            "{scheme}://{}:{}{}",
+            self.host,
+            self.port,
+            self.path.trim_end_matches('/')
+        )
+    }
+}
+
 impl FromStr for GitRepositoryParts {
     type Err = anyhow::Error;
 
@@ -136,6 +300,7 @@ impl FromStr for GitRepositoryParts {
             let url = url::Url::parse(s)?;
 
             Ok(GitRepositoryParts {
+                transport: GitTransport::Ssh,
                 user: url.username().to_owned(),
                 host: url.host_str().unwrap_or("").to_owned(),
                 port: url.port().unwrap_or(22),
@@ -143,6 +308,32 @@ impl FromStr for GitRepositoryParts {
                 // see more: https://git-scm.com/docs/pack-protocol/#_ssh_transport
                 path: url.path().to_owned(),
             })
+        } else if s.starts_with("https://") || s.starts_with("http://") {
+            let url = url::Url::parse(s)?;
+
+            if url.password().is_some() || url.query().is_some() {
+                return Err(anyhow!(
+                    "repository url can't contain a password or a query, use an http token"
+                ));
+            }
+
+            let transport = match url.scheme() {
+                "http" => GitTransport::Http,
+                _ => GitTransport::Https,
+            };
+
+            Ok(GitRepositoryParts {
+                transport,
+                user: url.username().to_owned(),
+                host: url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("repository url is missing a host"))?
+                    .to_owned(),
+                port: url
+                    .port_or_known_default()
+                    .ok_or_else(|| anyhow!("repository url is missing a port"))?,
+                path: url.path().to_owned(),
+            })
         } else {
             let captures = RE.captures(s);
             let captures = if let Some(captures) = captures {
@@ -156,6 +347,7 @@ impl FromStr for GitRepositoryParts {
             let path = captures.name("path").unwrap();
 
             Ok(GitRepositoryParts {
+                transport: GitTransport::Ssh,
                 user: user.as_str().to_owned(),
                 host: host.as_str().to_owned(),
                 port: 22,
@@ -165,6 +357,34 @@ impl FromStr for GitRepositoryParts {
     }
 }
 
+/// Token sent as the password of repositories reached over http.
+#[derive(Clone)]
+pub struct GitHttpToken(String);
+
+impl GitHttpToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for GitHttpToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<GitHttpToken> {
+        if s.is_empty() || s.chars().any(char::is_whitespace) {
+            return Err(anyhow!("invalid http token"));
+        }
+
+        Ok(GitHttpToken(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for GitHttpToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GitHttpToken(..)")
+    }
+}
+
 #[derive(Clone)]
 pub struct Ed25519 {
     secret_key: thrussh_keys::key::ed25519::SecretKey,
@@ -7,6 +7,12 @@ use crate::models;
 pub trait DomainRepository: Send + Sync {
     async fn create_domain(&self, domain: models::Domain) -> Result<()>;
 
+    async fn get_domain(&self, fqdn: &str) -> Result<Option<models::Domain>>;
+
+    async fn list_domains(&self, project_id: models::ProjectId) -> Result<Vec<models::Domain>>;
+
+    async fn delete_domain(&self, fqdn: &str) -> Result<()>;
+
     async fn create_certificate(
         &self,
         certificate: &models::Certificate,
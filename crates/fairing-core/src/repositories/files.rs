@@ -10,26 +10,74 @@ pub trait FileRepository: Send + Sync {
         checksum: &models::FileChecksum,
     ) -> Result<Option<models::File>>;
 
-    async fn create_chunk(
-        &self,
-        project_id: models::ProjectId,
-        checksum: &models::FileChecksum,
-        length: u64,
-        offset: u64,
-        data: Vec<u8>,
-    ) -> Result<()>;
+    async fn list_files(&self, project_id: models::ProjectId) -> Result<Vec<models::File>>;
 
-    async fn finish_file(
+    /// Stores a file as a list of blobs, all blobs must have been created before the file.
+    async fn create_file(
         &self,
         project_id: models::ProjectId,
         checksum: &models::FileChecksum,
         length: u64,
+        blobs: &[models::FileBlob],
     ) -> Result<()>;
 
+    /// Returns the chunks of a file that start within the range.
     async fn get_file_chunks(
         &self,
         project_id: models::ProjectId,
         checksum: models::FileChecksum,
         range: (u64, u64),
     ) -> Result<Vec<models::FileChunk>>;
+
+    async fn list_file_blobs(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Vec<models::FileBlob>>;
+
+    /// Deletes a file, but not the blobs that it's stored as.
+    async fn delete_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<()>;
+
+    async fn get_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<Option<models::Blob>>;
+
+    async fn create_blob(
+        &self,
+        project_id: models::ProjectId,
+        blob: &models::CreateBlob,
+    ) -> Result<()>;
+
+    async fn list_blobs(&self, project_id: models::ProjectId) -> Result<Vec<models::Blob>>;
+
+    async fn delete_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<()>;
+
+    /// Re-encrypts up to `limit` blobs that are not encrypted with the current file encryption
+    /// key of the project. Returns the number of blobs that were re-encrypted.
+    async fn reencrypt_blobs(&self, project_id: models::ProjectId, limit: usize) -> Result<usize>;
+
+    /// Returns the versions of the file encryption keys that blobs of the project are encrypted
+    /// with.
+    async fn list_blob_key_versions(&self, project_id: models::ProjectId) -> Result<Vec<u32>>;
+}
+
+/// Object storage for blob data, used by file repositories that only keep metadata themselves.
+/// Data is already encrypted when it's stored.
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
 }
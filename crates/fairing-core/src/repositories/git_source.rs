@@ -5,10 +5,23 @@ use crate::models;
 
 #[async_trait::async_trait]
 pub trait GitSourceRepository: Send + Sync {
+    /// Lists the refs of the repository that start with any of `ref_prefixes`. Fails with
+    /// [`models::GitHostKeyMismatch`] if the repository presents a host key that the source
+    /// doesn't trust.
     async fn git_list_latest(
         &self,
         source: &models::SourceWithKind<models::GitSource>,
-    ) -> Result<Vec<models::GitSourceRefAndCommit>>;
+        ref_prefixes: &[String],
+    ) -> Result<models::GitSourceLatest>;
+
+    /// Hashes of the trees or blobs at `paths` in the commit, `None` for paths that don't exist.
+    /// Paths are relative to the root of the repository and separated by `/`.
+    async fn git_tree_hashes(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        paths: &[String],
+    ) -> Result<Vec<Option<String>>>;
 
     async fn git_clone(
         &self,
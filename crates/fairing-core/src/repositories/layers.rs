@@ -27,6 +27,49 @@ pub trait LayerRepository: Send + Sync {
 
     async fn create_layer_set(&self, layer_set: &models::LayerSet) -> Result<()>;
 
+    /// Returns `None` if the retention of the layer set has never been set.
+    async fn get_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSetRetention>>;
+
+    async fn set_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        retention: &models::LayerSetRetention,
+    ) -> Result<()>;
+
+    /// Returns `None` if the limit of the project has never been set.
+    async fn get_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Option<models::RefLayerSetLimit>>;
+
+    async fn set_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+        limit: &models::RefLayerSetLimit,
+    ) -> Result<()>;
+
+    async fn set_layer_set_archived(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        archived: bool,
+    ) -> Result<()>;
+
+    /// Pins the layer set to a layer, or unpins it with `None`.
+    async fn set_pinned_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: Option<models::LayerId>,
+    ) -> Result<()>;
+
+    /// Records the last layer created in the layer set, which stale builds are compared against.
+    /// This doesn't change which layer is live, see [models::LayerSetBuildStatus::last_layer_id].
     async fn set_last_layer_id(
         &self,
         project_id: models::ProjectId,
@@ -42,6 +85,20 @@ pub trait LayerRepository: Send + Sync {
 
     async fn create_layer(&self, layer: &models::Layer) -> Result<()>;
 
+    async fn list_layers(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Vec<models::Layer>>;
+
+    /// Deletes a layer together with its members and changes.
+    async fn delete_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()>;
+
     async fn get_pending_layers(
         &self,
         filter: LayerPendingLayersFilter,
@@ -105,6 +162,15 @@ pub trait LayerRepository: Send + Sync {
 
     async fn create_layer_members(&self, layer_members: &[models::LayerMember]) -> Result<()>;
 
+    /// Returns the members that were created for this layer, not the members it inherits from
+    /// earlier layers.
+    async fn list_layer_members(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<Vec<models::LayerMember>>;
+
     async fn get_layer_member_summary(
         &self,
         project_id: models::ProjectId,
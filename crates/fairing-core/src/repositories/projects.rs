@@ -6,5 +6,19 @@ use crate::models;
 pub trait ProjectRepository: Send + Sync {
     async fn get_project(&self, id: &models::ProjectId) -> Result<Option<models::Project>>;
 
+    async fn list_projects(&self) -> Result<Vec<models::Project>>;
+
     async fn create_or_update_project(&self, project: &models::Project) -> Result<()>;
+
+    async fn create_file_encryption_key(
+        &self,
+        project_id: models::ProjectId,
+        key: &models::FileEncryptionKey,
+    ) -> Result<()>;
+
+    async fn delete_file_encryption_keys(
+        &self,
+        project_id: models::ProjectId,
+        versions: &[u32],
+    ) -> Result<()>;
 }
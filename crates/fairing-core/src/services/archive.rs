@@ -0,0 +1,717 @@
+use anyhow::{anyhow, ensure, Context as _, Result};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    io::{BufRead, BufReader, Read, Write},
+};
+use tokio::sync::mpsc;
+
+use super::{
+    auth::{Authentication, LayerPermissions},
+    members::list_live_layer_members,
+    uploads::{deleted_layer_member, validate_member_path},
+    FileWriter,
+};
+use crate::{
+    models,
+    repositories::{DomainRepository, FileRepository, LayerRepository},
+};
+
+const MANIFEST_PATH: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+const MANIFEST_MAX_LENGTH: usize = 16_777_216;
+
+/// Archive entries are sent to the importer in pieces of at most this many bytes.
+const ENTRY_CHUNK_SIZE: usize = 1_048_576;
+
+/// Files are exported in reads of this many bytes.
+const READ_LENGTH: u64 = 4_194_304;
+
+/// Frames of zstd compressed data start with these bytes.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LayerArchiveFormat {
+    Tar,
+    TarZstd,
+}
+
+impl LayerArchiveFormat {
+    /// Archives ending with `.zst` are compressed, everything else is a plain tar archive.
+    pub fn from_file_name(file_name: &str) -> LayerArchiveFormat {
+        if file_name.ends_with(".zst") {
+            LayerArchiveFormat::TarZstd
+        } else {
+            LayerArchiveFormat::Tar
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    version: u32,
+    members: Vec<ManifestMember>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ManifestMember {
+    path: String,
+    /// Archive entry with the contents of this member, members with the same contents share it.
+    file: String,
+    content_encoding_hint: ManifestContentEncodingHint,
+    headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ManifestContentEncodingHint {
+    Relative {
+        identity: u8,
+        gzip: u8,
+        zstd: u8,
+        brotli: u8,
+    },
+}
+
+impl From<models::ContentEncodingHint> for ManifestContentEncodingHint {
+    fn from(hint: models::ContentEncodingHint) -> ManifestContentEncodingHint {
+        match hint {
+            models::ContentEncodingHint::Relative {
+                identity,
+                gzip,
+                zstd,
+                brotli,
+            } => ManifestContentEncodingHint::Relative {
+                identity,
+                gzip,
+                zstd,
+                brotli,
+            },
+        }
+    }
+}
+
+impl From<ManifestContentEncodingHint> for models::ContentEncodingHint {
+    fn from(hint: ManifestContentEncodingHint) -> models::ContentEncodingHint {
+        match hint {
+            ManifestContentEncodingHint::Relative {
+                identity,
+                gzip,
+                zstd,
+                brotli,
+            } => models::ContentEncodingHint::Relative {
+                identity,
+                gzip,
+                zstd,
+                brotli,
+            },
+        }
+    }
+}
+
+/// Exports layers to tar archives and imports them again, possibly into another project or
+/// installation.
+///
+/// An archive has a `manifest.json` with the path, headers and content encoding hint of every
+/// member, the contents of the members are stored under `site/`, at the path of the first member
+/// with those contents.
+pub struct LayerArchiveService {
+    layer_repository: &'static dyn LayerRepository,
+    file_repository: &'static dyn FileRepository,
+    domain_repository: &'static dyn DomainRepository,
+    worker_id: models::WorkerId,
+}
+
+impl LayerArchiveService {
+    pub fn new(
+        layer_repository: &'static dyn LayerRepository,
+        file_repository: &'static dyn FileRepository,
+        domain_repository: &'static dyn DomainRepository,
+    ) -> LayerArchiveService {
+        LayerArchiveService {
+            layer_repository,
+            file_repository,
+            domain_repository,
+            worker_id: models::WorkerId::new(),
+        }
+    }
+
+    pub async fn export_layer<W>(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        writer: W,
+        format: LayerArchiveFormat,
+    ) -> Result<()>
+    where
+        W: Write + Send,
+    {
+        auth.can(LayerPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let layer = self
+            .layer_repository
+            .list_layers(project_id, layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == layer_id)
+            .ok_or_else(|| anyhow!("layer not found"))?;
+
+        ensure!(
+            matches!(layer.status, models::LayerStatus::Ready),
+            "only ready layers can be exported"
+        );
+
+        match format {
+            LayerArchiveFormat::Tar => {
+                let mut builder = tar::Builder::new(writer);
+                self.write_archive(&layer, &mut builder).await?;
+                builder.into_inner()?.flush()?;
+            }
+            LayerArchiveFormat::TarZstd => {
+                let mut builder = tar::Builder::new(zstd::Encoder::new(writer, 0)?);
+                self.write_archive(&layer, &mut builder).await?;
+                builder.into_inner()?.finish()?.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_archive<W>(
+        &self,
+        layer: &models::Layer,
+        builder: &mut tar::Builder<W>,
+    ) -> Result<()>
+    where
+        W: Write + Send,
+    {
+        let layer_members = list_live_layer_members(
+            self.layer_repository,
+            layer.project_id,
+            &layer.layer_set_name,
+            layer.id,
+        )
+        .await?
+        .into_values()
+        .collect::<Vec<_>>();
+
+        // Directory paths are served from their index file, so their contents are usually found
+        // at another path as well.
+        let mut entries = HashMap::new();
+        let mut files = vec![];
+
+        for layer_member in &layer_members {
+            if !layer_member.path.ends_with('/') && !entries.contains_key(&layer_member.checksum) {
+                let entry = format!("site{}", layer_member.path);
+                entries.insert(layer_member.checksum, entry.clone());
+                files.push((layer_member.checksum, entry));
+            }
+        }
+
+        for layer_member in &layer_members {
+            if let Entry::Vacant(vacant) = entries.entry(layer_member.checksum) {
+                let entry = format!("files/{}", hex::encode(layer_member.checksum.encode()));
+                vacant.insert(entry.clone());
+                files.push((layer_member.checksum, entry));
+            }
+        }
+
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            members: layer_members
+                .into_iter()
+                .map(|layer_member| ManifestMember {
+                    file: entries[&layer_member.checksum].clone(),
+                    path: layer_member.path,
+                    content_encoding_hint: layer_member.content_encoding_hint.into(),
+                    headers: layer_member.headers,
+                })
+                .collect(),
+        };
+
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+        append_entry(builder, MANIFEST_PATH, &manifest)?;
+
+        for (checksum, entry) in files {
+            let file = self
+                .file_repository
+                .get_file(layer.project_id, &checksum)
+                .await?
+                .ok_or_else(|| anyhow!("file of {entry} not found"))?;
+
+            append_entry_header(builder, &entry, file.length)?;
+
+            // Files are written as they are read, a range holds every blob starting in it.
+            let mut offset = 0;
+            while offset < file.length {
+                let mut chunks = self
+                    .file_repository
+                    .get_file_chunks(layer.project_id, checksum, (offset, offset + READ_LENGTH))
+                    .await?;
+                chunks.sort_by_key(|chunk| chunk.offset);
+
+                ensure!(!chunks.is_empty(), "file of {entry} is missing data");
+
+                for chunk in chunks {
+                    ensure!(chunk.offset == offset, "file of {entry} has a gap");
+                    ensure!(
+                        offset + chunk.data.len() as u64 <= file.length,
+                        "file of {entry} has the wrong length"
+                    );
+
+                    builder.get_mut().write_all(&chunk.data)?;
+                    offset += chunk.data.len() as u64;
+                }
+            }
+
+            append_entry_padding(builder, file.length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new layer in the layer set from an archive, the archive may be compressed with
+    /// zstd. Files that the project already has are not stored again.
+    pub async fn import_layer<R>(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        reader: R,
+    ) -> Result<models::Layer>
+    where
+        R: Read + Send + 'static,
+    {
+        auth.can(LayerPermissions::Create)?;
+        let project_id = auth.project_id()?;
+
+        let layer_set = self
+            .layer_repository
+            .get_layer_set(project_id, layer_set_name)
+            .await?
+            .ok_or_else(|| anyhow!("layer set not found"))?;
+
+        ensure!(
+            layer_set.source.is_none(),
+            "layers can only be imported into layer sets without a source"
+        );
+
+        let layer_id = models::LayerId::new()?;
+
+        let layer = models::Layer {
+            project_id,
+            layer_set_name: layer_set.name,
+            id: layer_id,
+            status: models::LayerStatus::Building,
+            source: None,
+        };
+
+        self.layer_repository.create_layer(&layer).await?;
+
+        if let Err(err) = self.import_into_layer(&layer, reader).await {
+            let res = self
+                .layer_repository
+                .cancel_layer(project_id, &layer.layer_set_name, layer.id)
+                .await;
+
+            if let Err(cancel_err) = res {
+                tracing::error!(
+                    "error cancelling layer ({}): {:?}",
+                    layer.id.into_uuid(),
+                    cancel_err
+                );
+            }
+
+            return Err(err);
+        }
+
+        Ok(models::Layer {
+            status: models::LayerStatus::Ready,
+            ..layer
+        })
+    }
+
+    async fn import_into_layer<R>(&self, layer: &models::Layer, reader: R) -> Result<()>
+    where
+        R: Read + Send + 'static,
+    {
+        self.layer_repository
+            .try_set_current_build(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.layer_repository
+            .build_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        // Archives are read on a blocking thread, entries are stored as they are read.
+        let (sender, mut receiver) = mpsc::channel(4);
+        let reader_task = tokio::task::spawn_blocking(move || read_entries(reader, sender));
+
+        let mut manifest = None;
+        let mut files = HashMap::new();
+        let mut current_entry = None;
+
+        while let Some(chunk) = receiver.recv().await {
+            match chunk {
+                ArchiveChunk::Entry(path) => {
+                    if let Some(entry) = current_entry.take() {
+                        self.finish_entry(entry, &mut manifest, &mut files).await?;
+                    }
+
+                    current_entry = Some(if path == MANIFEST_PATH {
+                        ImportEntry::Manifest(vec![])
+                    } else {
+                        ImportEntry::File {
+                            path,
+                            hasher: Box::new(models::FileChecksum::blake2b_hasher(
+                                layer.project_id,
+                            )),
+                            writer: FileWriter::new(self.file_repository, layer.project_id),
+                        }
+                    });
+                }
+                ArchiveChunk::Data(data) => match current_entry {
+                    Some(ImportEntry::Manifest(ref mut manifest)) => {
+                        ensure!(
+                            manifest.len() + data.len() <= MANIFEST_MAX_LENGTH,
+                            "{MANIFEST_PATH} is too large"
+                        );
+                        manifest.extend_from_slice(&data);
+                    }
+                    Some(ImportEntry::File {
+                        ref mut hasher,
+                        ref mut writer,
+                        ..
+                    }) => {
+                        hasher.update(&data);
+                        writer.write(&data).await?;
+                    }
+                    None => return Err(anyhow!("archive data without an entry")),
+                },
+            }
+        }
+
+        if let Some(entry) = current_entry.take() {
+            self.finish_entry(entry, &mut manifest, &mut files).await?;
+        }
+
+        reader_task.await??;
+
+        let manifest = manifest.ok_or_else(|| anyhow!("archive has no {MANIFEST_PATH}"))?;
+
+        let mut changes = Vec::with_capacity(manifest.members.len());
+
+        for member in manifest.members {
+            validate_member_path(&member.path)?;
+
+            let checksum = files.get(&member.file).ok_or_else(|| {
+                anyhow!(
+                    "{} of {} is missing from the archive",
+                    member.file,
+                    member.path
+                )
+            })?;
+
+            changes.push(models::LayerChange {
+                project_id: layer.project_id,
+                layer_set_name: layer.layer_set_name.clone(),
+                layer_id: layer.id,
+                worker_id: self.worker_id,
+                path: member.path,
+                checksum: *checksum,
+                content_encoding_hint: member.content_encoding_hint.into(),
+                headers: member.headers,
+            });
+        }
+
+        for changes in changes.chunks(128) {
+            self.layer_repository.create_layer_changes(changes).await?;
+        }
+
+        self.layer_repository
+            .finish_build(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        self.layer_repository
+            .finalize_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        let mut layer_members = changes
+            .into_iter()
+            .map(|layer_change| models::LayerMember {
+                project_id: layer_change.project_id,
+                layer_set_name: layer_change.layer_set_name,
+                layer_id: layer_change.layer_id,
+                path: layer_change.path,
+                checksum: layer_change.checksum,
+                content_encoding_hint: layer_change.content_encoding_hint,
+                headers: layer_change.headers,
+            })
+            .collect::<Vec<_>>();
+
+        // The archive has every member of the layer, the paths of earlier layers that aren't in
+        // it are deleted.
+        let paths = layer_members
+            .iter()
+            .map(|layer_member| layer_member.path.clone())
+            .collect::<HashSet<_>>();
+
+        let live_layer_members = list_live_layer_members(
+            self.layer_repository,
+            layer.project_id,
+            &layer.layer_set_name,
+            layer.id,
+        )
+        .await?;
+
+        for path in live_layer_members.into_keys() {
+            if !paths.contains(&path) {
+                layer_members.push(deleted_layer_member(layer, path));
+            }
+        }
+
+        for layer_members in layer_members.chunks(128) {
+            self.layer_repository
+                .create_layer_members(layer_members)
+                .await?;
+        }
+
+        self.layer_repository
+            .finish_finalizing(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        // Imports that fail don't replace the last layer of the layer set.
+        self.layer_repository
+            .set_last_layer_id(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.domain_repository
+            .create_domain(models::Domain::layer_preview(layer))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Parses the manifest or stores the file once all of the entry has been read.
+    async fn finish_entry(
+        &self,
+        entry: ImportEntry,
+        manifest: &mut Option<Manifest>,
+        files: &mut HashMap<String, models::FileChecksum>,
+    ) -> Result<()> {
+        match entry {
+            ImportEntry::Manifest(data) => {
+                let parsed: Manifest =
+                    serde_json::from_slice(&data).context("parsing the manifest")?;
+                ensure!(
+                    parsed.version == MANIFEST_VERSION,
+                    "unsupported manifest version {}",
+                    parsed.version
+                );
+
+                *manifest = Some(parsed);
+            }
+            ImportEntry::File {
+                path,
+                hasher,
+                writer,
+            } => {
+                // Checksums are keyed with the project, so the checksums of the exported project
+                // can't be reused.
+                let checksum = hasher.finalize();
+
+                let file_exists = self
+                    .file_repository
+                    .get_file(writer.project_id(), &checksum)
+                    .await?
+                    .is_some();
+
+                if !file_exists {
+                    writer.finish(&checksum).await?;
+                }
+
+                files.insert(path, checksum);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Pieces of an archive, sent from the thread reading it. Every entry is followed by its data.
+enum ArchiveChunk {
+    Entry(String),
+    Data(Vec<u8>),
+}
+
+/// Archive entry that is being imported.
+enum ImportEntry {
+    Manifest(Vec<u8>),
+    File {
+        path: String,
+        hasher: Box<models::Blake2bHasher>,
+        writer: FileWriter,
+    },
+}
+
+fn append_entry<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+
+    builder.append_data(&mut header, path, data)?;
+
+    Ok(())
+}
+
+/// Appends the header of an entry of `length` bytes, the data has to be written to the builder
+/// and padded with [append_entry_padding] after it.
+fn append_entry_header<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    length: u64,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(length);
+    header.set_mode(0o644);
+
+    // The builder takes care of paths that don't fit in the header, but only writes entries
+    // whose data it reads itself, so the header is built without any data first.
+    let mut header_builder = tar::Builder::new(vec![]);
+    header_builder.append_data(&mut header, path, std::io::empty())?;
+    builder.get_mut().write_all(header_builder.get_ref())?;
+
+    Ok(())
+}
+
+/// Pads the data of an entry to the block size of tar archives.
+fn append_entry_padding<W: Write>(builder: &mut tar::Builder<W>, length: u64) -> Result<()> {
+    let padding = (512 - length % 512) % 512;
+    builder.get_mut().write_all(&[0; 512][..padding as usize])?;
+
+    Ok(())
+}
+
+/// Sends the path and contents of every file in the archive, until the receiver is dropped.
+fn read_entries<R: Read>(reader: R, sender: mpsc::Sender<ArchiveChunk>) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+
+    if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        read_tar_entries(zstd::Decoder::with_buffer(reader)?, sender)
+    } else {
+        read_tar_entries(reader, sender)
+    }
+}
+
+fn read_tar_entries<R: Read>(reader: R, sender: mpsc::Sender<ArchiveChunk>) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+
+        let path = entry
+            .path()?
+            .to_str()
+            .ok_or_else(|| anyhow!("archive has a path that is not valid utf-8"))?
+            .to_owned();
+
+        if sender.blocking_send(ArchiveChunk::Entry(path)).is_err() {
+            return Ok(());
+        }
+
+        loop {
+            let mut data = vec![0; ENTRY_CHUNK_SIZE];
+            let length = read_full(&mut entry, &mut data)?;
+
+            if length == 0 {
+                break;
+            }
+
+            data.truncate(length);
+            if sender.blocking_send(ArchiveChunk::Data(data)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads until the buffer is full or the reader is exhausted, returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut length = 0;
+
+    while length < buffer.len() {
+        match reader.read(&mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_format_from_file_name() {
+        assert_eq!(
+            LayerArchiveFormat::from_file_name("site.tar"),
+            LayerArchiveFormat::Tar
+        );
+        assert_eq!(
+            LayerArchiveFormat::from_file_name("site.tar.zst"),
+            LayerArchiveFormat::TarZstd
+        );
+    }
+
+    #[test]
+    fn manifest_keeps_content_encoding_hints() {
+        let hint = models::ContentEncodingHint::Relative {
+            identity: 1,
+            gzip: 2,
+            zstd: 3,
+            brotli: 4,
+        };
+
+        let json = serde_json::to_string(&ManifestContentEncodingHint::from(hint)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"relative":{"identity":1,"gzip":2,"zstd":3,"brotli":4}}"#
+        );
+
+        let parsed: ManifestContentEncodingHint = serde_json::from_str(&json).unwrap();
+        let parsed: models::ContentEncodingHint = parsed.into();
+        assert_eq!(parsed.encode(), hint.encode());
+    }
+}
@@ -1,5 +1,6 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
+use super::RequestError;
 use crate::models;
 
 pub enum AuthenticationRole {
@@ -29,13 +30,18 @@ impl Authentication {
                 ..
             } => match permission {
                 ResourcePermissions::Project(ProjectPermissions::Get)
+                | ResourcePermissions::Project(ProjectPermissions::RotateFileEncryptionKey)
                 | ResourcePermissions::Source(SourcePermissions::Get)
                 | ResourcePermissions::Source(SourcePermissions::Create)
                 | ResourcePermissions::Source(SourcePermissions::Refresh)
+                | ResourcePermissions::Source(SourcePermissions::Update)
                 | ResourcePermissions::LayerSet(LayerSetPermissions::Get)
                 | ResourcePermissions::LayerSet(LayerSetPermissions::Create)
-                | ResourcePermissions::Layer(LayerPermissions::Create) => Ok(()),
-                _ => Err(anyhow!("not allowed")),
+                | ResourcePermissions::LayerSet(LayerSetPermissions::Update)
+                | ResourcePermissions::Layer(LayerPermissions::Get)
+                | ResourcePermissions::Layer(LayerPermissions::Create)
+                | ResourcePermissions::Layer(LayerPermissions::Promote) => Ok(()),
+                _ => Err(RequestError::NotAllowed.into()),
             },
             Authentication::Role {
                 role: AuthenticationRole::Viewer,
@@ -43,24 +49,30 @@ impl Authentication {
             } => match permission {
                 ResourcePermissions::Project(ProjectPermissions::Get)
                 | ResourcePermissions::Source(SourcePermissions::Get)
-                | ResourcePermissions::LayerSet(LayerSetPermissions::Get) => Ok(()),
-                _ => Err(anyhow!("not allowed")),
+                | ResourcePermissions::LayerSet(LayerSetPermissions::Get)
+                | ResourcePermissions::Layer(LayerPermissions::Get) => Ok(()),
+                _ => Err(RequestError::NotAllowed.into()),
             },
             Authentication::System {
                 project_id: Some(_),
             } => match permission {
                 ResourcePermissions::Project(ProjectPermissions::Get)
+                | ResourcePermissions::Project(ProjectPermissions::RotateFileEncryptionKey)
                 | ResourcePermissions::Source(SourcePermissions::Get)
                 | ResourcePermissions::Source(SourcePermissions::Create)
                 | ResourcePermissions::Source(SourcePermissions::Refresh)
+                | ResourcePermissions::Source(SourcePermissions::Update)
                 | ResourcePermissions::LayerSet(LayerSetPermissions::Get)
                 | ResourcePermissions::LayerSet(LayerSetPermissions::Create)
-                | ResourcePermissions::Layer(LayerPermissions::Create) => Ok(()),
-                _ => Err(anyhow!("not allowed")),
+                | ResourcePermissions::LayerSet(LayerSetPermissions::Update)
+                | ResourcePermissions::Layer(LayerPermissions::Get)
+                | ResourcePermissions::Layer(LayerPermissions::Create)
+                | ResourcePermissions::Layer(LayerPermissions::Promote) => Ok(()),
+                _ => Err(RequestError::NotAllowed.into()),
             },
             Authentication::System { project_id: None } => match permission {
                 ResourcePermissions::Project(ProjectPermissions::Create) => Ok(()),
-                _ => Err(anyhow!("not allowed")),
+                _ => Err(RequestError::NotAllowed.into()),
             },
         }
     }
@@ -69,7 +81,7 @@ impl Authentication {
         match self {
             Authentication::Role { project_id, .. } => Ok(*project_id),
             Authentication::System { project_id, .. } => {
-                project_id.ok_or_else(|| anyhow!("not allowed"))
+                project_id.ok_or_else(|| RequestError::NotAllowed.into())
             }
         }
     }
@@ -87,6 +99,7 @@ pub enum ResourcePermissions {
 pub enum ProjectPermissions {
     Get,
     Create,
+    RotateFileEncryptionKey,
 }
 
 impl Into<ResourcePermissions> for ProjectPermissions {
@@ -100,6 +113,7 @@ pub enum SourcePermissions {
     Get,
     Create,
     Refresh,
+    Update,
 }
 
 impl Into<ResourcePermissions> for SourcePermissions {
@@ -112,6 +126,7 @@ impl Into<ResourcePermissions> for SourcePermissions {
 pub enum LayerSetPermissions {
     Get,
     Create,
+    Update,
 }
 
 impl Into<ResourcePermissions> for LayerSetPermissions {
@@ -124,6 +139,7 @@ impl Into<ResourcePermissions> for LayerSetPermissions {
 pub enum LayerPermissions {
     Get,
     Create,
+    Promote,
 }
 
 impl Into<ResourcePermissions> for LayerPermissions {
@@ -1,11 +1,14 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use std::{collections::BTreeMap, path::Component, path::PathBuf};
 use tokio::{
     fs,
     io::{AsyncReadExt, AsyncSeekExt},
 };
 
-use super::auth::{Authentication, LayerPermissions, LayerSetPermissions};
+use super::{
+    auth::{Authentication, LayerPermissions, LayerSetPermissions},
+    FileWriter,
+};
 use crate::{
     models,
     repositories::{
@@ -21,6 +24,7 @@ pub struct BuildService {
     file_repository: &'static dyn FileRepository,
     domain_repository: &'static dyn DomainRepository,
     worker_id: models::WorkerId,
+    work_directory: PathBuf,
 }
 
 impl BuildService {
@@ -38,9 +42,17 @@ impl BuildService {
             file_repository,
             domain_repository,
             worker_id: models::WorkerId::new(),
+            work_directory: PathBuf::from(".data/builds"),
         }
     }
 
+    /// Sets the directory where sources are checked out while building, defaults to
+    /// `.data/builds`.
+    pub fn with_work_directory(mut self, work_directory: impl Into<PathBuf>) -> BuildService {
+        self.work_directory = work_directory.into();
+        self
+    }
+
     pub async fn build(&self) -> Result<()> {
         let pending_layers = self
             .layer_repository
@@ -75,6 +87,16 @@ impl BuildService {
                 _ => (),
             }
 
+            // Layers without a source are uploaded, they're built once the upload is finalized.
+            if layer_set.source.is_none() && layer.source.is_none() {
+                continue;
+            }
+
+            // Promoted layers are copied by the promotion itself.
+            if let Some(models::LayerSource::Promotion { .. }) = layer.source {
+                continue;
+            }
+
             let layer_id = layer.id;
 
             let result = self.build_single(layer_set.clone(), layer.clone()).await;
@@ -136,18 +158,20 @@ impl BuildService {
             )
             .await?;
 
-        let mut path = PathBuf::new();
-        path.push(".data");
-        path.push("builds");
+        let mut path = self.work_directory.clone();
         path.push(layer.id.into_uuid().to_string());
 
         fs::create_dir_all(&path).await?;
 
+        // Links are followed anywhere in the source, files are only published from its root.
+        let mut source_path = path.clone();
+        let mut published_paths = vec![];
+
         match (layer_set.source, layer.source) {
             (
                 Some(models::LayerSetSource {
                     name,
-                    kind: models::LayerSetSourceKind::Git { ref_ },
+                    kind: models::LayerSetSourceKind::Git { ref_, root, paths },
                 }),
                 Some(models::LayerSource::Git { commit }),
             ) => {
@@ -161,39 +185,101 @@ impl BuildService {
 
                 let ref_and_commit = models::GitSourceRefAndCommit { ref_, commit };
 
-                path = self
+                source_path = self
                     .git_source_repository
                     .git_clone(&source, &ref_and_commit, path.clone())
                     .await?;
+
+                path = source_path.join(root);
+                published_paths = paths;
             }
             (None, None) => (),
             _ => return Err(anyhow!("unsupported combination of sources")),
         }
 
         let base_path = path.clone();
-        let mut paths = vec![path];
+        let real_source_path = fs::canonicalize(&source_path).await?;
+        let real_base_path = fs::canonicalize(&base_path)
+            .await
+            .context("the root of the layer set isn't in the source")?;
+
+        ensure!(
+            real_base_path.starts_with(&real_source_path)
+                && fs::metadata(&real_base_path).await?.is_dir(),
+            "the root of the layer set isn't a directory in the source"
+        );
+
+        // Directories are listed with the directories that links were followed to on the way,
+        // a link back to any of them would be followed forever.
+        let mut paths = vec![(path, vec![])];
         let mut changes = vec![];
 
-        while let Some(path) = paths.pop() {
+        while let Some((path, followed)) = paths.pop() {
+            let real_path = fs::canonicalize(&path).await?;
             let mut dir = fs::read_dir(&path).await?;
 
             while let Some(entry) = dir.next_entry().await? {
-                let file_type = entry.file_type().await?;
+                let mut file_type = entry.file_type().await?;
+
+                // Links inside the tree are added as the files they point to, links that point
+                // outside of it are skipped.
+                if file_type.is_symlink() {
+                    let target = match fs::canonicalize(entry.path()).await {
+                        Ok(target) if target.starts_with(&real_source_path) => target,
+                        Ok(_) => {
+                            tracing::warn!(
+                                "skipping link {} pointing outside of the source",
+                                entry.path().display()
+                            );
+                            continue;
+                        }
+                        Err(_) => {
+                            tracing::warn!("skipping broken link {}", entry.path().display());
+                            continue;
+                        }
+                    };
+
+                    file_type = fs::metadata(&target).await?.file_type();
+
+                    if file_type.is_dir() {
+                        if real_path.starts_with(&target) || followed.contains(&target) {
+                            tracing::warn!("skipping link {} in a cycle", entry.path().display());
+                            continue;
+                        }
+
+                        let mut followed = followed.clone();
+                        followed.push(target);
+                        paths.push((entry.path(), followed));
+                        continue;
+                    }
+                }
 
                 if file_type.is_dir() {
-                    paths.push(entry.path());
+                    paths.push((entry.path(), followed.clone()));
                 } else if file_type.is_file() {
                     let path = entry.path();
                     let rel_path = path.strip_prefix(&base_path)?;
 
+                    if !published_paths.is_empty()
+                        && !published_paths
+                            .iter()
+                            .any(|published_path| rel_path.starts_with(published_path))
+                    {
+                        continue;
+                    }
+
                     let mut file = fs::OpenOptions::new().read(true).open(&path).await?;
-                    let metadata = file.metadata().await?;
 
                     let mut hasher = models::FileChecksum::blake2b_hasher(layer.project_id);
 
                     let mut buffer = vec![0u8; 1 << 20];
-                    while file.read(&mut buffer).await? != 0 {
-                        hasher.update(&buffer);
+                    loop {
+                        let read = file.read(&mut buffer).await?;
+                        if read == 0 {
+                            break;
+                        }
+
+                        hasher.update(&buffer[..read]);
                     }
 
                     let checksum = hasher.finalize();
@@ -205,56 +291,20 @@ impl BuildService {
                         .is_some();
 
                     if !file_exists {
-                        let mut file_offset = 0;
-                        let mut buffer_offset = 0;
+                        let mut writer = FileWriter::new(self.file_repository, layer.project_id);
 
                         file.rewind().await?;
 
                         loop {
                             let read = file.read(&mut buffer).await?;
-                            buffer_offset += read;
-
-                            if buffer_offset == buffer.len() || buffer_offset > 0 && read == 0 {
-                                let mut data = vec![0u8; buffer_offset];
-                                data.copy_from_slice(&buffer[..buffer_offset]);
-
-                                self.file_repository
-                                    .create_chunk(
-                                        layer.project_id,
-                                        &checksum,
-                                        metadata.len(),
-                                        file_offset,
-                                        data,
-                                    )
-                                    .await?;
-
-                                if buffer_offset < buffer.len() && read == 0 {
-                                    break;
-                                }
-
-                                file_offset += buffer_offset as u64;
-                                buffer_offset = 0;
+                            if read == 0 {
+                                break;
                             }
-                        }
-
-                        self.file_repository
-                            .finish_file(layer.project_id, &checksum, metadata.len())
-                            .await?;
-                    }
-
-                    let mut headers = BTreeMap::new();
 
-                    let content_type =
-                        path.extension()
-                            .and_then(|s| s.to_str())
-                            .and_then(|s| match s {
-                                "html" | "htm" => Some("text/html"),
-                                "css" => Some("text/stylesheet"),
-                                _ => None,
-                            });
+                            writer.write(&buffer[..read]).await?;
+                        }
 
-                    if let Some(content_type) = content_type {
-                        headers.insert("content-type".into(), content_type.into());
+                        writer.finish(&checksum).await?;
                     }
 
                     let mut path = String::new();
@@ -265,15 +315,9 @@ impl BuildService {
                         }
                     }
 
-                    let index_path = path
-                        .strip_suffix("/index.html")
-                        .or_else(|| path.strip_suffix("/index.htm"));
-
-                    if let Some(index_path) = index_path {
-                        let mut path = String::with_capacity(index_path.len() + 1);
-                        path.push_str(index_path);
-                        path.push('/');
+                    let headers = file_headers(&path);
 
+                    for path in file_paths(path) {
                         changes.push(models::LayerChange {
                             project_id: layer.project_id,
                             layer_set_name: layer.layer_set_name.clone(),
@@ -290,22 +334,6 @@ impl BuildService {
                             headers: headers.clone(),
                         });
                     }
-
-                    changes.push(models::LayerChange {
-                        project_id: layer.project_id,
-                        layer_set_name: layer.layer_set_name.clone(),
-                        layer_id: layer.id,
-                        worker_id: self.worker_id,
-                        path,
-                        checksum,
-                        content_encoding_hint: models::ContentEncodingHint::Relative {
-                            identity: 1,
-                            gzip: 0,
-                            zstd: 0,
-                            brotli: 0,
-                        },
-                        headers,
-                    });
                 }
             }
 
@@ -384,27 +412,48 @@ impl BuildService {
             )
             .await?;
 
-        let fqdn = format!("{}.localhost", layer.id.into_uuid().as_hyphenated());
-
-        /*
         self.domain_repository
-            .create_validated_domain(
-                models::ValidatedDomain {
-                    fqdn,
-                    project_id: layer.project_id,
-                    kind: models::ValidatedDomainKind::Layer {
-                        layer_set_name: layer.layer_set_name.clone(),
-                        layer_id: layer.id,
-                    },
-                },
-                models::ValidatedDomainCertificate {
-                    private_key: vec![],
-                    public_key_chain: vec![],
-                },
-            )
+            .create_domain(models::Domain::layer_preview(&layer))
             .await?;
-        */
 
         Ok(())
     }
 }
+
+/// Headers that a file is served with, based on its path.
+pub fn file_headers(path: &str) -> BTreeMap<String, String> {
+    let mut headers = BTreeMap::new();
+
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let content_type = file_name
+        .rsplit_once('.')
+        .and_then(|(_, extension)| match extension {
+            "html" | "htm" => Some("text/html"),
+            "css" => Some("text/stylesheet"),
+            _ => None,
+        });
+
+    if let Some(content_type) = content_type {
+        headers.insert("content-type".into(), content_type.into());
+    }
+
+    headers
+}
+
+/// Paths that a file is served at, index files are also served at the path of their directory.
+pub fn file_paths(path: String) -> Vec<String> {
+    let index_path = path
+        .strip_suffix("/index.html")
+        .or_else(|| path.strip_suffix("/index.htm"));
+
+    match index_path {
+        Some(index_path) => {
+            let mut directory_path = String::with_capacity(index_path.len() + 1);
+            directory_path.push_str(index_path);
+            directory_path.push('/');
+
+            vec![directory_path, path]
+        }
+        None => vec![path],
+    }
+}
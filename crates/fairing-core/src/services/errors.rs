@@ -0,0 +1,26 @@
+use std::fmt;
+
+/// Returned by services when a request can't be carried out as it was made, as opposed to
+/// failures of the repositories. The message is meant for whoever made the request.
+#[derive(Debug)]
+pub enum RequestError {
+    NotAllowed,
+    /// Names the kind of resource that wasn't found, like `layer set`.
+    NotFound(&'static str),
+    InvalidArgument(String),
+    /// The request is valid, but not in the current state of the resource.
+    FailedPrecondition(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::NotAllowed => f.write_str("not allowed"),
+            RequestError::NotFound(resource) => write!(f, "{resource} not found"),
+            RequestError::InvalidArgument(message) => f.write_str(message),
+            RequestError::FailedPrecondition(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
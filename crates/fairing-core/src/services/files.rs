@@ -0,0 +1,215 @@
+use anyhow::{ensure, Context as _, Result};
+
+use crate::{
+    models,
+    repositories::{FileRepository, ProjectRepository},
+};
+
+const BLOB_MIN_SIZE: usize = 262_144;
+const BLOB_AVG_SIZE: usize = 1_048_576;
+const BLOB_MAX_SIZE: usize = 4_194_304;
+
+/// Splits a file into content defined blobs as it is written, so that files that share data only
+/// need to store the shared blobs once.
+pub struct FileWriter {
+    file_repository: &'static dyn FileRepository,
+    project_id: models::ProjectId,
+    /// Data that has not yet been split into blobs.
+    buffer: Vec<u8>,
+    /// Number of bytes stored as blobs so far.
+    offset: u64,
+    blobs: Vec<models::FileBlob>,
+}
+
+impl FileWriter {
+    pub fn new(
+        file_repository: &'static dyn FileRepository,
+        project_id: models::ProjectId,
+    ) -> FileWriter {
+        FileWriter {
+            file_repository,
+            project_id,
+            buffer: Vec::with_capacity(BLOB_MAX_SIZE * 2),
+            offset: 0,
+            blobs: vec![],
+        }
+    }
+
+    pub fn project_id(&self) -> models::ProjectId {
+        self.project_id
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+
+        if self.buffer.len() >= BLOB_MAX_SIZE * 2 {
+            self.flush(false).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores any remaining data and creates the file from the written blobs.
+    pub async fn finish(mut self, checksum: &models::FileChecksum) -> Result<()> {
+        self.flush(true).await?;
+
+        self.file_repository
+            .create_file(self.project_id, checksum, self.offset, &self.blobs)
+            .await
+    }
+
+    async fn flush(&mut self, eof: bool) -> Result<()> {
+        use fastcdc::FastCDC;
+
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut chunked_bytes = 0;
+
+        let chunker = FastCDC::with_eof(&buffer, BLOB_MIN_SIZE, BLOB_AVG_SIZE, BLOB_MAX_SIZE, eof);
+        for chunk in chunker {
+            self.store_blob(&buffer[chunk.offset..chunk.offset + chunk.length])
+                .await?;
+            chunked_bytes += chunk.length;
+        }
+
+        // The chunker leaves the tail of the buffer alone unless it's the end of the file, it
+        // might still be part of a larger chunk once more data has been written.
+        self.buffer = buffer;
+        self.buffer.drain(..chunked_bytes);
+
+        if eof {
+            ensure!(self.buffer.is_empty(), "file was not fully chunked");
+        }
+
+        Ok(())
+    }
+
+    async fn store_blob(&mut self, data: &[u8]) -> Result<()> {
+        let checksum = models::BlobChecksum::blake2b(self.project_id, data);
+
+        let blob_exists = self
+            .file_repository
+            .get_blob(self.project_id, &checksum)
+            .await?
+            .is_some();
+
+        if !blob_exists {
+            let (compression, compressed_data) = models::BlobCompression::compress(data)?;
+
+            let blob = models::CreateBlob {
+                checksum,
+                length: data.len() as u64,
+                compression,
+                data: compressed_data,
+            };
+
+            self.file_repository
+                .create_blob(self.project_id, &blob)
+                .await?;
+        }
+
+        self.blobs.push(models::FileBlob {
+            offset: self.offset,
+            checksum,
+            length: data.len() as u64,
+        });
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Re-encrypts blobs after a project's file encryption key has been rotated, and retires the old
+/// keys once no blobs depend on them anymore.
+pub struct FileEncryptionService {
+    project_repository: &'static dyn ProjectRepository,
+    file_repository: &'static dyn FileRepository,
+}
+
+impl FileEncryptionService {
+    pub fn new(
+        project_repository: &'static dyn ProjectRepository,
+        file_repository: &'static dyn FileRepository,
+    ) -> FileEncryptionService {
+        FileEncryptionService {
+            project_repository,
+            file_repository,
+        }
+    }
+
+    pub async fn reencrypt_files(&self) -> Result<()> {
+        let projects = self
+            .project_repository
+            .list_projects()
+            .await
+            .context("list projects")?;
+
+        for project in projects {
+            let current_key = match project.current_file_encryption_key() {
+                Some(current_key) => current_key,
+                None => continue,
+            };
+
+            let retired_versions = project
+                .file_encryption_keys
+                .iter()
+                .map(|key| key.version)
+                .filter(|version| *version != current_key.version)
+                .collect::<Vec<_>>();
+
+            if retired_versions.is_empty() {
+                continue;
+            }
+
+            let mut reencrypted_blobs = 0;
+
+            loop {
+                let blobs = self
+                    .file_repository
+                    .reencrypt_blobs(project.id, 128)
+                    .await
+                    .context("re-encrypt blobs")?;
+
+                if blobs == 0 {
+                    break;
+                }
+
+                reencrypted_blobs += blobs;
+            }
+
+            tracing::info!(
+                "re-encrypted {reencrypted_blobs} blobs for project {}",
+                project.id.into_uuid()
+            );
+
+            // No blobs being left can also mean that every re-encryption lost a race, and blobs
+            // that were created while re-encrypting can still use an old key.
+            let key_versions = self
+                .file_repository
+                .list_blob_key_versions(project.id)
+                .await
+                .context("list blob key versions")?;
+
+            let (unused_versions, used_versions): (Vec<_>, Vec<_>) = retired_versions
+                .into_iter()
+                .partition(|version| !key_versions.contains(version));
+
+            if !used_versions.is_empty() {
+                tracing::info!(
+                    "keeping file encryption keys {used_versions:?} of project {}, blobs still use them",
+                    project.id.into_uuid()
+                );
+            }
+
+            if unused_versions.is_empty() {
+                continue;
+            }
+
+            self.project_repository
+                .delete_file_encryption_keys(project.id, &unused_versions)
+                .await
+                .context("delete file encryption keys")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{Context as _, Result};
+use chrono::{Duration, Utc};
+use std::{cmp::Reverse, collections::HashSet, ops::AddAssign};
+
+use crate::{
+    models,
+    repositories::{DomainRepository, FileRepository, LayerRepository, ProjectRepository},
+};
+
+/// What garbage collection deleted, or would have deleted in a dry run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GarbageCollectionReport {
+    pub layers: usize,
+    pub layer_members: usize,
+    pub files: usize,
+    /// Total length of the deleted files.
+    pub file_bytes: u64,
+    pub blobs: usize,
+    /// Total uncompressed length of the deleted blobs.
+    pub blob_bytes: u64,
+}
+
+impl AddAssign for GarbageCollectionReport {
+    fn add_assign(&mut self, other: GarbageCollectionReport) {
+        self.layers += other.layers;
+        self.layer_members += other.layer_members;
+        self.files += other.files;
+        self.file_bytes += other.file_bytes;
+        self.blobs += other.blobs;
+        self.blob_bytes += other.blob_bytes;
+    }
+}
+
+/// Deletes layers that fall outside the retention of their layer set, and then the files and blobs
+/// of a project that none of its remaining layers refer to.
+pub struct GarbageCollectionService {
+    project_repository: &'static dyn ProjectRepository,
+    layer_repository: &'static dyn LayerRepository,
+    file_repository: &'static dyn FileRepository,
+    domain_repository: &'static dyn DomainRepository,
+    grace_period: Duration,
+}
+
+impl GarbageCollectionService {
+    pub fn new(
+        project_repository: &'static dyn ProjectRepository,
+        layer_repository: &'static dyn LayerRepository,
+        file_repository: &'static dyn FileRepository,
+        domain_repository: &'static dyn DomainRepository,
+    ) -> GarbageCollectionService {
+        GarbageCollectionService {
+            project_repository,
+            layer_repository,
+            file_repository,
+            domain_repository,
+            grace_period: Duration::days(1),
+        }
+    }
+
+    /// Sets how long files and blobs are kept after they were stored even if no layer refers to
+    /// them, defaults to a day. Builds and uploads store files before any layer refers to them.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> GarbageCollectionService {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Collects the garbage of every project. Nothing is deleted in a dry run, but the report is
+    /// the same.
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<GarbageCollectionReport> {
+        let projects = self
+            .project_repository
+            .list_projects()
+            .await
+            .context("list projects")?;
+
+        let mut report = GarbageCollectionReport::default();
+
+        for project in projects {
+            let project_report = self
+                .collect_project_garbage(project.id, dry_run)
+                .await
+                .with_context(|| format!("project {}", project.id.into_uuid()))?;
+
+            tracing::info!(
+                "collected garbage of project {}: {project_report:?}",
+                project.id.into_uuid()
+            );
+
+            report += project_report;
+        }
+
+        Ok(report)
+    }
+
+    pub async fn collect_project_garbage(
+        &self,
+        project_id: models::ProjectId,
+        dry_run: bool,
+    ) -> Result<GarbageCollectionReport> {
+        let mut report = GarbageCollectionReport::default();
+
+        let routed_layer_ids = self
+            .domain_repository
+            .list_domains(project_id)
+            .await?
+            .into_iter()
+            .filter_map(|domain| match domain.kind {
+                models::DomainKind::Layer { layer_id, .. }
+                    if domain.fqdn != models::Domain::layer_preview_fqdn(layer_id) =>
+                {
+                    Some(layer_id.into_uuid())
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        let mut live_layers = vec![];
+        let mut has_pending_layers = false;
+
+        let abandoned_uploads_before = Utc::now() - Duration::days(1);
+        // Files and blobs stored after this might belong to layers created after they are listed.
+        let stored_before = Utc::now() - self.grace_period;
+
+        for layer_set in self.layer_repository.list_layer_sets(project_id).await? {
+            let retention = self
+                .layer_repository
+                .get_layer_set_retention(project_id, &layer_set.name)
+                .await?
+                .unwrap_or_default();
+
+            let mut layers = self
+                .layer_repository
+                .list_layers(project_id, &layer_set.name)
+                .await?;
+            layers.sort_by_key(|layer| Reverse(layer.id.into_uuid()));
+
+            let mut ready_layers = 0;
+
+            for layer in layers {
+                let keep = match layer.status {
+                    // Uploads that are never finalized would keep files from being collected.
+                    models::LayerStatus::Building
+                        if layer.source.is_none()
+                            && layer.id.created_at() < abandoned_uploads_before =>
+                    {
+                        false
+                    }
+                    models::LayerStatus::Building | models::LayerStatus::Finalizing => {
+                        has_pending_layers = true;
+                        true
+                    }
+                    models::LayerStatus::Ready => {
+                        ready_layers += 1;
+
+                        ready_layers <= retention.keep_ready_layers
+                            || layer_set.build_status.last_layer_id == Some(layer.id)
+                            || layer_set.pinned_layer_id == Some(layer.id)
+                            || routed_layer_ids.contains(&layer.id.into_uuid())
+                    }
+                    models::LayerStatus::Cancelled => false,
+                };
+
+                if keep {
+                    live_layers.push(layer);
+                    continue;
+                }
+
+                let layer_members = self
+                    .layer_repository
+                    .list_layer_members(project_id, &layer.layer_set_name, layer.id)
+                    .await?;
+
+                report.layers += 1;
+                report.layer_members += layer_members.len();
+
+                if !dry_run {
+                    self.domain_repository
+                        .delete_domain(&models::Domain::layer_preview_fqdn(layer.id))
+                        .await?;
+
+                    self.layer_repository
+                        .delete_layer(project_id, &layer.layer_set_name, layer.id)
+                        .await?;
+                }
+            }
+        }
+
+        // Builds store files before they're part of any layer, so they could be swept away.
+        if has_pending_layers {
+            tracing::info!(
+                "not collecting files of project {} while layers are being built",
+                project_id.into_uuid()
+            );
+            return Ok(report);
+        }
+
+        let mut live_files = HashSet::new();
+
+        for layer in &live_layers {
+            let layer_members = self
+                .layer_repository
+                .list_layer_members(project_id, &layer.layer_set_name, layer.id)
+                .await?;
+
+            live_files.extend(
+                layer_members
+                    .into_iter()
+                    .map(|layer_member| layer_member.checksum),
+            );
+        }
+
+        let mut live_blobs = HashSet::new();
+        let mut dead_files = vec![];
+
+        for file in self.file_repository.list_files(project_id).await? {
+            if live_files.contains(&file.checksum) || file.created_at > stored_before {
+                let file_blobs = self
+                    .file_repository
+                    .list_file_blobs(project_id, &file.checksum)
+                    .await?;
+
+                live_blobs.extend(file_blobs.into_iter().map(|file_blob| file_blob.checksum));
+            } else {
+                report.files += 1;
+                report.file_bytes += file.length;
+                dead_files.push(file.checksum);
+            }
+        }
+
+        let mut dead_blobs = vec![];
+
+        for blob in self.file_repository.list_blobs(project_id).await? {
+            if !live_blobs.contains(&blob.checksum) && blob.created_at <= stored_before {
+                report.blobs += 1;
+                report.blob_bytes += blob.length;
+                dead_blobs.push(blob.checksum);
+            }
+        }
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        // A build that started while the files were being marked might already depend on them.
+        if self.has_pending_layers(project_id).await? {
+            tracing::info!(
+                "not collecting files of project {}, a build started",
+                project_id.into_uuid()
+            );
+
+            return Ok(GarbageCollectionReport {
+                files: 0,
+                file_bytes: 0,
+                blobs: 0,
+                blob_bytes: 0,
+                ..report
+            });
+        }
+
+        for checksum in &dead_files {
+            self.file_repository
+                .delete_file(project_id, checksum)
+                .await?;
+        }
+
+        for checksum in &dead_blobs {
+            self.file_repository
+                .delete_blob(project_id, checksum)
+                .await?;
+        }
+
+        Ok(report)
+    }
+
+    async fn has_pending_layers(&self, project_id: models::ProjectId) -> Result<bool> {
+        for layer_set in self.layer_repository.list_layer_sets(project_id).await? {
+            let layers = self
+                .layer_repository
+                .list_layers(project_id, &layer_set.name)
+                .await?;
+
+            let has_pending_layers = layers.iter().any(|layer| {
+                matches!(
+                    layer.status,
+                    models::LayerStatus::Building | models::LayerStatus::Finalizing
+                )
+            });
+
+            if has_pending_layers {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
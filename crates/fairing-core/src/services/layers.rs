@@ -1,8 +1,12 @@
 use anyhow::{anyhow, ensure, Result};
 
-use super::auth::{Authentication, LayerPermissions, LayerSetPermissions};
+use super::{
+    auth::{Authentication, LayerPermissions, LayerSetPermissions},
+    RequestError,
+};
 use crate::{models, repositories::LayerRepository};
 
+#[derive(Copy, Clone)]
 pub struct LayerService {
     repository: &'static dyn LayerRepository,
 }
@@ -41,8 +45,23 @@ impl LayerService {
                 let kind = match (&source.kind, kind) {
                     (
                         &models::SourceKind::Git { .. },
-                        models::CreateLayerSetSourceKind::Git { ref_ },
-                    ) => models::LayerSetSourceKind::Git { ref_: ref_.clone() },
+                        models::CreateLayerSetSourceKind::Git { ref_, root, paths },
+                    ) => {
+                        let paths = paths
+                            .iter()
+                            .map(|path| {
+                                let path = models::normalize_source_path(path)?;
+                                ensure!(!path.is_empty(), "paths of a source can't be empty");
+                                Ok(path)
+                            })
+                            .collect::<Result<_>>()?;
+
+                        models::LayerSetSourceKind::Git {
+                            ref_: ref_.clone(),
+                            root: models::normalize_source_path(root)?,
+                            paths,
+                        }
+                    }
                 };
 
                 Some(models::LayerSetSource {
@@ -62,6 +81,9 @@ impl LayerService {
                 current_layer_id: None,
                 last_layer_id: None,
             },
+            pinned_layer_id: None,
+            template_name: None,
+            archived: false,
         };
 
         self.repository.create_layer_set(&layer_set).await?;
@@ -69,6 +91,139 @@ impl LayerService {
         Ok(layer_set)
     }
 
+    pub async fn get_layer_set_retention(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<models::LayerSetRetention> {
+        auth.can(LayerSetPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let retention = self
+            .repository
+            .get_layer_set_retention(project_id, layer_set_name)
+            .await?;
+
+        Ok(retention.unwrap_or_default())
+    }
+
+    pub async fn set_layer_set_retention(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        retention: &models::LayerSetRetention,
+    ) -> Result<()> {
+        auth.can(LayerSetPermissions::Update)?;
+        let project_id = auth.project_id()?;
+
+        ensure!(
+            retention.keep_ready_layers >= 1,
+            RequestError::InvalidArgument("at least one ready layer must be kept".into())
+        );
+
+        self.repository
+            .get_layer_set(project_id, layer_set_name)
+            .await?
+            .ok_or(RequestError::NotFound("layer set"))?;
+
+        self.repository
+            .set_layer_set_retention(project_id, layer_set_name, retention)
+            .await
+    }
+
+    pub async fn get_ref_layer_set_limit(
+        &self,
+        auth: &Authentication,
+    ) -> Result<models::RefLayerSetLimit> {
+        auth.can(LayerSetPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let limit = self.repository.get_ref_layer_set_limit(project_id).await?;
+
+        Ok(limit.unwrap_or_default())
+    }
+
+    /// Limits how many layer sets are created from ref patterns, layer sets that already exist
+    /// above the limit are kept until their ref is deleted.
+    pub async fn set_ref_layer_set_limit(
+        &self,
+        auth: &Authentication,
+        limit: &models::RefLayerSetLimit,
+    ) -> Result<()> {
+        auth.can(LayerSetPermissions::Update)?;
+        let project_id = auth.project_id()?;
+
+        self.repository
+            .set_ref_layer_set_limit(project_id, limit)
+            .await
+    }
+
+    /// Serves `layer_id` from the layer set until it is unpinned, regardless of newer builds.
+    pub async fn pin_layer(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        auth.can(LayerSetPermissions::Update)?;
+        let project_id = auth.project_id()?;
+
+        let layer = self
+            .repository
+            .list_layers(project_id, layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == layer_id)
+            .ok_or(RequestError::NotFound("layer"))?;
+
+        ensure!(
+            matches!(layer.status, models::LayerStatus::Ready),
+            RequestError::FailedPrecondition("only ready layers can be pinned".into())
+        );
+
+        self.repository
+            .set_pinned_layer_id(project_id, layer_set_name, Some(layer_id))
+            .await
+    }
+
+    /// Serves the last ready layer of the layer set again.
+    pub async fn unpin_layer_set(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<()> {
+        auth.can(LayerSetPermissions::Update)?;
+        let project_id = auth.project_id()?;
+
+        self.repository
+            .get_layer_set(project_id, layer_set_name)
+            .await?
+            .ok_or(RequestError::NotFound("layer set"))?;
+
+        self.repository
+            .set_pinned_layer_id(project_id, layer_set_name, None)
+            .await
+    }
+
+    pub async fn get_layer(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<Option<models::Layer>> {
+        auth.can(LayerPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let layer = self
+            .repository
+            .list_layers(project_id, layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == layer_id);
+
+        Ok(layer)
+    }
+
     pub async fn create_layer(
         &self,
         auth: &Authentication,
@@ -106,7 +261,11 @@ impl LayerService {
                     "no source data configured on layer, but layer set specified a source"
                 ))
             }
-            (None, None) => None,
+            (None, None) => {
+                return Err(anyhow!(
+                    "no source configured on layer set, upload the layer instead"
+                ))
+            }
         };
 
         let layer = models::Layer {
@@ -0,0 +1,256 @@
+use anyhow::{anyhow, ensure, Result};
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    auth::{Authentication, LayerPermissions},
+    RequestError,
+};
+use crate::{
+    models,
+    repositories::{FileRepository, LayerRepository},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Lists the files of layers and compares layers of the same layer set with each other.
+#[derive(Copy, Clone)]
+pub struct LayerMemberService {
+    layer_repository: &'static dyn LayerRepository,
+    file_repository: &'static dyn FileRepository,
+}
+
+impl LayerMemberService {
+    pub fn new(
+        layer_repository: &'static dyn LayerRepository,
+        file_repository: &'static dyn FileRepository,
+    ) -> LayerMemberService {
+        LayerMemberService {
+            layer_repository,
+            file_repository,
+        }
+    }
+
+    /// Returns a page of members ordered by path, and the token of the next page if there is one.
+    pub async fn list_layer_members(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        list: &models::ListLayerMembers,
+    ) -> Result<(Vec<models::LayerMemberFile>, Option<String>)> {
+        auth.can(LayerPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let page_size = match list.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+
+        let layer_members = self
+            .get_layer_members(project_id, layer_set_name, layer_id)
+            .await?;
+
+        let mut layer_members = layer_members
+            .into_values()
+            .filter(|layer_member| layer_member.path.starts_with(&list.prefix))
+            .filter(|layer_member| match list.page_token {
+                Some(ref page_token) => layer_member.path.as_str() > page_token.as_str(),
+                None => true,
+            })
+            .take(page_size + 1)
+            .collect::<Vec<_>>();
+
+        let next_page_token = if layer_members.len() > page_size {
+            layer_members.truncate(page_size);
+            layer_members
+                .last()
+                .map(|layer_member| layer_member.path.clone())
+        } else {
+            None
+        };
+
+        let mut lengths = HashMap::new();
+        let mut files = Vec::with_capacity(layer_members.len());
+
+        for layer_member in layer_members {
+            let length = self
+                .get_file_length(project_id, &mut lengths, layer_member.checksum)
+                .await?;
+
+            files.push(models::LayerMemberFile {
+                path: layer_member.path,
+                checksum: layer_member.checksum,
+                length,
+                headers: layer_member.headers,
+            });
+        }
+
+        Ok((files, next_page_token))
+    }
+
+    /// Lists the paths that were added, modified or deleted in a layer compared to a base layer,
+    /// ordered by path.
+    pub async fn diff_layers(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        base_layer_id: models::LayerId,
+        layer_id: models::LayerId,
+    ) -> Result<Vec<models::LayerDiffEntry>> {
+        auth.can(LayerPermissions::Get)?;
+        let project_id = auth.project_id()?;
+
+        let base_layer_members = self
+            .get_layer_members(project_id, layer_set_name, base_layer_id)
+            .await?;
+
+        let mut layer_members = self
+            .get_layer_members(project_id, layer_set_name, layer_id)
+            .await?;
+
+        let mut changes = vec![];
+
+        for (path, base_layer_member) in base_layer_members {
+            match layer_members.remove(&path) {
+                Some(layer_member)
+                    if layer_member.checksum == base_layer_member.checksum
+                        && layer_member.headers == base_layer_member.headers => {}
+                Some(layer_member) => changes.push((
+                    path,
+                    models::LayerDiffKind::Modified,
+                    Some(base_layer_member.checksum),
+                    Some(layer_member.checksum),
+                )),
+                None => changes.push((
+                    path,
+                    models::LayerDiffKind::Deleted,
+                    Some(base_layer_member.checksum),
+                    None,
+                )),
+            }
+        }
+
+        for (path, layer_member) in layer_members {
+            changes.push((
+                path,
+                models::LayerDiffKind::Added,
+                None,
+                Some(layer_member.checksum),
+            ));
+        }
+
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut lengths = HashMap::new();
+        let mut diff = Vec::with_capacity(changes.len());
+
+        for (path, kind, old_checksum, new_checksum) in changes {
+            let old_length = match old_checksum {
+                Some(checksum) => Some(
+                    self.get_file_length(project_id, &mut lengths, checksum)
+                        .await?,
+                ),
+                None => None,
+            };
+
+            let new_length = match new_checksum {
+                Some(checksum) => Some(
+                    self.get_file_length(project_id, &mut lengths, checksum)
+                        .await?,
+                ),
+                None => None,
+            };
+
+            diff.push(models::LayerDiffEntry {
+                path,
+                kind,
+                old_length,
+                new_length,
+            });
+        }
+
+        Ok(diff)
+    }
+
+    /// Returns the members that are served for a ready layer, by path.
+    async fn get_layer_members(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<BTreeMap<String, models::LayerMember>> {
+        let layer = self
+            .layer_repository
+            .list_layers(project_id, layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == layer_id)
+            .ok_or(RequestError::NotFound("layer"))?;
+
+        ensure!(
+            matches!(layer.status, models::LayerStatus::Ready),
+            RequestError::FailedPrecondition("layer is not ready".into())
+        );
+
+        list_live_layer_members(self.layer_repository, project_id, layer_set_name, layer_id).await
+    }
+
+    async fn get_file_length(
+        &self,
+        project_id: models::ProjectId,
+        lengths: &mut HashMap<models::FileChecksum, u64>,
+        checksum: models::FileChecksum,
+    ) -> Result<u64> {
+        if let Some(length) = lengths.get(&checksum) {
+            return Ok(*length);
+        }
+
+        let length = self
+            .file_repository
+            .get_file(project_id, &checksum)
+            .await?
+            .map(|file| file.length)
+            .ok_or_else(|| anyhow!("file {} not found", checksum.to_hex()))?;
+
+        lengths.insert(checksum, length);
+
+        Ok(length)
+    }
+}
+
+/// Resolves the members of a layer the way they are served: every path has the member of the
+/// newest layer up to and including `layer_id` that has one. Paths whose newest member is a
+/// deletion marker are left out.
+pub(super) async fn list_live_layer_members(
+    layer_repository: &dyn LayerRepository,
+    project_id: models::ProjectId,
+    layer_set_name: &models::LayerSetName,
+    layer_id: models::LayerId,
+) -> Result<BTreeMap<String, models::LayerMember>> {
+    let mut layer_ids = layer_repository
+        .list_layers(project_id, layer_set_name)
+        .await?
+        .into_iter()
+        .map(|layer| layer.id.into_uuid())
+        .filter(|id| *id <= layer_id.into_uuid())
+        .collect::<Vec<_>>();
+    layer_ids.sort();
+
+    let mut layer_members = BTreeMap::new();
+
+    for id in layer_ids {
+        let own_layer_members = layer_repository
+            .list_layer_members(project_id, layer_set_name, id.into())
+            .await?;
+
+        for layer_member in own_layer_members {
+            layer_members.insert(layer_member.path.clone(), layer_member);
+        }
+    }
+
+    layer_members.retain(|_, layer_member| layer_member.checksum != models::FileChecksum::Deleted);
+
+    Ok(layer_members)
+}
@@ -1,15 +1,29 @@
+mod archive;
 mod auth;
 mod build;
 mod domains;
+mod errors;
+mod files;
+mod gc;
 mod layers;
+mod members;
 mod projects;
+mod promotions;
 mod sources;
+mod uploads;
 mod web;
 
+pub use archive::*;
 pub use auth::*;
 pub use build::*;
 pub use domains::*;
+pub use errors::*;
+pub use files::*;
+pub use gc::*;
 pub use layers::*;
+pub use members::*;
 pub use projects::*;
+pub use promotions::*;
 pub use sources::*;
+pub use uploads::*;
 pub use web::*;
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use getrandom::getrandom;
 use uuid::Uuid;
 
@@ -36,20 +36,47 @@ impl ProjectService {
             hex::encode(label)
         };
 
-        let file_encryption_key = {
-            let mut key = [0u8; 32];
-            getrandom(&mut key)?;
-            key.to_vec()
-        };
+        let file_encryption_key = models::FileEncryptionKey::generate(1)?;
 
         let project = models::Project {
             id: Uuid::new_v4().into(),
             acme_dns_challenge_label,
-            file_encryption_key,
+            file_encryption_keys: vec![file_encryption_key],
         };
 
         self.repository.create_or_update_project(&project).await?;
 
         Ok(project)
     }
+
+    /// Creates a new file encryption key for the project. New blobs are encrypted with the new key
+    /// right away, existing blobs are re-encrypted by the [super::FileEncryptionService].
+    pub async fn rotate_file_encryption_key(
+        &self,
+        auth: &Authentication,
+    ) -> Result<models::Project> {
+        auth.can(ProjectPermissions::RotateFileEncryptionKey)?;
+        let project_id = auth.project_id()?;
+
+        let mut project = self
+            .repository
+            .get_project(&project_id)
+            .await?
+            .ok_or_else(|| anyhow!("project not found"))?;
+
+        let version = project
+            .current_file_encryption_key()
+            .map(|key| key.version + 1)
+            .unwrap_or(1);
+
+        let file_encryption_key = models::FileEncryptionKey::generate(version)?;
+
+        self.repository
+            .create_file_encryption_key(project_id, &file_encryption_key)
+            .await?;
+
+        project.file_encryption_keys.push(file_encryption_key);
+
+        Ok(project)
+    }
 }
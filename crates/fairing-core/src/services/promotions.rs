@@ -0,0 +1,214 @@
+use anyhow::{ensure, Result};
+
+use super::{
+    auth::{Authentication, LayerPermissions},
+    members::list_live_layer_members,
+    uploads::deleted_layer_member,
+    RequestError,
+};
+use crate::{
+    models,
+    repositories::{DomainRepository, LayerRepository},
+};
+
+/// Promotes ready layers from one layer set to another, e.g. from staging to production.
+///
+/// The promoted layer serves the same members as the layer it was promoted from, the files are
+/// shared between them and aren't copied or built again.
+#[derive(Copy, Clone)]
+pub struct LayerPromotionService {
+    layer_repository: &'static dyn LayerRepository,
+    domain_repository: &'static dyn DomainRepository,
+    worker_id: models::WorkerId,
+}
+
+impl LayerPromotionService {
+    pub fn new(
+        layer_repository: &'static dyn LayerRepository,
+        domain_repository: &'static dyn DomainRepository,
+    ) -> LayerPromotionService {
+        LayerPromotionService {
+            layer_repository,
+            domain_repository,
+            worker_id: models::WorkerId::new(),
+        }
+    }
+
+    /// Creates a new layer in `layer_set_name` with the members of a ready layer of another layer
+    /// set. The target layer set can't have a source, its builds would replace the promoted layer.
+    pub async fn promote_layer(
+        &self,
+        auth: &Authentication,
+        source_layer_set_name: &models::LayerSetName,
+        source_layer_id: models::LayerId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<models::Layer> {
+        auth.can(LayerPermissions::Promote)?;
+        let project_id = auth.project_id()?;
+
+        ensure!(
+            source_layer_set_name != layer_set_name,
+            RequestError::InvalidArgument(
+                "layers can only be promoted to another layer set".into()
+            )
+        );
+
+        let source_layer = self
+            .layer_repository
+            .list_layers(project_id, source_layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == source_layer_id)
+            .ok_or(RequestError::NotFound("layer"))?;
+
+        ensure!(
+            matches!(source_layer.status, models::LayerStatus::Ready),
+            RequestError::FailedPrecondition("only ready layers can be promoted".into())
+        );
+
+        let layer_set = self
+            .layer_repository
+            .get_layer_set(project_id, layer_set_name)
+            .await?
+            .ok_or(RequestError::NotFound("layer set"))?;
+
+        ensure!(
+            layer_set.source.is_none(),
+            RequestError::FailedPrecondition(
+                "layers can only be promoted to layer sets without a source".into()
+            )
+        );
+
+        let layer_id = models::LayerId::new()?;
+
+        let layer = models::Layer {
+            project_id,
+            layer_set_name: layer_set.name,
+            id: layer_id,
+            status: models::LayerStatus::Building,
+            source: Some(models::LayerSource::Promotion {
+                layer_set_name: source_layer.layer_set_name.clone(),
+                layer_id: source_layer.id,
+            }),
+        };
+
+        self.layer_repository.create_layer(&layer).await?;
+
+        if let Err(err) = self.promote_into_layer(&source_layer, &layer).await {
+            let res = self
+                .layer_repository
+                .cancel_layer(project_id, &layer.layer_set_name, layer.id)
+                .await;
+
+            if let Err(cancel_err) = res {
+                tracing::error!(
+                    "error cancelling layer ({}): {:?}",
+                    layer.id.into_uuid(),
+                    cancel_err
+                );
+            }
+
+            return Err(err);
+        }
+
+        Ok(models::Layer {
+            status: models::LayerStatus::Ready,
+            ..layer
+        })
+    }
+
+    async fn promote_into_layer(
+        &self,
+        source_layer: &models::Layer,
+        layer: &models::Layer,
+    ) -> Result<()> {
+        self.layer_repository
+            .try_set_current_build(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.layer_repository
+            .build_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        self.layer_repository
+            .finish_build(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        self.layer_repository
+            .finalize_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        // The promoted layer is served with the same members as the source layer, including the
+        // ones it inherits, and the members of earlier layers of the layer set are deleted.
+        let source_layer_members = list_live_layer_members(
+            self.layer_repository,
+            source_layer.project_id,
+            &source_layer.layer_set_name,
+            source_layer.id,
+        )
+        .await?;
+
+        let live_layer_members = list_live_layer_members(
+            self.layer_repository,
+            layer.project_id,
+            &layer.layer_set_name,
+            layer.id,
+        )
+        .await?;
+
+        let mut layer_members = live_layer_members
+            .into_keys()
+            .filter(|path| !source_layer_members.contains_key(path))
+            .map(|path| deleted_layer_member(layer, path))
+            .collect::<Vec<_>>();
+
+        layer_members.extend(source_layer_members.into_values().map(|layer_member| {
+            models::LayerMember {
+                layer_set_name: layer.layer_set_name.clone(),
+                layer_id: layer.id,
+                ..layer_member
+            }
+        }));
+
+        for layer_members in layer_members.chunks(128) {
+            self.layer_repository
+                .create_layer_members(layer_members)
+                .await?;
+        }
+
+        self.layer_repository
+            .finish_finalizing(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        // The layer only counts as the last layer of the layer set once it has been copied.
+        self.layer_repository
+            .set_last_layer_id(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.domain_repository
+            .create_domain(models::Domain::layer_preview(layer))
+            .await?;
+
+        Ok(())
+    }
+}
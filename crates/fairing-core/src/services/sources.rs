@@ -1,15 +1,20 @@
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, ensure, Context as _, Result};
 
-use super::auth::{Authentication, SourcePermissions};
+use super::{
+    auth::{Authentication, SourcePermissions},
+    RequestError,
+};
 use crate::{
     models,
-    repositories::{GitSourceRepository, LayerRepository, SourceRepository},
+    repositories::{DomainRepository, GitSourceRepository, LayerRepository, SourceRepository},
 };
 
+#[derive(Copy, Clone)]
 pub struct SourceService {
     repository: &'static dyn SourceRepository,
     git_repository: &'static dyn GitSourceRepository,
     layer_repository: &'static dyn LayerRepository,
+    domain_repository: &'static dyn DomainRepository,
 }
 
 impl SourceService {
@@ -17,11 +22,13 @@ impl SourceService {
         repository: &'static dyn SourceRepository,
         git_repository: &'static dyn GitSourceRepository,
         layer_repository: &'static dyn LayerRepository,
+        domain_repository: &'static dyn DomainRepository,
     ) -> SourceService {
         SourceService {
             repository,
             git_repository,
             layer_repository,
+            domain_repository,
         }
     }
 
@@ -51,11 +58,28 @@ impl SourceService {
         let project_id = auth.project_id()?;
 
         let kind = match source.kind {
-            models::CreateSourceKind::Git { ref repository_url } => {
+            models::CreateSourceKind::Git {
+                ref repository_url,
+                ref http_token,
+            } => {
+                // The token is sent as the password of the repository url, it would be readable
+                // by anyone on the way to a repository that isn't reached over https.
+                if http_token.is_some() {
+                    let transport = repository_url.parts()?.transport;
+                    ensure!(
+                        transport == models::GitTransport::Https,
+                        RequestError::InvalidArgument(
+                            "http tokens can only be used with https repository urls".into()
+                        )
+                    );
+                }
+
                 let id_ed25519 = models::Ed25519::generate();
                 models::SourceKind::Git {
                     repository_url: repository_url.clone(),
                     id_ed25519,
+                    known_host_keys: vec![],
+                    http_token: http_token.clone(),
                 }
             }
         };
@@ -64,6 +88,7 @@ impl SourceService {
             project_id,
             name: source.name.clone(),
             kind,
+            status: models::SourceStatus::Ok,
         };
 
         self.repository
@@ -74,6 +99,45 @@ impl SourceService {
         Ok(source)
     }
 
+    /// Replaces the known host keys of a git source, a source with a host key mismatch is
+    /// refreshed again once one of the new host keys is presented.
+    pub async fn pin_host_keys(
+        &self,
+        auth: &Authentication,
+        source_name: &models::SourceName,
+        host_keys: Vec<models::GitHostKey>,
+    ) -> Result<models::Source> {
+        auth.can(SourcePermissions::Update)?;
+        let project_id = auth.project_id()?;
+
+        if host_keys.is_empty() {
+            return Err(anyhow!("at least one host key must be pinned"));
+        }
+
+        let mut source = self
+            .repository
+            .get_source(&project_id, source_name)
+            .await
+            .context("get source")?
+            .ok_or_else(|| anyhow!("source not found"))?;
+
+        match source.kind {
+            models::SourceKind::Git {
+                ref mut known_host_keys,
+                ..
+            } => *known_host_keys = host_keys,
+        }
+
+        source.status = models::SourceStatus::Ok;
+
+        self.repository
+            .create_or_update_source(&source)
+            .await
+            .context("update source")?;
+
+        Ok(source)
+    }
+
     pub async fn refresh_source(
         &self,
         auth: &Authentication,
@@ -89,32 +153,74 @@ impl SourceService {
             .context("get source")?
             .ok_or_else(|| anyhow!("source not found"))?;
 
-        let layer_sets = self
+        let mut layer_sets = self
             .layer_repository
             .list_layer_sets_for_source(project_id, &source.name)
             .await
             .context("list layer sets for source")?;
 
+        // Only the refs that layer sets track are listed, repositories can have a lot of refs.
+        // Refs of archived layer sets are still listed as long as their ref pattern is.
+        let mut ref_prefixes = layer_sets
+            .iter()
+            .filter(|layer_set| !layer_set.archived)
+            .filter_map(|layer_set| match &layer_set.source {
+                Some(models::LayerSetSource {
+                    kind: models::LayerSetSourceKind::Git { ref_, .. },
+                    ..
+                }) => Some(models::ref_pattern_prefix(ref_).to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        ref_prefixes.sort();
+        ref_prefixes.dedup();
+
         match source.kind {
             models::SourceKind::Git { .. } => {
-                let source = source.try_with_kind()?;
-                let refs_and_commits = self
+                let git_source = source.clone().try_with_kind::<models::GitSource>()?;
+                let latest = match self
                     .git_repository
-                    .git_list_latest(&source)
+                    .git_list_latest(&git_source, &ref_prefixes)
                     .await
-                    .context("git list latest")?;
-
-                for ref_and_commit in refs_and_commits {
-                    let layer_sets =
-                        layer_sets
-                            .iter()
-                            .filter(|layer_set| match &layer_set.source {
-                                Some(models::LayerSetSource {
-                                    kind: models::LayerSetSourceKind::Git { ref_ },
-                                    ..
-                                }) if ref_ == &ref_and_commit.ref_ => true,
-                                _ => false,
-                            });
+                {
+                    Ok(latest) => latest,
+                    Err(err) => {
+                        if let Some(mismatch) = err.downcast_ref::<models::GitHostKeyMismatch>() {
+                            let source = models::Source {
+                                status: models::SourceStatus::HostKeyMismatch {
+                                    host_key: mismatch.host_key.clone(),
+                                },
+                                ..source
+                            };
+
+                            self.repository
+                                .create_or_update_source(&source)
+                                .await
+                                .context("update source status")?;
+                        }
+
+                        return Err(err).context("git list latest");
+                    }
+                };
+
+                self.update_host_key(source, &git_source.with, latest.host_key)
+                    .await?;
+
+                self.update_ref_layer_sets(project_id, &mut layer_sets, &latest.refs_and_commits)
+                    .await?;
+
+                for ref_and_commit in latest.refs_and_commits {
+                    let layer_sets = layer_sets
+                        .iter()
+                        .filter(|layer_set| !layer_set.archived)
+                        .filter(|layer_set| match &layer_set.source {
+                            Some(models::LayerSetSource {
+                                kind: models::LayerSetSourceKind::Git { ref_, .. },
+                                ..
+                            }) if ref_ == &ref_and_commit.ref_ => true,
+                            _ => false,
+                        });
 
                     for layer_set in layer_sets {
                         let last_layer = self
@@ -123,39 +229,404 @@ impl SourceService {
                             .await
                             .context("get last layer")?;
 
-                        match last_layer {
+                        let changed = match last_layer {
                             Some(models::Layer {
                                 source: Some(models::LayerSource::Git { ref commit }),
                                 ..
-                            }) if commit == &ref_and_commit.commit => (),
-                            _ => {
-                                let layer_id = models::LayerId::new()?;
-
-                                self.layer_repository
-                                    .set_last_layer_id(project_id, &layer_set.name, layer_id)
-                                    .await?;
-
-                                let layer = models::Layer {
-                                    project_id,
-                                    layer_set_name: layer_set.name.clone(),
-                                    id: layer_id,
-                                    status: models::LayerStatus::Building,
-                                    source: Some(models::LayerSource::Git {
-                                        commit: ref_and_commit.commit.clone(),
-                                    }),
-                                };
-
-                                self.layer_repository
-                                    .create_layer(&layer)
-                                    .await
-                                    .context("create layer")?;
+                            }) if commit == &ref_and_commit.commit => false,
+                            Some(models::Layer {
+                                source: Some(models::LayerSource::Git { ref commit }),
+                                ..
+                            }) => {
+                                self.tracked_paths_changed(
+                                    &git_source,
+                                    layer_set,
+                                    commit,
+                                    &ref_and_commit,
+                                )
+                                .await?
                             }
+                            _ => true,
+                        };
+
+                        if !changed {
+                            continue;
+                        }
+
+                        let layer_id = models::LayerId::new()?;
+
+                        self.layer_repository
+                            .set_last_layer_id(project_id, &layer_set.name, layer_id)
+                            .await?;
+
+                        let layer = models::Layer {
+                            project_id,
+                            layer_set_name: layer_set.name.clone(),
+                            id: layer_id,
+                            status: models::LayerStatus::Building,
+                            source: Some(models::LayerSource::Git {
+                                commit: ref_and_commit.commit.clone(),
+                            }),
+                        };
+
+                        self.layer_repository
+                            .create_layer(&layer)
+                            .await
+                            .context("create layer")?;
+                    }
+                }
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Creates a layer set from each layer set with a ref pattern for the refs matching it, and
+    /// archives the layer sets whose ref was deleted. Refs that match once the project has as
+    /// many of these layer sets as its limit allows are skipped until others are archived.
+    async fn update_ref_layer_sets(
+        &self,
+        project_id: models::ProjectId,
+        layer_sets: &mut Vec<models::LayerSet>,
+        refs_and_commits: &[models::GitSourceRefAndCommit],
+    ) -> Result<()> {
+        for layer_set in layer_sets.iter_mut() {
+            let ref_ = match layer_set {
+                models::LayerSet {
+                    template_name: Some(_),
+                    archived: false,
+                    source:
+                        Some(models::LayerSetSource {
+                            kind: models::LayerSetSourceKind::Git { ref_, .. },
+                            ..
+                        }),
+                    ..
+                } => ref_,
+                _ => continue,
+            };
+
+            if refs_and_commits
+                .iter()
+                .any(|ref_and_commit| &ref_and_commit.ref_ == ref_)
+            {
+                continue;
+            }
+
+            tracing::info!(
+                "archiving layer set {}, {ref_} was deleted",
+                layer_set.name.as_str()
+            );
+
+            self.layer_repository
+                .set_layer_set_archived(project_id, &layer_set.name, true)
+                .await
+                .context("archive layer set")?;
+
+            let fqdn = models::Domain::layer_set_preview_fqdn(project_id, &layer_set.name);
+            let domain = self
+                .domain_repository
+                .get_domain(&fqdn)
+                .await
+                .context("get preview domain")?;
+
+            match domain {
+                Some(domain) if domain.is_layer_set_preview(project_id, &layer_set.name) => {
+                    self.domain_repository
+                        .delete_domain(&fqdn)
+                        .await
+                        .context("delete preview domain")?;
+                }
+                Some(_) => {
+                    tracing::warn!(
+                        "not deleting {fqdn}, it isn't the preview domain of layer set {}",
+                        layer_set.name.as_str()
+                    );
+                }
+                None => (),
+            }
+
+            layer_set.archived = true;
+        }
+
+        let templates = layer_sets
+            .iter()
+            .filter(|layer_set| !layer_set.archived && layer_set.template_name.is_none())
+            .filter(|layer_set| match &layer_set.source {
+                Some(models::LayerSetSource {
+                    kind: models::LayerSetSourceKind::Git { ref_, .. },
+                    ..
+                }) => models::is_ref_pattern(ref_),
+                None => false,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if templates.is_empty() {
+            return Ok(());
+        }
+
+        let limit = self
+            .layer_repository
+            .get_ref_layer_set_limit(project_id)
+            .await
+            .context("get ref layer set limit")?
+            .unwrap_or_default();
+
+        // The limit is for the whole project, layer sets of other sources count as well.
+        let mut ref_layer_sets = self
+            .layer_repository
+            .list_layer_sets(project_id)
+            .await
+            .context("list layer sets")?
+            .iter()
+            .filter(|layer_set| layer_set.template_name.is_some() && !layer_set.archived)
+            .count();
+
+        for template in templates {
+            let (source_name, ref_pattern, root, paths) = match template.source {
+                Some(models::LayerSetSource {
+                    ref name,
+                    kind:
+                        models::LayerSetSourceKind::Git {
+                            ref ref_,
+                            ref root,
+                            ref paths,
+                        },
+                }) => (name, ref_, root, paths),
+                None => continue,
+            };
+
+            for ref_and_commit in refs_and_commits {
+                if !models::ref_pattern_matches(ref_pattern, &ref_and_commit.ref_) {
+                    continue;
+                }
+
+                let name = models::LayerSetName::for_ref(
+                    &template.name,
+                    ref_pattern,
+                    &ref_and_commit.ref_,
+                );
+
+                let existing = layer_sets
+                    .iter_mut()
+                    .find(|layer_set| layer_set.name == name);
+
+                match existing {
+                    Some(models::LayerSet {
+                        template_name: Some(ref template_name),
+                        source:
+                            Some(models::LayerSetSource {
+                                kind: models::LayerSetSourceKind::Git { ref ref_, .. },
+                                ..
+                            }),
+                        ref mut archived,
+                        ..
+                    }) if template_name == &template.name && ref_ == &ref_and_commit.ref_ => {
+                        if !*archived {
+                            continue;
+                        }
+
+                        if ref_layer_sets >= limit.max_layer_sets as usize {
+                            tracing::warn!(
+                                "not restoring layer set {} for {ref_}, the project has reached its limit",
+                                name.as_str()
+                            );
+                            continue;
                         }
+
+                        self.layer_repository
+                            .set_layer_set_archived(project_id, &name, false)
+                            .await
+                            .context("restore layer set")?;
+
+                        *archived = false;
+                        ref_layer_sets += 1;
+                    }
+                    Some(_) => {
+                        tracing::warn!(
+                            "not creating a layer set for {}, {} already exists",
+                            ref_and_commit.ref_,
+                            name.as_str()
+                        );
+                        continue;
+                    }
+                    None => {
+                        // Layer sets of other sources aren't listed, but could have the name.
+                        if self
+                            .layer_repository
+                            .get_layer_set(project_id, &name)
+                            .await
+                            .context("get layer set")?
+                            .is_some()
+                        {
+                            tracing::warn!(
+                                "not creating a layer set for {}, {} already exists",
+                                ref_and_commit.ref_,
+                                name.as_str()
+                            );
+                            continue;
+                        }
+
+                        if ref_layer_sets >= limit.max_layer_sets as usize {
+                            tracing::warn!(
+                                "not creating a layer set for {}, the project has reached its limit",
+                                ref_and_commit.ref_
+                            );
+                            continue;
+                        }
+
+                        let layer_set = models::LayerSet {
+                            project_id,
+                            name: name.clone(),
+                            visibility: template.visibility,
+                            source: Some(models::LayerSetSource {
+                                name: source_name.clone(),
+                                kind: models::LayerSetSourceKind::Git {
+                                    ref_: ref_and_commit.ref_.clone(),
+                                    root: root.clone(),
+                                    paths: paths.clone(),
+                                },
+                            }),
+                            build_status: models::LayerSetBuildStatus {
+                                current_layer_id: None,
+                                last_layer_id: None,
+                            },
+                            pinned_layer_id: None,
+                            template_name: Some(template.name.clone()),
+                            archived: false,
+                        };
+
+                        self.layer_repository
+                            .create_layer_set(&layer_set)
+                            .await
+                            .context("create layer set")?;
+
+                        layer_sets.push(layer_set);
+                        ref_layer_sets += 1;
+                    }
+                }
+
+                let fqdn = models::Domain::layer_set_preview_fqdn(project_id, &name);
+                let domain = self
+                    .domain_repository
+                    .get_domain(&fqdn)
+                    .await
+                    .context("get preview domain")?;
+
+                match domain {
+                    Some(domain) if domain.is_layer_set_preview(project_id, &name) => (),
+                    Some(_) => {
+                        tracing::warn!(
+                            "not previewing {} of layer set {}, {fqdn} is already in use",
+                            ref_and_commit.ref_,
+                            name.as_str()
+                        );
+                    }
+                    None => {
+                        tracing::info!(
+                            "previewing {} of layer set {} at {fqdn}",
+                            ref_and_commit.ref_,
+                            name.as_str()
+                        );
+
+                        self.domain_repository
+                            .create_domain(models::Domain::layer_set_preview(project_id, &name))
+                            .await
+                            .context("create preview domain")?;
                     }
                 }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the paths that the layer set is built from differ between the commit of its last
+    /// layer and the new commit, compared by the hashes of their trees.
+    async fn tracked_paths_changed(
+        &self,
+        git_source: &models::SourceWithKind<models::GitSource>,
+        layer_set: &models::LayerSet,
+        last_commit: &str,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+    ) -> Result<bool> {
+        let tracked_paths = match layer_set.source {
+            Some(ref source) => source.kind.tracked_paths(),
+            None => vec![],
+        };
+
+        if tracked_paths.is_empty() {
+            return Ok(true);
+        }
+
+        let last_ref_and_commit = models::GitSourceRefAndCommit {
+            ref_: ref_and_commit.ref_.clone(),
+            commit: last_commit.to_owned(),
+        };
+
+        // The last commit might be gone if the ref was force pushed, build the new one then.
+        let last_hashes = match self
+            .git_repository
+            .git_tree_hashes(git_source, &last_ref_and_commit, &tracked_paths)
+            .await
+        {
+            Ok(last_hashes) => last_hashes,
+            Err(err) => {
+                tracing::warn!("comparing with commit {last_commit}: {err:?}");
+                return Ok(true);
+            }
         };
 
+        let hashes = self
+            .git_repository
+            .git_tree_hashes(git_source, ref_and_commit, &tracked_paths)
+            .await
+            .context("git tree hashes")?;
+
+        if last_hashes == hashes {
+            tracing::debug!(
+                "commit {} doesn't change the paths of layer set {}",
+                ref_and_commit.commit,
+                layer_set.name.as_str()
+            );
+        }
+
+        Ok(last_hashes != hashes)
+    }
+
+    /// Pins the host key on the first refresh of a source without trusted host keys, and clears
+    /// a host key mismatch now that a trusted host key was presented.
+    async fn update_host_key(
+        &self,
+        mut source: models::Source,
+        git_source: &models::GitSource,
+        host_key: Option<models::GitHostKey>,
+    ) -> Result<()> {
+        let mut changed = matches!(source.status, models::SourceStatus::HostKeyMismatch { .. });
+        source.status = models::SourceStatus::Ok;
+
+        if let Some(host_key) = host_key {
+            if git_source.trusted_host_keys()?.is_empty() {
+                tracing::info!(%host_key, "pinning the host key of {}", source.name.as_str());
+
+                match source.kind {
+                    models::SourceKind::Git {
+                        ref mut known_host_keys,
+                        ..
+                    } => known_host_keys.push(host_key),
+                }
+
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.repository
+                .create_or_update_source(&source)
+                .await
+                .context("update source")?;
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,460 @@
+use anyhow::{ensure, Result};
+use std::collections::HashSet;
+
+use super::{
+    auth::{Authentication, LayerPermissions},
+    file_headers, file_paths,
+    members::list_live_layer_members,
+    FileWriter, RequestError,
+};
+use crate::{
+    models,
+    repositories::{DomainRepository, FileRepository, LayerRepository},
+};
+
+/// Creates layers from files that a client uploads, for layer sets without a source.
+///
+/// A client sends a manifest of all files first and then uploads the files that the project
+/// doesn't have yet, the layer is built once the client finalizes it.
+#[derive(Copy, Clone)]
+pub struct UploadService {
+    layer_repository: &'static dyn LayerRepository,
+    file_repository: &'static dyn FileRepository,
+    domain_repository: &'static dyn DomainRepository,
+}
+
+impl UploadService {
+    pub fn new(
+        layer_repository: &'static dyn LayerRepository,
+        file_repository: &'static dyn FileRepository,
+        domain_repository: &'static dyn DomainRepository,
+    ) -> UploadService {
+        UploadService {
+            layer_repository,
+            file_repository,
+            domain_repository,
+        }
+    }
+
+    /// Creates a layer from a manifest. Returns the layer and the checksums of the files that
+    /// have to be uploaded before it can be finalized.
+    pub async fn create_upload(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        manifest: &models::UploadManifest,
+    ) -> Result<(models::Layer, Vec<models::FileChecksum>)> {
+        auth.can(LayerPermissions::Create)?;
+        let project_id = auth.project_id()?;
+
+        let layer_set = self
+            .layer_repository
+            .get_layer_set(project_id, layer_set_name)
+            .await?
+            .ok_or(RequestError::NotFound("layer set"))?;
+
+        ensure!(
+            layer_set.source.is_none(),
+            RequestError::FailedPrecondition(
+                "layers can only be uploaded to layer sets without a source".into()
+            )
+        );
+
+        let mut paths = HashSet::new();
+        for file in &manifest.files {
+            validate_path(&file.path)?;
+            ensure!(
+                paths.insert(&file.path),
+                RequestError::InvalidArgument(format!("path {} is duplicated", file.path))
+            );
+        }
+
+        let layer_id = models::LayerId::new()?;
+
+        let layer = models::Layer {
+            project_id,
+            layer_set_name: layer_set.name,
+            id: layer_id,
+            status: models::LayerStatus::Building,
+            source: None,
+        };
+
+        self.layer_repository.create_layer(&layer).await?;
+
+        let mut changes = vec![];
+        for file in &manifest.files {
+            let headers = file_headers(&file.path);
+
+            for path in file_paths(file.path.clone()) {
+                changes.push(models::LayerChange {
+                    project_id,
+                    layer_set_name: layer.layer_set_name.clone(),
+                    layer_id,
+                    worker_id: upload_worker_id(layer_id),
+                    path,
+                    checksum: file.checksum,
+                    content_encoding_hint: models::ContentEncodingHint::Relative {
+                        identity: 1,
+                        gzip: 0,
+                        zstd: 0,
+                        brotli: 0,
+                    },
+                    headers: headers.clone(),
+                });
+            }
+        }
+
+        for changes in changes.chunks(128) {
+            self.layer_repository.create_layer_changes(changes).await?;
+        }
+
+        let mut checksums = HashSet::new();
+        let mut missing_checksums = vec![];
+
+        for file in &manifest.files {
+            if !checksums.insert(file.checksum) {
+                continue;
+            }
+
+            let existing_file = self
+                .file_repository
+                .get_file(project_id, &file.checksum)
+                .await?;
+
+            match existing_file {
+                Some(existing_file) => ensure!(
+                    existing_file.length == file.length,
+                    RequestError::InvalidArgument(format!(
+                        "length of {} does not match its checksum",
+                        file.path
+                    ))
+                ),
+                None => missing_checksums.push(file.checksum),
+            }
+        }
+
+        Ok((layer, missing_checksums))
+    }
+
+    /// Starts uploading a file of an upload that hasn't been finalized yet.
+    pub async fn upload_file(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        checksum: models::FileChecksum,
+        length: u64,
+    ) -> Result<FileUpload> {
+        auth.can(LayerPermissions::Create)?;
+        let project_id = auth.project_id()?;
+
+        self.get_pending_upload(project_id, layer_set_name, layer_id)
+            .await?;
+
+        Ok(FileUpload {
+            file_repository: self.file_repository,
+            project_id,
+            writer: FileWriter::new(self.file_repository, project_id),
+            hasher: models::FileChecksum::blake2b_hasher(project_id),
+            checksum,
+            length,
+            written: 0,
+        })
+    }
+
+    /// Builds an uploaded layer once all of its files have been uploaded.
+    pub async fn finalize_upload(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<models::Layer> {
+        auth.can(LayerPermissions::Create)?;
+        let project_id = auth.project_id()?;
+
+        let layer = self
+            .get_pending_upload(project_id, layer_set_name, layer_id)
+            .await?;
+
+        let worker_id = upload_worker_id(layer_id);
+
+        let layer_changes = self
+            .layer_repository
+            .list_layer_changes(project_id, layer_set_name, layer_id, worker_id)
+            .await?;
+
+        let checksums = layer_changes
+            .iter()
+            .map(|layer_change| layer_change.checksum)
+            .collect::<HashSet<_>>();
+
+        for checksum in checksums {
+            let file = self.file_repository.get_file(project_id, &checksum).await?;
+            ensure!(
+                file.is_some(),
+                RequestError::FailedPrecondition(format!(
+                    "file {} has not been uploaded",
+                    checksum.to_hex()
+                ))
+            );
+        }
+
+        self.layer_repository
+            .try_set_current_build(project_id, layer_set_name, layer_id)
+            .await?;
+
+        let res = self.build_upload(&layer, layer_changes).await;
+
+        if let Err(err) = res {
+            let res = self
+                .layer_repository
+                .cancel_layer(project_id, layer_set_name, layer_id)
+                .await;
+
+            if let Err(cancel_err) = res {
+                tracing::error!(
+                    "error cancelling layer ({}): {:?}",
+                    layer_id.into_uuid(),
+                    cancel_err
+                );
+            }
+
+            return Err(err);
+        }
+
+        Ok(models::Layer {
+            status: models::LayerStatus::Ready,
+            ..layer
+        })
+    }
+
+    async fn build_upload(
+        &self,
+        layer: &models::Layer,
+        layer_changes: Vec<models::LayerChange>,
+    ) -> Result<()> {
+        let worker_id = upload_worker_id(layer.id);
+
+        self.layer_repository
+            .build_layer(layer.project_id, &layer.layer_set_name, layer.id, worker_id)
+            .await?;
+
+        self.layer_repository
+            .finish_build(layer.project_id, &layer.layer_set_name, layer.id, worker_id)
+            .await?;
+
+        self.layer_repository
+            .finalize_layer(layer.project_id, &layer.layer_set_name, layer.id, worker_id)
+            .await?;
+
+        let mut layer_members = layer_changes
+            .into_iter()
+            .map(|layer_change| models::LayerMember {
+                project_id: layer_change.project_id,
+                layer_set_name: layer_change.layer_set_name,
+                layer_id: layer_change.layer_id,
+                path: layer_change.path,
+                checksum: layer_change.checksum,
+                content_encoding_hint: layer_change.content_encoding_hint,
+                headers: layer_change.headers,
+            })
+            .collect::<Vec<_>>();
+
+        // Members of earlier layers are served until a newer layer replaces them, the paths that
+        // are missing from the manifest are deleted explicitly.
+        let paths = layer_members
+            .iter()
+            .map(|layer_member| layer_member.path.clone())
+            .collect::<HashSet<_>>();
+
+        let live_layer_members = list_live_layer_members(
+            self.layer_repository,
+            layer.project_id,
+            &layer.layer_set_name,
+            layer.id,
+        )
+        .await?;
+
+        for path in live_layer_members.into_keys() {
+            if !paths.contains(&path) {
+                layer_members.push(deleted_layer_member(layer, path));
+            }
+        }
+
+        for layer_members in layer_members.chunks(128) {
+            self.layer_repository
+                .create_layer_members(layer_members)
+                .await?;
+        }
+
+        self.layer_repository
+            .finish_finalizing(layer.project_id, &layer.layer_set_name, layer.id, worker_id)
+            .await?;
+
+        // Uploads that are never finalized don't replace the last layer of the layer set.
+        self.layer_repository
+            .set_last_layer_id(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.domain_repository
+            .create_domain(models::Domain::layer_preview(layer))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_pending_upload(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<models::Layer> {
+        let layer = self
+            .layer_repository
+            .list_layers(project_id, layer_set_name)
+            .await?
+            .into_iter()
+            .find(|layer| layer.id == layer_id)
+            .ok_or(RequestError::NotFound("layer"))?;
+
+        ensure!(
+            matches!(layer.status, models::LayerStatus::Building) && layer.source.is_none(),
+            RequestError::FailedPrecondition("layer is not a pending upload".into())
+        );
+
+        Ok(layer)
+    }
+}
+
+/// Data of a single file, its checksum is verified before the file is stored.
+pub struct FileUpload {
+    file_repository: &'static dyn FileRepository,
+    project_id: models::ProjectId,
+    writer: FileWriter,
+    hasher: models::Blake2bHasher,
+    checksum: models::FileChecksum,
+    length: u64,
+    written: u64,
+}
+
+impl FileUpload {
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.written += data.len() as u64;
+        ensure!(
+            self.written <= self.length,
+            RequestError::InvalidArgument("file is longer than its length".into())
+        );
+
+        self.hasher.update(data);
+        self.writer.write(data).await
+    }
+
+    pub async fn finish(self) -> Result<()> {
+        ensure!(
+            self.written == self.length,
+            RequestError::InvalidArgument("file is shorter than its length".into())
+        );
+
+        let checksum = self.hasher.finalize();
+        ensure!(
+            checksum == self.checksum,
+            RequestError::InvalidArgument(format!(
+                "file does not match its checksum {}",
+                self.checksum.to_hex()
+            ))
+        );
+
+        // The same file might have been uploaded for another layer in the meantime.
+        let file_exists = self
+            .file_repository
+            .get_file(self.project_id, &checksum)
+            .await?
+            .is_some();
+
+        if !file_exists {
+            self.writer.finish(&checksum).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Uploads span several requests that might be handled by different servers, so their changes
+/// are kept under a worker id that every server can derive from the layer.
+fn upload_worker_id(layer_id: models::LayerId) -> models::WorkerId {
+    layer_id.into_uuid().into()
+}
+
+/// Marks a path that an earlier layer has as deleted in `layer`.
+pub(super) fn deleted_layer_member(layer: &models::Layer, path: String) -> models::LayerMember {
+    models::LayerMember {
+        project_id: layer.project_id,
+        layer_set_name: layer.layer_set_name.clone(),
+        layer_id: layer.id,
+        path,
+        checksum: models::FileChecksum::Deleted,
+        content_encoding_hint: models::ContentEncodingHint::Relative {
+            identity: 1,
+            gzip: 0,
+            zstd: 0,
+            brotli: 0,
+        },
+        headers: Default::default(),
+    }
+}
+
+fn validate_path(path: &str) -> Result<()> {
+    let relative_path = path.strip_prefix('/').ok_or_else(|| {
+        RequestError::InvalidArgument(format!("path {path} must start with a slash"))
+    })?;
+
+    let is_normalized = relative_path
+        .split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    ensure!(
+        is_normalized,
+        RequestError::InvalidArgument(format!("path {path} is not a normalized file path"))
+    );
+
+    Ok(())
+}
+
+/// Like [validate_path], but also accepts the paths of directories that index files are served
+/// at, which are members of built layers.
+pub(super) fn validate_member_path(path: &str) -> Result<()> {
+    match path.strip_suffix('/') {
+        Some("") => Ok(()),
+        Some(directory) => validate_path(directory),
+        None => validate_path(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_upload_paths() {
+        assert!(validate_path("/index.html").is_ok());
+        assert!(validate_path("/blog/post/index.html").is_ok());
+
+        assert!(validate_path("index.html").is_err());
+        assert!(validate_path("/").is_err());
+        assert!(validate_path("/blog/").is_err());
+        assert!(validate_path("/blog//index.html").is_err());
+        assert!(validate_path("/blog/../index.html").is_err());
+        assert!(validate_path("/./index.html").is_err());
+    }
+
+    #[test]
+    fn validate_member_paths() {
+        assert!(validate_member_path("/").is_ok());
+        assert!(validate_member_path("/blog/").is_ok());
+        assert!(validate_member_path("/blog/index.html").is_ok());
+
+        assert!(validate_member_path("blog/").is_err());
+        assert!(validate_member_path("//").is_err());
+        assert!(validate_member_path("/blog//").is_err());
+        assert!(validate_member_path("/../").is_err());
+    }
+}
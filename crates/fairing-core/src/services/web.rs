@@ -144,30 +144,35 @@ impl HttpConnection {
             }
         };
 
-        let validate_domain = self.domain_repository.get_validated_domain(host).await?;
+        let domain = self.domain_repository.get_domain(host).await?;
 
-        let (project_id, layer_set_name, layer_id) = match validate_domain {
-            /*
-            Some(models::ValidatedDomain {
+        let (project_id, layer_set_name, layer_id) = match domain {
+            Some(models::Domain {
                 project_id,
                 kind:
-                    models::ValidatedDomainKind::Layer {
+                    models::DomainKind::Layer {
                         layer_set_name,
                         layer_id,
                     },
                 ..
             }) => (project_id, layer_set_name, layer_id),
-            */
-            _ => {
-                let builder = self
-                    .default_response(StatusCode::NOT_FOUND)
-                    .header(header::CONTENT_TYPE, "text/plain");
-
-                let body = HttpBody::Static {
-                    data: Some(b"404 Not found".to_vec()),
-                };
-                return Ok(builder.body(body)?);
+            Some(models::Domain {
+                project_id,
+                kind: models::DomainKind::LayerSet { layer_set_name },
+                ..
+            }) => {
+                let layer_id = self
+                    .layer_repository
+                    .get_layer_set(project_id, &layer_set_name)
+                    .await?
+                    .and_then(|layer_set| layer_set.live_layer_id());
+
+                match layer_id {
+                    Some(layer_id) => (project_id, layer_set_name, layer_id),
+                    None => return self.not_found(),
+                }
             }
+            _ => return self.not_found(),
         };
 
         let layer_members = self
@@ -180,7 +185,11 @@ impl HttpConnection {
             )
             .await?;
 
-        if let Some(layer_member) = layer_members.first() {
+        let layer_member = layer_members
+            .first()
+            .filter(|layer_member| layer_member.checksum != models::FileChecksum::Deleted);
+
+        if let Some(layer_member) = layer_member {
             let mut builder = self.default_response(StatusCode::OK);
 
             for (key, value) in layer_member.headers.iter() {
@@ -215,17 +224,21 @@ impl HttpConnection {
 
             Ok(builder.body(body)?)
         } else {
-            let builder = self
-                .default_response(StatusCode::NOT_FOUND)
-                .header(header::CONTENT_TYPE, "text/plain");
-
-            let body = HttpBody::Static {
-                data: Some(b"404 Not found".to_vec()),
-            };
-            Ok(builder.body(body)?)
+            self.not_found()
         }
     }
 
+    fn not_found(&self) -> Result<Response<HttpBody>> {
+        let builder = self
+            .default_response(StatusCode::NOT_FOUND)
+            .header(header::CONTENT_TYPE, "text/plain");
+
+        let body = HttpBody::Static {
+            data: Some(b"404 Not found".to_vec()),
+        };
+        Ok(builder.body(body)?)
+    }
+
     fn default_response(&self, status_code: StatusCode) -> http::response::Builder {
         Response::builder()
             .status(status_code)
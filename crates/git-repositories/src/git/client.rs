@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+
+use fairing_core2::models;
+
+use super::{
+    http::{HttpClient, HttpClientConfig},
+    protocol::{GitProtocol, GitProtocolReader, GIT_PROTOCOL_V2},
+    ssh::{SshClient, SshClientConfig},
+    GitReader,
+};
+
+/// Connection to a repository over either transport, both carry the same pkt lines and packs
+/// once `git-upload-pack` has been started.
+pub enum GitClient {
+    Ssh(SshClient),
+    Http(HttpClient),
+}
+
+impl GitClient {
+    pub async fn connect(
+        source: &models::SourceWithKind<models::GitSource>,
+        repository: &models::GitRepositoryParts,
+    ) -> Result<GitClient> {
+        match repository.transport {
+            models::GitTransport::Ssh => {
+                let config = SshClientConfig {
+                    addr: (repository.host.as_str(), repository.port),
+                    user: &repository.user,
+                    key_pair: source.with.id_ed25519.key_pair(),
+                    trusted_host_keys: source.with.trusted_host_keys()?,
+                };
+
+                Ok(GitClient::Ssh(SshClient::connect(config).await?))
+            }
+            models::GitTransport::Http | models::GitTransport::Https => {
+                let config = HttpClientConfig {
+                    repository,
+                    http_token: source.with.http_token.as_ref(),
+                };
+
+                Ok(GitClient::Http(HttpClient::connect(config)?))
+            }
+        }
+    }
+
+    /// Starts `git-upload-pack` and asks for protocol v2. Repositories that answer with v0 have
+    /// their ref advertisement left to be read.
+    pub async fn upload_pack(
+        &mut self,
+        repository: &models::GitRepositoryParts,
+    ) -> Result<GitProtocol> {
+        match self {
+            GitClient::Ssh(client) => {
+                let command = format!("git-upload-pack '{}'", repository.path);
+                client.set_env("GIT_PROTOCOL", GIT_PROTOCOL_V2).await?;
+                client.exec(&command).await?;
+            }
+            GitClient::Http(client) => client.exec("git-upload-pack").await?,
+        }
+
+        let mut reader = GitProtocolReader::new();
+
+        while let Some(output) = self.read(&mut reader).await? {
+            if let Some(protocol) = output {
+                return Ok(protocol);
+            }
+        }
+
+        Err(anyhow!("the repository closed the connection"))
+    }
+
+    pub async fn read<R: GitReader>(&mut self, reader: &mut R) -> Result<Option<R::Output>> {
+        match self {
+            GitClient::Ssh(client) => client.read(reader).await,
+            GitClient::Http(client) => client.read(reader).await,
+        }
+    }
+
+    pub async fn data(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            GitClient::Ssh(client) => client.data(data).await,
+            GitClient::Http(client) => client.data(data).await,
+        }
+    }
+
+    /// Host key that the repository presented, only repositories reached over ssh have one.
+    pub fn host_key(&self) -> Option<&models::GitHostKey> {
+        match self {
+            GitClient::Ssh(client) => Some(client.host_key()),
+            GitClient::Http(_) => None,
+        }
+    }
+
+    pub async fn disconnect(self) -> Result<()> {
+        match self {
+            GitClient::Ssh(client) => client.disconnect().await,
+            GitClient::Http(_) => Ok(()),
+        }
+    }
+}
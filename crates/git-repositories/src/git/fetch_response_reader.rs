@@ -0,0 +1,194 @@
+use anyhow::{ensure, Result};
+use std::sync::Arc;
+
+use nom::error::{Error, ErrorKind};
+
+use super::{
+    git_pack_file_reader::GitPackFileReader,
+    parsers::{pkt_line, PktLine},
+    GitReader,
+};
+
+pub enum GitFetchOutput {
+    Progress,
+    Done,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    /// Protocol v0 responses list the shallow commits and then `NAK`, or `ACK` if the
+    /// repository found a commit in common. The pack follows as is.
+    Nak,
+    Pack,
+    /// Protocol v2 responses are split into sections, each starting with its name.
+    SectionHeader,
+    Section,
+    /// The pack is sent in pkt lines on band 1, interleaved with progress messages.
+    SideBand,
+    Done,
+}
+
+/// Reads the response to a fetch into a [`GitPackFileReader`].
+pub struct GitFetchResponseReader {
+    pack: GitPackFileReader,
+    state: State,
+    /// Pack data from band 1 that the pack reader hasn't consumed yet.
+    buffer: Vec<u8>,
+    /// Why the repository didn't send a pack, if it said so.
+    remote_error: Option<String>,
+}
+
+impl GitFetchResponseReader {
+    pub fn v0(pack: GitPackFileReader) -> GitFetchResponseReader {
+        GitFetchResponseReader {
+            pack,
+            state: State::Nak,
+            buffer: vec![],
+            remote_error: None,
+        }
+    }
+
+    pub fn v2(pack: GitPackFileReader) -> GitFetchResponseReader {
+        GitFetchResponseReader {
+            pack,
+            state: State::SectionHeader,
+            buffer: vec![],
+            remote_error: None,
+        }
+    }
+
+    pub fn remote_error(&self) -> Option<&str> {
+        self.remote_error.as_deref()
+    }
+
+    pub async fn flush(self) -> Result<Arc<rocksdb::DB>> {
+        ensure!(
+            self.state == State::Done,
+            "the response ended before the pack"
+        );
+        ensure!(self.buffer.is_empty(), "unexpected data after the pack");
+
+        self.pack.flush().await
+    }
+}
+
+#[async_trait::async_trait]
+impl GitReader for GitFetchResponseReader {
+    type Output = GitFetchOutput;
+
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
+        match self.state {
+            State::Nak => match pkt_line(input)? {
+                (rest, PktLine::Data(line)) if line == b"NAK\n" || line.starts_with(b"ACK ") => {
+                    self.state = State::Pack;
+                    Ok((rest, GitFetchOutput::Progress))
+                }
+                (rest, PktLine::Data(line))
+                    if line.starts_with(b"shallow ") || line.starts_with(b"unshallow ") =>
+                {
+                    Ok((rest, GitFetchOutput::Progress))
+                }
+                // Ends the list of shallow commits.
+                (rest, PktLine::Flush) => Ok((rest, GitFetchOutput::Progress)),
+                (_, PktLine::Data(line)) => self.unexpected(input, line),
+                (_, PktLine::Delim) => self.unexpected(input, b""),
+            },
+            State::Pack => match self.pack.read(input).await? {
+                (rest, Some(())) => Ok((rest, GitFetchOutput::Progress)),
+                (rest, None) => {
+                    self.state = State::Done;
+                    Ok((rest, GitFetchOutput::Done))
+                }
+            },
+            State::SectionHeader => match pkt_line(input)? {
+                (rest, PktLine::Data(b"packfile\n")) => {
+                    self.state = State::SideBand;
+                    Ok((rest, GitFetchOutput::Progress))
+                }
+                (_, PktLine::Data(line)) if line.starts_with(b"ERR ") => {
+                    self.unexpected(input, line)
+                }
+                (rest, PktLine::Data(section)) => {
+                    tracing::trace!("skipping section {:?}", String::from_utf8_lossy(section));
+                    self.state = State::Section;
+                    Ok((rest, GitFetchOutput::Progress))
+                }
+                (_, PktLine::Flush | PktLine::Delim) => {
+                    tracing::debug!("the response has no pack");
+                    Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
+                }
+            },
+            State::Section => match pkt_line(input)? {
+                (rest, PktLine::Data(_)) => Ok((rest, GitFetchOutput::Progress)),
+                (rest, PktLine::Delim) => {
+                    self.state = State::SectionHeader;
+                    Ok((rest, GitFetchOutput::Progress))
+                }
+                (_, PktLine::Flush) => {
+                    tracing::debug!("the response has no pack");
+                    Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
+                }
+            },
+            State::SideBand => {
+                // Let the pack reader make progress on what it has first, the input is left
+                // untouched until it needs more.
+                if !self.buffer.is_empty() {
+                    match self.pack.read(&self.buffer).await {
+                        Ok((rest, _)) => {
+                            let consumed = self.buffer.len() - rest.len();
+                            self.buffer.drain(..consumed);
+                            return Ok((input, GitFetchOutput::Progress));
+                        }
+                        Err(nom::Err::Incomplete(_)) => (),
+                        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => {
+                            return Err(nom::Err::Failure(Error::new(input, err.code)))
+                        }
+                    }
+                }
+
+                match pkt_line(input)? {
+                    (rest, PktLine::Data([1, data @ ..])) => {
+                        self.buffer.extend_from_slice(data);
+                        Ok((rest, GitFetchOutput::Progress))
+                    }
+                    (rest, PktLine::Data([2, message @ ..])) => {
+                        tracing::trace!("remote: {}", String::from_utf8_lossy(message).trim_end());
+                        Ok((rest, GitFetchOutput::Progress))
+                    }
+                    (_, PktLine::Data([3, message @ ..])) => {
+                        let message = String::from_utf8_lossy(message).trim_end().to_owned();
+                        self.remote_error = Some(message);
+                        Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
+                    }
+                    (rest, PktLine::Flush) => {
+                        self.state = State::Done;
+                        Ok((rest, GitFetchOutput::Done))
+                    }
+                    (_, PktLine::Data(_) | PktLine::Delim) => {
+                        Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
+                    }
+                }
+            }
+            State::Done => Ok((input, GitFetchOutput::Done)),
+        }
+    }
+}
+
+impl GitFetchResponseReader {
+    /// Fails on a line that doesn't belong in the response, keeping the message of error lines.
+    fn unexpected<'a>(
+        &mut self,
+        input: &'a [u8],
+        line: &[u8],
+    ) -> nom::IResult<&'a [u8], GitFetchOutput> {
+        match line.strip_prefix(b"ERR ") {
+            Some(message) => {
+                let message = String::from_utf8_lossy(message).trim_end().to_owned();
+                self.remote_error = Some(message);
+            }
+            None => tracing::debug!("unexpected line {:?}", String::from_utf8_lossy(line)),
+        }
+
+        Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)))
+    }
+}
@@ -1,19 +1,21 @@
 use anyhow::{ensure, Result};
 use miniz_oxide::inflate::stream::InflateState;
 use sha1::{Digest, Sha1};
-use std::{path::Path, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc};
 use tokio::{
     fs,
     io::{AsyncWrite, AsyncWriteExt},
     task,
 };
 
+use nom::error::{Error, ErrorKind};
+
 use super::{
     parsers::{
         pack_file_header, pack_file_object_header, PackFileHeader, PackFileObjectHeader,
         PackFileObjectType,
     },
-    IndexObject, SshClient, SshReader,
+    GitReader, IndexObject,
 };
 
 pub struct GitPackFileReader {
@@ -22,12 +24,22 @@ pub struct GitPackFileReader {
     pack: fs::File,
     decoder: ObjectDecoder,
 
+    /// Checksum of the pack as it is received, compared with the trailer of the pack.
+    pack_sha1_hasher: Sha1,
+    pack_offset: u64,
+    pack_verified: bool,
+
+    /// Index keys of the objects read so far by where they start in the pack, offset deltas
+    /// refer to their base objects this way.
+    object_keys: HashMap<u64, [u8; 20]>,
+
     next_object_file_offset: u64,
     next_object_index: u32,
-    current_object: Option<PackFileObjectHeader>,
+    current_object: Option<(u64, PackFileObjectHeader)>,
 }
 
 impl GitPackFileReader {
+    /// Opens the pack and index in `path`, objects are added to the ones that are already there.
     pub async fn open(path: impl AsRef<Path>) -> Result<GitPackFileReader> {
         let path = path.as_ref().to_owned();
         let datbase_path = path.join("index");
@@ -37,16 +49,21 @@ impl GitPackFileReader {
 
         let pack = fs::OpenOptions::new()
             .create(true)
-            .write(true)
+            .append(true)
             .open(pack_path)
             .await?;
+        let pack_len = pack.metadata().await?.len();
 
         Ok(GitPackFileReader {
             header: None,
             index: Arc::new(index),
             pack,
             decoder: ObjectDecoder::new(),
-            next_object_file_offset: 0,
+            pack_sha1_hasher: Sha1::new(),
+            pack_offset: 0,
+            pack_verified: false,
+            object_keys: HashMap::new(),
+            next_object_file_offset: pack_len,
             next_object_index: 0,
             current_object: None,
         })
@@ -62,6 +79,8 @@ impl GitPackFileReader {
                 header.objects,
                 self.next_object_index
             );
+
+            ensure!(self.pack_verified, "pack is missing its checksum");
         }
 
         self.pack.flush().await?;
@@ -70,33 +89,53 @@ impl GitPackFileReader {
 }
 
 #[async_trait::async_trait]
-impl SshReader for GitPackFileReader {
+impl GitReader for GitPackFileReader {
     type Output = Option<()>;
 
-    async fn read<'a>(
-        &mut self,
-        _client: &mut SshClient,
-        input: &'a [u8],
-    ) -> nom::IResult<&'a [u8], Self::Output> {
-        let (input, header) = if let Some(header) = self.header {
-            (input, header)
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
+        let header = if let Some(header) = self.header {
+            header
         } else {
-            let (input, _) = nom::bytes::streaming::tag(b"0008NAK\n")(input)?;
-            let (input, header) = pack_file_header(input)?;
+            let (rest, header) = pack_file_header(input)?;
 
-            // TODO: check the version and number of objects.
+            if !matches!(header.version, 2 | 3) {
+                tracing::debug!("unsupported pack version {}", header.version);
+                return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+            }
+
+            tracing::trace!("reading {} objects", header.objects);
 
+            self.consume_pack(input, rest);
             self.header = Some(header);
 
-            (input, header)
+            // Return here so that the header isn't read again if the rest is incomplete.
+            return Ok((rest, Some(())));
         };
 
-        if let Some(current_object) = self.current_object {
+        if self.next_object_index == header.objects {
+            // The pack ends with a checksum of everything before it.
+            let (rest, checksum) = nom::bytes::streaming::take(20_usize)(input)?;
+
+            let sha1_hash = self.pack_sha1_hasher.finalize_reset();
+            if checksum != &sha1_hash[..] {
+                tracing::debug!("pack checksum doesn't match");
+                return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+            }
+
+            tracing::trace!("read all {} objects", header.objects);
+            self.pack_verified = true;
+
+            return Ok((rest, None));
+        }
+
+        if let Some((object_offset, current_object)) = self.current_object {
             let (rest, decoded_object) = self
                 .decoder
                 .write(input, current_object, &mut self.pack)
                 .await?;
 
+            self.consume_pack(input, rest);
+
             if let Some(decoded_object) = decoded_object {
                 let key = decoded_object.sha1_hash;
 
@@ -113,28 +152,52 @@ impl SshReader for GitPackFileReader {
                     .unwrap()
                     .unwrap();
 
+                self.object_keys.insert(object_offset, key);
                 self.next_object_file_offset += decoded_object.decompressed_length;
                 self.next_object_index += 1;
                 self.current_object = None;
             }
 
-            if self.next_object_index == header.objects {
-                // We have read all the objects we expected to read.
-                tracing::trace!("read all {} objects", header.objects);
-                Ok((rest, None))
-            } else {
-                Ok((rest, Some(())))
-            }
+            Ok((rest, Some(())))
         } else {
-            let (input, object_header) = pack_file_object_header(input)?;
+            let (rest, mut object_header) = pack_file_object_header(input)?;
+
+            // Offset deltas are stored like ref deltas, with the key of their base as parent.
+            if let PackFileObjectType::OfsDelta { offset } = object_header.type_ {
+                let parent = self
+                    .pack_offset
+                    .checked_sub(offset)
+                    .filter(|_| offset > 0)
+                    .and_then(|base_offset| self.object_keys.get(&base_offset))
+                    .copied();
+
+                match parent {
+                    Some(parent) => object_header.type_ = PackFileObjectType::RefDelta { parent },
+                    None => {
+                        tracing::debug!("offset delta has no base {offset} bytes before it");
+                        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+                    }
+                }
+            }
 
-            self.current_object = Some(object_header);
+            self.current_object = Some((self.pack_offset, object_header));
+            self.consume_pack(input, rest);
 
-            Ok((input, Some(())))
+            Ok((rest, Some(())))
         }
     }
 }
 
+impl GitPackFileReader {
+    /// Adds the bytes between `input` and `rest` to the checksum of the pack.
+    fn consume_pack(&mut self, input: &[u8], rest: &[u8]) {
+        let consumed = &input[..input.len() - rest.len()];
+
+        self.pack_sha1_hasher.update(consumed);
+        self.pack_offset += consumed.len() as u64;
+    }
+}
+
 struct ObjectDecoder {
     sha1_hasher: Sha1,
     inflate_state: Box<InflateState>,
@@ -188,7 +251,14 @@ impl ObjectDecoder {
                 PackFileObjectType::Tree => self.sha1_hasher.update(b"tree"),
                 PackFileObjectType::Blob => self.sha1_hasher.update(b"blob"),
                 PackFileObjectType::Tag => self.sha1_hasher.update(b"tag"),
-                PackFileObjectType::RefDelta { .. } => self.sha1_hasher.update(b"ref-delta"),
+                PackFileObjectType::OfsDelta { .. } => unreachable!("offset deltas are resolved"),
+                PackFileObjectType::RefDelta { parent } => {
+                    // Deltas are stored under a key of their own until they are reconstructed,
+                    // the parent is part of it so that equal deltas of different parents don't
+                    // overwrite each other.
+                    self.sha1_hasher.update(b"ref-delta");
+                    self.sha1_hasher.update(parent);
+                }
             }
 
             let length_header = format!(" {}\0", object_header.length);
@@ -0,0 +1,928 @@
+use anyhow::{anyhow, Context, Result};
+
+use fairing_core2::models;
+
+use super::{
+    parsers::{data_pkt, flush_pkt},
+    protocol::GIT_PROTOCOL_V2,
+    GitReader,
+};
+
+/// Username sent with http tokens if the repository url doesn't have one, forges only look at
+/// the token.
+const DEFAULT_HTTP_USER: &str = "x-access-token";
+
+pub struct HttpClientConfig<'a> {
+    pub repository: &'a models::GitRepositoryParts,
+    pub http_token: Option<&'a models::GitHttpToken>,
+}
+
+/// Speaks the smart http protocol with the same interface as the ssh client. Data is buffered
+/// until the next read, which sends it as the body of a single request.
+pub struct HttpClient {
+    client: reqwest::Client,
+    url: String,
+    authorization: Option<String>,
+    request: Vec<u8>,
+    response: Option<reqwest::Response>,
+    buffer: Option<Vec<u8>>,
+}
+
+impl HttpClient {
+    pub fn connect(config: HttpClientConfig<'_>) -> Result<HttpClient> {
+        let client = reqwest::Client::builder()
+            .build()
+            .context("building http client")?;
+
+        let authorization = config
+            .http_token
+            .map(|http_token| authorization_header(config.repository, http_token));
+
+        Ok(HttpClient {
+            client,
+            url: config.repository.http_url(),
+            authorization,
+            request: vec![],
+            response: None,
+            buffer: None,
+        })
+    }
+
+    /// Requests the ref advertisement of `service`, reading past the service announcement that
+    /// only the http transport sends for protocol v0.
+    pub async fn exec(&mut self, service: &str) -> Result<()> {
+        self.buffer = None;
+        self.request.clear();
+
+        let mut request = self
+            .client
+            .get(format!("{}/info/refs?service={service}", self.url))
+            .header("Git-Protocol", GIT_PROTOCOL_V2);
+
+        if let Some(ref authorization) = self.authorization {
+            request = request.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+
+        let response = request.send().await.context("requesting refs")?;
+        let response = check_response(response, &format!("{service}-advertisement"))?;
+        self.response = Some(response);
+
+        let mut reader = ServiceAnnouncementReader {
+            announcement: format!("# service={service}\n"),
+            announced: false,
+        };
+
+        while let Some(done) = self.read(&mut reader).await? {
+            if done {
+                return Ok(());
+            }
+        }
+
+        Err(anyhow!("the repository didn't advertise any refs"))
+    }
+
+    pub async fn read<R: GitReader>(&mut self, reader: &mut R) -> Result<Option<R::Output>> {
+        if !self.request.is_empty() {
+            self.send_request().await?;
+        }
+
+        if let Some(buffer) = self.buffer.take() {
+            let result = reader.read(&buffer).await;
+            match result {
+                Ok((input, result)) => {
+                    if !input.is_empty() {
+                        self.buffer = Some(input.to_vec());
+                    }
+
+                    return Ok(Some(result));
+                }
+                Err(nom::Err::Incomplete(_)) => self.buffer = Some(buffer),
+                Err(nom::Err::Error(err)) => return Err(anyhow!("{:?}", err)),
+                Err(nom::Err::Failure(err)) => return Err(anyhow!("{:?}", err)),
+            }
+        }
+
+        let response = match self.response {
+            Some(ref mut response) => response,
+            None => return Ok(None),
+        };
+
+        while let Some(data) = response.chunk().await.context("reading response")? {
+            let buffer = if let Some(mut buffer) = self.buffer.take() {
+                buffer.extend_from_slice(&data);
+                buffer
+            } else {
+                data.to_vec()
+            };
+
+            let result = reader.read(&buffer).await;
+            match result {
+                Ok((input, result)) => {
+                    if !input.is_empty() {
+                        self.buffer = Some(input.to_vec());
+                    }
+
+                    return Ok(Some(result));
+                }
+                Err(nom::Err::Incomplete(_)) => self.buffer = Some(buffer),
+                Err(nom::Err::Error(err)) => return Err(anyhow!("{:?}", err)),
+                Err(nom::Err::Failure(err)) => return Err(anyhow!("{:?}", err)),
+            }
+        }
+
+        self.response = None;
+
+        Ok(None)
+    }
+
+    pub async fn data(&mut self, data: &[u8]) -> Result<()> {
+        self.request.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn send_request(&mut self) -> Result<()> {
+        let body = std::mem::take(&mut self.request);
+
+        let mut request = self
+            .client
+            .post(format!("{}/git-upload-pack", self.url))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-git-upload-pack-request",
+            )
+            .header(
+                reqwest::header::ACCEPT,
+                "application/x-git-upload-pack-result",
+            )
+            .header("Git-Protocol", GIT_PROTOCOL_V2)
+            .body(body);
+
+        if let Some(ref authorization) = self.authorization {
+            request = request.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+
+        let response = request.send().await.context("requesting pack")?;
+        let response = check_response(response, "git-upload-pack-result")?;
+
+        self.buffer = None;
+        self.response = Some(response);
+
+        Ok(())
+    }
+}
+
+/// Value of the authorization header for repositories that need a token.
+pub fn authorization_header(
+    repository: &models::GitRepositoryParts,
+    http_token: &models::GitHttpToken,
+) -> String {
+    let user = if repository.user.is_empty() {
+        DEFAULT_HTTP_USER
+    } else {
+        &repository.user
+    };
+
+    let credentials = format!("{user}:{}", http_token.as_str());
+    format!("Basic {}", base64::encode(credentials))
+}
+
+fn check_response(response: reqwest::Response, result: &str) -> Result<reqwest::Response> {
+    let status = response.status();
+
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(anyhow!(
+            "the repository returned {status}, check the http token of the source"
+        ));
+    } else if !status.is_success() {
+        return Err(anyhow!("the repository returned {status}"));
+    }
+
+    // Dumb http servers return the refs as plain text.
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .unwrap_or("")
+        .trim();
+
+    if content_type != format!("application/x-{result}") {
+        return Err(anyhow!(
+            "the repository doesn't support the smart http protocol, got {content_type:?}"
+        ));
+    }
+
+    Ok(response)
+}
+
+/// Reads `# service=<service>` followed by a flush, returns true once both have been read or if
+/// the response starts with anything else.
+struct ServiceAnnouncementReader {
+    announcement: String,
+    announced: bool,
+}
+
+#[async_trait::async_trait]
+impl GitReader for ServiceAnnouncementReader {
+    type Output = bool;
+
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
+        if self.announced {
+            let (input, _) = flush_pkt::<()>(input)?;
+            return Ok((input, true));
+        }
+
+        let (rest, data) = data_pkt(input)?;
+
+        if data != self.announcement.as_bytes() {
+            return Ok((input, true));
+        }
+
+        self.announced = true;
+
+        Ok((rest, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fairing_core2::repositories::GitSourceRepository;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use std::{
+        convert::Infallible,
+        io::Write,
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    };
+
+    const HTTP_TOKEN: &str = "secret-token";
+
+    #[derive(Copy, Clone, Default)]
+    struct ServerOptions {
+        /// Requests must carry `HTTP_TOKEN`.
+        require_token: bool,
+        /// Drops the `Git-Protocol` header, like servers with a git older than protocol v2.
+        protocol_v0: bool,
+    }
+
+    /// Runs `git http-backend` for every request, like a web server would run it as a cgi
+    /// script.
+    async fn handle(
+        project_root: PathBuf,
+        options: ServerOptions,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+
+        if options.require_token {
+            let expected = format!("Basic {}", base64::encode(format!("git:{HTTP_TOKEN}")));
+            let authorization = parts
+                .headers
+                .get("authorization")
+                .and_then(|value| value.to_str().ok());
+
+            if authorization != Some(expected.as_str()) {
+                let response = Response::builder().status(401).body(Body::empty());
+                return Ok(response.unwrap());
+            }
+        }
+
+        let content_type = parts
+            .headers
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+
+        let git_protocol = match parts.headers.get("git-protocol") {
+            Some(value) if !options.protocol_v0 => value.to_str().unwrap(),
+            _ => "",
+        };
+
+        let mut child = Command::new("git")
+            .arg("http-backend")
+            .env("HTTP_GIT_PROTOCOL", git_protocol)
+            .env("GIT_PROJECT_ROOT", &project_root)
+            .env("GIT_HTTP_EXPORT_ALL", "1")
+            .env("PATH_INFO", parts.uri.path())
+            .env("QUERY_STRING", parts.uri.query().unwrap_or(""))
+            .env("REQUEST_METHOD", parts.method.as_str())
+            .env("CONTENT_TYPE", content_type)
+            .env("CONTENT_LENGTH", body.len().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        child.stdin.take().unwrap().write_all(&body).unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        // The cgi response starts with headers, separated from the body by an empty line.
+        let header_end = output
+            .stdout
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let headers = std::str::from_utf8(&output.stdout[..header_end]).unwrap();
+
+        let mut response = Response::builder();
+        for header in headers.split("\r\n") {
+            let (name, value) = header.split_once(": ").unwrap();
+
+            if name.eq_ignore_ascii_case("status") {
+                response = response.status(&value[..3]);
+            } else {
+                response = response.header(name, value);
+            }
+        }
+
+        let body = output.stdout[header_end + 4..].to_vec();
+        Ok(response.body(Body::from(body)).unwrap())
+    }
+
+    fn serve(project_root: &Path, options: ServerOptions) -> SocketAddr {
+        let project_root = project_root.to_owned();
+
+        let make_service = make_service_fn(move |_| {
+            let project_root = project_root.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle(project_root.clone(), options, request)
+                }))
+            }
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        addr
+    }
+
+    fn git(directory: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(directory)
+            .env("GIT_AUTHOR_NAME", "Fairing")
+            .env("GIT_AUTHOR_EMAIL", "fairing@localhost")
+            .env("GIT_COMMITTER_NAME", "Fairing")
+            .env("GIT_COMMITTER_EMAIL", "fairing@localhost")
+            .status()
+            .unwrap();
+
+        assert!(status.success(), "git {args:?}");
+    }
+
+    /// Creates `site.git` in `project_root` with a commit on `main`, and another commit on top
+    /// of it on `feature`.
+    fn create_repository(project_root: &Path) {
+        let work_tree = project_root.join("work");
+        std::fs::create_dir(&work_tree).unwrap();
+        std::fs::write(work_tree.join("index.html"), b"hello over http").unwrap();
+
+        git(&work_tree, &["init", "--quiet", "--initial-branch=main"]);
+        git(&work_tree, &["add", "index.html"]);
+        git(&work_tree, &["commit", "--quiet", "--message", "initial"]);
+
+        std::fs::write(work_tree.join("feature.html"), b"feature").unwrap();
+        git(&work_tree, &["checkout", "--quiet", "-b", "feature"]);
+        git(&work_tree, &["add", "feature.html"]);
+        git(&work_tree, &["commit", "--quiet", "--message", "feature"]);
+        git(&work_tree, &["checkout", "--quiet", "main"]);
+
+        git(
+            project_root,
+            &["clone", "--quiet", "--bare", "work", "site.git"],
+        );
+    }
+
+    /// Moves `main` of `site.git` to a new commit that adds `path`.
+    fn commit_to_main(project_root: &Path, path: &str, data: &[u8]) {
+        let work_tree = project_root.join("work");
+        std::fs::write(work_tree.join(path), data).unwrap();
+
+        git(&work_tree, &["add", path]);
+        git(&work_tree, &["commit", "--quiet", "--message", path]);
+        git(&work_tree, &["push", "--quiet", "../site.git", "main"]);
+    }
+
+    fn rev_parse(directory: &Path, rev: &str) -> String {
+        let output = Command::new("git")
+            .args(["rev-parse", rev])
+            .current_dir(directory)
+            .output()
+            .unwrap();
+
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    /// Creates `theme.git` in `project_root` and adds it to `main` of `site.git` as a submodule
+    /// at `themes/theme`, with a url relative to `site.git`.
+    fn add_submodule(project_root: &Path) {
+        let theme_tree = project_root.join("theme");
+        std::fs::create_dir(&theme_tree).unwrap();
+        std::fs::write(theme_tree.join("layout.html"), b"theme").unwrap();
+
+        git(&theme_tree, &["init", "--quiet", "--initial-branch=main"]);
+        git(&theme_tree, &["add", "layout.html"]);
+        git(&theme_tree, &["commit", "--quiet", "--message", "theme"]);
+        git(
+            project_root,
+            &["clone", "--quiet", "--bare", "theme", "theme.git"],
+        );
+
+        let commit = rev_parse(&theme_tree, "HEAD");
+
+        let work_tree = project_root.join("work");
+        std::fs::write(
+            work_tree.join(".gitmodules"),
+            "[submodule \"theme\"]\n\tpath = themes/theme\n\turl = ../theme.git\n",
+        )
+        .unwrap();
+
+        let cacheinfo = format!("160000,{commit},themes/theme");
+        git(
+            &work_tree,
+            &["update-index", "--add", "--cacheinfo", &cacheinfo],
+        );
+        git(&work_tree, &["add", ".gitmodules"]);
+        git(&work_tree, &["commit", "--quiet", "--message", "theme"]);
+        git(&work_tree, &["push", "--quiet", "../site.git", "main"]);
+    }
+
+    fn source(url: &str, http_token: Option<&str>) -> models::SourceWithKind<models::GitSource> {
+        models::SourceWithKind {
+            project_id: "00000000-0000-0000-0000-000000000000".parse().unwrap(),
+            name: "site".parse().unwrap(),
+            with: models::GitSource {
+                repository_url: url.parse().unwrap(),
+                id_ed25519: models::Ed25519::generate(),
+                known_host_keys: vec![],
+                http_token: http_token.map(|http_token| http_token.parse().unwrap()),
+            },
+        }
+    }
+
+    /// Git source that keeps its objects next to the repositories.
+    fn git_source(project_root: &Path) -> crate::ThrusshGitSource {
+        crate::ThrusshGitSource::new().with_cache_directory(project_root.join("cache"))
+    }
+
+    fn ref_prefixes(ref_prefixes: &[&str]) -> Vec<String> {
+        ref_prefixes.iter().map(|s| s.to_string()).collect()
+    }
+
+    async fn list_and_clone(options: ServerOptions) {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        let addr = serve(project_root.path(), options);
+        let source = source(&format!("http://{addr}/site.git"), None);
+
+        let latest = git_source
+            .git_list_latest(&source, &ref_prefixes(&["refs/heads/main"]))
+            .await
+            .unwrap();
+
+        assert!(latest.host_key.is_none());
+        assert_eq!(latest.refs_and_commits.len(), 1);
+        assert_eq!(latest.refs_and_commits[0].ref_, "refs/heads/main");
+
+        let work_directory = tempfile::tempdir().unwrap();
+        let source_directory = git_source
+            .git_clone(
+                &source,
+                &latest.refs_and_commits[0],
+                work_directory.path().to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let index = tokio::fs::read(source_directory.join("index.html"))
+            .await
+            .unwrap();
+        assert_eq!(index, b"hello over http");
+    }
+
+    #[tokio::test]
+    async fn list_and_clone_public_repository() {
+        list_and_clone(ServerOptions::default()).await;
+    }
+
+    #[tokio::test]
+    async fn list_and_clone_over_protocol_v0() {
+        list_and_clone(ServerOptions {
+            protocol_v0: true,
+            ..ServerOptions::default()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn list_only_tracked_refs() {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        for protocol_v0 in [false, true] {
+            let options = ServerOptions {
+                protocol_v0,
+                ..ServerOptions::default()
+            };
+
+            let addr = serve(project_root.path(), options);
+            let source = source(&format!("http://{addr}/site.git"), None);
+
+            let latest = git_source
+                .git_list_latest(&source, &ref_prefixes(&["refs/heads/"]))
+                .await
+                .unwrap();
+            assert_eq!(latest.refs_and_commits.len(), 2);
+
+            let latest = git_source
+                .git_list_latest(
+                    &source,
+                    &ref_prefixes(&["refs/heads/feature", "refs/tags/"]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(latest.refs_and_commits.len(), 1);
+            assert_eq!(latest.refs_and_commits[0].ref_, "refs/heads/feature");
+
+            let latest = git_source.git_list_latest(&source, &[]).await.unwrap();
+            assert!(latest.refs_and_commits.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn list_repository_with_http_token() {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        let addr = serve(
+            project_root.path(),
+            ServerOptions {
+                require_token: true,
+                ..ServerOptions::default()
+            },
+        );
+
+        let ref_prefixes = ref_prefixes(&["refs/heads/main"]);
+
+        let res = git_source
+            .git_list_latest(
+                &source(&format!("http://{addr}/site.git"), None),
+                &ref_prefixes,
+            )
+            .await;
+        assert!(res.is_err());
+
+        let source = source(&format!("http://git@{addr}/site.git"), Some(HTTP_TOKEN));
+        let latest = git_source
+            .git_list_latest(&source, &ref_prefixes)
+            .await
+            .unwrap();
+        assert_eq!(latest.refs_and_commits.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn clone_commit_after_ref_moved() {
+        for (protocol_v0, allow_reachable) in [(false, false), (true, false), (true, true)] {
+            let project_root = tempfile::tempdir().unwrap();
+            let git_source = git_source(project_root.path());
+            create_repository(project_root.path());
+
+            if allow_reachable {
+                git(
+                    &project_root.path().join("site.git"),
+                    &["config", "uploadpack.allowReachableSHA1InWant", "true"],
+                );
+            }
+
+            let options = ServerOptions {
+                protocol_v0,
+                ..ServerOptions::default()
+            };
+
+            let addr = serve(project_root.path(), options);
+            let source = source(&format!("http://{addr}/site.git"), None);
+
+            let latest = git_source
+                .git_list_latest(&source, &ref_prefixes(&["refs/heads/main"]))
+                .await
+                .unwrap();
+
+            commit_to_main(project_root.path(), "moved.html", b"moved");
+
+            let work_directory = tempfile::tempdir().unwrap();
+            let source_directory = git_source
+                .git_clone(
+                    &source,
+                    &latest.refs_and_commits[0],
+                    work_directory.path().to_owned(),
+                )
+                .await
+                .unwrap();
+
+            let index = tokio::fs::read(source_directory.join("index.html"))
+                .await
+                .unwrap();
+            assert_eq!(index, b"hello over http");
+            assert!(!source_directory.join("moved.html").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn clone_commit_outside_of_ref() {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        let addr = serve(
+            project_root.path(),
+            ServerOptions {
+                protocol_v0: true,
+                ..ServerOptions::default()
+            },
+        );
+        let source = source(&format!("http://{addr}/site.git"), None);
+
+        let latest = git_source
+            .git_list_latest(&source, &ref_prefixes(&["refs/heads/feature"]))
+            .await
+            .unwrap();
+
+        // The feature commit is on top of main, and protocol v0 repositories only send it if
+        // they are asked for feature.
+        let ref_and_commit = models::GitSourceRefAndCommit {
+            ref_: "refs/heads/main".into(),
+            commit: latest.refs_and_commits[0].commit.clone(),
+        };
+
+        let work_directory = tempfile::tempdir().unwrap();
+        let err = git_source
+            .git_clone(&source, &ref_and_commit, work_directory.path().to_owned())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't part of the history of"));
+    }
+
+    #[tokio::test]
+    async fn clone_missing_commit() {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        let addr = serve(project_root.path(), ServerOptions::default());
+        let source = source(&format!("http://{addr}/site.git"), None);
+
+        let ref_and_commit = models::GitSourceRefAndCommit {
+            ref_: "refs/heads/main".into(),
+            commit: "0123456789012345678901234567890123456789".into(),
+        };
+
+        let work_directory = tempfile::tempdir().unwrap();
+        let err = git_source
+            .git_clone(&source, &ref_and_commit, work_directory.path().to_owned())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("couldn't send commit"));
+    }
+
+    #[tokio::test]
+    async fn clone_incrementally_from_object_store() {
+        for protocol_v0 in [false, true] {
+            let project_root = tempfile::tempdir().unwrap();
+            let git_source = git_source(project_root.path());
+            create_repository(project_root.path());
+
+            let large = vec![b'x'; 1 << 20];
+            commit_to_main(project_root.path(), "large.bin", &large);
+
+            let options = ServerOptions {
+                protocol_v0,
+                ..ServerOptions::default()
+            };
+
+            let addr = serve(project_root.path(), options);
+            let source = source(&format!("http://{addr}/site.git"), None);
+            let ref_prefixes = ref_prefixes(&["refs/heads/main"]);
+
+            let mut pack_lens = vec![];
+
+            for path in ["first.html", "second.html"] {
+                commit_to_main(project_root.path(), path, path.as_bytes());
+
+                let latest = git_source
+                    .git_list_latest(&source, &ref_prefixes)
+                    .await
+                    .unwrap();
+
+                let work_directory = tempfile::tempdir().unwrap();
+                let source_directory = git_source
+                    .git_clone(
+                        &source,
+                        &latest.refs_and_commits[0],
+                        work_directory.path().to_owned(),
+                    )
+                    .await
+                    .unwrap();
+
+                let data = tokio::fs::read(source_directory.join("large.bin"))
+                    .await
+                    .unwrap();
+                assert_eq!(data, large);
+                assert!(source_directory.join(path).exists());
+
+                let store = std::fs::read_dir(project_root.path().join("cache"))
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .unwrap();
+                let pack = std::fs::metadata(store.path().join("pack")).unwrap();
+                pack_lens.push(pack.len());
+            }
+
+            // The second fetch only brings the objects that changed.
+            assert!(pack_lens[0] > large.len() as u64);
+            assert!(pack_lens[1] - pack_lens[0] < 4096, "{pack_lens:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn clone_with_submodule() {
+        for protocol_v0 in [false, true] {
+            let project_root = tempfile::tempdir().unwrap();
+            let git_source = git_source(project_root.path());
+            create_repository(project_root.path());
+            add_submodule(project_root.path());
+
+            let options = ServerOptions {
+                protocol_v0,
+                ..ServerOptions::default()
+            };
+
+            let addr = serve(project_root.path(), options);
+            let source = source(&format!("http://{addr}/site.git"), None);
+
+            let latest = git_source
+                .git_list_latest(&source, &ref_prefixes(&["refs/heads/main"]))
+                .await
+                .unwrap();
+
+            let work_directory = tempfile::tempdir().unwrap();
+            let source_directory = git_source
+                .git_clone(
+                    &source,
+                    &latest.refs_and_commits[0],
+                    work_directory.path().to_owned(),
+                )
+                .await
+                .unwrap();
+
+            let index = tokio::fs::read(source_directory.join("index.html"))
+                .await
+                .unwrap();
+            assert_eq!(index, b"hello over http");
+
+            let layout = tokio::fs::read(source_directory.join("themes/theme/layout.html"))
+                .await
+                .unwrap();
+            assert_eq!(layout, b"theme");
+        }
+    }
+
+    #[test]
+    fn submodule_on_other_host_without_credentials() {
+        let public_key = |source: &models::SourceWithKind<models::GitSource>| {
+            models::GitHostKey::from_public_key(&source.with.id_ed25519.public_key())
+        };
+
+        let mut parent = source("git@git.example.com:fairing/site.git", Some("secret"));
+        parent.with.known_host_keys = vec![public_key(&parent)];
+
+        let same_host = super::super::submodule_source(&parent, "../theme.git").unwrap();
+        assert_eq!(public_key(&same_host), public_key(&parent));
+        assert_eq!(same_host.with.known_host_keys, parent.with.known_host_keys);
+        assert!(same_host.with.http_token.is_some());
+
+        let other_host =
+            super::super::submodule_source(&parent, "https://github.com/fairing/theme.git")
+                .unwrap();
+        assert_ne!(public_key(&other_host), public_key(&parent));
+        assert_eq!(
+            other_host.with.known_host_keys,
+            models::GitHostKey::builtin("github.com")
+        );
+        assert!(other_host.with.http_token.is_none());
+    }
+
+    #[test]
+    fn submodule_over_ssh_needs_known_host_keys() {
+        let parent = source("https://git.example.com/fairing/site.git", None);
+
+        let forge =
+            super::super::submodule_source(&parent, "git@github.com:fairing/theme.git").unwrap();
+        assert_eq!(
+            forge.with.known_host_keys,
+            models::GitHostKey::builtin("github.com")
+        );
+
+        let http =
+            super::super::submodule_source(&parent, "https://git.example.org/theme.git").unwrap();
+        assert!(http.with.known_host_keys.is_empty());
+
+        // Neither the source over http nor the other host has pinned host keys to trust.
+        assert!(super::super::submodule_source(&parent, "git@git.example.com:theme.git").is_err());
+        assert!(super::super::submodule_source(&parent, "git@git.example.org:theme.git").is_err());
+    }
+
+    #[tokio::test]
+    async fn clone_with_symlinks() {
+        let project_root = tempfile::tempdir().unwrap();
+        let git_source = git_source(project_root.path());
+        create_repository(project_root.path());
+
+        let work_tree = project_root.path().join("work");
+        std::os::unix::fs::symlink("index.html", work_tree.join("latest.html")).unwrap();
+        std::os::unix::fs::symlink("../../etc/passwd", work_tree.join("passwd")).unwrap();
+        git(&work_tree, &["add", "latest.html", "passwd"]);
+        git(&work_tree, &["commit", "--quiet", "--message", "symlinks"]);
+        git(&work_tree, &["push", "--quiet", "../site.git", "main"]);
+
+        let addr = serve(project_root.path(), ServerOptions::default());
+        let source = source(&format!("http://{addr}/site.git"), None);
+
+        let latest = git_source
+            .git_list_latest(&source, &ref_prefixes(&["refs/heads/main"]))
+            .await
+            .unwrap();
+
+        let work_directory = tempfile::tempdir().unwrap();
+        let source_directory = git_source
+            .git_clone(
+                &source,
+                &latest.refs_and_commits[0],
+                work_directory.path().to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let target = tokio::fs::read_link(source_directory.join("latest.html"))
+            .await
+            .unwrap();
+        assert_eq!(target, Path::new("index.html"));
+
+        let passwd = tokio::fs::symlink_metadata(source_directory.join("passwd")).await;
+        assert!(passwd.is_err());
+    }
+
+    #[tokio::test]
+    async fn tree_hashes_of_paths() {
+        for protocol_v0 in [false, true] {
+            let project_root = tempfile::tempdir().unwrap();
+            let git_source = git_source(project_root.path());
+            create_repository(project_root.path());
+            add_submodule(project_root.path());
+
+            let options = ServerOptions {
+                protocol_v0,
+                ..ServerOptions::default()
+            };
+
+            let addr = serve(project_root.path(), options);
+            let source = source(&format!("http://{addr}/site.git"), None);
+
+            let latest = git_source
+                .git_list_latest(&source, &ref_prefixes(&["refs/heads/main"]))
+                .await
+                .unwrap();
+
+            let paths = ref_prefixes(&["", "index.html", "themes", "themes/theme", "missing"]);
+            let hashes = git_source
+                .git_tree_hashes(&source, &latest.refs_and_commits[0], &paths)
+                .await
+                .unwrap();
+
+            let work_tree = project_root.path().join("work");
+            assert_eq!(
+                hashes,
+                vec![
+                    Some(rev_parse(&work_tree, "main^{tree}")),
+                    Some(rev_parse(&work_tree, "main:index.html")),
+                    Some(rev_parse(&work_tree, "main:themes")),
+                    Some(rev_parse(&work_tree, "main:themes/theme")),
+                    None,
+                ]
+            );
+        }
+    }
+}
@@ -12,7 +12,7 @@ use tokio::{
 
 use fairing_core2::models;
 
-use super::{SshClient, SshReader};
+use super::{http, ssh::SshClient, GitReader};
 
 #[derive(Clone, Debug, serde::Deserialize)]
 struct GitLfsAuthenticate {
@@ -127,20 +127,16 @@ async fn parse_attributes(path: impl AsRef<Path>) -> Result<Vec<glob::Pattern>>
     Ok(globs)
 }
 
-struct SshVecReader {
+struct VecReader {
     limit: usize,
     data: Vec<u8>,
 }
 
 #[async_trait::async_trait]
-impl SshReader for SshVecReader {
+impl GitReader for VecReader {
     type Output = ();
 
-    async fn read<'a>(
-        &mut self,
-        _client: &mut SshClient,
-        input: &'a [u8],
-    ) -> nom::IResult<&'a [u8], Self::Output> {
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
         if self.data.len() + input.len() > self.limit {
             Err(nom::Err::Error(nom::error::Error::new(
                 input,
@@ -153,23 +149,18 @@ impl SshReader for SshVecReader {
     }
 }
 
-/// Download every git lfs file to the source directory.
+/// Download every git lfs file to the source directory, authenticating over ssh.
 #[tracing::instrument(skip(client, source_directory))]
 pub async fn download(
     client: &mut SshClient,
     repository: &models::GitRepositoryParts,
     source_directory: impl AsRef<Path>,
 ) -> Result<()> {
-    let (sender, mut receiver) = mpsc::channel(32);
-
-    let source_directory = fs::canonicalize(source_directory).await?;
-    let list_files = task::spawn(list_files(source_directory, sender));
-
     let command = format!("git-lfs-authenticate '{}' download", repository.path);
     client.exec(&command).await?;
 
     // Read the response of the command into a buffer.
-    let mut reader = SshVecReader {
+    let mut reader = VecReader {
         limit: 16_384,
         data: Vec::with_capacity(1024),
     };
@@ -185,6 +176,46 @@ pub async fn download(
         format!("https://{}/{}/info/lfs", repository.host, repository.path)
     });
 
+    download_objects(&auth_href, &auth.header, source_directory).await
+}
+
+/// Download every git lfs file to the source directory from a repository reached over http,
+/// using the same token as for the repository itself.
+#[tracing::instrument(skip(http_token, source_directory))]
+pub async fn download_http(
+    repository: &models::GitRepositoryParts,
+    http_token: Option<&models::GitHttpToken>,
+    source_directory: impl AsRef<Path>,
+) -> Result<()> {
+    // https://github.com/git-lfs/git-lfs/blob/main/docs/api/server-discovery.md
+    let url = repository.http_url();
+    let auth_href = if url.ends_with(".git") {
+        format!("{url}/info/lfs")
+    } else {
+        format!("{url}.git/info/lfs")
+    };
+
+    let mut auth_headers = BTreeMap::new();
+    if let Some(http_token) = http_token {
+        auth_headers.insert(
+            "authorization".to_owned(),
+            http::authorization_header(repository, http_token),
+        );
+    }
+
+    download_objects(&auth_href, &auth_headers, source_directory).await
+}
+
+async fn download_objects(
+    auth_href: &str,
+    auth_headers: &BTreeMap<String, String>,
+    source_directory: impl AsRef<Path>,
+) -> Result<()> {
+    let (sender, mut receiver) = mpsc::channel(32);
+
+    let source_directory = fs::canonicalize(source_directory).await?;
+    let list_files = task::spawn(list_files(source_directory, sender));
+
     tracing::trace!("using git lfs endpoint {auth_href}");
 
     let mut http_client = reqwest::Client::builder().build()?;
@@ -195,7 +226,7 @@ pub async fn download(
         file_buffer.push(lfs_file);
 
         if file_buffer.len() == file_buffer.capacity() {
-            fetch(&mut http_client, &auth_href, &auth.header, &file_buffer).await?;
+            fetch(&mut http_client, auth_href, auth_headers, &file_buffer).await?;
             file_buffer.clear();
         }
     }
@@ -205,7 +236,7 @@ pub async fn download(
     list_files.await??;
 
     if !file_buffer.is_empty() {
-        fetch(&mut http_client, &auth_href, &auth.header, &file_buffer).await?;
+        fetch(&mut http_client, auth_href, auth_headers, &file_buffer).await?;
     }
 
     Ok(())
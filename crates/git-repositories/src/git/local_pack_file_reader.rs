@@ -5,7 +5,7 @@ use std::{
     convert::TryInto,
     fs::Permissions,
     os::unix::fs::PermissionsExt,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::Arc,
 };
 use tokio::{
@@ -19,6 +19,7 @@ use super::{
         commit_object, delta_instruction, pack_file_variable_length, tree_item, DeltaInstruction,
         PackFileObjectType, TreeItem, TreeItemBlobMode,
     },
+    submodules::Gitlink,
     IndexObject,
 };
 
@@ -106,14 +107,18 @@ impl LocalPackFileReader {
         }
     }
 
+    /// Lists ref deltas whose parents can be reconstructed, together with the number of ref
+    /// deltas whose parents are missing and the number of ref deltas that have to wait for their
+    /// parents to be reconstructed first.
     pub async fn list_ref_deltas(
         &self,
-    ) -> Result<(Vec<(Box<[u8]>, IndexObject, IndexObject)>, usize)> {
+    ) -> Result<(Vec<(Box<[u8]>, IndexObject, IndexObject)>, usize, usize)> {
         let index = self.index.clone();
 
         task::spawn_blocking(move || {
             let mut ref_deltas = vec![];
             let mut ref_delta_parents_not_found = 0;
+            let mut ref_delta_parents_waiting = 0;
 
             for (key, value) in index.full_iterator(rocksdb::IteratorMode::Start) {
                 if ref_deltas.len() == 127 {
@@ -130,13 +135,22 @@ impl LocalPackFileReader {
 
                 if let Some(parent) = index.get(&parent)? {
                     let parent = bincode::deserialize::<IndexObject>(&parent)?;
-                    ref_deltas.push((key, value, parent));
+
+                    if let PackFileObjectType::RefDelta { .. } = parent.type_ {
+                        ref_delta_parents_waiting += 1;
+                    } else {
+                        ref_deltas.push((key, value, parent));
+                    }
                 } else {
                     ref_delta_parents_not_found += 1;
                 }
             }
 
-            Ok((ref_deltas, ref_delta_parents_not_found))
+            Ok((
+                ref_deltas,
+                ref_delta_parents_not_found,
+                ref_delta_parents_waiting,
+            ))
         })
         .await?
     }
@@ -151,22 +165,20 @@ struct TreeParser<'a> {
     path: PathBuf,
 }
 
-#[tracing::instrument(skip(work_directory))]
-pub async fn extract(
-    commit_key: [u8; 20],
-    work_directory: impl AsRef<Path>,
+/// Opens the object store in `store_directory`, with the ref deltas of the last fetch
+/// reconstructed.
+async fn open_reconstructed(
+    store_directory: impl AsRef<Path>,
     index: Arc<rocksdb::DB>,
-) -> Result<PathBuf> {
-    let work_directory = work_directory.as_ref();
-    let mut pack_file_reader = LocalPackFileReader::open(work_directory, index).await?;
-    let source_directory = work_directory.join("source");
+) -> Result<LocalPackFileReader> {
+    let mut pack_file_reader = LocalPackFileReader::open(store_directory, index).await?;
 
     let mut reconstructed_ref_deltas = 0;
 
     // Reconstruct ref deltas.
     tracing::trace!("reconstructing ref deltas");
     loop {
-        let (ref_deltas, ref_delta_parents_not_found) = pack_file_reader
+        let (ref_deltas, ref_delta_parents_not_found, ref_delta_parents_waiting) = pack_file_reader
             .list_ref_deltas()
             .await
             .context("listing ref deltas")?;
@@ -177,6 +189,11 @@ pub async fn extract(
                 "found {} orphaned ref deltas",
                 ref_delta_parents_not_found
             );
+            ensure!(
+                ref_delta_parents_waiting == 0,
+                "found {} ref deltas with circular parents",
+                ref_delta_parents_waiting
+            );
             break;
         } else {
             reconstructed_ref_deltas += ref_deltas.len();
@@ -190,9 +207,11 @@ pub async fn extract(
 
             let index = pack_file_reader.index();
 
+            // Deltas that were offset deltas in the pack refer to their parents by the key of
+            // the delta, keep the reconstructed object under that key as well.
             task::spawn_blocking(move || {
-                index.put(reconstructed_key, value)?;
-                index.delete(ref_delta_key)?;
+                index.put(reconstructed_key, &value)?;
+                index.put(ref_delta_key, &value)?;
                 Ok::<_, anyhow::Error>(())
             })
             .await??;
@@ -203,6 +222,99 @@ pub async fn extract(
 
     tracing::debug!("reconstructed {reconstructed_ref_deltas} ref deltas");
 
+    Ok(pack_file_reader)
+}
+
+/// Hashes of the objects at `paths` in the tree of the commit, `None` for paths that don't
+/// exist. Paths are separated by `/`, the empty path is the tree of the commit itself.
+#[tracing::instrument(skip(store_directory, index))]
+pub async fn tree_hashes(
+    commit_key: [u8; 20],
+    store_directory: impl AsRef<Path>,
+    index: Arc<rocksdb::DB>,
+    paths: &[String],
+) -> Result<Vec<Option<[u8; 20]>>> {
+    let pack_file_reader = open_reconstructed(store_directory, index).await?;
+
+    let (_commit, commit_data) = pack_file_reader
+        .object(commit_key)
+        .await?
+        .ok_or_else(|| anyhow!("couldn't find commit"))?;
+
+    let (_, commit) =
+        commit_object(commit_data).map_err(|err| anyhow!("error parsing commit: {:?}", err))?;
+
+    let mut hashes = vec![];
+
+    for path in paths {
+        let mut hash = Some(commit.tree);
+        let mut is_tree = true;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let tree = match hash {
+                Some(tree) if is_tree => tree,
+                _ => {
+                    hash = None;
+                    break;
+                }
+            };
+
+            let (_tree, mut tree_data) = pack_file_reader
+                .object(tree)
+                .await?
+                .ok_or_else(|| anyhow!("couldn't find tree {:?}", tree))?;
+
+            hash = None;
+
+            while !tree_data.is_empty() {
+                let (input, tree_item) = tree_item(tree_data)
+                    .map_err(|err| anyhow!("error when parsing tree: {:?}", err))?;
+                tree_data = input;
+
+                match tree_item {
+                    TreeItem::Tree {
+                        name: item_name,
+                        hash: item_hash,
+                    } if item_name == name => {
+                        hash = Some(item_hash);
+                        is_tree = true;
+                        break;
+                    }
+                    TreeItem::Blob {
+                        name: item_name,
+                        hash: item_hash,
+                        ..
+                    }
+                    | TreeItem::Gitlink {
+                        name: item_name,
+                        hash: item_hash,
+                    } if item_name == name => {
+                        hash = Some(item_hash);
+                        is_tree = false;
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        hashes.push(hash);
+    }
+
+    Ok(hashes)
+}
+
+/// Writes the tree of the commit to `source_directory`, with the objects of the object store in
+/// `store_directory`. Submodules are left as empty directories, and returned to be fetched.
+#[tracing::instrument(skip(store_directory, index, source_directory))]
+pub async fn extract(
+    commit_key: [u8; 20],
+    store_directory: impl AsRef<Path>,
+    index: Arc<rocksdb::DB>,
+    source_directory: PathBuf,
+) -> Result<Vec<Gitlink>> {
+    let pack_file_reader = open_reconstructed(store_directory, index).await?;
+
     let (_commit, commit_data) = pack_file_reader
         .object(commit_key)
         .await?
@@ -221,6 +333,7 @@ pub async fn extract(
     fs::create_dir_all(&source_directory).await?;
 
     let path_build = fs::canonicalize(&source_directory).await?;
+    let mut gitlinks = vec![];
     let mut tree_parsers = vec![TreeParser {
         input: tree_data,
         path: path_build.clone(),
@@ -255,7 +368,17 @@ pub async fn extract(
                     TreeItemBlobMode::SymbolicLink => {
                         let target_path = std::str::from_utf8(blob_data)
                             .map_err(|_| anyhow!("symlink is not a valid string"))?;
-                        fs::symlink(&path, target_path).await?;
+
+                        // Builds check again where links lead once all of them exist, links
+                        // can point through other links.
+                        if link_stays_in(&path_build, &path, target_path) {
+                            fs::symlink(target_path, &path).await?;
+                        } else {
+                            tracing::warn!(
+                                "skipping symlink {} pointing outside of the source",
+                                path.display()
+                            );
+                        }
                     }
                 };
             }
@@ -278,6 +401,20 @@ pub async fn extract(
                     path,
                 });
             }
+            TreeItem::Gitlink { name, hash } => {
+                let path = tree_parser.path.join(name);
+                let parent_path = fs::canonicalize(path.parent().unwrap()).await?;
+                if !parent_path.starts_with(&path_build) {
+                    return Err(anyhow!("path points outside of build directory"));
+                }
+
+                fs::create_dir(&path).await?;
+
+                gitlinks.push(Gitlink {
+                    path: path.strip_prefix(&path_build)?.to_owned(),
+                    commit: hex::encode(hash),
+                });
+            }
         }
 
         if !input.is_empty() {
@@ -288,7 +425,30 @@ pub async fn extract(
         }
     }
 
-    Ok(source_directory)
+    Ok(gitlinks)
+}
+
+/// Whether `target` of the link at `path` is inside `root`, without following other links.
+fn link_stays_in(root: &Path, path: &Path, target: &str) -> bool {
+    let mut resolved = match path.parent() {
+        Some(parent) => parent.to_owned(),
+        None => return false,
+    };
+
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !resolved.pop() || !resolved.starts_with(root) {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    resolved.starts_with(root)
 }
 
 async fn reconstruct<'a>(
@@ -314,7 +474,7 @@ async fn reconstruct<'a>(
         PackFileObjectType::Tree => sha1_hasher.update(b"tree"),
         PackFileObjectType::Blob => sha1_hasher.update(b"blob"),
         PackFileObjectType::Tag => sha1_hasher.update(b"tag"),
-        PackFileObjectType::RefDelta { .. } => {
+        PackFileObjectType::OfsDelta { .. } | PackFileObjectType::RefDelta { .. } => {
             return Err(anyhow!(
                 "ref delta can not have another ref delta as a parent"
             ));
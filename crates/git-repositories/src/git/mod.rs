@@ -1,21 +1,37 @@
-use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use anyhow::{anyhow, ensure, Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use fairing_core2::{models, repositories::GitSourceRepository};
 
+use client::GitClient;
+use fetch_response_reader::{GitFetchOutput, GitFetchResponseReader};
 use git_pack_file_reader::GitPackFileReader;
+use object_store::{ObjectStore, ObjectStoreCache};
 use parsers::PackFileObjectType;
 use pkt_line_reader::{GitPktLineOutput, GitPktLineReader};
-use ssh::{SshClient, SshClientConfig, SshReader};
+use protocol::{fetch_request, ls_refs_request, GitCapabilities, GitProtocol};
+use submodules::Gitlink;
 
+mod client;
+mod fetch_response_reader;
 mod git_pack_file_reader;
+mod http;
 mod lfs;
 mod local_pack_file_reader;
+mod object_store;
 mod parsers;
 mod pkt_line_reader;
+mod protocol;
 mod ssh;
+mod submodules;
 
-const REVISION_LIMIT: usize = 4096;
+const DEFAULT_CACHE_SIZE: u64 = 10 << 30;
+
+/// How deep submodules can be nested in submodules.
+const MAX_SUBMODULE_DEPTH: usize = 4;
 
 #[derive(Copy, Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct IndexObject {
@@ -24,49 +40,102 @@ pub struct IndexObject {
     length: u64,
 }
 
-pub struct ThrusshGitSource;
+/// Parses data read from a repository, the data may end anywhere and is buffered until the
+/// reader can make progress.
+#[async_trait::async_trait]
+pub trait GitReader {
+    type Output;
+
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output>;
+}
+
+/// Git source reached over ssh or smart http.
+pub struct ThrusshGitSource {
+    object_stores: ObjectStoreCache,
+}
+
+impl ThrusshGitSource {
+    pub fn new() -> ThrusshGitSource {
+        ThrusshGitSource {
+            object_stores: ObjectStoreCache::new(PathBuf::from(".data/git"), DEFAULT_CACHE_SIZE),
+        }
+    }
+
+    /// Sets the directory where the objects of each source are kept between builds, defaults to
+    /// `.data/git`.
+    pub fn with_cache_directory(mut self, cache_directory: impl Into<PathBuf>) -> ThrusshGitSource {
+        self.object_stores.set_directory(cache_directory.into());
+        self
+    }
+
+    /// Sets how many bytes the objects of all sources may take up before the least recently
+    /// used are evicted, defaults to 10 GiB.
+    pub fn with_max_cache_size(mut self, max_cache_size: u64) -> ThrusshGitSource {
+        self.object_stores.set_max_size(max_cache_size);
+        self
+    }
+}
 
 #[async_trait::async_trait]
 impl GitSourceRepository for ThrusshGitSource {
     async fn git_list_latest(
         &self,
         source: &models::SourceWithKind<models::GitSource>,
-    ) -> Result<Vec<models::GitSourceRefAndCommit>> {
+        ref_prefixes: &[String],
+    ) -> Result<models::GitSourceLatest> {
         let repository = source.with.repository_url.parts()?;
 
-        let config = SshClientConfig {
-            addr: (repository.host.as_str(), repository.port),
-            user: &repository.user,
-            key_pair: source.with.id_ed25519.key_pair(),
-        };
+        let mut client = GitClient::connect(source, &repository).await?;
 
-        let mut client = SshClient::connect(config).await?;
-        let mut reader = GitPktLineReader::new();
+        let mut protocol = client.upload_pack(&repository).await?;
+        let refs_and_commits = list_refs(&mut client, &mut protocol, ref_prefixes).await?;
 
-        let mut revisions = vec![];
+        client.data(&b"0000"[..]).await?;
 
-        let command = format!("git-upload-pack '{}'", repository.path);
-        client.exec(&command).await?;
+        let host_key = client.host_key().cloned();
 
-        while let Some(output) = client.read(&mut reader).await? {
-            if revisions.len() > REVISION_LIMIT {
-                tracing::debug!("too many revisions");
-                break;
-            }
+        client.disconnect().await?;
 
-            match output {
-                Some(GitPktLineOutput::RefPkt(tree_revision)) => revisions.push(tree_revision),
-                Some(GitPktLineOutput::Flush) => {
-                    client.data(&b"0000"[..]).await?;
-                    break;
-                }
-                None => (),
-            }
+        Ok(models::GitSourceLatest {
+            host_key,
+            refs_and_commits,
+        })
+    }
+
+    async fn git_tree_hashes(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        paths: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        // The commit is fetched in full, it is usually built next and is already in the object
+        // store by then.
+        let store = self.object_stores.open(source).await?;
+
+        let (mut client, protocol, want) = connect_for_commit(source, ref_and_commit).await?;
+
+        let hashes = async {
+            let index = fetch(&mut client, &protocol, &store, &want, ref_and_commit).await?;
+
+            local_pack_file_reader::tree_hashes(
+                commit_key(&ref_and_commit.commit)?,
+                store.path(),
+                index,
+                paths,
+            )
+            .await
+            .with_context(|| read_context(&want, ref_and_commit))
         }
+        .await;
+
+        let hashes = self.finish_fetch(store, ref_and_commit, hashes).await?;
 
         client.disconnect().await?;
 
-        Ok(revisions)
+        Ok(hashes
+            .into_iter()
+            .map(|hash| hash.map(hex::encode))
+            .collect())
     }
 
     async fn git_clone(
@@ -75,88 +144,357 @@ impl GitSourceRepository for ThrusshGitSource {
         ref_and_commit: &models::GitSourceRefAndCommit,
         work_directory: PathBuf,
     ) -> Result<PathBuf> {
+        let source_directory = work_directory.join("source");
+
+        let gitlinks = self
+            .clone_commit(source, ref_and_commit, &source_directory)
+            .await?;
+
+        // Submodules are fetched from the repository they point to, relative urls are resolved
+        // against the repository of the source or submodule that they are nested in.
+        let mut pending = vec![(source.clone(), source_directory.clone(), gitlinks, 1)];
+
+        while let Some((parent, parent_directory, gitlinks, depth)) = pending.pop() {
+            if gitlinks.is_empty() {
+                continue;
+            }
+
+            ensure!(
+                depth <= MAX_SUBMODULE_DEPTH,
+                "submodules are nested more than {MAX_SUBMODULE_DEPTH} levels deep"
+            );
+
+            let submodules = submodules::read_gitmodules(&parent_directory).await?;
+
+            for gitlink in gitlinks {
+                let submodule = match submodules
+                    .iter()
+                    .find(|submodule| Path::new(&submodule.path) == gitlink.path)
+                {
+                    Some(submodule) => submodule,
+                    None => {
+                        tracing::warn!("no submodule configured for {}", gitlink.path.display());
+                        continue;
+                    }
+                };
+
+                let source = submodule_source(&parent, &submodule.url)?;
+
+                // The branch is only needed to find the commit in repositories that don't allow
+                // fetching it directly.
+                let ref_ = match submodule.branch {
+                    Some(ref branch) if branch != "." => format!("refs/heads/{branch}"),
+                    _ => "HEAD".to_owned(),
+                };
+
+                let ref_and_commit = models::GitSourceRefAndCommit {
+                    ref_,
+                    commit: gitlink.commit,
+                };
+
+                let directory = parent_directory.join(&gitlink.path);
+                let gitlinks = self
+                    .clone_commit(&source, &ref_and_commit, &directory)
+                    .await
+                    .with_context(|| format!("fetching submodule {}", submodule.path))?;
+
+                pending.push((source, directory, gitlinks, depth + 1));
+            }
+        }
+
+        Ok(source_directory)
+    }
+}
+
+impl ThrusshGitSource {
+    /// Fetches the commit and writes its tree to `source_directory`, returning the submodules
+    /// that are left to fetch.
+    async fn clone_commit(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        source_directory: &Path,
+    ) -> Result<Vec<Gitlink>> {
         let repository = source.with.repository_url.parts()?;
 
-        let config = SshClientConfig {
-            addr: (repository.host.as_str(), repository.port),
-            user: &repository.user,
-            key_pair: source.with.id_ed25519.key_pair(),
-        };
+        // Builds of the same source wait here, before they connect to the repository.
+        let store = self.object_stores.open(source).await?;
+
+        let (mut client, protocol, want) = connect_for_commit(source, ref_and_commit).await?;
 
-        let mut client = SshClient::connect(config).await?;
-        let mut reader = GitPktLineReader::new();
+        let gitlinks = async {
+            let index = fetch(&mut client, &protocol, &store, &want, ref_and_commit).await?;
 
-        let mut found_hash: Option<String> = None;
+            local_pack_file_reader::extract(
+                commit_key(&ref_and_commit.commit)?,
+                store.path(),
+                index,
+                source_directory.to_owned(),
+            )
+            .await
+            .with_context(|| read_context(&want, ref_and_commit))
+        }
+        .await;
 
-        let command = format!("git-upload-pack '{}'", repository.path);
-        client.exec(&command).await?;
+        let gitlinks = self.finish_fetch(store, ref_and_commit, gitlinks).await?;
 
-        while let Some(output) = client.read(&mut reader).await? {
-            match output {
-                Some(GitPktLineOutput::RefPkt(pkt)) => {
-                    if pkt.ref_ == ref_and_commit.ref_ {
-                        found_hash = Some(pkt.commit);
-                    }
+        if lfs::detect(source_directory).await? {
+            match client {
+                GitClient::Ssh(ref mut client) => {
+                    lfs::download(client, &repository, source_directory).await?
                 }
-                Some(GitPktLineOutput::Flush) => {
-                    tracing::info!("caps: {:?}", reader.capabilities());
-
-                    if let Some(found_hash) = found_hash {
-                        // Request the commit we want if it was part of the ref pkts above,
-                        // otherwise request the closest commit.
-                        // TODO: look for allowReachableSHA1InWant or allowAnySHA1InWant in capabilities.
-                        let line = if found_hash == ref_and_commit.commit {
-                            format!("want {} deepen 1\n", found_hash)
-                        } else {
-                            format!("want {}\n", found_hash)
-                        };
-
-                        let line = format!("{:04x}{}", line.len() + 4, line);
-                        client.data(line.as_bytes()).await?;
-                        client.data(&b"0000"[..]).await?;
-                        client.data(&b"0009done\n"[..]).await?;
-                        break;
-                    } else {
-                        client.data(&b"0000"[..]).await?;
-                        client.disconnect().await?;
-                        return Err(anyhow!("remote ref no longer available"));
-                    }
+                GitClient::Http(_) => {
+                    let http_token = source.with.http_token.as_ref();
+                    lfs::download_http(&repository, http_token, source_directory).await?
                 }
-                None => (),
             }
         }
 
-        let mut reader = GitPackFileReader::open(&work_directory).await?;
+        client.disconnect().await?;
 
-        while let Some(Some(())) = client.read(&mut reader).await? {}
+        Ok(gitlinks)
+    }
 
-        let index = reader.flush().await?;
+    /// Keeps the commit in the object store if it was read from it, and evicts other stores if
+    /// the cache has grown too large.
+    async fn finish_fetch<T>(
+        &self,
+        store: ObjectStore,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        result: Result<T>,
+    ) -> Result<T> {
+        let output = match result {
+            Ok(output) => output,
+            Err(err) => {
+                // Objects from a failed fetch might depend on objects that never arrived.
+                store.clear().await?;
+                return Err(err);
+            }
+        };
 
-        //client.disconnect().await?;
+        store.add_commit(&ref_and_commit.commit).await?;
+        drop(store);
 
-        let commit_key = {
-            let mut key = [0u8; 20];
-            hex::decode_to_slice(&ref_and_commit.commit, &mut key)?;
-            key
-        };
+        if let Err(err) = self.object_stores.evict().await {
+            tracing::warn!("evicting object stores: {err:?}");
+        }
 
-        let source_directory =
-            local_pack_file_reader::extract(commit_key, &work_directory, index).await?;
+        Ok(output)
+    }
+}
 
-        if lfs::detect(&source_directory).await? {
-            let config = SshClientConfig {
-                addr: (repository.host.as_str(), repository.port),
-                user: &repository.user,
-                key_pair: source.with.id_ed25519.key_pair(),
-            };
+/// Connects to the repository of the source and picks what to fetch to get the commit.
+async fn connect_for_commit(
+    source: &models::SourceWithKind<models::GitSource>,
+    ref_and_commit: &models::GitSourceRefAndCommit,
+) -> Result<(GitClient, GitProtocol, String)> {
+    let repository = source.with.repository_url.parts()?;
+
+    let mut client = GitClient::connect(source, &repository).await?;
+
+    let mut protocol = client.upload_pack(&repository).await?;
+    let commit = &ref_and_commit.commit;
+
+    // Protocol v2 repositories send any commit they have. Protocol v0 repositories only send
+    // commits that no ref points to if their capabilities allow it, otherwise the history of
+    // the ref has to be fetched and the commit looked up in it.
+    let want = if let GitProtocol::V2(_) = protocol {
+        commit.clone()
+    } else {
+        let ref_prefixes = std::slice::from_ref(&ref_and_commit.ref_);
+        let found_hash = list_refs(&mut client, &mut protocol, ref_prefixes)
+            .await?
+            .into_iter()
+            .find(|found| found.ref_ == ref_and_commit.ref_)
+            .map(|found| found.commit);
+
+        match found_hash {
+            Some(found_hash) if &found_hash == commit => found_hash,
+            _ if protocol.allows_any_want() => commit.clone(),
+            Some(found_hash) => {
+                tracing::debug!("fetching the history of {}", ref_and_commit.ref_);
+                found_hash
+            }
+            None => {
+                client.data(&b"0000"[..]).await?;
+                client.disconnect().await?;
+                return Err(anyhow!("remote ref no longer available"));
+            }
+        }
+    };
 
-            //let mut client = SshClient::connect(config).await?;
+    Ok((client, protocol, want))
+}
 
-            lfs::download(&mut client, &repository, &source_directory).await?;
+/// Source for the repository of a submodule. The credentials of the source are only used if the
+/// submodule is on the same host.
+///
+/// Submodules don't pin the host key they are presented with, so a submodule reached over ssh
+/// needs known host keys: the pinned host keys of a source on the same host reached over ssh, or
+/// the built-in host keys of a forge.
+fn submodule_source(
+    parent: &models::SourceWithKind<models::GitSource>,
+    url: &str,
+) -> Result<models::SourceWithKind<models::GitSource>> {
+    let url = submodules::resolve_url(parent.with.repository_url.as_str(), url)?;
+    let repository_url: models::GitRepository = url
+        .parse()
+        .with_context(|| format!("invalid submodule url {url}"))?;
+
+    let repository = repository_url.parts()?;
+    let parent_repository = parent.with.repository_url.parts()?;
+    let same_host = repository.host == parent_repository.host;
+
+    let known_host_keys = if same_host && parent_repository.transport == models::GitTransport::Ssh {
+        parent.with.trusted_host_keys()?
+    } else {
+        models::GitHostKey::builtin(&repository.host)
+    };
+
+    ensure!(
+        repository.transport != models::GitTransport::Ssh || !known_host_keys.is_empty(),
+        "submodule {url} is reached over ssh, but the host key of {} isn't known",
+        repository.host
+    );
+
+    Ok(models::SourceWithKind {
+        project_id: parent.project_id,
+        name: parent.name.clone(),
+        with: models::GitSource {
+            repository_url,
+            // Submodules on other hosts are fetched anonymously, a throwaway key doesn't identify
+            // the source to them.
+            id_ed25519: if same_host {
+                parent.with.id_ed25519.clone()
+            } else {
+                models::Ed25519::generate()
+            },
+            known_host_keys,
+            http_token: if same_host {
+                parent.with.http_token.clone()
+            } else {
+                None
+            },
+        },
+    })
+}
+
+/// Fetches `want` into the object store, leaving out the objects of the commits that the store
+/// already has.
+async fn fetch(
+    client: &mut GitClient,
+    protocol: &GitProtocol,
+    store: &ObjectStore,
+    want: &str,
+    ref_and_commit: &models::GitSourceRefAndCommit,
+) -> Result<Arc<rocksdb::DB>> {
+    let commit = &ref_and_commit.commit;
+    let deepen = want == commit && protocol.allows_shallow();
+
+    let haves = if protocol.allows_shallow() {
+        store.commits().await?
+    } else {
+        vec![]
+    };
+
+    let pack_reader = GitPackFileReader::open(store.path()).await?;
+
+    let mut reader = match protocol {
+        GitProtocol::V0(_) => GitFetchResponseReader::v0(pack_reader),
+        GitProtocol::V2(capabilities) => {
+            capabilities.require("fetch")?;
+            GitFetchResponseReader::v2(pack_reader)
         }
+    };
+
+    let request = fetch_request(protocol, want, deepen, &haves);
+    client.data(&request).await?;
+
+    loop {
+        match client.read(&mut reader).await {
+            Ok(Some(GitFetchOutput::Progress)) => (),
+            Ok(_) => break,
+            Err(err) => match reader.remote_error() {
+                Some(remote_error) => {
+                    return Err(anyhow!(
+                        "the repository couldn't send commit {commit}: {remote_error}"
+                    ))
+                }
+                None => return Err(err).context("fetching pack"),
+            },
+        }
+    }
 
-        client.disconnect().await?;
+    let index = reader.flush().await?;
 
-        Ok(source_directory)
+    // Protocol v2 repositories wait for another command until they get a flush.
+    if let GitProtocol::V2(_) = protocol {
+        client.data(&b"0000"[..]).await?;
+    }
+
+    Ok(index)
+}
+
+fn commit_key(commit: &str) -> Result<[u8; 20]> {
+    let mut key = [0u8; 20];
+    hex::decode_to_slice(commit, &mut key)?;
+    Ok(key)
+}
+
+/// Context for errors reading the commit from the object store, when the repository sent the
+/// history of the ref instead the commit might not be part of it.
+fn read_context(want: &str, ref_and_commit: &models::GitSourceRefAndCommit) -> String {
+    let commit = &ref_and_commit.commit;
+
+    if want == commit {
+        format!("reading commit {commit}")
+    } else {
+        format!(
+            "commit {commit} isn't part of the history of {}, and the repository doesn't allow \
+             fetching it directly",
+            ref_and_commit.ref_
+        )
+    }
+}
+
+/// Lists the refs that start with any of `ref_prefixes`. Protocol v2 repositories only send
+/// those, v0 repositories advertise all of their refs and the others are skipped. The
+/// capabilities that v0 repositories advertise with their refs are kept in `protocol`.
+async fn list_refs(
+    client: &mut GitClient,
+    protocol: &mut GitProtocol,
+    ref_prefixes: &[String],
+) -> Result<Vec<models::GitSourceRefAndCommit>> {
+    match protocol {
+        GitProtocol::V0(_) => (),
+        GitProtocol::V2(_) if ref_prefixes.is_empty() => return Ok(vec![]),
+        GitProtocol::V2(capabilities) => {
+            capabilities.require("ls-refs")?;
+
+            let request = ls_refs_request(ref_prefixes);
+            client.data(&request).await?;
+        }
     }
+
+    let mut reader = GitPktLineReader::new(ref_prefixes);
+    let mut refs_and_commits = vec![];
+
+    while let Some(output) = client.read(&mut reader).await? {
+        match output {
+            Some(GitPktLineOutput::RefPkt(ref_and_commit)) => refs_and_commits.push(ref_and_commit),
+            Some(GitPktLineOutput::Flush) => {
+                if let GitProtocol::V0(capabilities) = protocol {
+                    *capabilities = GitCapabilities::from_v0(reader.capabilities().unwrap_or(""));
+                    tracing::trace!("capabilities: {:?}", capabilities);
+                }
+
+                return Ok(refs_and_commits);
+            }
+            None => (),
+        }
+    }
+
+    Err(anyhow!(
+        "the repository closed the connection while listing refs"
+    ))
 }
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::OwnedMutexGuard};
+
+use fairing_core2::models;
+
+/// How many of the last fetched commits are kept as `have` lines, older commits have most of
+/// their objects in common with these anyway.
+const MAX_COMMITS: usize = 32;
+
+const COMMITS_FILE: &str = "commits";
+const LAST_USED_FILE: &str = "last-used";
+
+/// Object stores of the sources that have been built, one directory each. The least recently
+/// used stores are evicted once they take up more than `max_size` bytes together.
+pub struct ObjectStoreCache {
+    directory: PathBuf,
+    max_size: u64,
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ObjectStoreCache {
+    pub fn new(directory: PathBuf, max_size: u64) -> ObjectStoreCache {
+        ObjectStoreCache {
+            directory,
+            max_size,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_directory(&mut self, directory: PathBuf) {
+        self.directory = directory;
+    }
+
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    /// Opens the store of the source, waiting for builds of the same source to be done with it.
+    /// Sources that are recreated with another repository get a new store.
+    pub async fn open(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+    ) -> Result<ObjectStore> {
+        let key = {
+            let mut hasher = Sha1::new();
+            hasher.update(source.project_id.into_uuid().as_bytes());
+            hasher.update(source.name.as_str());
+            hasher.update(b"\0");
+            hasher.update(source.with.repository_url.as_str());
+            hex::encode(hasher.finalize())
+        };
+
+        let path = self.directory.join(key);
+        let guard = self.lock(&path).lock_owned().await;
+
+        fs::create_dir_all(&path)
+            .await
+            .context("creating object store")?;
+
+        let last_used = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        fs::write(path.join(LAST_USED_FILE), last_used.to_string()).await?;
+
+        Ok(ObjectStore {
+            path,
+            _guard: guard,
+        })
+    }
+
+    /// Deletes the least recently used stores until the rest fit, stores that are in use are
+    /// skipped.
+    pub async fn evict(&self) -> Result<()> {
+        let mut stores = vec![];
+        let mut size = 0;
+
+        let mut dir = match fs::read_dir(&self.directory).await {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("listing object stores"),
+        };
+
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+
+            let last_used = fs::read_to_string(path.join(LAST_USED_FILE))
+                .await
+                .ok()
+                .and_then(|last_used| last_used.parse::<u128>().ok())
+                .unwrap_or(0);
+
+            let store_size = directory_size(&path).await?;
+            size += store_size;
+
+            stores.push((last_used, path, store_size));
+        }
+
+        stores.sort();
+
+        for (_, path, store_size) in stores {
+            if size <= self.max_size {
+                break;
+            }
+
+            let lock = self.lock(&path);
+            let _guard = match lock.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => continue,
+            };
+
+            tracing::debug!("evicting object store {}", path.display());
+
+            fs::remove_dir_all(&path)
+                .await
+                .context("evicting object store")?;
+
+            size -= store_size;
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.entry(path.to_owned()).or_default().clone()
+    }
+}
+
+/// Pack and index of the objects fetched from a source, together with the commits whose trees
+/// are complete in it. The store is locked until it is dropped.
+pub struct ObjectStore {
+    path: PathBuf,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl ObjectStore {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Commits that have been fetched into the store, the most recent last.
+    pub async fn commits(&self) -> Result<Vec<String>> {
+        match fs::read_to_string(self.path.join(COMMITS_FILE)).await {
+            Ok(commits) => Ok(commits.lines().map(|commit| commit.to_owned()).collect()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err).context("reading commits of object store"),
+        }
+    }
+
+    pub async fn add_commit(&self, commit: &str) -> Result<()> {
+        let mut commits = self.commits().await?;
+        commits.retain(|existing| existing != commit);
+        commits.push(commit.to_owned());
+
+        let skip = commits.len().saturating_sub(MAX_COMMITS);
+        let commits = commits[skip..]
+            .iter()
+            .map(|commit| format!("{commit}\n"))
+            .collect::<String>();
+
+        fs::write(self.path.join(COMMITS_FILE), commits)
+            .await
+            .context("writing commits of object store")
+    }
+
+    /// Empties the store, for when it might have been left inconsistent.
+    pub async fn clear(&self) -> Result<()> {
+        fs::remove_dir_all(&self.path)
+            .await
+            .context("clearing object store")?;
+        fs::create_dir_all(&self.path)
+            .await
+            .context("clearing object store")
+    }
+}
+
+async fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    let mut paths = vec![path.to_owned()];
+
+    while let Some(path) = paths.pop() {
+        let mut dir = fs::read_dir(&path).await?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+
+            if metadata.is_dir() {
+                paths.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_store(cache: &ObjectStoreCache, name: &str, last_used: u128) -> PathBuf {
+        let path = cache.directory.join(name);
+        fs::create_dir_all(&path).await.unwrap();
+        fs::write(path.join(LAST_USED_FILE), last_used.to_string())
+            .await
+            .unwrap();
+        fs::write(path.join("pack"), vec![0; 1024]).await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ObjectStoreCache::new(directory.path().to_owned(), 1500);
+
+        let old = create_store(&cache, "old", 1).await;
+        let new = create_store(&cache, "new", 2).await;
+
+        cache.evict().await.unwrap();
+
+        assert!(!old.exists());
+        assert!(new.exists());
+    }
+
+    #[tokio::test]
+    async fn evict_skips_stores_in_use() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ObjectStoreCache::new(directory.path().to_owned(), 1500);
+
+        let old = create_store(&cache, "old", 1).await;
+        let new = create_store(&cache, "new", 2).await;
+
+        let _guard = cache.lock(&old).lock_owned().await;
+        cache.evict().await.unwrap();
+
+        assert!(old.exists());
+        assert!(!new.exists());
+    }
+}
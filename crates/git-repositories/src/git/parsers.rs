@@ -5,6 +5,8 @@ use anyhow::anyhow;
 pub enum PktLine<D> {
     Data(D),
     Flush,
+    /// Separates the sections of protocol v2 requests and responses.
+    Delim,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -38,6 +40,19 @@ pub fn flush_pkt<D>(input: &[u8]) -> nom::IResult<&[u8], PktLine<D>> {
     Ok((input, PktLine::Flush))
 }
 
+pub fn delim_pkt<D>(input: &[u8]) -> nom::IResult<&[u8], PktLine<D>> {
+    let (input, _) = nom::bytes::streaming::tag(b"0001")(input)?;
+    Ok((input, PktLine::Delim))
+}
+
+pub fn pkt_line(input: &[u8]) -> nom::IResult<&[u8], PktLine<&[u8]>> {
+    nom::branch::alt((
+        flush_pkt,
+        delim_pkt,
+        nom::combinator::map(data_pkt, PktLine::Data),
+    ))(input)
+}
+
 pub fn ref_pkt_line(input: &[u8]) -> nom::IResult<&[u8], PktLine<RefPkt>> {
     nom::branch::alt((flush_pkt, ref_pkt))(input)
 }
@@ -90,7 +105,13 @@ pub enum PackFileObjectType {
     Tree,
     Blob,
     Tag,
-    RefDelta { parent: [u8; 20] },
+    /// Delta against the object that starts `offset` bytes before this object in the pack.
+    OfsDelta {
+        offset: u64,
+    },
+    RefDelta {
+        parent: [u8; 20],
+    },
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -134,6 +155,11 @@ pub fn pack_file_object_header(input: &[u8]) -> nom::IResult<&[u8], PackFileObje
         2 => (input, PackFileObjectType::Tree),
         3 => (input, PackFileObjectType::Blob),
         4 => (input, PackFileObjectType::Tag),
+        6 => {
+            let (input, offset) = pack_file_ofs_delta_offset(input)?;
+
+            (input, PackFileObjectType::OfsDelta { offset })
+        }
         7 => {
             let (input, parent) = nom::bytes::streaming::take(20usize)(input)?;
             let parent = {
@@ -161,6 +187,36 @@ pub fn pack_file_object_header(input: &[u8]) -> nom::IResult<&[u8], PackFileObje
     ))
 }
 
+/// Offsets of offset deltas are big-endian, and one is added to every byte but the last so that
+/// each offset has exactly one encoding.
+pub fn pack_file_ofs_delta_offset(input: &[u8]) -> nom::IResult<&[u8], u64> {
+    let input_start = input;
+
+    let (mut input, byte) = nom::number::streaming::u8(input)?;
+
+    let mut offset = (byte & 0x7f) as u64;
+    let mut has_more = byte & 0x80 != 0;
+
+    while has_more {
+        let (rest, byte) = nom::number::streaming::u8(input)?;
+        input = rest;
+
+        offset = offset
+            .checked_add(1)
+            .and_then(|offset| offset.checked_mul(1 << 7))
+            .ok_or_else(|| {
+                nom::Err::Failure(nom::error::Error::new(
+                    input_start,
+                    nom::error::ErrorKind::TooLarge,
+                ))
+            })?
+            | (byte & 0x7f) as u64;
+        has_more = byte & 0x80 != 0;
+    }
+
+    Ok((input, offset))
+}
+
 pub fn pack_file_variable_length(input: &[u8]) -> nom::IResult<&[u8], u64> {
     let (input, ((offset, object_length_first), object_length_last)) =
         nom::bits::<_, ((u64, u64), u64), nom::error::Error<(&[u8], usize)>, _, _>(
@@ -291,6 +347,11 @@ pub enum TreeItem<'a> {
         hash: [u8; 20],
         name: &'a str,
     },
+    /// Submodule, `hash` is the commit of the submodule's repository that is checked out.
+    Gitlink {
+        hash: [u8; 20],
+        name: &'a str,
+    },
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -340,6 +401,20 @@ pub fn tree_item<'a>(input: &'a [u8]) -> nom::IResult<&[u8], TreeItem<'a>> {
                 })
             },
         ),
+        nom::combinator::map_res(
+            nom::sequence::tuple((
+                nom::bytes::complete::tag(b"160000 "),
+                nom::bytes::complete::take_while_m_n(1, 200, |c| c != b'\0' && c != b'/'),
+                nom::bytes::complete::tag(b"\0"),
+                object_hash_binary,
+            )),
+            |(_, name, _, hash)| {
+                Ok::<_, anyhow::Error>(TreeItem::Gitlink {
+                    hash,
+                    name: std::str::from_utf8(name).map_err(|_| anyhow!("invalid name"))?,
+                })
+            },
+        ),
     ))(input)
 }
 
@@ -361,6 +436,45 @@ fn tree_item_blob_mode(input: &[u8]) -> nom::IResult<&[u8], TreeItemBlobMode> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn pack_file_object_header_ofs_delta() {
+        let (input, header) =
+            pack_file_object_header(&[0b11100101, 0b00000001, 0x91, 0x2e, 0xff]).unwrap();
+
+        assert_eq!(input, &[0xff]);
+        assert_eq!(header.length, 0b0001_0101);
+        assert!(matches!(
+            header.type_,
+            PackFileObjectType::OfsDelta { offset: 2350 }
+        ));
+    }
+
+    #[test]
+    fn pack_file_ofs_delta_offset_single_byte() {
+        assert_eq!(
+            pack_file_ofs_delta_offset(&[0x7f]),
+            nom::IResult::Ok((&[][..], 127))
+        );
+    }
+
+    #[test]
+    fn pack_file_ofs_delta_offset_multiple_bytes() {
+        assert_eq!(
+            pack_file_ofs_delta_offset(&[0x80, 0x00]),
+            nom::IResult::Ok((&[][..], 128))
+        );
+
+        assert_eq!(
+            pack_file_ofs_delta_offset(&[0x91, 0x2e]),
+            nom::IResult::Ok((&[][..], 2350))
+        );
+
+        assert!(matches!(
+            pack_file_ofs_delta_offset(&[0x91]),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
+
     #[test]
     fn delta_instruction_insert_data() {
         assert_eq!(
@@ -404,4 +518,21 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn tree_item_gitlink() {
+        let mut input = b"160000 theme\0".to_vec();
+        input.extend_from_slice(&[7; 20]);
+
+        assert_eq!(
+            tree_item(&input),
+            nom::IResult::Ok((
+                &[][..],
+                TreeItem::Gitlink {
+                    hash: [7; 20],
+                    name: "theme",
+                }
+            ))
+        );
+    }
 }
@@ -2,7 +2,7 @@ use fairing_core2::models;
 
 use super::{
     parsers::{ref_pkt_line, PktLine, RefPkt},
-    SshClient, SshReader,
+    GitReader,
 };
 
 pub enum GitPktLineOutput {
@@ -10,15 +10,17 @@ pub enum GitPktLineOutput {
     Flush,
 }
 
+/// Reads refs as they are listed by a v0 ref advertisement or a v2 `ls-refs` response, keeping
+/// the ones that start with any of the ref prefixes.
 pub struct GitPktLineReader {
-    head_hash: Option<String>,
+    ref_prefixes: Vec<String>,
     capabilities: Option<String>,
 }
 
 impl GitPktLineReader {
-    pub fn new() -> GitPktLineReader {
+    pub fn new(ref_prefixes: &[String]) -> GitPktLineReader {
         GitPktLineReader {
-            head_hash: None,
+            ref_prefixes: ref_prefixes.to_vec(),
             capabilities: None,
         }
     }
@@ -29,14 +31,10 @@ impl GitPktLineReader {
 }
 
 #[async_trait::async_trait]
-impl SshReader for GitPktLineReader {
+impl GitReader for GitPktLineReader {
     type Output = Option<GitPktLineOutput>;
 
-    async fn read<'a>(
-        &mut self,
-        _client: &mut SshClient,
-        input: &'a [u8],
-    ) -> nom::IResult<&'a [u8], Self::Output> {
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
         let (input, pkt_line) = ref_pkt_line(input)?;
 
         // Only the first pkt line is allowed to contain capabilities.
@@ -47,34 +45,29 @@ impl SshReader for GitPktLineReader {
         }
 
         match pkt_line {
-            PktLine::Data(RefPkt {
-                hash,
-                ref_name: "HEAD",
-                ..
-            }) => {
-                self.head_hash = Some(hash.to_owned());
+            // Peeled tags are listed after the tag itself, with the commit it points to.
+            PktLine::Data(RefPkt { ref_name, .. }) if ref_name.ends_with("^{}") => {
                 Ok((input, None))
             }
-            PktLine::Data(RefPkt { hash, ref_name, .. }) if ref_name.starts_with("refs/heads/") => {
-                let hash = hash.to_owned();
-
-                match self.head_hash {
-                    Some(ref head_hash) if head_hash == &hash => (),
-                    _ => return Ok((input, None)),
-                }
-
+            PktLine::Data(RefPkt { hash, ref_name, .. })
+                if self
+                    .ref_prefixes
+                    .iter()
+                    .any(|ref_prefix| ref_name.starts_with(ref_prefix.as_str())) =>
+            {
                 let build = models::GitSourceRefAndCommit {
                     ref_: ref_name.into(),
-                    commit: hash,
+                    commit: hash.to_owned(),
                 };
 
                 Ok((input, Some(GitPktLineOutput::RefPkt(build))))
             }
             PktLine::Data(RefPkt { .. }) => {
-                // Ignore anything that is not a branch.
+                // Ignore refs that aren't tracked.
                 Ok((input, None))
             }
             PktLine::Flush => Ok((input, Some(GitPktLineOutput::Flush))),
+            PktLine::Delim => Ok((input, None)),
         }
     }
 }
@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+
+use super::{
+    parsers::{pkt_line, PktLine},
+    GitReader,
+};
+
+/// Value of `GIT_PROTOCOL` that asks the repository for protocol v2.
+pub const GIT_PROTOCOL_V2: &str = "version=2";
+
+/// Protocol that the repository answered with. Repositories that don't support protocol v2, or
+/// ssh servers that don't pass `GIT_PROTOCOL` on, ignore the request and advertise their refs.
+/// Their capabilities are only known once the first ref has been read.
+pub enum GitProtocol {
+    V0(GitCapabilities),
+    V2(GitCapabilities),
+}
+
+impl GitProtocol {
+    /// Whether commits that no ref points to can be fetched. Protocol v2 allows any object.
+    pub fn allows_any_want(&self) -> bool {
+        match self {
+            GitProtocol::V0(capabilities) => {
+                capabilities.get("allow-reachable-sha1-in-want").is_some()
+                    || capabilities.get("allow-any-sha1-in-want").is_some()
+            }
+            GitProtocol::V2(_) => true,
+        }
+    }
+
+    /// Whether fetches can be limited to a depth, protocol v2 lists it as a feature of `fetch`.
+    pub fn allows_shallow(&self) -> bool {
+        match self {
+            GitProtocol::V0(capabilities) => capabilities.get("shallow").is_some(),
+            GitProtocol::V2(capabilities) => capabilities
+                .get("fetch")
+                .map(|features| features.split(' ').any(|feature| feature == "shallow"))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Capabilities as `<name>[=<value>]`. Protocol v2 advertises one per line, v0 separates them by
+/// spaces after the first ref.
+#[derive(Debug, Default)]
+pub struct GitCapabilities(Vec<String>);
+
+impl GitCapabilities {
+    pub fn from_v0(capabilities: &str) -> GitCapabilities {
+        let capabilities = capabilities
+            .trim_start_matches('\0')
+            .split_whitespace()
+            .map(|capability| capability.to_owned())
+            .collect();
+
+        GitCapabilities(capabilities)
+    }
+
+    /// Value of the capability, capabilities without a value have an empty one.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find_map(|capability| match capability.split_once('=') {
+                Some((capability_name, value)) if capability_name == name => Some(value),
+                None if capability == name => Some(""),
+                _ => None,
+            })
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str> {
+        self.get(name)
+            .ok_or_else(|| anyhow!("the repository doesn't support the {name} command"))
+    }
+}
+
+/// Reads the first pkt line to tell the protocol versions apart, and the capabilities that
+/// follow if it is `version 2`. A v0 ref advertisement is left for [`GitPktLineReader`] to read.
+///
+/// [`GitPktLineReader`]: super::pkt_line_reader::GitPktLineReader
+pub struct GitProtocolReader {
+    capabilities: Option<Vec<String>>,
+}
+
+impl GitProtocolReader {
+    pub fn new() -> GitProtocolReader {
+        GitProtocolReader { capabilities: None }
+    }
+}
+
+#[async_trait::async_trait]
+impl GitReader for GitProtocolReader {
+    type Output = Option<GitProtocol>;
+
+    async fn read<'a>(&mut self, input: &'a [u8]) -> nom::IResult<&'a [u8], Self::Output> {
+        let (rest, pkt) = pkt_line(input)?;
+
+        let capabilities = match self.capabilities {
+            Some(ref mut capabilities) => capabilities,
+            None => {
+                return match pkt {
+                    PktLine::Data(b"version 2\n") => {
+                        self.capabilities = Some(vec![]);
+                        Ok((rest, None))
+                    }
+                    _ => Ok((input, Some(GitProtocol::V0(GitCapabilities::default())))),
+                };
+            }
+        };
+
+        match pkt {
+            PktLine::Data(capability) => {
+                let capability = std::str::from_utf8(capability).map_err(|_| {
+                    nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))
+                })?;
+
+                capabilities.push(capability.trim_end_matches('\n').to_owned());
+                Ok((rest, None))
+            }
+            PktLine::Flush => {
+                let capabilities = GitCapabilities(std::mem::take(capabilities));
+                Ok((rest, Some(GitProtocol::V2(capabilities))))
+            }
+            PktLine::Delim => Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
+            ))),
+        }
+    }
+}
+
+/// Appends `line` as a data pkt line.
+fn write_pkt_line(request: &mut Vec<u8>, line: &str) {
+    request.extend_from_slice(format!("{:04x}", line.len() + 4).as_bytes());
+    request.extend_from_slice(line.as_bytes());
+}
+
+/// Protocol v2 `ls-refs` request for the refs that start with any of `ref_prefixes`.
+pub fn ls_refs_request(ref_prefixes: &[String]) -> Vec<u8> {
+    let mut request = vec![];
+
+    write_pkt_line(&mut request, "command=ls-refs\n");
+    request.extend_from_slice(b"0001");
+
+    for ref_prefix in ref_prefixes {
+        write_pkt_line(&mut request, &format!("ref-prefix {ref_prefix}\n"));
+    }
+
+    request.extend_from_slice(b"0000");
+    request
+}
+
+/// Request for a pack with `want`, only the commit itself is fetched if `deepen` is set. The
+/// repository leaves out objects that are part of `haves`, and may send deltas against them.
+/// Their trees have to be complete but not their history, they are sent as shallow commits so
+/// both `haves` and `deepen` need a repository that allows shallow fetches.
+pub fn fetch_request(
+    protocol: &GitProtocol,
+    want: &str,
+    deepen: bool,
+    haves: &[String],
+) -> Vec<u8> {
+    let mut request = vec![];
+
+    match protocol {
+        GitProtocol::V0(capabilities) => {
+            // Capabilities are sent with the first want, the wants end with a flush.
+            let mut line = format!("want {want}");
+
+            if capabilities.get("ofs-delta").is_some() {
+                line.push_str(" ofs-delta");
+            }
+
+            if !haves.is_empty() && capabilities.get("thin-pack").is_some() {
+                line.push_str(" thin-pack");
+            }
+
+            if deepen || !haves.is_empty() {
+                line.push_str(" shallow");
+            }
+
+            write_pkt_line(&mut request, &format!("{line}\n"));
+
+            for have in haves {
+                write_pkt_line(&mut request, &format!("shallow {have}\n"));
+            }
+
+            if deepen {
+                write_pkt_line(&mut request, "deepen 1\n");
+            }
+
+            request.extend_from_slice(b"0000");
+
+            for have in haves {
+                write_pkt_line(&mut request, &format!("have {have}\n"));
+            }
+
+            write_pkt_line(&mut request, "done\n");
+        }
+        GitProtocol::V2(_) => {
+            write_pkt_line(&mut request, "command=fetch\n");
+            request.extend_from_slice(b"0001");
+            write_pkt_line(&mut request, "ofs-delta\n");
+
+            if !haves.is_empty() {
+                write_pkt_line(&mut request, "thin-pack\n");
+            }
+
+            write_pkt_line(&mut request, &format!("want {want}\n"));
+
+            for have in haves {
+                write_pkt_line(&mut request, &format!("shallow {have}\n"));
+                write_pkt_line(&mut request, &format!("have {have}\n"));
+            }
+
+            if deepen {
+                write_pkt_line(&mut request, "deepen 1\n");
+            }
+
+            write_pkt_line(&mut request, "done\n");
+            request.extend_from_slice(b"0000");
+        }
+    }
+
+    request
+}
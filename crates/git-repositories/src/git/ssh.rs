@@ -1,27 +1,23 @@
 use anyhow::{anyhow, Context, Result};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-#[async_trait::async_trait]
-pub trait SshReader {
-    type Output;
+use fairing_core2::models;
 
-    async fn read<'a>(
-        &mut self,
-        client: &mut SshClient,
-        input: &'a [u8],
-    ) -> nom::IResult<&'a [u8], Self::Output>;
-}
+use super::GitReader;
 
 pub struct SshClientConfig<'a, Addr: tokio::net::ToSocketAddrs> {
     pub addr: Addr,
     pub user: &'a str,
     pub key_pair: thrussh_keys::key::KeyPair,
+    /// Any host key is accepted if this is empty, the caller is expected to pin it.
+    pub trusted_host_keys: Vec<models::GitHostKey>,
 }
 
 pub struct SshClient {
     session: thrussh::client::Handle<Client>,
     channel: thrussh::client::Channel,
     buffer: Option<thrussh::CryptoVec>,
+    host_key: models::GitHostKey,
 }
 
 impl SshClient {
@@ -37,9 +33,23 @@ impl SshClient {
             .await
             .context("connecting to the repository")?;
 
-        let mut session = thrussh::client::connect_stream(ssh_config, tcp_stream, Client)
-            .await
-            .context("connecting to the repository")?;
+        let host_key = Arc::new(Mutex::new(None));
+        let client = Client {
+            trusted_host_keys: config.trusted_host_keys,
+            host_key: host_key.clone(),
+        };
+
+        let session = thrussh::client::connect_stream(ssh_config, tcp_stream, client).await;
+        let presented_host_key = host_key.lock().unwrap().take();
+
+        let (mut session, host_key) = match (session, presented_host_key) {
+            (Ok(session), Some((host_key, true))) => (session, host_key),
+            (_, Some((host_key, false))) => {
+                return Err(models::GitHostKeyMismatch { host_key }.into())
+            }
+            (Ok(_), None) => return Err(anyhow!("connection closed before the host key check")),
+            (Err(err), _) => return Err(err).context("connecting to the repository"),
+        };
 
         let success = session
             .authenticate_publickey(config.user, key_pair)
@@ -59,9 +69,25 @@ impl SshClient {
             session,
             channel,
             buffer: None,
+            host_key,
         })
     }
 
+    /// Host key that the repository presented, it has already been checked against the trusted
+    /// host keys.
+    pub fn host_key(&self) -> &models::GitHostKey {
+        &self.host_key
+    }
+
+    pub async fn set_env(&mut self, name: &str, value: &str) -> Result<()> {
+        self.channel
+            .set_env(false, name, value)
+            .await
+            .context("setting environment variable")?;
+
+        Ok(())
+    }
+
     pub async fn exec(&mut self, command: &str) -> Result<()> {
         self.buffer = None;
 
@@ -84,11 +110,11 @@ impl SshClient {
         Ok(())
     }
 
-    pub async fn read<R: SshReader>(&mut self, reader: &mut R) -> Result<Option<R::Output>> {
+    pub async fn read<R: GitReader>(&mut self, reader: &mut R) -> Result<Option<R::Output>> {
         // Parse any buffered data first. Otherwise the buffer might get very large, or we will
         // lose data if the server disconnects.
         if let Some(buffer) = self.buffer.take() {
-            let result = reader.read(self, &buffer).await;
+            let result = reader.read(&buffer).await;
             match result {
                 Ok((input, result)) => {
                     if !input.is_empty() {
@@ -115,7 +141,7 @@ impl SshClient {
                         data
                     };
 
-                    let result = reader.read(self, &buffer).await;
+                    let result = reader.read(&buffer).await;
                     match result {
                         Ok((input, result)) => {
                             if !input.is_empty() {
@@ -147,7 +173,11 @@ impl SshClient {
     }
 }
 
-struct Client;
+struct Client {
+    trusted_host_keys: Vec<models::GitHostKey>,
+    /// The presented host key and whether it was trusted, read back after connecting.
+    host_key: Arc<Mutex<Option<(models::GitHostKey, bool)>>>,
+}
 
 impl thrussh::client::Handler for Client {
     type Error = anyhow::Error;
@@ -167,7 +197,17 @@ impl thrussh::client::Handler for Client {
         self,
         server_public_key: &thrussh_keys::key::PublicKey,
     ) -> Self::FutureBool {
-        tracing::info!("check_server_key: {:?}", server_public_key);
-        self.finished_bool(true)
+        let trusted = self.trusted_host_keys.is_empty()
+            || self
+                .trusted_host_keys
+                .iter()
+                .any(|host_key| host_key.matches(server_public_key));
+
+        let host_key = models::GitHostKey::from_public_key(server_public_key);
+        tracing::debug!(%host_key, trusted, "check_server_key");
+
+        *self.host_key.lock().unwrap() = Some((host_key, trusted));
+
+        self.finished_bool(trusted)
     }
 }
@@ -0,0 +1,196 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Tree entry that checks out a commit of another repository, the directory at `path` is left
+/// empty until the submodule has been fetched.
+#[derive(Clone, Debug)]
+pub struct Gitlink {
+    /// Path relative to the source directory.
+    pub path: PathBuf,
+    pub commit: String,
+}
+
+/// Submodule as it is configured in `.gitmodules`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Submodule {
+    pub path: String,
+    pub url: String,
+    pub branch: Option<String>,
+}
+
+/// Reads the submodules of the tree extracted to `source_directory`.
+pub async fn read_gitmodules(source_directory: &Path) -> Result<Vec<Submodule>> {
+    match fs::read_to_string(source_directory.join(".gitmodules")).await {
+        Ok(gitmodules) => parse_gitmodules(&gitmodules),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err).context("reading .gitmodules"),
+    }
+}
+
+/// Parses the `submodule` sections of a `.gitmodules` file, other sections are skipped.
+pub fn parse_gitmodules(gitmodules: &str) -> Result<Vec<Submodule>> {
+    let mut submodules = vec![];
+    let mut current: Option<Submodule> = None;
+
+    for line in gitmodules.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[') {
+            let section = section
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("invalid section in .gitmodules: {line}"))?;
+
+            submodules.extend(current.take());
+
+            if section.trim_start().starts_with("submodule ") {
+                current = Some(Submodule::default());
+            }
+
+            continue;
+        }
+
+        let submodule = match current {
+            Some(ref mut submodule) => submodule,
+            None => continue,
+        };
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid line in .gitmodules: {line}"))?;
+        let value = value.trim().trim_matches('"').to_owned();
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "path" => submodule.path = value,
+            "url" => submodule.url = value,
+            "branch" => submodule.branch = Some(value),
+            _ => (),
+        }
+    }
+
+    submodules.extend(current);
+
+    Ok(submodules)
+}
+
+/// Resolves the url of a submodule against the url of its parent repository. Urls starting with
+/// `./` or `../` are relative to the parent, where `../` removes the parent's last component.
+pub fn resolve_url(parent: &str, url: &str) -> Result<String> {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return Ok(url.to_owned());
+    }
+
+    let mut base = parent.trim_end_matches('/');
+    let mut url = url;
+
+    loop {
+        if let Some(rest) = url.strip_prefix("./") {
+            url = rest;
+        } else if let Some(rest) = url.strip_prefix("../") {
+            ensure!(
+                !base.ends_with(':'),
+                "relative submodule url goes above the root of {parent}"
+            );
+
+            let index = base
+                .rfind(['/', ':'])
+                .ok_or_else(|| anyhow!("relative submodule url goes above the root of {parent}"))?;
+
+            // The host of urls, or the host of scp-like urls, can't be removed.
+            ensure!(
+                !base[..index].is_empty() && !base[..index].ends_with(['/', ':']),
+                "relative submodule url goes above the root of {parent}"
+            );
+
+            base = if base[index..].starts_with(':') {
+                &base[..=index]
+            } else {
+                &base[..index]
+            };
+            url = rest;
+        } else {
+            break;
+        }
+    }
+
+    if base.ends_with(':') {
+        Ok(format!("{base}{url}"))
+    } else {
+        Ok(format!("{base}/{url}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_submodules() {
+        let gitmodules = r#"
+[core]
+	path = ignored
+[submodule "themes/ananke"]
+	path = themes/ananke
+	url = https://github.com/theNewDynamic/gohugo-theme-ananke.git
+; comment
+[submodule "shared"]
+	Path = "shared"
+	url = ../shared.git
+	branch = stable
+"#;
+
+        assert_eq!(
+            parse_gitmodules(gitmodules).unwrap(),
+            vec![
+                Submodule {
+                    path: "themes/ananke".to_owned(),
+                    url: "https://github.com/theNewDynamic/gohugo-theme-ananke.git".to_owned(),
+                    branch: None,
+                },
+                Submodule {
+                    path: "shared".to_owned(),
+                    url: "../shared.git".to_owned(),
+                    branch: Some("stable".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_relative_urls() {
+        let parent = "https://example.com/org/site.git";
+        assert_eq!(
+            resolve_url(parent, "../theme.git").unwrap(),
+            "https://example.com/org/theme.git"
+        );
+        assert_eq!(
+            resolve_url(parent, "../../other/theme.git").unwrap(),
+            "https://example.com/other/theme.git"
+        );
+        assert_eq!(
+            resolve_url(parent, "./theme.git").unwrap(),
+            "https://example.com/org/site.git/theme.git"
+        );
+        assert!(resolve_url(parent, "../../../theme.git").is_err());
+
+        let parent = "git@example.com:org/site.git";
+        assert_eq!(
+            resolve_url(parent, "../theme.git").unwrap(),
+            "git@example.com:org/theme.git"
+        );
+        assert_eq!(
+            resolve_url(parent, "../../theme.git").unwrap(),
+            "git@example.com:theme.git"
+        );
+        assert!(resolve_url(parent, "../../../theme.git").is_err());
+
+        assert_eq!(
+            resolve_url(parent, "https://example.com/theme.git").unwrap(),
+            "https://example.com/theme.git"
+        );
+    }
+}
@@ -11,11 +11,37 @@ impl GitSourceRepository for LocalGitSource {
     async fn git_list_latest(
         &self,
         _source: &models::SourceWithKind<models::GitSource>,
-    ) -> Result<Vec<models::GitSourceRefAndCommit>> {
-        Ok(vec![models::GitSourceRefAndCommit {
+        ref_prefixes: &[String],
+    ) -> Result<models::GitSourceLatest> {
+        let refs_and_commits = vec![models::GitSourceRefAndCommit {
             ref_: "refs/heads/master".into(),
             commit: "46720c277c549b0b59a1d80c0128ff69f42a13b5".into(),
-        }])
+        }];
+
+        Ok(models::GitSourceLatest {
+            host_key: None,
+            refs_and_commits: refs_and_commits
+                .into_iter()
+                .filter(|ref_and_commit| {
+                    ref_prefixes
+                        .iter()
+                        .any(|ref_prefix| ref_and_commit.ref_.starts_with(ref_prefix.as_str()))
+                })
+                .collect(),
+        })
+    }
+
+    async fn git_tree_hashes(
+        &self,
+        _source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        paths: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        // Every commit is taken to change every path.
+        Ok(paths
+            .iter()
+            .map(|_| Some(ref_and_commit.commit.clone()))
+            .collect())
     }
 
     async fn git_clone(
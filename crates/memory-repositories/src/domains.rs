@@ -0,0 +1,268 @@
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::DomainRepository};
+
+use crate::MemoryRepository;
+
+#[derive(Default)]
+pub(crate) struct Domains {
+    domains: BTreeMap<String, models::Domain>,
+    certificates: BTreeMap<(Uuid, String), Certificate>,
+    validated_domains: BTreeMap<String, models::ValidatedDomain>,
+    acme_challenges: BTreeMap<(String, String), DateTime<Utc>>,
+}
+
+struct Certificate {
+    project_id: models::ProjectId,
+    name: String,
+    domain_names: Vec<String>,
+    next_processing_time: DateTime<Utc>,
+    renewal: Option<models::CertificateRenewal>,
+}
+
+impl Domains {
+    fn certificate_mut(
+        &mut self,
+        project_id: models::ProjectId,
+        name: &str,
+    ) -> Result<&mut Certificate> {
+        self.certificates
+            .get_mut(&(project_id.into_uuid(), name.to_owned()))
+            .ok_or_else(|| anyhow!("certificate not found"))
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainRepository for MemoryRepository {
+    async fn create_domain(&self, domain: models::Domain) -> Result<()> {
+        let mut state = self.state();
+
+        ensure!(
+            !state.domains.domains.contains_key(&domain.fqdn),
+            "domain already exists"
+        );
+
+        state.domains.domains.insert(domain.fqdn.clone(), domain);
+
+        Ok(())
+    }
+
+    async fn get_domain(&self, fqdn: &str) -> Result<Option<models::Domain>> {
+        let state = self.state();
+        Ok(state.domains.domains.get(fqdn).cloned())
+    }
+
+    async fn list_domains(&self, project_id: models::ProjectId) -> Result<Vec<models::Domain>> {
+        let state = self.state();
+        let domains = state
+            .domains
+            .domains
+            .values()
+            .filter(|domain| domain.project_id == project_id)
+            .cloned()
+            .collect();
+
+        Ok(domains)
+    }
+
+    async fn delete_domain(&self, fqdn: &str) -> Result<()> {
+        let mut state = self.state();
+        state.domains.domains.remove(fqdn);
+
+        Ok(())
+    }
+
+    async fn create_certificate(
+        &self,
+        certificate: &models::Certificate,
+        queue_timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut state = self.state();
+
+        let domain_names = certificate
+            .domain_names
+            .iter()
+            .map(|domain_name| domain_name.to_fqdn())
+            .collect();
+
+        state.domains.certificates.insert(
+            (certificate.project_id.into_uuid(), certificate.name.clone()),
+            Certificate {
+                project_id: certificate.project_id,
+                name: certificate.name.clone(),
+                domain_names,
+                next_processing_time: queue_timestamp,
+                renewal: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get_certificate(
+        &self,
+        project_id: models::ProjectId,
+        name: &str,
+    ) -> Result<Option<models::Certificate>> {
+        let state = self.state();
+
+        let certificate = match state
+            .domains
+            .certificates
+            .get(&(project_id.into_uuid(), name.to_owned()))
+        {
+            Some(certificate) => certificate,
+            None => return Ok(None),
+        };
+
+        let domain_names = certificate
+            .domain_names
+            .iter()
+            .map(|domain_name| domain_name.parse())
+            .collect::<Result<_>>()?;
+
+        Ok(Some(models::Certificate {
+            project_id: certificate.project_id,
+            name: certificate.name.clone(),
+            domain_names,
+        }))
+    }
+
+    async fn update_certificate(
+        &self,
+        project_id: models::ProjectId,
+        name: &str,
+        _keys: &models::CertificateKeys,
+    ) -> Result<()> {
+        // Keys are only ever read through validated domains, so there is nothing to keep here.
+        let mut state = self.state();
+        state.domains.certificate_mut(project_id, name)?;
+
+        Ok(())
+    }
+
+    async fn process_certificate(
+        &self,
+        project_id: models::ProjectId,
+        certificate_name: &str,
+        current_timestamp: DateTime<Utc>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<models::CertificateRenewal>> {
+        let mut state = self.state();
+        let certificate = state
+            .domains
+            .certificate_mut(project_id, certificate_name)?;
+
+        ensure!(
+            certificate.next_processing_time == current_timestamp,
+            "certificate is being processed by someone else"
+        );
+
+        certificate.next_processing_time = timestamp;
+
+        let renewal = certificate
+            .renewal
+            .as_ref()
+            .map(|renewal| models::CertificateRenewal {
+                acme_order_url: renewal.acme_order_url.clone(),
+                csr: renewal.csr.clone(),
+                csr_secret_key: renewal.csr_secret_key.clone(),
+            });
+
+        Ok(renewal)
+    }
+
+    async fn update_certificate_renewal(
+        &self,
+        project_id: models::ProjectId,
+        certificate_name: &str,
+        certificate_renewal: Option<models::CertificateRenewal>,
+        current_timestamp: DateTime<Utc>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let certificate = state
+            .domains
+            .certificate_mut(project_id, certificate_name)?;
+
+        ensure!(
+            certificate.next_processing_time == current_timestamp,
+            "certificate is being processed by someone else"
+        );
+
+        certificate.next_processing_time = timestamp;
+        certificate.renewal = certificate_renewal;
+
+        Ok(())
+    }
+
+    async fn get_queued_certificates(
+        &self,
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<Vec<models::QueuedCertificate>> {
+        let state = self.state();
+        let queued_certificates = state
+            .domains
+            .certificates
+            .values()
+            .filter(|certificate| timestamps.contains(&certificate.next_processing_time))
+            .map(|certificate| models::QueuedCertificate {
+                project_id: certificate.project_id,
+                name: certificate.name.clone(),
+            })
+            .collect();
+
+        Ok(queued_certificates)
+    }
+
+    async fn create_acme_challenge(&self, challenge: models::AcmeChallenge) -> Result<()> {
+        let mut state = self.state();
+        state.domains.acme_challenges.insert(
+            (challenge.acme_dns_challenge_label, challenge.dns_01_token),
+            Utc::now() + challenge.ttl,
+        );
+
+        Ok(())
+    }
+
+    async fn get_acme_dns_01_challenges(
+        &self,
+        acme_dns_challenge_label: &str,
+    ) -> Result<Vec<String>> {
+        let state = self.state();
+        let now = Utc::now();
+
+        let dns_01_tokens = state
+            .domains
+            .acme_challenges
+            .iter()
+            .filter(|((label, _), expires_at)| {
+                label == acme_dns_challenge_label && **expires_at > now
+            })
+            .map(|((_, dns_01_token), _)| dns_01_token.clone())
+            .collect();
+
+        Ok(dns_01_tokens)
+    }
+
+    async fn create_validated_domain(
+        &self,
+        validated_domain: &models::ValidatedDomain,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .domains
+            .validated_domains
+            .insert(validated_domain.fqdn.clone(), validated_domain.clone());
+
+        Ok(())
+    }
+
+    async fn get_validated_domain(&self, fqdn: &str) -> Result<Option<models::ValidatedDomain>> {
+        let state = self.state();
+        Ok(state.domains.validated_domains.get(fqdn).cloned())
+    }
+}
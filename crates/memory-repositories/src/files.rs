@@ -0,0 +1,325 @@
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::FileRepository};
+
+use crate::{MemoryRepository, State};
+
+#[derive(Default)]
+pub(crate) struct Files {
+    files: BTreeMap<(Uuid, Vec<u8>), File>,
+    blobs: BTreeMap<(Uuid, Vec<u8>), Blob>,
+}
+
+struct File {
+    length: u64,
+    blobs: Vec<models::FileBlob>,
+    created_at: DateTime<Utc>,
+}
+
+/// Blob data is kept compressed but unencrypted, the key version is tracked so that key rotation
+/// and deleted keys behave like they do in the other repositories.
+struct Blob {
+    length: u64,
+    compression: models::BlobCompression,
+    key_version: u32,
+    data: Vec<u8>,
+    created_at: DateTime<Utc>,
+}
+
+impl State {
+    fn current_file_encryption_key_version(&self, project_id: models::ProjectId) -> Result<u32> {
+        self.projects
+            .get(&project_id.into_uuid())
+            .ok_or_else(|| anyhow!("project not found"))?
+            .current_file_encryption_key()
+            .map(|key| key.version)
+            .ok_or_else(|| anyhow!("project has no file encryption key"))
+    }
+
+    fn has_file_encryption_key(&self, project_id: models::ProjectId, version: u32) -> bool {
+        self.projects
+            .get(&project_id.into_uuid())
+            .and_then(|project| project.file_encryption_key(version))
+            .is_some()
+    }
+}
+
+#[async_trait::async_trait]
+impl FileRepository for MemoryRepository {
+    async fn get_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Option<models::File>> {
+        let state = self.state();
+        let file = state
+            .files
+            .files
+            .get(&(project_id.into_uuid(), checksum.encode()))
+            .map(|file| models::File {
+                project_id,
+                checksum: *checksum,
+                length: file.length,
+                created_at: file.created_at,
+            });
+
+        Ok(file)
+    }
+
+    async fn list_files(&self, project_id: models::ProjectId) -> Result<Vec<models::File>> {
+        let state = self.state();
+
+        let mut files = vec![];
+        for ((file_project_id, checksum), file) in &state.files.files {
+            if *file_project_id == project_id.into_uuid() {
+                files.push(models::File {
+                    project_id,
+                    checksum: models::FileChecksum::decode(checksum)?,
+                    length: file.length,
+                    created_at: file.created_at,
+                });
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn create_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+        length: u64,
+        blobs: &[models::FileBlob],
+    ) -> Result<()> {
+        let blobs_length = blobs.iter().map(|blob| blob.length).sum::<u64>();
+        ensure!(
+            blobs_length == length,
+            "file blobs must cover the whole file"
+        );
+
+        let mut state = self.state();
+        state.files.files.insert(
+            (project_id.into_uuid(), checksum.encode()),
+            File {
+                length,
+                blobs: blobs.to_vec(),
+                created_at: Utc::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get_file_chunks(
+        &self,
+        project_id: models::ProjectId,
+        checksum: models::FileChecksum,
+        (range_start, range_end): (u64, u64),
+    ) -> Result<Vec<models::FileChunk>> {
+        let state = self.state();
+
+        let file = match state
+            .files
+            .files
+            .get(&(project_id.into_uuid(), checksum.encode()))
+        {
+            Some(file) => file,
+            None => return Ok(vec![]),
+        };
+
+        let file_blobs = file
+            .blobs
+            .iter()
+            .filter(|blob| blob.offset >= range_start && blob.offset < range_end);
+
+        let mut chunks = vec![];
+
+        for file_blob in file_blobs {
+            let blob = state
+                .files
+                .blobs
+                .get(&(project_id.into_uuid(), file_blob.checksum.encode()))
+                .ok_or_else(|| anyhow!("blob {} not found", file_blob.checksum.to_hex()))?;
+
+            ensure!(
+                state.has_file_encryption_key(project_id, blob.key_version),
+                "file encryption key {} not found",
+                blob.key_version
+            );
+
+            let data = blob.compression.decompress(blob.data.clone())?;
+
+            ensure!(
+                data.len() as u64 == file_blob.length,
+                "blob {} has the wrong length",
+                file_blob.checksum.to_hex()
+            );
+
+            chunks.push(models::FileChunk {
+                total_length: file.length,
+                offset: file_blob.offset,
+                data,
+            });
+        }
+
+        Ok(chunks)
+    }
+
+    async fn list_file_blobs(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Vec<models::FileBlob>> {
+        let state = self.state();
+        let file_blobs = state
+            .files
+            .files
+            .get(&(project_id.into_uuid(), checksum.encode()))
+            .map(|file| file.blobs.clone())
+            .unwrap_or_default();
+
+        Ok(file_blobs)
+    }
+
+    async fn delete_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .files
+            .files
+            .remove(&(project_id.into_uuid(), checksum.encode()));
+
+        Ok(())
+    }
+
+    async fn get_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<Option<models::Blob>> {
+        let state = self.state();
+        let blob = state
+            .files
+            .blobs
+            .get(&(project_id.into_uuid(), checksum.encode()))
+            .map(|blob| models::Blob {
+                project_id,
+                checksum: *checksum,
+                length: blob.length,
+                compression: blob.compression,
+                created_at: blob.created_at,
+            });
+
+        Ok(blob)
+    }
+
+    async fn create_blob(
+        &self,
+        project_id: models::ProjectId,
+        blob: &models::CreateBlob,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let key_version = state.current_file_encryption_key_version(project_id)?;
+
+        state.files.blobs.insert(
+            (project_id.into_uuid(), blob.checksum.encode()),
+            Blob {
+                length: blob.length,
+                compression: blob.compression,
+                key_version,
+                data: blob.data.clone(),
+                created_at: Utc::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn list_blobs(&self, project_id: models::ProjectId) -> Result<Vec<models::Blob>> {
+        let state = self.state();
+
+        let mut blobs = vec![];
+        for ((blob_project_id, checksum), blob) in &state.files.blobs {
+            if *blob_project_id == project_id.into_uuid() {
+                blobs.push(models::Blob {
+                    project_id,
+                    checksum: models::BlobChecksum::decode(checksum)?,
+                    length: blob.length,
+                    compression: blob.compression,
+                    created_at: blob.created_at,
+                });
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    async fn delete_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .files
+            .blobs
+            .remove(&(project_id.into_uuid(), checksum.encode()));
+
+        Ok(())
+    }
+
+    async fn reencrypt_blobs(&self, project_id: models::ProjectId, limit: usize) -> Result<usize> {
+        let mut state = self.state();
+        let key_version = state.current_file_encryption_key_version(project_id)?;
+
+        let checksums = state
+            .files
+            .blobs
+            .iter()
+            .filter(|((blob_project_id, _), blob)| {
+                *blob_project_id == project_id.into_uuid() && blob.key_version != key_version
+            })
+            .map(|((_, checksum), blob)| (checksum.clone(), blob.key_version))
+            .take(limit)
+            .collect::<Vec<_>>();
+
+        for (checksum, old_key_version) in &checksums {
+            ensure!(
+                state.has_file_encryption_key(project_id, *old_key_version),
+                "file encryption key {old_key_version} not found"
+            );
+
+            let blob = state
+                .files
+                .blobs
+                .get_mut(&(project_id.into_uuid(), checksum.clone()))
+                .unwrap();
+
+            blob.key_version = key_version;
+        }
+
+        Ok(checksums.len())
+    }
+
+    async fn list_blob_key_versions(&self, project_id: models::ProjectId) -> Result<Vec<u32>> {
+        let state = self.state();
+        let mut key_versions = state
+            .files
+            .blobs
+            .iter()
+            .filter(|((blob_project_id, _), _)| *blob_project_id == project_id.into_uuid())
+            .map(|(_, blob)| blob.key_version)
+            .collect::<Vec<_>>();
+
+        key_versions.sort_unstable();
+        key_versions.dedup();
+
+        Ok(key_versions)
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::Mutex,
+};
+use tokio::fs;
+
+use fairing_core2::{models, repositories::GitSourceRepository};
+
+/// Git source backed by fixture trees instead of remote repositories.
+#[derive(Default)]
+pub struct FixtureGitSource {
+    repositories: Mutex<BTreeMap<String, FixtureRepository>>,
+}
+
+#[derive(Default)]
+struct FixtureRepository {
+    host_key: Option<models::GitHostKey>,
+    refs: BTreeMap<String, String>,
+    commits: BTreeMap<String, BTreeMap<String, FixtureEntry>>,
+}
+
+#[derive(Clone, Hash)]
+enum FixtureEntry {
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+impl FixtureGitSource {
+    pub fn new() -> FixtureGitSource {
+        FixtureGitSource::default()
+    }
+
+    /// Points `ref_` at a new commit containing exactly `files`, returns the id of the commit.
+    pub fn commit(&self, repository_url: &str, ref_: &str, files: &[(&str, &[u8])]) -> String {
+        self.commit_with_symlinks(repository_url, ref_, files, &[])
+    }
+
+    /// Like [`FixtureGitSource::commit`], with `symlinks` as pairs of paths and link targets.
+    pub fn commit_with_symlinks(
+        &self,
+        repository_url: &str,
+        ref_: &str,
+        files: &[(&str, &[u8])],
+        symlinks: &[(&str, &str)],
+    ) -> String {
+        let mut repositories = self.repositories.lock().unwrap();
+
+        let commit_count = repositories
+            .values()
+            .map(|repository| repository.commits.len())
+            .sum::<usize>();
+        let commit = format!("{:040x}", commit_count + 1);
+
+        let files = files
+            .iter()
+            .map(|(path, data)| (path.to_string(), FixtureEntry::File(data.to_vec())));
+        let symlinks = symlinks
+            .iter()
+            .map(|(path, target)| (path.to_string(), FixtureEntry::Symlink(target.to_string())));
+        let tree = files.chain(symlinks).collect();
+
+        let repository = repositories.entry(repository_url.to_owned()).or_default();
+        repository.commits.insert(commit.clone(), tree);
+        repository.refs.insert(ref_.to_owned(), commit.clone());
+
+        commit
+    }
+
+    /// Deletes `ref_`, its commits are kept.
+    pub fn delete_ref(&self, repository_url: &str, ref_: &str) {
+        let mut repositories = self.repositories.lock().unwrap();
+        let repository = repositories.entry(repository_url.to_owned()).or_default();
+        repository.refs.remove(ref_);
+    }
+
+    /// Makes the repository present `host_key`, as if it was reached over ssh.
+    pub fn set_host_key(&self, repository_url: &str, host_key: models::GitHostKey) {
+        let mut repositories = self.repositories.lock().unwrap();
+        let repository = repositories.entry(repository_url.to_owned()).or_default();
+        repository.host_key = Some(host_key);
+    }
+}
+
+#[async_trait::async_trait]
+impl GitSourceRepository for FixtureGitSource {
+    async fn git_list_latest(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_prefixes: &[String],
+    ) -> Result<models::GitSourceLatest> {
+        let repositories = self.repositories.lock().unwrap();
+        let repository = repositories
+            .get(source.with.repository_url.as_str())
+            .ok_or_else(|| anyhow!("repository not found"))?;
+
+        if let Some(ref host_key) = repository.host_key {
+            let trusted_host_keys = source.with.trusted_host_keys()?;
+
+            if !trusted_host_keys.is_empty() && !trusted_host_keys.contains(host_key) {
+                return Err(models::GitHostKeyMismatch {
+                    host_key: host_key.clone(),
+                }
+                .into());
+            }
+        }
+
+        let refs_and_commits = repository
+            .refs
+            .iter()
+            .filter(|(ref_, _)| {
+                ref_prefixes
+                    .iter()
+                    .any(|ref_prefix| ref_.starts_with(ref_prefix.as_str()))
+            })
+            .map(|(ref_, commit)| models::GitSourceRefAndCommit {
+                ref_: ref_.clone(),
+                commit: commit.clone(),
+            })
+            .collect();
+
+        Ok(models::GitSourceLatest {
+            host_key: repository.host_key.clone(),
+            refs_and_commits,
+        })
+    }
+
+    async fn git_tree_hashes(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        paths: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        let repositories = self.repositories.lock().unwrap();
+        let tree = repositories
+            .get(source.with.repository_url.as_str())
+            .and_then(|repository| repository.commits.get(&ref_and_commit.commit))
+            .ok_or_else(|| anyhow!("commit not found"))?;
+
+        // Stands in for the hash of a git tree, equal for equal subtrees.
+        let hashes = paths
+            .iter()
+            .map(|path| {
+                let prefix = format!("{path}/");
+                let mut hasher = DefaultHasher::new();
+                let mut found = false;
+
+                for (entry_path, entry) in tree {
+                    if let Some(rest) = entry_path.strip_prefix(&prefix) {
+                        (rest, entry).hash(&mut hasher);
+                        found = true;
+                    } else if entry_path == path {
+                        ("", entry).hash(&mut hasher);
+                        found = true;
+                    }
+                }
+
+                found.then(|| format!("{:016x}", hasher.finish()))
+            })
+            .collect();
+
+        Ok(hashes)
+    }
+
+    async fn git_clone(
+        &self,
+        source: &models::SourceWithKind<models::GitSource>,
+        ref_and_commit: &models::GitSourceRefAndCommit,
+        work_directory: PathBuf,
+    ) -> Result<PathBuf> {
+        let tree = {
+            let repositories = self.repositories.lock().unwrap();
+            repositories
+                .get(source.with.repository_url.as_str())
+                .and_then(|repository| repository.commits.get(&ref_and_commit.commit))
+                .cloned()
+                .ok_or_else(|| anyhow!("commit not found"))?
+        };
+
+        for (path, entry) in tree {
+            let path = work_directory.join(path);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            match entry {
+                FixtureEntry::File(data) => fs::write(path, data).await?,
+                FixtureEntry::Symlink(target) => fs::symlink(target, path).await?,
+            }
+        }
+
+        Ok(work_directory)
+    }
+}
@@ -0,0 +1,704 @@
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{
+    models,
+    repositories::{LayerPendingLayersFilter, LayerRepository},
+};
+
+use crate::MemoryRepository;
+
+/// How long a build worker may hold a layer before another worker can take over.
+const BUILD_TIMEOUT_SECONDS: i64 = 300;
+
+/// How long a finalizing worker may hold a layer before another worker can take over.
+const FINALIZE_TIMEOUT_SECONDS: i64 = 60;
+
+#[derive(Default)]
+pub(crate) struct Layers {
+    layer_sets: BTreeMap<(Uuid, String), LayerSet>,
+    retentions: BTreeMap<(Uuid, String), models::LayerSetRetention>,
+    ref_layer_set_limits: BTreeMap<Uuid, models::RefLayerSetLimit>,
+    layers: BTreeMap<(Uuid, String, Uuid), Layer>,
+    layer_changes: BTreeMap<(Uuid, String, Uuid, Uuid), BTreeMap<String, models::LayerChange>>,
+    layer_members: BTreeMap<(Uuid, String, String), BTreeMap<Uuid, models::LayerMember>>,
+}
+
+struct LayerSet {
+    layer_set: models::LayerSet,
+    last_layer_id: Option<Uuid>,
+}
+
+struct Layer {
+    layer: models::Layer,
+    build_worker: Option<Worker>,
+    finalize_worker: Option<Worker>,
+}
+
+#[derive(Copy, Clone)]
+struct Worker {
+    id: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Worker {
+    fn new(worker_id: models::WorkerId, timeout_seconds: i64) -> Worker {
+        Worker {
+            id: worker_id.into_uuid(),
+            expires_at: Some(Utc::now() + Duration::seconds(timeout_seconds)),
+        }
+    }
+
+    fn is_expired(worker: &Option<Worker>) -> bool {
+        match worker {
+            Some(Worker {
+                expires_at: Some(expires_at),
+                ..
+            }) => *expires_at < Utc::now(),
+            Some(Worker {
+                expires_at: None, ..
+            }) => false,
+            None => true,
+        }
+    }
+
+    fn is_held_by(worker: &Option<Worker>, worker_id: models::WorkerId) -> bool {
+        match worker {
+            Some(Worker {
+                id,
+                expires_at: Some(expires_at),
+            }) => *id == worker_id.into_uuid() && *expires_at >= Utc::now(),
+            _ => false,
+        }
+    }
+}
+
+fn layer_set_key(project_id: models::ProjectId, name: &models::LayerSetName) -> (Uuid, String) {
+    (project_id.into_uuid(), name.as_str().to_owned())
+}
+
+fn layer_key(
+    project_id: models::ProjectId,
+    layer_set_name: &models::LayerSetName,
+    layer_id: models::LayerId,
+) -> (Uuid, String, Uuid) {
+    (
+        project_id.into_uuid(),
+        layer_set_name.as_str().to_owned(),
+        layer_id.into_uuid(),
+    )
+}
+
+impl Layers {
+    fn layer_set_mut(
+        &mut self,
+        project_id: models::ProjectId,
+        name: &models::LayerSetName,
+    ) -> Result<&mut LayerSet> {
+        self.layer_sets
+            .get_mut(&layer_set_key(project_id, name))
+            .ok_or_else(|| anyhow!("layer set not found"))
+    }
+
+    fn layer_mut(
+        &mut self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<&mut Layer> {
+        self.layers
+            .get_mut(&layer_key(project_id, layer_set_name, layer_id))
+            .ok_or_else(|| anyhow!("layer not found"))
+    }
+}
+
+#[async_trait::async_trait]
+impl LayerRepository for MemoryRepository {
+    async fn get_layer_set(
+        &self,
+        project_id: models::ProjectId,
+        name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSet>> {
+        let state = self.state();
+        let layer_set = state
+            .layers
+            .layer_sets
+            .get(&layer_set_key(project_id, name))
+            .map(|layer_set| layer_set.layer_set.clone());
+
+        Ok(layer_set)
+    }
+
+    async fn list_layer_sets(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Vec<models::LayerSet>> {
+        let state = self.state();
+        let layer_sets = state
+            .layers
+            .layer_sets
+            .values()
+            .filter(|layer_set| layer_set.layer_set.project_id == project_id)
+            .map(|layer_set| layer_set.layer_set.clone())
+            .collect();
+
+        Ok(layer_sets)
+    }
+
+    async fn list_layer_sets_for_source(
+        &self,
+        project_id: models::ProjectId,
+        name: &models::SourceName,
+    ) -> Result<Vec<models::LayerSet>> {
+        let state = self.state();
+        let layer_sets = state
+            .layers
+            .layer_sets
+            .values()
+            .map(|layer_set| &layer_set.layer_set)
+            .filter(|layer_set| layer_set.project_id == project_id)
+            .filter(|layer_set| match &layer_set.source {
+                Some(source) => source.name.as_str() == name.as_str(),
+                None => false,
+            })
+            .cloned()
+            .collect();
+
+        Ok(layer_sets)
+    }
+
+    async fn create_layer_set(&self, layer_set: &models::LayerSet) -> Result<()> {
+        let mut state = self.state();
+        state
+            .layers
+            .layer_sets
+            .entry(layer_set_key(layer_set.project_id, &layer_set.name))
+            .or_insert_with(|| LayerSet {
+                layer_set: layer_set.clone(),
+                last_layer_id: None,
+            });
+
+        Ok(())
+    }
+
+    async fn get_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSetRetention>> {
+        let state = self.state();
+        let retention = state
+            .layers
+            .retentions
+            .get(&layer_set_key(project_id, layer_set_name))
+            .copied();
+
+        Ok(retention)
+    }
+
+    async fn set_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        retention: &models::LayerSetRetention,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .layers
+            .retentions
+            .insert(layer_set_key(project_id, layer_set_name), *retention);
+
+        Ok(())
+    }
+
+    async fn get_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Option<models::RefLayerSetLimit>> {
+        let state = self.state();
+        let limit = state
+            .layers
+            .ref_layer_set_limits
+            .get(&project_id.into_uuid())
+            .copied();
+
+        Ok(limit)
+    }
+
+    async fn set_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+        limit: &models::RefLayerSetLimit,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .layers
+            .ref_layer_set_limits
+            .insert(project_id.into_uuid(), *limit);
+
+        Ok(())
+    }
+
+    async fn set_layer_set_archived(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        archived: bool,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer_set = state.layers.layer_set_mut(project_id, layer_set_name)?;
+        layer_set.layer_set.archived = archived;
+
+        Ok(())
+    }
+
+    async fn set_pinned_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: Option<models::LayerId>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer_set = state.layers.layer_set_mut(project_id, layer_set_name)?;
+        layer_set.layer_set.pinned_layer_id = layer_id;
+
+        Ok(())
+    }
+
+    async fn set_last_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer_set = state.layers.layer_set_mut(project_id, layer_set_name)?;
+
+        match layer_set.last_layer_id {
+            Some(last_layer_id) if last_layer_id >= layer_id.into_uuid() => {
+                Err(anyhow!("new layer id is older than previous layer id"))
+            }
+            _ => {
+                layer_set.last_layer_id = Some(layer_id.into_uuid());
+                Ok(())
+            }
+        }
+    }
+
+    async fn get_last_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::Layer>> {
+        let state = self.state();
+
+        let last_layer_id = match state
+            .layers
+            .layer_sets
+            .get(&layer_set_key(project_id, layer_set_name))
+        {
+            Some(LayerSet {
+                last_layer_id: Some(last_layer_id),
+                ..
+            }) => *last_layer_id,
+            _ => return Ok(None),
+        };
+
+        let (project_id, layer_set_name) = layer_set_key(project_id, layer_set_name);
+        let layer = state
+            .layers
+            .layers
+            .range(
+                (project_id, layer_set_name.clone(), Uuid::nil())
+                    ..=(project_id, layer_set_name, last_layer_id),
+            )
+            .next_back()
+            .map(|(_, layer)| layer.layer.clone());
+
+        Ok(layer)
+    }
+
+    async fn create_layer(&self, layer: &models::Layer) -> Result<()> {
+        let mut state = self.state();
+        state.layers.layers.insert(
+            layer_key(layer.project_id, &layer.layer_set_name, layer.id),
+            Layer {
+                layer: layer.clone(),
+                build_worker: None,
+                finalize_worker: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn list_layers(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Vec<models::Layer>> {
+        let state = self.state();
+
+        let (project_id, layer_set_name) = layer_set_key(project_id, layer_set_name);
+        let layers = state
+            .layers
+            .layers
+            .range(
+                (project_id, layer_set_name.clone(), Uuid::nil())
+                    ..=(project_id, layer_set_name, Uuid::from_u128(u128::MAX)),
+            )
+            .map(|(_, layer)| layer.layer.clone())
+            .collect();
+
+        Ok(layers)
+    }
+
+    async fn delete_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let key = layer_key(project_id, layer_set_name, layer_id);
+
+        state.layers.layers.remove(&key);
+
+        state
+            .layers
+            .layer_changes
+            .retain(|(project_id, layer_set_name, layer_id, _), _| {
+                (*project_id, layer_set_name, *layer_id) != (key.0, &key.1, key.2)
+            });
+
+        for ((project_id, layer_set_name, _), layer_members) in
+            state.layers.layer_members.iter_mut()
+        {
+            if (*project_id, layer_set_name) == (key.0, &key.1) {
+                layer_members.remove(&key.2);
+            }
+        }
+
+        state
+            .layers
+            .layer_members
+            .retain(|_, layer_members| !layer_members.is_empty());
+
+        Ok(())
+    }
+
+    async fn get_pending_layers(
+        &self,
+        filter: LayerPendingLayersFilter,
+    ) -> Result<Vec<models::Layer>> {
+        let state = self.state();
+        let layers = state
+            .layers
+            .layers
+            .values()
+            .filter(|layer| match (filter, layer.layer.status) {
+                (LayerPendingLayersFilter::Building, models::LayerStatus::Building) => {
+                    Worker::is_expired(&layer.build_worker)
+                }
+                (LayerPendingLayersFilter::Finalizing, models::LayerStatus::Finalizing) => {
+                    Worker::is_expired(&layer.finalize_worker)
+                }
+                _ => false,
+            })
+            .map(|layer| layer.layer.clone())
+            .collect();
+
+        Ok(layers)
+    }
+
+    async fn try_set_current_build(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let build_status = &mut state
+            .layers
+            .layer_set_mut(project_id, layer_set_name)?
+            .layer_set
+            .build_status;
+
+        match build_status {
+            models::LayerSetBuildStatus {
+                current_layer_id: None,
+                last_layer_id: Some(last_layer_id),
+            } if *last_layer_id >= layer_id => (),
+            models::LayerSetBuildStatus {
+                current_layer_id: current_layer_id @ None,
+                ..
+            } => *current_layer_id = Some(layer_id),
+            _ => (),
+        }
+
+        match build_status.current_layer_id {
+            Some(current_layer_id) if current_layer_id == layer_id => Ok(()),
+            Some(_) => Err(anyhow!("layer set is already locked by a build")),
+            None => Err(anyhow!("layer set has already built a more recent layer, therefore this layer cannot be built")),
+        }
+    }
+
+    async fn build_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer = state
+            .layers
+            .layer_mut(project_id, layer_set_name, layer_id)?;
+
+        ensure!(
+            matches!(layer.layer.status, models::LayerStatus::Building)
+                && Worker::is_expired(&layer.build_worker),
+            "layer is already locked by another build worker"
+        );
+
+        layer.build_worker = Some(Worker::new(worker_id, BUILD_TIMEOUT_SECONDS));
+
+        Ok(())
+    }
+
+    async fn finish_build(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer = state
+            .layers
+            .layer_mut(project_id, layer_set_name, layer_id)?;
+
+        ensure!(
+            matches!(layer.layer.status, models::LayerStatus::Building)
+                && Worker::is_held_by(&layer.build_worker, worker_id),
+            "build worker timed out and the build could not be finished"
+        );
+
+        layer.layer.status = models::LayerStatus::Finalizing;
+        if let Some(worker) = layer.build_worker.as_mut() {
+            worker.expires_at = None;
+        }
+
+        Ok(())
+    }
+
+    async fn finalize_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer = state
+            .layers
+            .layer_mut(project_id, layer_set_name, layer_id)?;
+
+        ensure!(
+            matches!(layer.layer.status, models::LayerStatus::Finalizing)
+                && Worker::is_expired(&layer.finalize_worker),
+            "layer is already locked by another finalizing worker"
+        );
+
+        layer.finalize_worker = Some(Worker::new(worker_id, FINALIZE_TIMEOUT_SECONDS));
+
+        Ok(())
+    }
+
+    async fn finish_finalizing(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layers = &mut state.layers;
+
+        // Both conditions are checked before anything is updated, like in a conditional batch.
+        let layer = layers.layer_mut(project_id, layer_set_name, layer_id)?;
+        ensure!(
+            matches!(layer.layer.status, models::LayerStatus::Finalizing)
+                && Worker::is_held_by(&layer.finalize_worker, worker_id)
+        );
+
+        let layer_set = layers.layer_set_mut(project_id, layer_set_name)?;
+        ensure!(layer_set.layer_set.build_status.current_layer_id == Some(layer_id));
+
+        layer_set.layer_set.build_status = models::LayerSetBuildStatus {
+            current_layer_id: None,
+            last_layer_id: Some(layer_id),
+        };
+
+        let layer = layers.layer_mut(project_id, layer_set_name, layer_id)?;
+        layer.layer.status = models::LayerStatus::Ready;
+        if let Some(worker) = layer.finalize_worker.as_mut() {
+            worker.expires_at = None;
+        }
+
+        Ok(())
+    }
+
+    async fn cancel_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let layer = state
+            .layers
+            .layer_mut(project_id, layer_set_name, layer_id)?;
+
+        ensure!(
+            matches!(layer.layer.status, models::LayerStatus::Building),
+            "layer cannot be cancelled because of its status"
+        );
+
+        layer.layer.status = models::LayerStatus::Cancelled;
+
+        // Make sure this layer is not the current build.
+        let build_status = &mut state
+            .layers
+            .layer_set_mut(project_id, layer_set_name)?
+            .layer_set
+            .build_status;
+
+        if build_status.current_layer_id == Some(layer_id) {
+            build_status.current_layer_id = None;
+        }
+
+        Ok(())
+    }
+
+    async fn create_layer_changes(&self, layer_changes: &[models::LayerChange]) -> Result<()> {
+        let mut state = self.state();
+
+        for layer_change in layer_changes {
+            state
+                .layers
+                .layer_changes
+                .entry((
+                    layer_change.project_id.into_uuid(),
+                    layer_change.layer_set_name.as_str().to_owned(),
+                    layer_change.layer_id.into_uuid(),
+                    layer_change.worker_id.into_uuid(),
+                ))
+                .or_default()
+                .insert(layer_change.path.clone(), layer_change.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn list_layer_changes(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<Vec<models::LayerChange>> {
+        let state = self.state();
+        let layer_changes = state
+            .layers
+            .layer_changes
+            .get(&(
+                project_id.into_uuid(),
+                layer_set_name.as_str().to_owned(),
+                layer_id.into_uuid(),
+                worker_id.into_uuid(),
+            ))
+            .map(|layer_changes| layer_changes.values().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(layer_changes)
+    }
+
+    async fn create_layer_members(&self, layer_members: &[models::LayerMember]) -> Result<()> {
+        let mut state = self.state();
+
+        for layer_member in layer_members {
+            state
+                .layers
+                .layer_members
+                .entry((
+                    layer_member.project_id.into_uuid(),
+                    layer_member.layer_set_name.as_str().to_owned(),
+                    layer_member.path.clone(),
+                ))
+                .or_default()
+                .insert(layer_member.layer_id.into_uuid(), layer_member.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn list_layer_members(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<Vec<models::LayerMember>> {
+        let state = self.state();
+        let layer_members = state
+            .layers
+            .layer_members
+            .iter()
+            .filter(|((member_project_id, member_layer_set_name, _), _)| {
+                *member_project_id == project_id.into_uuid()
+                    && member_layer_set_name == layer_set_name.as_str()
+            })
+            .flat_map(|(_, layer_members)| layer_members.get(&layer_id.into_uuid()))
+            .cloned()
+            .collect();
+
+        Ok(layer_members)
+    }
+
+    async fn get_layer_member_summary(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        paths: &[&str],
+    ) -> Result<Vec<models::LayerMemberSummary>> {
+        let state = self.state();
+
+        let layer_members = paths
+            .iter()
+            .flat_map(|path| {
+                state
+                    .layers
+                    .layer_members
+                    .get(&(
+                        project_id.into_uuid(),
+                        layer_set_name.as_str().to_owned(),
+                        path.to_string(),
+                    ))
+                    .and_then(|layer_members| {
+                        layer_members.range(..=layer_id.into_uuid()).next_back()
+                    })
+            })
+            .map(|(_, layer_member)| models::LayerMemberSummary {
+                path: layer_member.path.clone(),
+                checksum: layer_member.checksum,
+                content_encoding_hint: layer_member.content_encoding_hint,
+                headers: layer_member.headers.clone(),
+            })
+            .collect();
+
+        Ok(layer_members)
+    }
+}
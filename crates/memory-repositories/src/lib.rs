@@ -0,0 +1,40 @@
+use std::sync::{Mutex, MutexGuard};
+
+mod domains;
+mod files;
+mod git_source;
+mod layers;
+mod projects;
+mod queue;
+mod sources;
+
+pub use git_source::FixtureGitSource;
+
+/// Repository that keeps everything in memory, meant for tests.
+///
+/// Conditional updates are checked the same way as the lightweight transactions of the Scylla
+/// repository, so services behave the same against both.
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    projects: projects::Projects,
+    sources: sources::Sources,
+    layers: layers::Layers,
+    files: files::Files,
+    domains: domains::Domains,
+    queue: queue::Queue,
+}
+
+impl MemoryRepository {
+    pub fn new() -> MemoryRepository {
+        MemoryRepository::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
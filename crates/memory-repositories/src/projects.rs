@@ -0,0 +1,73 @@
+use anyhow::{anyhow, ensure, Result};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::ProjectRepository};
+
+use crate::MemoryRepository;
+
+pub(crate) type Projects = BTreeMap<Uuid, models::Project>;
+
+#[async_trait::async_trait]
+impl ProjectRepository for MemoryRepository {
+    async fn get_project(&self, id: &models::ProjectId) -> Result<Option<models::Project>> {
+        let state = self.state();
+        Ok(state.projects.get(&id.into_uuid()).cloned())
+    }
+
+    async fn list_projects(&self) -> Result<Vec<models::Project>> {
+        let state = self.state();
+        Ok(state.projects.values().cloned().collect())
+    }
+
+    async fn create_or_update_project(&self, project: &models::Project) -> Result<()> {
+        let mut state = self.state();
+        state
+            .projects
+            .insert(project.id.into_uuid(), project.clone());
+
+        Ok(())
+    }
+
+    async fn create_file_encryption_key(
+        &self,
+        project_id: models::ProjectId,
+        key: &models::FileEncryptionKey,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let project = state
+            .projects
+            .get_mut(&project_id.into_uuid())
+            .ok_or_else(|| anyhow!("project not found"))?;
+
+        let exists = project
+            .file_encryption_keys
+            .iter()
+            .any(|existing_key| existing_key.version == key.version);
+
+        ensure!(
+            !exists,
+            "a file encryption key with this version already exists"
+        );
+
+        project.file_encryption_keys.push(key.clone());
+
+        Ok(())
+    }
+
+    async fn delete_file_encryption_keys(
+        &self,
+        project_id: models::ProjectId,
+        versions: &[u32],
+    ) -> Result<()> {
+        let mut state = self.state();
+
+        if let Some(project) = state.projects.get_mut(&project_id.into_uuid()) {
+            project
+                .file_encryption_keys
+                .retain(|key| !versions.contains(&key.version));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::QueueRepository};
+
+use crate::MemoryRepository;
+
+/// Messages that are not finished within this time are assigned to another worker.
+const ASSIGNMENT_TIMEOUT_SECONDS: i64 = 1800;
+
+pub(crate) type Queue = BTreeMap<Uuid, BuildQueueMessage>;
+
+pub(crate) struct BuildQueueMessage {
+    message: models::BuildQueueMessage,
+    worker_expires_at: Option<DateTime<Utc>>,
+}
+
+#[async_trait::async_trait]
+impl QueueRepository for MemoryRepository {
+    async fn queue_build(&self, message: &models::BuildQueueMessage) -> Result<()> {
+        let mut state = self.state();
+        state.queue.insert(
+            message.id.into_uuid(),
+            BuildQueueMessage {
+                message: message.clone(),
+                worker_expires_at: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn assign_build(
+        &self,
+        _worker_id: models::WorkerId,
+    ) -> Result<Option<models::BuildQueueMessage>> {
+        let mut state = self.state();
+        let now = Utc::now();
+
+        let message = state
+            .queue
+            .values_mut()
+            .find(|message| match message.worker_expires_at {
+                Some(worker_expires_at) => worker_expires_at < now,
+                None => true,
+            });
+
+        let message = message.map(|message| {
+            message.worker_expires_at = Some(now + Duration::seconds(ASSIGNMENT_TIMEOUT_SECONDS));
+            message.message.clone()
+        });
+
+        Ok(message)
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::SourceRepository};
+
+use crate::MemoryRepository;
+
+pub(crate) type Sources = BTreeMap<(Uuid, String), models::Source>;
+
+#[async_trait::async_trait]
+impl SourceRepository for MemoryRepository {
+    async fn get_source(
+        &self,
+        project_id: &models::ProjectId,
+        name: &models::SourceName,
+    ) -> Result<Option<models::Source>> {
+        let state = self.state();
+        let source = state
+            .sources
+            .get(&(project_id.into_uuid(), name.as_str().to_owned()))
+            .cloned();
+
+        Ok(source)
+    }
+
+    async fn list_sources(&self, project_id: &models::ProjectId) -> Result<Vec<models::Source>> {
+        let state = self.state();
+        let sources = state
+            .sources
+            .values()
+            .filter(|source| source.project_id == *project_id)
+            .cloned()
+            .collect();
+
+        Ok(sources)
+    }
+
+    async fn create_or_update_source(&self, source: &models::Source) -> Result<()> {
+        let mut state = self.state();
+        state.sources.insert(
+            (
+                source.project_id.into_uuid(),
+                source.name.as_str().to_owned(),
+            ),
+            source.clone(),
+        );
+
+        Ok(())
+    }
+}
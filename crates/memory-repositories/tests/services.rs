@@ -0,0 +1,1871 @@
+use http::{header, Request, StatusCode};
+use http_body::Body as _;
+use std::{net::SocketAddr, time::Duration};
+
+use fairing_core2::{
+    models,
+    repositories::{DomainRepository, FileRepository, LayerRepository},
+    services::{
+        Authentication, AuthenticationRole, BuildService, ConnectionMeta, FileEncryptionService,
+        GarbageCollectionReport, GarbageCollectionService, HttpService, LayerArchiveFormat,
+        LayerArchiveService, LayerMemberService, LayerPromotionService, LayerService,
+        ProjectService, RequestError, SourceService, UploadService,
+    },
+};
+use memory_repositories::{FixtureGitSource, MemoryRepository};
+
+const REPOSITORY_URL: &str = "git@github.com:fairing/site.git";
+
+struct Harness {
+    repository: &'static MemoryRepository,
+    git_source: &'static FixtureGitSource,
+    project_service: ProjectService,
+    layer_service: LayerService,
+    source_service: SourceService,
+    build_service: BuildService,
+    http_service: HttpService,
+    gc_service: GarbageCollectionService,
+    archive_service: LayerArchiveService,
+    upload_service: UploadService,
+    member_service: LayerMemberService,
+    promotion_service: LayerPromotionService,
+    auth: Authentication,
+    _work_directory: tempfile::TempDir,
+}
+
+impl Harness {
+    /// Creates a project with a git source and a layer set following `refs/heads/main`.
+    async fn new() -> Harness {
+        let repository: &'static MemoryRepository = Box::leak(Box::new(MemoryRepository::new()));
+        let git_source: &'static FixtureGitSource = Box::leak(Box::new(FixtureGitSource::new()));
+        let work_directory = tempfile::tempdir().unwrap();
+
+        let project_service = ProjectService::new(repository);
+        let layer_service = LayerService::new(repository);
+        let source_service = SourceService::new(repository, git_source, repository, repository);
+        let build_service =
+            BuildService::new(repository, repository, git_source, repository, repository)
+                .with_work_directory(work_directory.path());
+        let http_service = HttpService::new(repository, repository, repository);
+        let gc_service =
+            GarbageCollectionService::new(repository, repository, repository, repository)
+                .with_grace_period(chrono::Duration::zero());
+        let archive_service = LayerArchiveService::new(repository, repository, repository);
+        let upload_service = UploadService::new(repository, repository, repository);
+        let member_service = LayerMemberService::new(repository, repository);
+        let promotion_service = LayerPromotionService::new(repository, repository);
+
+        let project = project_service
+            .create_project(
+                &Authentication::System { project_id: None },
+                &models::CreateProject,
+            )
+            .await
+            .unwrap();
+
+        let auth = Authentication::System {
+            project_id: Some(project.id),
+        };
+
+        let source = source_service
+            .create_source(
+                &auth,
+                &models::CreateSource {
+                    name: "site".parse().unwrap(),
+                    kind: models::CreateSourceKind::Git {
+                        repository_url: REPOSITORY_URL.parse().unwrap(),
+                        http_token: None,
+                    },
+                },
+            )
+            .await
+            .unwrap();
+
+        layer_service
+            .create_layer_set(
+                &auth,
+                &models::CreateLayerSet {
+                    name: "production".parse().unwrap(),
+                    visibility: models::LayerSetVisibility::Public,
+                    source: Some(models::CreateLayerSetSource {
+                        source,
+                        kind: models::CreateLayerSetSourceKind::Git {
+                            ref_: "refs/heads/main".into(),
+                            root: String::new(),
+                            paths: vec![],
+                        },
+                    }),
+                },
+            )
+            .await
+            .unwrap();
+
+        Harness {
+            repository,
+            git_source,
+            project_service,
+            layer_service,
+            source_service,
+            build_service,
+            http_service,
+            gc_service,
+            archive_service,
+            upload_service,
+            member_service,
+            promotion_service,
+            auth,
+            _work_directory: work_directory,
+        }
+    }
+
+    /// Commits `files` to `refs/heads/main`, refreshes the source and builds the new layer.
+    async fn deploy(&self, files: &[(&str, &[u8])]) -> models::LayerId {
+        self.deploy_with_symlinks(files, &[]).await
+    }
+
+    async fn deploy_with_symlinks(
+        &self,
+        files: &[(&str, &[u8])],
+        symlinks: &[(&str, &str)],
+    ) -> models::LayerId {
+        // Layer ids only have millisecond precision, layers created within the same millisecond
+        // aren't ordered.
+        tokio::time::sleep(Duration::from_millis(2)).await;
+
+        self.git_source
+            .commit_with_symlinks(REPOSITORY_URL, "refs/heads/main", files, symlinks);
+
+        self.source_service
+            .refresh_source(&self.auth, &"site".parse().unwrap())
+            .await
+            .unwrap();
+
+        self.build_service.build().await.unwrap();
+
+        let layer_set = self
+            .layer_service
+            .get_layer_set(&self.auth, &"production".parse().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(layer_set.build_status.current_layer_id.is_none());
+        layer_set.build_status.last_layer_id.unwrap()
+    }
+
+    async fn get(&self, host: &str, path: &str) -> (StatusCode, http::HeaderMap, Vec<u8>) {
+        let remote_addr: SocketAddr = "[::1]:50000".parse().unwrap();
+        let connection = self
+            .http_service
+            .handle_connection(ConnectionMeta::new(remote_addr, Some(host)));
+
+        let request = Request::builder()
+            .uri(path)
+            .header(header::HOST, host)
+            .body(hyper_body::Empty)
+            .unwrap();
+
+        let response = connection.handle_request(request).await.unwrap();
+        let (parts, mut body) = response.into_parts();
+
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap().into_inner());
+        }
+
+        (parts.status, parts.headers, data)
+    }
+}
+
+fn file_checksum(auth: &Authentication, data: &[u8]) -> models::FileChecksum {
+    let mut hasher = models::FileChecksum::blake2b_hasher(auth.project_id().unwrap());
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Pseudo random data so that it's split into several blobs and isn't compressed away.
+fn random_data(length: usize) -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1d_u64;
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn layer_host(layer_id: models::LayerId) -> String {
+    format!("{}.localhost", layer_id.into_uuid().as_hyphenated())
+}
+
+fn layer_set_host(harness: &Harness, layer_set_name: &str) -> String {
+    models::Domain::layer_set_preview_fqdn(
+        harness.auth.project_id().unwrap(),
+        &layer_set_name.parse().unwrap(),
+    )
+}
+
+mod hyper_body {
+    use http::HeaderMap;
+    use std::{pin::Pin, task};
+
+    /// Request body without any data.
+    pub struct Empty;
+
+    impl http_body::Body for Empty {
+        type Data = std::io::Cursor<Vec<u8>>;
+        type Error = std::convert::Infallible;
+
+        fn poll_data(
+            self: Pin<&mut Self>,
+            _cx: &mut task::Context<'_>,
+        ) -> task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            task::Poll::Ready(None)
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut task::Context<'_>,
+        ) -> task::Poll<Result<Option<HeaderMap>, Self::Error>> {
+            task::Poll::Ready(Ok(None))
+        }
+    }
+}
+
+#[tokio::test]
+async fn build_and_serve_layer() {
+    let harness = Harness::new().await;
+
+    let layer_id = harness
+        .deploy(&[
+            ("index.html", b"<h1>Hello</h1>"),
+            ("blog/index.html", b"<h1>Blog</h1>"),
+            ("style.css", b"h1 { color: red; }"),
+        ])
+        .await;
+
+    let host = layer_host(layer_id);
+
+    let (status, headers, body) = harness.get(&host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(body, b"<h1>Hello</h1>");
+
+    let (status, _, body) = harness.get(&host, "/blog/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Blog</h1>");
+
+    let (status, _, body) = harness.get(&host, "/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"h1 { color: red; }");
+
+    let (status, _, _) = harness.get(&host, "/missing.html").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, _) = harness.get("unknown.localhost", "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn serve_symlinks_inside_source() {
+    let harness = Harness::new().await;
+
+    let layer_id = harness
+        .deploy_with_symlinks(
+            &[
+                ("index.html", b"<h1>Hello</h1>"),
+                ("assets/style.css", b"h1 { color: red; }"),
+            ],
+            &[
+                ("style.css", "assets/style.css"),
+                ("static", "assets"),
+                ("assets/root", ".."),
+                ("missing.html", "nothing.html"),
+                ("passwd", "/etc/passwd"),
+                ("outside", "../../.."),
+            ],
+        )
+        .await;
+
+    let host = layer_host(layer_id);
+
+    let (status, _, body) = harness.get(&host, "/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"h1 { color: red; }");
+
+    let (status, _, body) = harness.get(&host, "/static/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"h1 { color: red; }");
+
+    for path in [
+        "/assets/root/index.html",
+        "/missing.html",
+        "/passwd",
+        "/outside/",
+    ] {
+        let (status, _, _) = harness.get(&host, path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn new_commits_build_new_layers() {
+    let harness = Harness::new().await;
+
+    let first_layer_id = harness.deploy(&[("index.html", b"first")]).await;
+    let second_layer_id = harness.deploy(&[("index.html", b"second")]).await;
+    assert!(second_layer_id > first_layer_id);
+
+    let (_, _, body) = harness.get(&layer_host(first_layer_id), "/").await;
+    assert_eq!(body, b"first");
+
+    let (_, _, body) = harness.get(&layer_host(second_layer_id), "/").await;
+    assert_eq!(body, b"second");
+
+    // Refreshing without new commits doesn't create another layer.
+    harness
+        .source_service
+        .refresh_source(&harness.auth, &"site".parse().unwrap())
+        .await
+        .unwrap();
+    harness.build_service.build().await.unwrap();
+
+    let layer_set = harness
+        .layer_service
+        .get_layer_set(&harness.auth, &"production".parse().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(layer_set.build_status.last_layer_id, Some(second_layer_id));
+}
+
+#[tokio::test]
+async fn serve_files_larger_than_a_blob() {
+    let harness = Harness::new().await;
+
+    let data = random_data(10 << 20);
+
+    let layer_id = harness.deploy(&[("large.bin", &data)]).await;
+
+    let (status, _, body) = harness.get(&layer_host(layer_id), "/large.bin").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.len(), data.len());
+    assert!(body == data);
+}
+
+#[tokio::test]
+async fn read_files_after_rotating_file_encryption_key() {
+    let harness = Harness::new().await;
+    let project_id = harness.auth.project_id().unwrap();
+
+    let layer_id = harness.deploy(&[("index.html", b"<h1>hello</h1>")]).await;
+
+    let project = harness
+        .project_service
+        .rotate_file_encryption_key(&harness.auth)
+        .await
+        .unwrap();
+    assert_eq!(project.current_file_encryption_key().unwrap().version, 2);
+
+    // Blobs written before the re-encryption are still read with the old key.
+    let (status, _, body) = harness.get(&layer_host(layer_id), "/index.html").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>hello</h1>");
+
+    FileEncryptionService::new(harness.repository, harness.repository)
+        .reencrypt_files()
+        .await
+        .unwrap();
+
+    let key_versions = harness
+        .repository
+        .list_blob_key_versions(project_id)
+        .await
+        .unwrap();
+    assert_eq!(key_versions, vec![2]);
+
+    let project = harness
+        .project_service
+        .get_project(&harness.auth)
+        .await
+        .unwrap()
+        .unwrap();
+    let versions = project
+        .file_encryption_keys
+        .iter()
+        .map(|key| key.version)
+        .collect::<Vec<_>>();
+    assert_eq!(versions, vec![2]);
+
+    let (status, _, body) = harness.get(&layer_host(layer_id), "/index.html").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>hello</h1>");
+}
+
+#[tokio::test]
+async fn collect_garbage_of_expired_layers() {
+    let harness = Harness::new().await;
+
+    harness
+        .layer_service
+        .set_layer_set_retention(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            &models::LayerSetRetention {
+                keep_ready_layers: 1,
+            },
+        )
+        .await
+        .unwrap();
+
+    let err = harness
+        .layer_service
+        .set_layer_set_retention(
+            &harness.auth,
+            &"missing".parse().unwrap(),
+            &models::LayerSetRetention {
+                keep_ready_layers: 1,
+            },
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::NotFound("layer set"))
+    ));
+
+    let first_layer_id = harness
+        .deploy(&[("index.html", b"first"), ("shared.css", b"shared")])
+        .await;
+    let second_layer_id = harness
+        .deploy(&[("index.html", b"second"), ("shared.css", b"shared")])
+        .await;
+
+    let expected_report = GarbageCollectionReport {
+        layers: 1,
+        layer_members: 3,
+        files: 1,
+        file_bytes: 5,
+        blobs: 1,
+        blob_bytes: 5,
+    };
+
+    let report = harness.gc_service.collect_garbage(true).await.unwrap();
+    assert_eq!(report, expected_report);
+
+    // Nothing is deleted in a dry run.
+    let (_, _, body) = harness.get(&layer_host(first_layer_id), "/").await;
+    assert_eq!(body, b"first");
+
+    let report = harness.gc_service.collect_garbage(false).await.unwrap();
+    assert_eq!(report, expected_report);
+
+    let (status, _, _) = harness.get(&layer_host(first_layer_id), "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_, _, body) = harness.get(&layer_host(second_layer_id), "/").await;
+    assert_eq!(body, b"second");
+
+    let (_, _, body) = harness
+        .get(&layer_host(second_layer_id), "/shared.css")
+        .await;
+    assert_eq!(body, b"shared");
+
+    let report = harness.gc_service.collect_garbage(false).await.unwrap();
+    assert_eq!(report, GarbageCollectionReport::default());
+}
+
+#[tokio::test]
+async fn keep_recently_stored_files_when_collecting_garbage() {
+    let harness = Harness::new().await;
+
+    harness
+        .layer_service
+        .set_layer_set_retention(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            &models::LayerSetRetention {
+                keep_ready_layers: 1,
+            },
+        )
+        .await
+        .unwrap();
+
+    harness
+        .deploy(&[("index.html", b"first"), ("shared.css", b"shared")])
+        .await;
+    harness
+        .deploy(&[("index.html", b"second"), ("shared.css", b"shared")])
+        .await;
+
+    let gc_service = GarbageCollectionService::new(
+        harness.repository,
+        harness.repository,
+        harness.repository,
+        harness.repository,
+    );
+
+    // The files of the expired layer were stored within the grace period.
+    let report = gc_service.collect_garbage(false).await.unwrap();
+    assert_eq!(
+        report,
+        GarbageCollectionReport {
+            layers: 1,
+            layer_members: 3,
+            ..GarbageCollectionReport::default()
+        }
+    );
+
+    let report = harness.gc_service.collect_garbage(false).await.unwrap();
+    assert_eq!(
+        report,
+        GarbageCollectionReport {
+            files: 1,
+            file_bytes: 5,
+            blobs: 1,
+            blob_bytes: 5,
+            ..GarbageCollectionReport::default()
+        }
+    );
+}
+
+#[tokio::test]
+async fn export_and_import_layer() {
+    let harness = Harness::new().await;
+
+    let large = random_data(10 << 20);
+
+    let layer_id = harness
+        .deploy(&[
+            ("index.html", b"<h1>Hello</h1>"),
+            ("copy.html", b"<h1>Hello</h1>"),
+            ("style.css", b"h1 { color: red; }"),
+            ("large.bin", &large),
+        ])
+        .await;
+
+    let mut archive = vec![];
+    harness
+        .archive_service
+        .export_layer(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            layer_id,
+            &mut archive,
+            LayerArchiveFormat::TarZstd,
+        )
+        .await
+        .unwrap();
+
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: "imported".parse().unwrap(),
+                visibility: models::LayerSetVisibility::Public,
+                source: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let layer = harness
+        .archive_service
+        .import_layer(
+            &harness.auth,
+            &"imported".parse().unwrap(),
+            std::io::Cursor::new(archive),
+        )
+        .await
+        .unwrap();
+
+    let host = layer_host(layer.id);
+
+    let (status, headers, body) = harness.get(&host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(body, b"<h1>Hello</h1>");
+
+    let (_, _, body) = harness.get(&host, "/copy.html").await;
+    assert_eq!(body, b"<h1>Hello</h1>");
+
+    let (_, _, body) = harness.get(&host, "/style.css").await;
+    assert_eq!(body, b"h1 { color: red; }");
+
+    let (_, _, body) = harness.get(&host, "/large.bin").await;
+    assert!(body == large);
+
+    let layer_set = harness
+        .layer_service
+        .get_layer_set(&harness.auth, &"imported".parse().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(layer_set.build_status.last_layer_id, Some(layer.id));
+    assert!(layer_set.build_status.current_layer_id.is_none());
+
+    // Archives have the members that a layer inherits, and importing them deletes the members
+    // of earlier layers that the archive doesn't have.
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    let project_id = harness.auth.project_id().unwrap();
+    let incremental_layer = models::Layer {
+        project_id,
+        layer_set_name: "production".parse().unwrap(),
+        id: models::LayerId::new().unwrap(),
+        status: models::LayerStatus::Ready,
+        source: None,
+    };
+    harness
+        .repository
+        .create_layer(&incremental_layer)
+        .await
+        .unwrap();
+    harness
+        .repository
+        .create_layer_members(&[models::LayerMember {
+            project_id,
+            layer_set_name: incremental_layer.layer_set_name.clone(),
+            layer_id: incremental_layer.id,
+            path: "/copy.html".into(),
+            checksum: models::FileChecksum::Deleted,
+            content_encoding_hint: models::ContentEncodingHint::Relative {
+                identity: 1,
+                gzip: 0,
+                zstd: 0,
+                brotli: 0,
+            },
+            headers: Default::default(),
+        }])
+        .await
+        .unwrap();
+
+    let mut archive = vec![];
+    harness
+        .archive_service
+        .export_layer(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            incremental_layer.id,
+            &mut archive,
+            LayerArchiveFormat::Tar,
+        )
+        .await
+        .unwrap();
+
+    let layer = harness
+        .archive_service
+        .import_layer(
+            &harness.auth,
+            &"imported".parse().unwrap(),
+            std::io::Cursor::new(archive),
+        )
+        .await
+        .unwrap();
+
+    let host = layer_host(layer.id);
+
+    let (_, _, body) = harness.get(&host, "/style.css").await;
+    assert_eq!(body, b"h1 { color: red; }");
+
+    let (status, _, _) = harness.get(&host, "/copy.html").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Imports only go into layer sets without a source.
+    let res = harness
+        .archive_service
+        .import_layer(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            std::io::Cursor::new(vec![]),
+        )
+        .await;
+    assert!(res.is_err());
+
+    // Member paths are validated like the paths of uploads.
+    let mut archive = vec![];
+    harness
+        .archive_service
+        .export_layer(
+            &harness.auth,
+            &"production".parse().unwrap(),
+            layer_id,
+            &mut archive,
+            LayerArchiveFormat::Tar,
+        )
+        .await
+        .unwrap();
+
+    let path = br#""/style.css""#;
+    let position = archive
+        .windows(path.len())
+        .position(|window| window == path)
+        .unwrap();
+    archive[position..position + path.len()].copy_from_slice(br#""/../le.css""#);
+
+    let res = harness
+        .archive_service
+        .import_layer(
+            &harness.auth,
+            &"imported".parse().unwrap(),
+            std::io::Cursor::new(archive),
+        )
+        .await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn upload_layer() {
+    let harness = Harness::new().await;
+    let layer_set_name: models::LayerSetName = "uploads".parse().unwrap();
+
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: layer_set_name.clone(),
+                visibility: models::LayerSetVisibility::Public,
+                source: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let index: &[u8] = b"<h1>Hello</h1>";
+    let style: &[u8] = b"h1 { color: red; }";
+
+    let manifest = models::UploadManifest {
+        files: [
+            ("/index.html", index),
+            ("/copy.html", index),
+            ("/style.css", style),
+        ]
+        .into_iter()
+        .map(|(path, data)| models::UploadManifestFile {
+            path: path.into(),
+            checksum: file_checksum(&harness.auth, data),
+            length: data.len() as u64,
+        })
+        .collect(),
+    };
+
+    let (layer, missing_checksums) = harness
+        .upload_service
+        .create_upload(&harness.auth, &layer_set_name, &manifest)
+        .await
+        .unwrap();
+    assert_eq!(
+        missing_checksums,
+        vec![
+            file_checksum(&harness.auth, index),
+            file_checksum(&harness.auth, style)
+        ]
+    );
+
+    // Files are only built by finalizing the upload.
+    harness.build_service.build().await.unwrap();
+
+    let project_id = harness.auth.project_id().unwrap();
+    let last_layer = harness
+        .repository
+        .get_last_layer(project_id, &layer_set_name)
+        .await
+        .unwrap();
+    assert!(last_layer.is_none());
+
+    let err = harness
+        .upload_service
+        .finalize_upload(&harness.auth, &layer_set_name, layer.id)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::FailedPrecondition(_))
+    ));
+
+    let mut upload = harness
+        .upload_service
+        .upload_file(
+            &harness.auth,
+            &layer_set_name,
+            layer.id,
+            file_checksum(&harness.auth, index),
+            index.len() as u64,
+        )
+        .await
+        .unwrap();
+    let err = upload.write(style).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::InvalidArgument(_))
+    ));
+
+    for data in [index, style] {
+        let mut upload = harness
+            .upload_service
+            .upload_file(
+                &harness.auth,
+                &layer_set_name,
+                layer.id,
+                file_checksum(&harness.auth, data),
+                data.len() as u64,
+            )
+            .await
+            .unwrap();
+        upload.write(&data[..4]).await.unwrap();
+        upload.write(&data[4..]).await.unwrap();
+        upload.finish().await.unwrap();
+    }
+
+    let layer = harness
+        .upload_service
+        .finalize_upload(&harness.auth, &layer_set_name, layer.id)
+        .await
+        .unwrap();
+
+    let last_layer = harness
+        .repository
+        .get_last_layer(project_id, &layer_set_name)
+        .await
+        .unwrap();
+    assert_eq!(last_layer.map(|last_layer| last_layer.id), Some(layer.id));
+
+    let host = layer_host(layer.id);
+
+    let (status, headers, body) = harness.get(&host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(body, index);
+
+    let (_, _, body) = harness.get(&host, "/copy.html").await;
+    assert_eq!(body, index);
+
+    let (_, _, body) = harness.get(&host, "/style.css").await;
+    assert_eq!(body, style);
+
+    // The files of the next upload are already stored.
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    let (layer, missing_checksums) = harness
+        .upload_service
+        .create_upload(&harness.auth, &layer_set_name, &manifest)
+        .await
+        .unwrap();
+    assert!(missing_checksums.is_empty());
+
+    harness
+        .upload_service
+        .finalize_upload(&harness.auth, &layer_set_name, layer.id)
+        .await
+        .unwrap();
+
+    let (_, _, body) = harness.get(&layer_host(layer.id), "/style.css").await;
+    assert_eq!(body, style);
+}
+
+#[tokio::test]
+async fn remove_files_missing_from_upload() {
+    let harness = Harness::new().await;
+    let layer_set_name: models::LayerSetName = "uploads".parse().unwrap();
+
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: layer_set_name.clone(),
+                visibility: models::LayerSetVisibility::Public,
+                source: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let index: &[u8] = b"<h1>Hello</h1>";
+    let style: &[u8] = b"h1 { color: red; }";
+
+    let mut layer_ids = vec![];
+
+    for files in [
+        &[("/index.html", index), ("/style.css", style)][..],
+        &[("/index.html", index)][..],
+    ] {
+        // Layer ids only have millisecond precision.
+        tokio::time::sleep(Duration::from_millis(2)).await;
+
+        let manifest = models::UploadManifest {
+            files: files
+                .iter()
+                .map(|(path, data)| models::UploadManifestFile {
+                    path: path.to_string(),
+                    checksum: file_checksum(&harness.auth, data),
+                    length: data.len() as u64,
+                })
+                .collect(),
+        };
+
+        let (layer, missing_checksums) = harness
+            .upload_service
+            .create_upload(&harness.auth, &layer_set_name, &manifest)
+            .await
+            .unwrap();
+
+        for (_, data) in files {
+            let checksum = file_checksum(&harness.auth, data);
+            if !missing_checksums.contains(&checksum) {
+                continue;
+            }
+
+            let mut upload = harness
+                .upload_service
+                .upload_file(
+                    &harness.auth,
+                    &layer_set_name,
+                    layer.id,
+                    checksum,
+                    data.len() as u64,
+                )
+                .await
+                .unwrap();
+            upload.write(data).await.unwrap();
+            upload.finish().await.unwrap();
+        }
+
+        let layer = harness
+            .upload_service
+            .finalize_upload(&harness.auth, &layer_set_name, layer.id)
+            .await
+            .unwrap();
+        layer_ids.push(layer.id);
+    }
+
+    let (status, _, body) = harness.get(&layer_host(layer_ids[0]), "/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, style);
+
+    let host = layer_host(layer_ids[1]);
+
+    let (status, _, body) = harness.get(&host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, index);
+
+    let (status, _, _) = harness.get(&host, "/style.css").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn list_and_diff_layer_members() {
+    let harness = Harness::new().await;
+    let layer_set_name: models::LayerSetName = "production".parse().unwrap();
+
+    let base_layer_id = harness
+        .deploy(&[
+            ("index.html", b"<h1>Hello</h1>"),
+            ("blog/index.html", b"<h1>Blog</h1>"),
+            ("style.css", b"h1 { color: red; }"),
+        ])
+        .await;
+
+    let layer_id = harness
+        .deploy(&[
+            ("index.html", b"<h1>Hello, world</h1>"),
+            ("blog/index.html", b"<h1>Blog</h1>"),
+            ("about.html", b"<h1>About</h1>"),
+        ])
+        .await;
+
+    let mut paths = vec![];
+    let mut list = models::ListLayerMembers {
+        page_size: 2,
+        ..Default::default()
+    };
+
+    loop {
+        let (members, next_page_token) = harness
+            .member_service
+            .list_layer_members(&harness.auth, &layer_set_name, base_layer_id, &list)
+            .await
+            .unwrap();
+        assert!(members.len() <= 2);
+
+        paths.extend(members.into_iter().map(|member| member.path));
+
+        match next_page_token {
+            Some(page_token) => list.page_token = Some(page_token),
+            None => break,
+        }
+    }
+
+    assert_eq!(
+        paths,
+        [
+            "/",
+            "/blog/",
+            "/blog/index.html",
+            "/index.html",
+            "/style.css"
+        ]
+    );
+
+    let (members, next_page_token) = harness
+        .member_service
+        .list_layer_members(
+            &harness.auth,
+            &layer_set_name,
+            base_layer_id,
+            &models::ListLayerMembers {
+                prefix: "/blog/".into(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(next_page_token.is_none());
+    assert_eq!(members.len(), 2);
+    assert_eq!(members[1].path, "/blog/index.html");
+    assert_eq!(members[1].length, 13);
+    assert_eq!(members[1].headers["content-type"], "text/html");
+
+    let diff = harness
+        .member_service
+        .diff_layers(&harness.auth, &layer_set_name, base_layer_id, layer_id)
+        .await
+        .unwrap();
+
+    let diff = diff
+        .iter()
+        .map(|entry| {
+            (
+                entry.path.as_str(),
+                entry.kind,
+                entry.old_length,
+                entry.new_length,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        diff,
+        [
+            ("/", models::LayerDiffKind::Modified, Some(14), Some(21)),
+            ("/about.html", models::LayerDiffKind::Added, None, Some(14)),
+            (
+                "/index.html",
+                models::LayerDiffKind::Modified,
+                Some(14),
+                Some(21)
+            ),
+        ]
+    );
+
+    // Members of earlier layers are inherited until a layer replaces or deletes them.
+    let (status, _, _) = harness.get(&layer_host(layer_id), "/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (members, _) = harness
+        .member_service
+        .list_layer_members(
+            &harness.auth,
+            &layer_set_name,
+            layer_id,
+            &models::ListLayerMembers::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        members
+            .iter()
+            .map(|member| member.path.as_str())
+            .collect::<Vec<_>>(),
+        [
+            "/",
+            "/about.html",
+            "/blog/",
+            "/blog/index.html",
+            "/index.html",
+            "/style.css"
+        ]
+    );
+
+    let err = harness
+        .member_service
+        .diff_layers(
+            &harness.auth,
+            &layer_set_name,
+            models::LayerId::new().unwrap(),
+            layer_id,
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::NotFound("layer"))
+    ));
+}
+
+#[tokio::test]
+async fn serve_last_ready_layer_while_building() {
+    let harness = Harness::new().await;
+
+    harness
+        .repository
+        .create_domain(models::Domain {
+            project_id: harness.auth.project_id().unwrap(),
+            fqdn: "site.localhost".into(),
+            kind: models::DomainKind::LayerSet {
+                layer_set_name: "production".parse().unwrap(),
+            },
+        })
+        .await
+        .unwrap();
+
+    let refresh_without_building = |files: &'static [(&'static str, &'static [u8])]| {
+        let harness = &harness;
+        async move {
+            harness
+                .git_source
+                .commit(REPOSITORY_URL, "refs/heads/main", files);
+
+            harness
+                .source_service
+                .refresh_source(&harness.auth, &"site".parse().unwrap())
+                .await
+                .unwrap();
+        }
+    };
+
+    // The first layer is pending, there is nothing to serve yet.
+    refresh_without_building(&[("index.html", b"first")]).await;
+
+    let (status, _, _) = harness.get("site.localhost", "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    harness.build_service.build().await.unwrap();
+
+    let (status, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"first");
+
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    refresh_without_building(&[("index.html", b"second")]).await;
+
+    let (status, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"first");
+
+    harness.build_service.build().await.unwrap();
+
+    let (_, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(body, b"second");
+}
+
+#[tokio::test]
+async fn pin_layer_set() {
+    let harness = Harness::new().await;
+    let layer_set_name: models::LayerSetName = "production".parse().unwrap();
+
+    harness
+        .repository
+        .create_domain(models::Domain {
+            project_id: harness.auth.project_id().unwrap(),
+            fqdn: "site.localhost".into(),
+            kind: models::DomainKind::LayerSet {
+                layer_set_name: layer_set_name.clone(),
+            },
+        })
+        .await
+        .unwrap();
+
+    let (status, _, _) = harness.get("site.localhost", "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let first_layer_id = harness.deploy(&[("index.html", b"first")]).await;
+    harness.deploy(&[("index.html", b"second")]).await;
+
+    let (_, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(body, b"second");
+
+    harness
+        .layer_service
+        .pin_layer(&harness.auth, &layer_set_name, first_layer_id)
+        .await
+        .unwrap();
+
+    let (_, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(body, b"first");
+
+    // New layers are built but don't go live while the layer set is pinned.
+    let third_layer_id = harness.deploy(&[("index.html", b"third")]).await;
+
+    let (_, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(body, b"first");
+
+    let (_, _, body) = harness.get(&layer_host(third_layer_id), "/").await;
+    assert_eq!(body, b"third");
+
+    let layer_set = harness
+        .layer_service
+        .get_layer_set(&harness.auth, &layer_set_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(layer_set.pinned_layer_id, Some(first_layer_id));
+    assert_eq!(layer_set.build_status.last_layer_id, Some(third_layer_id));
+
+    harness
+        .layer_service
+        .unpin_layer_set(&harness.auth, &layer_set_name)
+        .await
+        .unwrap();
+
+    let (_, _, body) = harness.get("site.localhost", "/").await;
+    assert_eq!(body, b"third");
+
+    let err = harness
+        .layer_service
+        .pin_layer(
+            &harness.auth,
+            &layer_set_name,
+            models::LayerId::new().unwrap(),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::NotFound("layer"))
+    ));
+}
+
+#[tokio::test]
+async fn build_subdirectory_of_repository() {
+    let harness = Harness::new().await;
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &"site".parse().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let blog: models::LayerSetName = "blog".parse().unwrap();
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: blog.clone(),
+                visibility: models::LayerSetVisibility::Public,
+                source: Some(models::CreateLayerSetSource {
+                    source,
+                    kind: models::CreateLayerSetSourceKind::Git {
+                        ref_: "refs/heads/main".into(),
+                        root: "/sites/blog/".into(),
+                        paths: vec!["public".into()],
+                    },
+                }),
+            },
+        )
+        .await
+        .unwrap();
+
+    let last_blog_layer = || async {
+        harness
+            .layer_service
+            .get_layer_set(&harness.auth, &blog)
+            .await
+            .unwrap()
+            .unwrap()
+            .build_status
+            .last_layer_id
+            .unwrap()
+    };
+
+    harness
+        .deploy(&[
+            ("sites/blog/public/index.html", b"<h1>Blog</h1>"),
+            ("sites/blog/README.md", b"# Blog"),
+            ("sites/docs/index.html", b"<h1>Docs</h1>"),
+        ])
+        .await;
+
+    let first_layer_id = last_blog_layer().await;
+    let host = layer_host(first_layer_id);
+
+    let (status, _, body) = harness.get(&host, "/public/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Blog</h1>");
+
+    let (status, _, _) = harness.get(&host, "/README.md").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, _) = harness.get(&host, "/sites/docs/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Changes outside of the published paths don't build a new layer.
+    harness
+        .deploy(&[
+            ("sites/blog/public/index.html", b"<h1>Blog</h1>"),
+            ("sites/blog/README.md", b"# The blog"),
+            ("sites/docs/index.html", b"<h1>New docs</h1>"),
+        ])
+        .await;
+
+    assert_eq!(last_blog_layer().await, first_layer_id);
+
+    harness
+        .deploy(&[
+            ("sites/blog/public/index.html", b"<h1>New blog</h1>"),
+            ("sites/blog/README.md", b"# The blog"),
+            ("sites/docs/index.html", b"<h1>New docs</h1>"),
+        ])
+        .await;
+
+    let layer_id = last_blog_layer().await;
+    assert_ne!(layer_id, first_layer_id);
+
+    let (status, _, body) = harness.get(&layer_host(layer_id), "/public/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>New blog</h1>");
+}
+
+#[tokio::test]
+async fn preview_branches_of_ref_pattern() {
+    let harness = Harness::new().await;
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &"site".parse().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: "preview".parse().unwrap(),
+                visibility: models::LayerSetVisibility::Public,
+                source: Some(models::CreateLayerSetSource {
+                    source,
+                    kind: models::CreateLayerSetSourceKind::Git {
+                        ref_: "refs/heads/*".into(),
+                        root: String::new(),
+                        paths: vec![],
+                    },
+                }),
+            },
+        )
+        .await
+        .unwrap();
+
+    harness
+        .layer_service
+        .set_ref_layer_set_limit(
+            &harness.auth,
+            &models::RefLayerSetLimit { max_layer_sets: 2 },
+        )
+        .await
+        .unwrap();
+
+    harness.git_source.commit(
+        REPOSITORY_URL,
+        "refs/heads/feature/login",
+        &[("index.html", b"<h1>Login</h1>")],
+    );
+    harness.git_source.commit(
+        REPOSITORY_URL,
+        "refs/heads/feature/signup",
+        &[("index.html", b"<h1>Signup</h1>")],
+    );
+
+    harness.deploy(&[("index.html", b"<h1>Main</h1>")]).await;
+
+    let get_layer_set = |name: &'static str| {
+        let harness = &harness;
+        async move {
+            harness
+                .layer_service
+                .get_layer_set(&harness.auth, &name.parse().unwrap())
+                .await
+                .unwrap()
+        }
+    };
+
+    let template = get_layer_set("preview").await.unwrap();
+    assert!(template.build_status.last_layer_id.is_none());
+
+    let login = get_layer_set("preview-feature-login").await.unwrap();
+    assert_eq!(login.template_name, Some("preview".parse().unwrap()));
+    assert!(!login.archived);
+    assert!(login.build_status.last_layer_id.is_some());
+
+    let (status, _, body) = harness
+        .get(&layer_set_host(&harness, "preview-feature-login"), "/")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Login</h1>");
+
+    let (status, _, body) = harness
+        .get(&layer_set_host(&harness, "preview-feature-signup"), "/")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Signup</h1>");
+
+    // The limit of the project was reached before `main` was matched.
+    assert!(get_layer_set("preview-main").await.is_none());
+
+    harness
+        .git_source
+        .delete_ref(REPOSITORY_URL, "refs/heads/feature/login");
+
+    harness
+        .source_service
+        .refresh_source(&harness.auth, &"site".parse().unwrap())
+        .await
+        .unwrap();
+    harness.build_service.build().await.unwrap();
+
+    let login = get_layer_set("preview-feature-login").await.unwrap();
+    assert!(login.archived);
+
+    let (status, _, _) = harness
+        .get(&layer_set_host(&harness, "preview-feature-login"), "/")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, body) = harness
+        .get(&layer_set_host(&harness, "preview-main"), "/")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Main</h1>");
+
+    // Domains that aren't the preview of a layer set are left alone when it's archived.
+    let signup_host = layer_set_host(&harness, "preview-feature-signup");
+    let production = get_layer_set("production").await.unwrap();
+    harness
+        .repository
+        .delete_domain(&signup_host)
+        .await
+        .unwrap();
+    harness
+        .repository
+        .create_domain(models::Domain {
+            project_id: production.project_id,
+            fqdn: signup_host.clone(),
+            kind: models::DomainKind::Layer {
+                layer_set_name: production.name.clone(),
+                layer_id: production.build_status.last_layer_id.unwrap(),
+            },
+        })
+        .await
+        .unwrap();
+
+    // The layer set is restored once the branch is pushed again and there is room for it.
+    harness
+        .git_source
+        .delete_ref(REPOSITORY_URL, "refs/heads/feature/signup");
+    harness.git_source.commit(
+        REPOSITORY_URL,
+        "refs/heads/feature/login",
+        &[("index.html", b"<h1>New login</h1>")],
+    );
+
+    harness
+        .source_service
+        .refresh_source(&harness.auth, &"site".parse().unwrap())
+        .await
+        .unwrap();
+    harness.build_service.build().await.unwrap();
+
+    assert!(
+        get_layer_set("preview-feature-signup")
+            .await
+            .unwrap()
+            .archived
+    );
+    assert!(
+        !get_layer_set("preview-feature-login")
+            .await
+            .unwrap()
+            .archived
+    );
+
+    let (status, _, body) = harness
+        .get(&layer_set_host(&harness, "preview-feature-login"), "/")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>New login</h1>");
+
+    let (status, _, body) = harness.get(&signup_host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Main</h1>");
+}
+
+#[tokio::test]
+async fn promote_layer() {
+    let harness = Harness::new().await;
+    let staging: models::LayerSetName = "production".parse().unwrap();
+    let live: models::LayerSetName = "live".parse().unwrap();
+
+    harness
+        .layer_service
+        .create_layer_set(
+            &harness.auth,
+            &models::CreateLayerSet {
+                name: live.clone(),
+                visibility: models::LayerSetVisibility::Public,
+                source: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let staging_layer_id = harness
+        .deploy(&[("index.html", b"<h1>Hello</h1>"), ("style.css", b"h1 {}")])
+        .await;
+
+    let layer = harness
+        .promotion_service
+        .promote_layer(&harness.auth, &staging, staging_layer_id, &live)
+        .await
+        .unwrap();
+    assert!(matches!(layer.status, models::LayerStatus::Ready));
+
+    let layer = harness
+        .layer_service
+        .get_layer(&harness.auth, &live, layer.id)
+        .await
+        .unwrap()
+        .unwrap();
+    match layer.source {
+        Some(models::LayerSource::Promotion {
+            layer_set_name,
+            layer_id,
+        }) => {
+            assert_eq!(layer_set_name, staging);
+            assert_eq!(layer_id, staging_layer_id);
+        }
+        source => panic!("unexpected source {source:?}"),
+    }
+
+    let (status, _, body) = harness.get(&layer_host(layer.id), "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Hello</h1>");
+
+    let list = models::ListLayerMembers::default();
+    let (staging_members, _) = harness
+        .member_service
+        .list_layer_members(&harness.auth, &staging, staging_layer_id, &list)
+        .await
+        .unwrap();
+    let (live_members, _) = harness
+        .member_service
+        .list_layer_members(&harness.auth, &live, layer.id, &list)
+        .await
+        .unwrap();
+    assert_eq!(
+        staging_members
+            .iter()
+            .map(|member| (&member.path, member.checksum, &member.headers))
+            .collect::<Vec<_>>(),
+        live_members
+            .iter()
+            .map(|member| (&member.path, member.checksum, &member.headers))
+            .collect::<Vec<_>>(),
+    );
+
+    // Layers only have members for the paths that changed, the rest is inherited.
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    let project_id = harness.auth.project_id().unwrap();
+    let incremental_layer = models::Layer {
+        project_id,
+        layer_set_name: staging.clone(),
+        id: models::LayerId::new().unwrap(),
+        status: models::LayerStatus::Ready,
+        source: None,
+    };
+    harness
+        .repository
+        .create_layer(&incremental_layer)
+        .await
+        .unwrap();
+    harness
+        .repository
+        .create_layer_members(&[models::LayerMember {
+            project_id,
+            layer_set_name: staging.clone(),
+            layer_id: incremental_layer.id,
+            path: "/style.css".into(),
+            checksum: models::FileChecksum::Deleted,
+            content_encoding_hint: models::ContentEncodingHint::Relative {
+                identity: 1,
+                gzip: 0,
+                zstd: 0,
+                brotli: 0,
+            },
+            headers: Default::default(),
+        }])
+        .await
+        .unwrap();
+
+    let incremental_promoted_layer = harness
+        .promotion_service
+        .promote_layer(&harness.auth, &staging, incremental_layer.id, &live)
+        .await
+        .unwrap();
+
+    let host = layer_host(incremental_promoted_layer.id);
+
+    let (status, _, body) = harness.get(&host, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Hello</h1>");
+
+    let (status, _, _) = harness.get(&host, "/style.css").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Layer sets with a source would replace the promoted layer with their next build.
+    let err = harness
+        .promotion_service
+        .promote_layer(&harness.auth, &live, layer.id, &staging)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::FailedPrecondition(_))
+    ));
+
+    let viewer = Authentication::Role {
+        project_id: harness.auth.project_id().unwrap(),
+        role: AuthenticationRole::Viewer,
+    };
+    let err = harness
+        .promotion_service
+        .promote_layer(&viewer, &staging, staging_layer_id, &live)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::NotAllowed)
+    ));
+
+    // Builds leave layers that are still being promoted to the promotion.
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    let pending_layer = models::Layer {
+        project_id: harness.auth.project_id().unwrap(),
+        layer_set_name: live.clone(),
+        id: models::LayerId::new().unwrap(),
+        status: models::LayerStatus::Building,
+        source: Some(models::LayerSource::Promotion {
+            layer_set_name: staging.clone(),
+            layer_id: staging_layer_id,
+        }),
+    };
+    harness
+        .repository
+        .create_layer(&pending_layer)
+        .await
+        .unwrap();
+
+    harness.build_service.build().await.unwrap();
+
+    let pending_layer = harness
+        .layer_service
+        .get_layer(&harness.auth, &live, pending_layer.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        pending_layer.status,
+        models::LayerStatus::Building
+    ));
+
+    let live_layer_set = harness
+        .layer_service
+        .get_layer_set(&harness.auth, &live)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(live_layer_set.build_status.current_layer_id.is_none());
+}
+
+fn random_host_key() -> models::GitHostKey {
+    models::GitHostKey::from_public_key(&models::Ed25519::generate().public_key())
+}
+
+#[tokio::test]
+async fn reject_http_token_without_https() {
+    let harness = Harness::new().await;
+
+    for repository_url in ["http://git.example.com/fairing/site.git", REPOSITORY_URL] {
+        let err = harness
+            .source_service
+            .create_source(
+                &harness.auth,
+                &models::CreateSource {
+                    name: "token".parse().unwrap(),
+                    kind: models::CreateSourceKind::Git {
+                        repository_url: repository_url.parse().unwrap(),
+                        http_token: Some("secret".parse().unwrap()),
+                    },
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RequestError>(),
+            Some(RequestError::InvalidArgument(_))
+        ));
+    }
+
+    harness
+        .source_service
+        .create_source(
+            &harness.auth,
+            &models::CreateSource {
+                name: "token".parse().unwrap(),
+                kind: models::CreateSourceKind::Git {
+                    repository_url: "https://git.example.com/fairing/site.git".parse().unwrap(),
+                    http_token: Some("secret".parse().unwrap()),
+                },
+            },
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn pin_host_key_on_first_refresh() {
+    const SELF_HOSTED_URL: &str = "git@git.example.com:fairing/site.git";
+
+    let harness = Harness::new().await;
+    let source_name: models::SourceName = "self-hosted".parse().unwrap();
+
+    harness
+        .source_service
+        .create_source(
+            &harness.auth,
+            &models::CreateSource {
+                name: source_name.clone(),
+                kind: models::CreateSourceKind::Git {
+                    repository_url: SELF_HOSTED_URL.parse().unwrap(),
+                    http_token: None,
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+    let host_key = random_host_key();
+    harness
+        .git_source
+        .set_host_key(SELF_HOSTED_URL, host_key.clone());
+    harness
+        .git_source
+        .commit(SELF_HOSTED_URL, "refs/heads/main", &[("index.html", b"hi")]);
+
+    harness
+        .source_service
+        .refresh_source(&harness.auth, &source_name)
+        .await
+        .unwrap();
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &source_name)
+        .await
+        .unwrap()
+        .unwrap();
+    let models::SourceKind::Git {
+        known_host_keys, ..
+    } = source.kind;
+    assert_eq!(known_host_keys, vec![host_key.clone()]);
+
+    // The server now presents another host key, nothing is fetched until it is pinned.
+    let new_host_key = random_host_key();
+    harness
+        .git_source
+        .set_host_key(SELF_HOSTED_URL, new_host_key.clone());
+
+    let res = harness
+        .source_service
+        .refresh_source(&harness.auth, &source_name)
+        .await;
+    assert!(res.is_err());
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &source_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        source.status,
+        models::SourceStatus::HostKeyMismatch { ref host_key } if host_key == &new_host_key
+    ));
+
+    harness
+        .source_service
+        .pin_host_keys(&harness.auth, &source_name, vec![new_host_key.clone()])
+        .await
+        .unwrap();
+
+    harness
+        .source_service
+        .refresh_source(&harness.auth, &source_name)
+        .await
+        .unwrap();
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &source_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(source.status, models::SourceStatus::Ok));
+    let models::SourceKind::Git {
+        known_host_keys, ..
+    } = source.kind;
+    assert_eq!(known_host_keys, vec![new_host_key]);
+}
+
+#[tokio::test]
+async fn reject_unknown_host_key_of_builtin_forge() {
+    let harness = Harness::new().await;
+    let source_name: models::SourceName = "site".parse().unwrap();
+
+    // The repository of the harness is on github.com, its host keys are built in.
+    harness
+        .git_source
+        .set_host_key(REPOSITORY_URL, random_host_key());
+    harness
+        .git_source
+        .commit(REPOSITORY_URL, "refs/heads/main", &[("index.html", b"hi")]);
+
+    let res = harness
+        .source_service
+        .refresh_source(&harness.auth, &source_name)
+        .await;
+    assert!(res.is_err());
+
+    let source = harness
+        .source_service
+        .get_source(&harness.auth, &source_name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        source.status,
+        models::SourceStatus::HostKeyMismatch { .. }
+    ));
+
+    let layer_set = harness
+        .layer_service
+        .get_layer_set(&harness.auth, &"production".parse().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(layer_set.build_status.last_layer_id.is_none());
+}
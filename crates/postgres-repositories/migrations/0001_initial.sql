@@ -0,0 +1,182 @@
+CREATE TABLE IF NOT EXISTS projects (
+    id UUID NOT NULL,
+    acme_dns_challenge_label TEXT NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS file_encryption_keys (
+    project_id UUID NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    key BYTEA NOT NULL,
+    PRIMARY KEY (project_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS sources (
+    project_id UUID NOT NULL,
+    name TEXT NOT NULL,
+
+    -- git source settings
+    git_repository_url TEXT,
+    git_ed25519_secret_key BYTEA,
+
+    PRIMARY KEY (project_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS layer_sets (
+    project_id UUID NOT NULL,
+    name TEXT NOT NULL,
+
+    visibility TEXT NOT NULL,
+
+    source_name TEXT,
+    source_git_ref TEXT,
+
+    last_layer_id UUID,
+
+    build_current_layer_id UUID,
+    build_last_layer_id UUID,
+
+    PRIMARY KEY (project_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS layer_sets_source_name ON layer_sets (project_id, source_name);
+
+-- Worker ids expire so that layers are picked up again if a worker dies, the same way that the
+-- ScyllaDB backend uses TTLs.
+CREATE TABLE IF NOT EXISTS layers (
+    project_id UUID NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    id UUID NOT NULL,
+
+    status TEXT NOT NULL,
+
+    build_worker_id UUID,
+    build_worker_expires_at TIMESTAMPTZ,
+    finalize_worker_id UUID,
+    finalize_worker_expires_at TIMESTAMPTZ,
+
+    source_git_commit TEXT,
+
+    PRIMARY KEY (project_id, layer_set_name, id)
+);
+
+CREATE INDEX IF NOT EXISTS layers_status ON layers (status);
+
+CREATE TABLE IF NOT EXISTS layer_changes (
+    project_id UUID NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    layer_id UUID NOT NULL,
+    worker_id UUID NOT NULL,
+
+    path TEXT NOT NULL,
+    checksum BYTEA NOT NULL,
+    content_encoding_hint BIGINT NOT NULL,
+    headers JSONB NOT NULL,
+
+    PRIMARY KEY (project_id, layer_set_name, layer_id, worker_id, path)
+);
+
+CREATE TABLE IF NOT EXISTS layer_members (
+    project_id UUID NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    layer_id UUID NOT NULL,
+
+    checksum BYTEA NOT NULL,
+    content_encoding_hint BIGINT NOT NULL,
+    headers JSONB NOT NULL,
+
+    PRIMARY KEY (project_id, layer_set_name, path, layer_id)
+);
+
+CREATE TABLE IF NOT EXISTS files (
+    project_id UUID NOT NULL,
+    checksum BYTEA NOT NULL,
+
+    length BIGINT NOT NULL,
+
+    PRIMARY KEY (project_id, checksum)
+);
+
+CREATE TABLE IF NOT EXISTS file_blobs (
+    project_id UUID NOT NULL,
+    checksum BYTEA NOT NULL,
+    "offset" BIGINT NOT NULL,
+
+    blob_checksum BYTEA NOT NULL,
+    blob_length BIGINT NOT NULL,
+
+    PRIMARY KEY (project_id, checksum, "offset")
+);
+
+-- The data is not set when it's kept in the blob store.
+CREATE TABLE IF NOT EXISTS blobs (
+    project_id UUID NOT NULL,
+    checksum BYTEA NOT NULL,
+
+    length BIGINT NOT NULL,
+    compression TEXT NOT NULL,
+    key_version INTEGER NOT NULL,
+    data BYTEA,
+
+    PRIMARY KEY (project_id, checksum)
+);
+
+CREATE TABLE IF NOT EXISTS domains (
+    fqdn TEXT NOT NULL,
+    project_id UUID NOT NULL,
+    kind TEXT NOT NULL,
+
+    layer_set_name TEXT,
+    layer_id UUID,
+
+    PRIMARY KEY (fqdn)
+);
+
+CREATE TABLE IF NOT EXISTS certificates (
+    project_id UUID NOT NULL,
+    name TEXT NOT NULL,
+
+    domains TEXT[] NOT NULL,
+
+    next_processing_time TIMESTAMPTZ,
+
+    keys BYTEA,
+
+    acme_order_url TEXT,
+    csr BYTEA,
+    csr_secret_key BYTEA,
+
+    PRIMARY KEY (project_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS certificates_next_processing_time
+    ON certificates (next_processing_time);
+
+CREATE TABLE IF NOT EXISTS validated_domains (
+    fqdn TEXT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (fqdn)
+);
+
+CREATE TABLE IF NOT EXISTS acme_challenges (
+    acme_dns_challenge_label TEXT NOT NULL,
+    dns_01_token TEXT NOT NULL,
+    project_id UUID NOT NULL,
+    certificate_name TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (acme_dns_challenge_label, dns_01_token)
+);
+
+CREATE TABLE IF NOT EXISTS build_queue_messages (
+    id UUID NOT NULL,
+
+    worker_id UUID,
+    worker_expires_at TIMESTAMPTZ,
+
+    project_id UUID NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    layer_id UUID NOT NULL,
+
+    PRIMARY KEY (id)
+);
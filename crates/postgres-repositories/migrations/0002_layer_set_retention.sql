@@ -0,0 +1,8 @@
+CREATE TABLE layer_set_retention (
+    project_id UUID NOT NULL,
+    layer_set_name TEXT NOT NULL,
+
+    keep_ready_layers INTEGER NOT NULL,
+
+    PRIMARY KEY (project_id, layer_set_name)
+);
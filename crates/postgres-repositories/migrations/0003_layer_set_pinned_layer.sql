@@ -0,0 +1 @@
+ALTER TABLE layer_sets ADD COLUMN pinned_layer_id UUID;
@@ -0,0 +1,2 @@
+ALTER TABLE layers ADD COLUMN source_layer_set_name TEXT;
+ALTER TABLE layers ADD COLUMN source_layer_id UUID;
@@ -0,0 +1,2 @@
+ALTER TABLE layer_sets ADD COLUMN source_git_root TEXT;
+ALTER TABLE layer_sets ADD COLUMN source_git_paths TEXT[];
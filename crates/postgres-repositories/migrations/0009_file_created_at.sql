@@ -0,0 +1,6 @@
+-- Files and blobs that were stored before this migration count as stored long ago, garbage
+-- collection may delete them as soon as no layer refers to them.
+ALTER TABLE files ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT 'epoch';
+ALTER TABLE files ALTER COLUMN created_at DROP DEFAULT;
+ALTER TABLE blobs ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT 'epoch';
+ALTER TABLE blobs ALTER COLUMN created_at DROP DEFAULT;
@@ -0,0 +1,626 @@
+//! Moves teams, sources, layer sets and files from the schema used by the legacy gRPC services.
+//!
+//! Teams become projects with the same id, the sources of a team become sources of the project
+//! and every layer set gets the files of its latest complete build as its first layer. Anything
+//! that already exists is left alone, so the migration can be run again if it fails halfway.
+
+use anyhow::{anyhow, Context as _, Result};
+use sqlx::{postgres::PgPool, FromRow};
+use std::path::PathBuf;
+use tokio::fs;
+use uuid::Uuid;
+
+use fairing_core2::{
+    models,
+    repositories::{
+        DomainRepository, FileRepository, LayerRepository, ProjectRepository, SourceRepository,
+    },
+    services::{file_headers, file_paths, FileWriter},
+};
+
+#[derive(Debug, FromRow)]
+struct Team {
+    id: Uuid,
+    name: String,
+}
+
+#[derive(Debug, FromRow)]
+struct Source {
+    name: String,
+    repository_url: String,
+    id_ed25519_secret_key: Option<Vec<u8>>,
+}
+
+#[derive(Debug, FromRow)]
+struct LayerSet {
+    id: Uuid,
+    name: String,
+    source_name: String,
+}
+
+#[derive(Debug, FromRow)]
+struct Build {
+    layer_id: Uuid,
+    source_reference: String,
+}
+
+#[derive(Debug, FromRow)]
+struct LayerMember {
+    path: String,
+    file_keyspace: Uuid,
+    file_checksum: Vec<u8>,
+}
+
+#[derive(Debug, FromRow)]
+struct FileChunk {
+    blob_checksum: Vec<u8>,
+    compression_algorithm: Option<i16>,
+}
+
+/// A team that has been moved to a project.
+#[derive(Debug)]
+pub struct MigratedTeam {
+    pub name: String,
+    pub project_id: models::ProjectId,
+}
+
+pub struct LegacyMigration {
+    pool: PgPool,
+    file_storage: PathBuf,
+    project_repository: &'static dyn ProjectRepository,
+    source_repository: &'static dyn SourceRepository,
+    layer_repository: &'static dyn LayerRepository,
+    file_repository: &'static dyn FileRepository,
+    domain_repository: &'static dyn DomainRepository,
+    worker_id: models::WorkerId,
+}
+
+impl LegacyMigration {
+    /// Connects to the legacy database, `file_storage` is the directory where the legacy
+    /// services kept their blobs.
+    pub async fn connect(
+        url: &str,
+        file_storage: impl Into<PathBuf>,
+        project_repository: &'static dyn ProjectRepository,
+        source_repository: &'static dyn SourceRepository,
+        layer_repository: &'static dyn LayerRepository,
+        file_repository: &'static dyn FileRepository,
+        domain_repository: &'static dyn DomainRepository,
+    ) -> Result<LegacyMigration> {
+        let pool = PgPool::connect(url)
+            .await
+            .context("connecting to legacy database")?;
+
+        Ok(LegacyMigration {
+            pool,
+            file_storage: file_storage.into(),
+            project_repository,
+            source_repository,
+            layer_repository,
+            file_repository,
+            domain_repository,
+            worker_id: models::WorkerId::new(),
+        })
+    }
+
+    pub async fn migrate(&self) -> Result<Vec<MigratedTeam>> {
+        let teams = sqlx::query_as::<_, Team>(
+            r"
+            SELECT id, name
+            FROM teams
+            ORDER BY name;
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut migrated_teams = Vec::with_capacity(teams.len());
+
+        for team in teams {
+            let project_id = self
+                .migrate_team(&team)
+                .await
+                .with_context(|| format!("migrating team {}", team.name))?;
+
+            migrated_teams.push(MigratedTeam {
+                name: team.name,
+                project_id,
+            });
+        }
+
+        Ok(migrated_teams)
+    }
+
+    async fn migrate_team(&self, team: &Team) -> Result<models::ProjectId> {
+        let project_id = models::ProjectId::from(team.id);
+
+        if self
+            .project_repository
+            .get_project(&project_id)
+            .await?
+            .is_none()
+        {
+            let acme_dns_challenge_label = hex::encode(&Uuid::new_v4().as_bytes()[..12]);
+
+            let project = models::Project {
+                id: project_id,
+                acme_dns_challenge_label,
+                file_encryption_keys: vec![models::FileEncryptionKey::generate(1)?],
+            };
+
+            self.project_repository
+                .create_or_update_project(&project)
+                .await?;
+
+            tracing::info!("created project {} for team {}", team.id, team.name);
+        }
+
+        let sources = sqlx::query_as::<_, Source>(
+            r"
+            SELECT sources.name, source_git.repository_url, source_git.id_ed25519_secret_key
+            FROM sources
+            JOIN source_git ON source_git.source_id = sources.id
+            WHERE sources.team_id = $1;
+            ",
+        )
+        .bind(team.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for source in sources {
+            let name = source.name.parse()?;
+
+            if self
+                .source_repository
+                .get_source(&project_id, &name)
+                .await?
+                .is_some()
+            {
+                continue;
+            }
+
+            let id_ed25519 = match source.id_ed25519_secret_key {
+                Some(secret_key) => models::Ed25519::from_row(secret_key),
+                None => models::Ed25519::generate(),
+            };
+
+            self.source_repository
+                .create_or_update_source(&models::Source {
+                    project_id,
+                    name,
+                    kind: models::SourceKind::Git {
+                        repository_url: source.repository_url.parse()?,
+                        id_ed25519,
+                        known_host_keys: vec![],
+                        http_token: None,
+                    },
+                    status: models::SourceStatus::Ok,
+                })
+                .await?;
+
+            tracing::info!("created source {} for team {}", source.name, team.name);
+        }
+
+        let layer_sets = sqlx::query_as::<_, LayerSet>(
+            r"
+            SELECT layer_sets.id, layer_sets.name, sources.name AS source_name
+            FROM layer_sets
+            JOIN sources ON sources.id = layer_sets.source_id
+            WHERE sources.team_id = $1;
+            ",
+        )
+        .bind(team.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for layer_set in layer_sets {
+            self.migrate_layer_set(project_id, &layer_set)
+                .await
+                .with_context(|| format!("migrating layer set {}", layer_set.name))?;
+        }
+
+        Ok(project_id)
+    }
+
+    async fn migrate_layer_set(
+        &self,
+        project_id: models::ProjectId,
+        legacy_layer_set: &LayerSet,
+    ) -> Result<()> {
+        // Legacy layer sets are named after the branch they're built from, with `:` instead of
+        // `/`, and are unique per source rather than per project.
+        let ref_ = legacy_layer_set.name.replace(':', "/");
+        let branch = ref_.strip_prefix("refs/heads/").unwrap_or(&ref_);
+        let name: models::LayerSetName =
+            format!("{}-{branch}", legacy_layer_set.source_name).parse()?;
+
+        self.layer_repository
+            .create_layer_set(&models::LayerSet {
+                project_id,
+                name: name.clone(),
+                visibility: models::LayerSetVisibility::Public,
+                source: Some(models::LayerSetSource {
+                    name: legacy_layer_set.source_name.parse()?,
+                    kind: models::LayerSetSourceKind::Git {
+                        ref_: ref_.clone(),
+                        root: String::new(),
+                        paths: vec![],
+                    },
+                }),
+                build_status: models::LayerSetBuildStatus {
+                    current_layer_id: None,
+                    last_layer_id: None,
+                },
+                pinned_layer_id: None,
+                template_name: None,
+                archived: false,
+            })
+            .await?;
+
+        let layer_set = self
+            .layer_repository
+            .get_layer_set(project_id, &name)
+            .await?
+            .ok_or_else(|| anyhow!("layer set was not created"))?;
+
+        if layer_set.build_status.last_layer_id.is_some() {
+            // The files have already been migrated.
+            return Ok(());
+        }
+
+        let build = sqlx::query_as::<_, Build>(
+            r"
+            SELECT layer_id, source_reference
+            FROM builds
+            WHERE layer_set_id = $1 AND status = 'complete'
+            ORDER BY created_time DESC
+            LIMIT 1;
+            ",
+        )
+        .bind(legacy_layer_set.id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let build = match build {
+            Some(build) => build,
+            None => return Ok(()),
+        };
+
+        let layer = models::Layer {
+            project_id,
+            layer_set_name: name,
+            id: models::LayerId::new()?,
+            status: models::LayerStatus::Building,
+            source: Some(models::LayerSource::Git {
+                commit: build.source_reference,
+            }),
+        };
+
+        self.layer_repository.create_layer(&layer).await?;
+
+        self.layer_repository
+            .set_last_layer_id(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.layer_repository
+            .try_set_current_build(layer.project_id, &layer.layer_set_name, layer.id)
+            .await?;
+
+        self.layer_repository
+            .build_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        let layer_members = sqlx::query_as::<_, LayerMember>(
+            r"
+            SELECT path, file_keyspace, file_checksum
+            FROM layer_members
+            WHERE layer_set_id = $1 AND layer_id = $2 AND file_checksum IS NOT NULL
+            ORDER BY path;
+            ",
+        )
+        .bind(legacy_layer_set.id)
+        .bind(build.layer_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut changes = vec![];
+
+        for layer_member in layer_members {
+            let checksum = self
+                .migrate_file(project_id, &layer_member)
+                .await
+                .with_context(|| format!("migrating file {}", layer_member.path))?;
+
+            let headers = file_headers(&layer_member.path);
+
+            for path in file_paths(layer_member.path) {
+                changes.push(models::LayerChange {
+                    project_id,
+                    layer_set_name: layer.layer_set_name.clone(),
+                    layer_id: layer.id,
+                    worker_id: self.worker_id,
+                    path,
+                    checksum,
+                    content_encoding_hint: models::ContentEncodingHint::Relative {
+                        identity: 1,
+                        gzip: 0,
+                        zstd: 0,
+                        brotli: 0,
+                    },
+                    headers: headers.clone(),
+                });
+            }
+        }
+
+        self.layer_repository.create_layer_changes(&changes).await?;
+
+        self.layer_repository
+            .finish_build(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        self.layer_repository
+            .finalize_layer(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        let layer_members = changes
+            .into_iter()
+            .map(|change| models::LayerMember {
+                project_id: change.project_id,
+                layer_set_name: change.layer_set_name,
+                layer_id: change.layer_id,
+                path: change.path,
+                checksum: change.checksum,
+                content_encoding_hint: change.content_encoding_hint,
+                headers: change.headers,
+            })
+            .collect::<Vec<_>>();
+
+        self.layer_repository
+            .create_layer_members(&layer_members)
+            .await?;
+
+        self.layer_repository
+            .finish_finalizing(
+                layer.project_id,
+                &layer.layer_set_name,
+                layer.id,
+                self.worker_id,
+            )
+            .await?;
+
+        self.domain_repository
+            .create_domain(models::Domain::layer_preview(&layer))
+            .await?;
+
+        tracing::info!(
+            "migrated layer set {} into layer {}",
+            legacy_layer_set.name,
+            layer.id.into_uuid()
+        );
+
+        Ok(())
+    }
+
+    /// Copies a legacy file into the file repository, returns its new checksum. The file is read
+    /// twice, once to find the checksum and once to store it if it doesn't already exist.
+    async fn migrate_file(
+        &self,
+        project_id: models::ProjectId,
+        layer_member: &LayerMember,
+    ) -> Result<models::FileChecksum> {
+        let chunks = sqlx::query_as::<_, FileChunk>(
+            r"
+            SELECT file_chunks.blob_checksum, blobs.compression_algorithm
+            FROM file_chunks
+            JOIN blobs ON blobs.checksum = file_chunks.blob_checksum
+            WHERE file_chunks.file_keyspace = $1 AND file_chunks.file_checksum = $2
+            ORDER BY file_chunks.start_byte_offset;
+            ",
+        )
+        .bind(layer_member.file_keyspace)
+        .bind(&layer_member.file_checksum)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut hasher = models::FileChecksum::blake2b_hasher(project_id);
+        for chunk in &chunks {
+            hasher.update(&self.read_chunk(chunk).await?);
+        }
+
+        let checksum = hasher.finalize();
+
+        let file_exists = self
+            .file_repository
+            .get_file(project_id, &checksum)
+            .await?
+            .is_some();
+
+        if !file_exists {
+            let mut writer = FileWriter::new(self.file_repository, project_id);
+
+            for chunk in &chunks {
+                writer.write(&self.read_chunk(chunk).await?).await?;
+            }
+
+            writer.finish(&checksum).await?;
+        }
+
+        Ok(checksum)
+    }
+
+    async fn read_chunk(&self, chunk: &FileChunk) -> Result<Vec<u8>> {
+        let blob_checksum = hex::encode(&chunk.blob_checksum);
+
+        let mut path = self.file_storage.clone();
+        path.push(&blob_checksum[..2]);
+        path.push(&blob_checksum);
+
+        let data = fs::read(&path)
+            .await
+            .with_context(|| format!("reading legacy blob {blob_checksum}"))?;
+
+        match chunk.compression_algorithm {
+            None | Some(0) => Ok(data),
+            Some(1) => models::BlobCompression::Zstd.decompress(data),
+            Some(compression_algorithm) => Err(anyhow!(
+                "unknown compression algorithm {compression_algorithm}"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor as _;
+
+    use super::*;
+    use crate::{test_database, PostgresRepository};
+
+    async fn create_legacy_database(file_storage: &tempfile::TempDir) -> (PgPool, Uuid) {
+        let pool = test_database::create_pool().await;
+
+        pool.execute(include_str!(
+            "../../../fairing/migrations/postgres/0001_initial.sql"
+        ))
+        .await
+        .unwrap();
+
+        let team_id = Uuid::new_v4();
+        let file_keyspace_id = Uuid::new_v4();
+        let source_id = Uuid::new_v4();
+        let layer_set_id = Uuid::new_v4();
+        let layer_id = Uuid::new_v4();
+
+        let queries = [
+            format!("INSERT INTO file_keyspace VALUES ('{file_keyspace_id}', '\\x00');"),
+            format!(
+                "INSERT INTO teams VALUES ('{team_id}', 'acme', now(), '{file_keyspace_id}');"
+            ),
+            format!(
+                "INSERT INTO sources VALUES ('{source_id}', now(), 'web', '{team_id}', 'token');"
+            ),
+            format!(
+                "INSERT INTO source_git VALUES ('{source_id}', 'git@github.com:acme/web.git', NULL);"
+            ),
+            format!(
+                "INSERT INTO layer_sets VALUES ('{layer_set_id}', now(), 'refs:heads:main', '{source_id}');"
+            ),
+            format!(
+                "INSERT INTO builds VALUES ('{}', now(), 'build', '{layer_set_id}', '{layer_id}', 'complete', 'abc');",
+                Uuid::new_v4(),
+            ),
+            "INSERT INTO blobs VALUES ('\\xaa01', 0, 6, 6, 1, 14), ('\\xbb02', 0, 5, 5, 0, 0);"
+                .into(),
+            format!("INSERT INTO files VALUES ('{file_keyspace_id}', '\\xff', 11, true);"),
+            format!(
+                "INSERT INTO file_chunks VALUES ('{file_keyspace_id}', '\\xff', 0, 6, '\\xaa01'), ('{file_keyspace_id}', '\\xff', 6, 11, '\\xbb02');"
+            ),
+            format!(
+                "INSERT INTO layer_members VALUES ('{layer_set_id}', '{layer_id}', '/index.html', '{file_keyspace_id}', '\\xff');"
+            ),
+        ];
+
+        for query in queries {
+            pool.execute(query.as_str()).await.unwrap();
+        }
+
+        let compressed = zstd::encode_all(&b"hello "[..], 3).unwrap();
+        std::fs::create_dir_all(file_storage.path().join("aa")).unwrap();
+        std::fs::write(file_storage.path().join("aa/aa01"), compressed).unwrap();
+        std::fs::create_dir_all(file_storage.path().join("bb")).unwrap();
+        std::fs::write(file_storage.path().join("bb/bb02"), b"world").unwrap();
+
+        (pool, team_id)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs FAIRING_TEST_POSTGRES_URL"]
+    async fn migrate_legacy_team() {
+        let file_storage = tempfile::tempdir().unwrap();
+        let (pool, team_id) = create_legacy_database(&file_storage).await;
+
+        let repository: &'static PostgresRepository =
+            Box::leak(Box::new(test_database::create().await));
+
+        let migration = LegacyMigration {
+            pool,
+            file_storage: file_storage.path().into(),
+            project_repository: repository,
+            source_repository: repository,
+            layer_repository: repository,
+            file_repository: repository,
+            domain_repository: repository,
+            worker_id: models::WorkerId::new(),
+        };
+
+        let migrated_teams = migration.migrate().await.unwrap();
+        assert_eq!(migrated_teams.len(), 1);
+        assert_eq!(migrated_teams[0].name, "acme");
+
+        let project_id = migrated_teams[0].project_id;
+        assert_eq!(project_id.into_uuid(), team_id);
+
+        // Running it again doesn't change anything.
+        migration.migrate().await.unwrap();
+
+        let source = repository
+            .get_source(&project_id, &"web".parse().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let source = source.try_with_kind::<models::GitSource>().unwrap();
+        assert_eq!(
+            source.with.repository_url.as_str(),
+            "git@github.com:acme/web.git"
+        );
+
+        let layer_set_name = "web-main".parse().unwrap();
+        let layer_set = repository
+            .get_layer_set(project_id, &layer_set_name)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(layer_set.build_status.current_layer_id.is_none());
+        let layer_id = layer_set.build_status.last_layer_id.unwrap();
+
+        let layer_members = repository
+            .get_layer_member_summary(project_id, &layer_set_name, layer_id, &["/", "/index.html"])
+            .await
+            .unwrap();
+        assert_eq!(layer_members.len(), 2);
+        assert_eq!(layer_members[0].headers["content-type"], "text/html");
+
+        let data = repository
+            .get_file_chunks(project_id, layer_members[0].checksum, (0, u64::MAX >> 1))
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|chunk| chunk.data)
+            .collect::<Vec<_>>();
+        assert_eq!(data, b"hello world");
+
+        let layer = repository
+            .get_last_layer(project_id, &layer_set_name)
+            .await
+            .unwrap()
+            .unwrap();
+        let domain = models::Domain::layer_preview(&layer);
+        assert!(repository.get_domain(&domain.fqdn).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::{Context as _, Result};
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use std::str::FromStr as _;
+
+use fairing_core2::repositories::BlobStore;
+
+mod domains;
+mod files;
+mod layers;
+pub mod legacy;
+mod migrations;
+mod projects;
+mod queue;
+mod sources;
+
+/// Repository that keeps everything in PostgreSQL. Its tables live in a schema of their own, so
+/// they can share a database with the tables of the legacy services.
+pub struct PostgresRepository {
+    pool: PgPool,
+    blob_store: Option<Box<dyn BlobStore>>,
+}
+
+impl PostgresRepository {
+    /// Connects to the database at `url` and keeps every table in `schema`, which is created if
+    /// it doesn't exist yet.
+    pub async fn connect(url: &str, schema: &str) -> Result<PostgresRepository> {
+        let options = PgConnectOptions::from_str(url).context("parsing postgres url")?;
+
+        PostgresRepository::connect_with(options, schema).await
+    }
+
+    async fn connect_with(options: PgConnectOptions, schema: &str) -> Result<PostgresRepository> {
+        anyhow::ensure!(
+            is_schema_name(schema),
+            "schema {schema:?} must only contain lowercase letters, digits and underscores"
+        );
+
+        let pool = PgPoolOptions::new()
+            .connect_with(options.clone())
+            .await
+            .context("connecting to postgres")?;
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {schema};"))
+            .execute(&pool)
+            .await
+            .context("creating schema")?;
+        pool.close().await;
+
+        let pool = PgPoolOptions::new()
+            .connect_with(options.options([("search_path", schema)]))
+            .await
+            .context("connecting to postgres")?;
+
+        migrations::migrate(&pool).await?;
+
+        Ok(PostgresRepository {
+            pool,
+            blob_store: None,
+        })
+    }
+
+    /// Keeps the data of new blobs in `blob_store` instead of in PostgreSQL. Blobs that are
+    /// already stored in the database stay there until they are re-encrypted.
+    pub fn with_blob_store(mut self, blob_store: Box<dyn BlobStore>) -> PostgresRepository {
+        self.blob_store = Some(blob_store);
+        self
+    }
+}
+
+fn is_schema_name(schema: &str) -> bool {
+    schema.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && schema
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+pub(crate) mod test_database {
+    use sqlx::ConnectOptions as _;
+
+    use super::*;
+
+    /// Creates an empty database on the server pointed to by `FAIRING_TEST_POSTGRES_URL`.
+    pub(crate) async fn create_options() -> PgConnectOptions {
+        let url = std::env::var("FAIRING_TEST_POSTGRES_URL")
+            .expect("FAIRING_TEST_POSTGRES_URL must be set to run the postgres tests");
+
+        let options = PgConnectOptions::from_str(&url).unwrap();
+        let mut connection = options.connect().await.unwrap();
+
+        let database = format!("fairing_test_{}", uuid::Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE DATABASE {database};"))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+
+        options.database(&database)
+    }
+
+    pub(crate) async fn create_pool() -> PgPool {
+        PgPoolOptions::new()
+            .connect_with(create_options().await)
+            .await
+            .unwrap()
+    }
+
+    pub(crate) async fn create() -> PostgresRepository {
+        PostgresRepository::connect_with(create_options().await, "fairing")
+            .await
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor as _;
+
+    use super::*;
+
+    #[test]
+    fn schema_names() {
+        assert!(is_schema_name("fairing"));
+        assert!(is_schema_name("_fairing_2"));
+        assert!(!is_schema_name(""));
+        assert!(!is_schema_name("2fairing"));
+        assert!(!is_schema_name("Fairing"));
+        assert!(!is_schema_name("fairing; DROP TABLE files"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs FAIRING_TEST_POSTGRES_URL"]
+    async fn share_database_with_legacy_tables() {
+        let options = test_database::create_options().await;
+
+        let legacy_pool = PgPoolOptions::new()
+            .connect_with(options.clone())
+            .await
+            .unwrap();
+        legacy_pool
+            .execute(include_str!(
+                "../../../fairing/migrations/postgres/0001_initial.sql"
+            ))
+            .await
+            .unwrap();
+
+        let repository = PostgresRepository::connect_with(options, "fairing")
+            .await
+            .unwrap();
+
+        let tables: Vec<(String,)> = sqlx::query_as(
+            "SELECT table_schema FROM information_schema.tables WHERE table_name = 'layer_members' ORDER BY table_schema;",
+        )
+        .fetch_all(&repository.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            tables,
+            vec![("fairing".to_string(),), ("public".to_string(),)]
+        );
+
+        // The tables of the repository are created next to the legacy ones.
+        let projects: Vec<(uuid::Uuid,)> = sqlx::query_as("SELECT id FROM projects;")
+            .fetch_all(&repository.pool)
+            .await
+            .unwrap();
+        assert!(projects.is_empty());
+    }
+}
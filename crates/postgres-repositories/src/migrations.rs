@@ -0,0 +1,134 @@
+use anyhow::{Context as _, Result};
+use sqlx::postgres::PgPool;
+
+/// Schema migrations, applied in order. Every migration runs in a transaction together with the
+/// row in `schema_migrations` that records it, so it's applied once or not at all. The table is
+/// locked while a migration runs, servers that start at the same time wait for each other.
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (1, "initial", include_str!("../migrations/0001_initial.sql")),
+    (
+        2,
+        "layer_set_retention",
+        include_str!("../migrations/0002_layer_set_retention.sql"),
+    ),
+    (
+        3,
+        "layer_set_pinned_layer",
+        include_str!("../migrations/0003_layer_set_pinned_layer.sql"),
+    ),
+    (
+        4,
+        "layer_promotions",
+        include_str!("../migrations/0004_layer_promotions.sql"),
+    ),
+    (
+        5,
+        "source_host_keys",
+        include_str!("../migrations/0005_source_host_keys.sql"),
+    ),
+    (
+        6,
+        "source_http_token",
+        include_str!("../migrations/0006_source_http_token.sql"),
+    ),
+    (
+        7,
+        "layer_set_source_root",
+        include_str!("../migrations/0007_layer_set_source_root.sql"),
+    ),
+    (
+        8,
+        "ref_layer_sets",
+        include_str!("../migrations/0008_ref_layer_sets.sql"),
+    ),
+    (
+        9,
+        "file_created_at",
+        include_str!("../migrations/0009_file_created_at.sql"),
+    ),
+];
+
+/// Applies all migrations that aren't recorded in `schema_migrations` yet.
+pub(crate) async fn migrate(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL,
+
+            PRIMARY KEY (version)
+        );
+        ",
+    )
+    .execute(pool)
+    .await
+    .context("creating schema_migrations")?;
+
+    for &(version, name, sql) in MIGRATIONS {
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query("LOCK TABLE schema_migrations IN EXCLUSIVE MODE;")
+            .execute(&mut transaction)
+            .await?;
+
+        let applied = sqlx::query("SELECT version FROM schema_migrations WHERE version = $1;")
+            .bind(version)
+            .fetch_optional(&mut transaction)
+            .await?
+            .is_some();
+        if applied {
+            continue;
+        }
+
+        let statements = sql
+            .split_inclusive(';')
+            .map(|statement| statement.trim())
+            .filter(|statement| !statement.is_empty());
+
+        for statement in statements {
+            sqlx::query(statement)
+                .execute(&mut transaction)
+                .await
+                .with_context(|| format!("migration {version:04} {name}: {statement}"))?;
+        }
+
+        sqlx::query(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, now());",
+        )
+        .bind(version)
+        .bind(name)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_database;
+
+    #[tokio::test]
+    #[ignore = "needs FAIRING_TEST_POSTGRES_URL"]
+    async fn apply_migrations_once() {
+        let repository = test_database::create().await;
+        migrate(&repository.pool).await.unwrap();
+
+        let versions: Vec<(i32,)> =
+            sqlx::query_as("SELECT version FROM schema_migrations ORDER BY version;")
+                .fetch_all(&repository.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            versions,
+            MIGRATIONS
+                .iter()
+                .map(|&(version, _, _)| (version,))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::Result;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::QueueRepository};
+
+use crate::PostgresRepository;
+
+/// Messages that are not finished within this time are assigned to another worker.
+const ASSIGNMENT_TIMEOUT_SECONDS: i64 = 1800;
+
+#[derive(Debug, FromRow)]
+struct BuildQueueMessage {
+    id: Uuid,
+    project_id: Uuid,
+    layer_set_name: String,
+    layer_id: Uuid,
+}
+
+impl Into<models::BuildQueueMessage> for BuildQueueMessage {
+    fn into(self) -> models::BuildQueueMessage {
+        models::BuildQueueMessage {
+            id: self.id.into(),
+            project_id: self.project_id.into(),
+            layer_set_name: self.layer_set_name.parse().unwrap(),
+            layer_id: self.layer_id.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueRepository for PostgresRepository {
+    async fn queue_build(&self, message: &models::BuildQueueMessage) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO build_queue_messages (
+                id, project_id, layer_set_name, layer_id
+            )
+            VALUES ($1, $2, $3, $4);
+            ",
+        )
+        .bind(message.id.into_uuid())
+        .bind(message.project_id.into_uuid())
+        .bind(message.layer_set_name.as_str())
+        .bind(message.layer_id.into_uuid())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn assign_build(
+        &self,
+        worker_id: models::WorkerId,
+    ) -> Result<Option<models::BuildQueueMessage>> {
+        let message = sqlx::query_as::<_, BuildQueueMessage>(
+            r"
+            UPDATE build_queue_messages
+            SET worker_id = $1, worker_expires_at = now() + $2
+            WHERE id = (
+                SELECT id
+                FROM build_queue_messages
+                WHERE worker_id IS NULL OR worker_expires_at < now()
+                ORDER BY id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, project_id, layer_set_name, layer_id;
+            ",
+        )
+        .bind(worker_id.into_uuid())
+        .bind(chrono::Duration::seconds(ASSIGNMENT_TIMEOUT_SECONDS))
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(message)
+    }
+}
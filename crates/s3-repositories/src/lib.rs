@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Context as _, Result};
+use reqwest::{Method, StatusCode};
+use std::collections::BTreeMap;
+use url::Url;
+
+use fairing_core2::repositories::BlobStore;
+
+mod signing;
+
+pub struct S3Options {
+    /// For example `https://s3.eu-north-1.amazonaws.com` or `http://localhost:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Put the bucket in the path instead of the host name, this is usually needed for MinIO.
+    pub path_style: bool,
+    /// Prepended to all object keys.
+    pub prefix: String,
+}
+
+/// Keeps blobs in a bucket of any storage service that implements the S3 API.
+pub struct S3BlobStore {
+    client: reqwest::Client,
+    base_url: Url,
+    region: String,
+    credentials: signing::Credentials,
+}
+
+impl S3BlobStore {
+    pub fn new(options: S3Options) -> Result<S3BlobStore> {
+        let endpoint = Url::parse(&options.endpoint).context("parsing s3 endpoint")?;
+
+        let mut base_url = if options.path_style {
+            endpoint.join(&format!("{}/", options.bucket))?
+        } else {
+            let host = endpoint
+                .host_str()
+                .ok_or_else(|| anyhow!("s3 endpoint is missing a host"))?;
+
+            let mut base_url = endpoint.clone();
+            base_url.set_host(Some(&format!("{}.{host}", options.bucket)))?;
+            base_url
+        };
+
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let base_url = base_url.join(&signing::encode_path(&options.prefix))?;
+
+        Ok(S3BlobStore {
+            client: reqwest::Client::new(),
+            base_url,
+            region: options.region,
+            credentials: signing::Credentials {
+                access_key_id: options.access_key_id,
+                secret_access_key: options.secret_access_key,
+            },
+        })
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        Ok(self.base_url.join(&signing::encode_path(key))?)
+    }
+
+    async fn request(&self, method: Method, key: &str, body: Vec<u8>) -> Result<reqwest::Response> {
+        let url = self.object_url(key)?;
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(anyhow!("s3 url is missing a host")),
+        };
+
+        let time = chrono::Utc::now();
+        let payload_hash = signing::sha256_hex(&body);
+
+        let headers = BTreeMap::from([
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), signing::amz_date(time)),
+        ]);
+
+        let authorization = signing::authorization(
+            &self.credentials,
+            &self.region,
+            method.as_str(),
+            url.path(),
+            &headers,
+            &payload_hash,
+            time,
+        );
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("authorization", authorization);
+
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        Ok(request.body(body).send().await?)
+    }
+}
+
+async fn error_for_status(response: reqwest::Response) -> anyhow::Error {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    anyhow!("s3 request failed with {status}: {body}")
+}
+
+#[async_trait::async_trait]
+impl BlobStore for S3BlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, key, vec![]).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            _ => Err(error_for_status(response).await),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let response = self.request(Method::PUT, key, data).await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(error_for_status(response).await)
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self.request(Method::DELETE, key, vec![]).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            _ => Err(error_for_status(response).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use std::{
+        collections::HashMap,
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A minimal S3 stand-in that keeps objects in memory. It checks that requests are signed
+    /// and that the payload hash matches the body, but it doesn't verify signatures.
+    async fn handle(
+        objects: Objects,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+
+        let signed = parts
+            .headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("AWS4-HMAC-SHA256 Credential=test-key/"))
+            .unwrap_or(false);
+        let payload_hash = parts
+            .headers
+            .get("x-amz-content-sha256")
+            .and_then(|value| value.to_str().ok());
+
+        if !signed || payload_hash != Some(&signing::sha256_hex(&body)) {
+            let response = Response::builder().status(403).body(Body::empty());
+            return Ok(response.unwrap());
+        }
+
+        let path = parts.uri.path().to_string();
+        let mut objects = objects.lock().unwrap();
+
+        let response = match parts.method {
+            hyper::Method::GET => match objects.get(&path) {
+                Some(data) => Response::new(Body::from(data.clone())),
+                None => Response::builder().status(404).body(Body::empty()).unwrap(),
+            },
+            hyper::Method::PUT => {
+                objects.insert(path, body.to_vec());
+                Response::new(Body::empty())
+            }
+            hyper::Method::DELETE => {
+                objects.remove(&path);
+                Response::builder().status(204).body(Body::empty()).unwrap()
+            }
+            _ => Response::builder().status(405).body(Body::empty()).unwrap(),
+        };
+
+        Ok(response)
+    }
+
+    fn serve() -> (SocketAddr, Objects) {
+        let objects = Objects::default();
+
+        let service_objects = objects.clone();
+        let make_service = make_service_fn(move |_| {
+            let objects = service_objects.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(objects.clone(), request)))
+            }
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, objects)
+    }
+
+    fn options(addr: SocketAddr) -> S3Options {
+        S3Options {
+            endpoint: format!("http://{addr}"),
+            region: "us-east-1".into(),
+            bucket: "fairing".into(),
+            access_key_id: "test-key".into(),
+            secret_access_key: "test-secret".into(),
+            path_style: true,
+            prefix: "test/".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn put_get_and_delete_objects() {
+        let (addr, objects) = serve();
+        let blob_store = S3BlobStore::new(options(addr)).unwrap();
+
+        assert_eq!(blob_store.get("blobs/a/1").await.unwrap(), None);
+
+        blob_store
+            .put("blobs/a/1", b"hello world".to_vec())
+            .await
+            .unwrap();
+
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("/fairing/test/blobs/a/1"));
+        assert_eq!(
+            blob_store.get("blobs/a/1").await.unwrap(),
+            Some(b"hello world".to_vec())
+        );
+
+        blob_store.delete("blobs/a/1").await.unwrap();
+        assert_eq!(blob_store.get("blobs/a/1").await.unwrap(), None);
+
+        // Deleting a missing object is not an error.
+        blob_store.delete("blobs/a/1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_are_errors() {
+        let (addr, _objects) = serve();
+
+        let blob_store = S3BlobStore::new(S3Options {
+            access_key_id: "other-key".into(),
+            ..options(addr)
+        })
+        .unwrap();
+
+        assert!(blob_store.put("blobs/a/1", vec![1, 2, 3]).await.is_err());
+        assert!(blob_store.get("blobs/a/1").await.is_err());
+    }
+
+    #[test]
+    fn virtual_hosted_style_urls() {
+        let blob_store = S3BlobStore::new(S3Options {
+            endpoint: "https://s3.eu-north-1.amazonaws.com".into(),
+            path_style: false,
+            prefix: "".into(),
+            ..options("127.0.0.1:9000".parse().unwrap())
+        })
+        .unwrap();
+
+        assert_eq!(
+            blob_store.object_url("blobs/a/1").unwrap().as_str(),
+            "https://fairing.s3.eu-north-1.amazonaws.com/blobs/a/1"
+        );
+    }
+}
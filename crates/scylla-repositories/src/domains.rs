@@ -15,6 +15,55 @@ use crate::{
     ScyllaRepository,
 };
 
+#[derive(Debug, FromRow)]
+struct Domain {
+    fqdn: String,
+    project_id: Uuid,
+    kind: String,
+    layer_set_name: Option<String>,
+    layer_id: Option<Uuid>,
+}
+
+impl Into<models::Domain> for Domain {
+    fn into(self) -> models::Domain {
+        let kind = match (self.kind.as_str(), self.layer_set_name, self.layer_id) {
+            ("layer", Some(layer_set_name), Some(layer_id)) => models::DomainKind::Layer {
+                layer_set_name: layer_set_name.parse().unwrap(),
+                layer_id: layer_id.into(),
+            },
+            ("layer_set", Some(layer_set_name), None) => models::DomainKind::LayerSet {
+                layer_set_name: layer_set_name.parse().unwrap(),
+            },
+            ("wildcard_private", _, _) => models::DomainKind::WildCard {
+                kind: models::WildCardKind::Private,
+            },
+            ("wildcard_public", _, _) => models::DomainKind::WildCard {
+                kind: models::WildCardKind::Public,
+            },
+            _ => unreachable!("unknown domain kind"),
+        };
+
+        models::Domain {
+            project_id: self.project_id.into(),
+            fqdn: self.fqdn,
+            kind,
+        }
+    }
+}
+
+fn domain_kind_to_str(kind: &models::DomainKind) -> &'static str {
+    match kind {
+        models::DomainKind::Layer { .. } => "layer",
+        models::DomainKind::LayerSet { .. } => "layer_set",
+        models::DomainKind::WildCard {
+            kind: models::WildCardKind::Private,
+        } => "wildcard_private",
+        models::DomainKind::WildCard {
+            kind: models::WildCardKind::Public,
+        } => "wildcard_public",
+    }
+}
+
 #[derive(Debug, FromRow)]
 struct Certificate {
     project_id: Uuid,
@@ -72,6 +121,12 @@ impl Into<models::ValidatedDomain> for ValidatedDomain {
 }
 
 pub(crate) struct Statements {
+    create_domain: PreparedStatement,
+    get_domain: PreparedStatement,
+    index_domain: PreparedStatement,
+    list_domains: PreparedStatement,
+    delete_domain: PreparedStatement,
+    delete_domain_index: PreparedStatement,
     create_certificate: PreparedStatement,
     get_certificate: PreparedStatement,
     update_certificate: PreparedStatement,
@@ -85,6 +140,73 @@ pub(crate) struct Statements {
 
 impl Statements {
     pub(crate) async fn prepare(session: &Session) -> Result<Statements> {
+        let mut create_domain = session
+            .prepare(
+                r"
+                INSERT INTO domain_routes (
+                    fqdn, bucket, project_id, kind, layer_set_name, layer_id
+                )
+                VALUES (?, ?, ?, ?, ?, ?)
+                IF NOT EXISTS;
+                ",
+            )
+            .await?;
+        create_domain.set_serial_consistency(Some(SerialConsistency::Serial));
+
+        let mut get_domain = session
+            .prepare(
+                r"
+                SELECT fqdn, project_id, kind, layer_set_name, layer_id
+                FROM domain_routes
+                WHERE fqdn = ? AND bucket = ?;
+                ",
+            )
+            .await?;
+        get_domain.set_consistency(Consistency::LocalQuorum);
+
+        let mut index_domain = session
+            .prepare(
+                r"
+                INSERT INTO domain_routes_by_project (
+                    project_id, bucket, fqdn, kind, layer_set_name, layer_id
+                )
+                VALUES (?, ?, ?, ?, ?, ?);
+                ",
+            )
+            .await?;
+        index_domain.set_consistency(Consistency::EachQuorum);
+
+        let mut list_domains = session
+            .prepare(
+                r"
+                SELECT fqdn, project_id, kind, layer_set_name, layer_id
+                FROM domain_routes_by_project
+                WHERE project_id = ? AND bucket = ?;
+                ",
+            )
+            .await?;
+        list_domains.set_consistency(Consistency::LocalQuorum);
+
+        let mut delete_domain = session
+            .prepare(
+                r"
+                DELETE FROM domain_routes
+                WHERE fqdn = ? AND bucket = ?;
+                ",
+            )
+            .await?;
+        delete_domain.set_consistency(Consistency::EachQuorum);
+
+        let mut delete_domain_index = session
+            .prepare(
+                r"
+                DELETE FROM domain_routes_by_project
+                WHERE project_id = ? AND bucket = ? AND fqdn = ?;
+                ",
+            )
+            .await?;
+        delete_domain_index.set_consistency(Consistency::EachQuorum);
+
         let mut create_certificate = session
             .prepare(
                 r"
@@ -188,6 +310,12 @@ impl Statements {
             .await?;
 
         Ok(Statements {
+            create_domain,
+            get_domain,
+            index_domain,
+            list_domains,
+            delete_domain,
+            delete_domain_index,
             create_certificate,
             get_certificate,
             update_certificate,
@@ -204,7 +332,108 @@ impl Statements {
 #[async_trait::async_trait]
 impl DomainRepository for ScyllaRepository {
     async fn create_domain(&self, domain: models::Domain) -> Result<()> {
-        todo!();
+        let (layer_set_name, layer_id) = match domain.kind {
+            models::DomainKind::Layer {
+                ref layer_set_name,
+                layer_id,
+            } => (Some(layer_set_name.as_str()), Some(layer_id.into_uuid())),
+            models::DomainKind::LayerSet { ref layer_set_name } => {
+                (Some(layer_set_name.as_str()), None)
+            }
+            models::DomainKind::WildCard { .. } => (None, None),
+        };
+
+        let row = self
+            .session
+            .execute(
+                &self.domain_statements.create_domain,
+                (
+                    &domain.fqdn,
+                    0_i64,
+                    domain.project_id.into_uuid(),
+                    domain_kind_to_str(&domain.kind),
+                    layer_set_name,
+                    layer_id,
+                ),
+            )
+            .await?
+            .first_row()?;
+
+        // ScyllaDB returns the existing row next to `[applied]`, so the row can't be read as a tuple.
+        let applied = row
+            .columns
+            .first()
+            .and_then(|applied| applied.as_ref())
+            .and_then(|applied| applied.as_boolean());
+
+        ensure!(applied == Some(true), "domain already exists");
+
+        self.session
+            .execute(
+                &self.domain_statements.index_domain,
+                (
+                    domain.project_id.into_uuid(),
+                    0_i64,
+                    &domain.fqdn,
+                    domain_kind_to_str(&domain.kind),
+                    layer_set_name,
+                    layer_id,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_domain(&self, fqdn: &str) -> Result<Option<models::Domain>> {
+        let domain = self
+            .session
+            .execute(&self.domain_statements.get_domain, (fqdn, 0_i64))
+            .await?
+            .maybe_first_row_typed::<Domain>()?
+            .map(Into::into);
+
+        Ok(domain)
+    }
+
+    async fn list_domains(&self, project_id: models::ProjectId) -> Result<Vec<models::Domain>> {
+        let domains = self
+            .session
+            .execute(
+                &self.domain_statements.list_domains,
+                (project_id.into_uuid(), 0_i64),
+            )
+            .await?
+            .rows_typed()?
+            .map(|row| {
+                let row: Domain = row?;
+                Ok(row.into())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(domains)
+    }
+
+    async fn delete_domain(&self, fqdn: &str) -> Result<()> {
+        let domain = match self.get_domain(fqdn).await? {
+            Some(domain) => domain,
+            None => return Ok(()),
+        };
+
+        self.session
+            .execute(&self.domain_statements.delete_domain, (fqdn, 0_i64))
+            .await?;
+
+        // Domains are listed from the index, it goes last so that a domain that still routes
+        // requests is always listed.
+        self.session
+            .execute(
+                &self.domain_statements.delete_domain_index,
+                (domain.project_id.into_uuid(), 0_i64, fqdn),
+            )
+            .await?;
+
+        Ok(())
     }
 
     async fn create_certificate(
@@ -1,19 +1,74 @@
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use scylla::{
-    frame::value::MaybeUnset, prepared_statement::PreparedStatement, statement::Consistency,
+    frame::value::{MaybeUnset, Timestamp},
+    prepared_statement::PreparedStatement,
+    statement::{Consistency, SerialConsistency},
     FromRow, Session,
 };
 use uuid::Uuid;
 
-use fairing_core2::{models, repositories::FileRepository};
+use fairing_core2::{
+    models,
+    repositories::{FileRepository, ProjectRepository},
+};
+
+use crate::{
+    time::{from_timestamp, to_timestamp},
+    ScyllaRepository,
+};
+
+/// Every file is split into partitions of this many bytes, so that large files don't end up in
+/// a single huge partition.
+const BUCKET_SIZE: u64 = 67_108_864;
+
+/// Returns the bucket that the byte at `offset` is stored in.
+fn bucket_for_offset(offset: u64) -> i64 {
+    (offset / BUCKET_SIZE) as i64
+}
+
+/// Returns the number of buckets needed to store a file of `length` bytes.
+/// Empty files still use the first bucket to store their metadata.
+fn bucket_count(length: u64) -> i64 {
+    ((length + BUCKET_SIZE - 1) / BUCKET_SIZE).max(1) as i64
+}
+
+/// Returns the first and last bucket that contain blobs starting within the byte range.
+fn bucket_range((range_start, range_end): (u64, u64)) -> (i64, i64) {
+    let first_bucket = bucket_for_offset(range_start);
+    let last_bucket = bucket_for_offset(range_end.max(range_start + 1) - 1);
+
+    (first_bucket, last_bucket)
+}
 
-use crate::ScyllaRepository;
+/// Files and blobs stored before their creation time was kept count as stored long ago.
+fn created_at(created_at: Option<Timestamp>) -> DateTime<Utc> {
+    created_at
+        .as_ref()
+        .map(from_timestamp)
+        .unwrap_or_else(|| Utc.timestamp_millis_opt(0).unwrap())
+}
 
 #[derive(Debug, FromRow)]
 struct File {
     project_id: Uuid,
     checksum: Vec<u8>,
     length: Option<i64>,
+    buckets: Option<i64>,
+    created_at: Option<Timestamp>,
+}
+
+impl File {
+    /// Files are only complete once the length and the number of buckets have been set. Files
+    /// written before the bucket count was stored only have a length, their chunks are kept
+    /// inline in the rows.
+    fn is_complete(&self) -> bool {
+        match (self.length, self.buckets) {
+            (Some(length), Some(buckets)) => bucket_count(length as u64) == buckets,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Into<models::File> for File {
@@ -22,32 +77,144 @@ impl Into<models::File> for File {
             project_id: self.project_id.into(),
             checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
             length: self.length.unwrap() as u64,
+            created_at: created_at(self.created_at),
         }
     }
 }
 
 #[derive(Debug, FromRow)]
-struct FileChunk {
+struct FileBlob {
     length: i64,
     offset: i64,
-    data: Vec<u8>,
+    blob_checksum: Option<Vec<u8>>,
+    blob_length: Option<i64>,
+    /// Unencrypted chunk of a file written before blobs were introduced.
+    data: Option<Vec<u8>>,
 }
 
-impl Into<models::FileChunk> for FileChunk {
-    fn into(self) -> models::FileChunk {
-        models::FileChunk {
-            total_length: self.length as u64,
-            offset: self.offset as u64,
-            data: self.data,
+#[derive(Debug, FromRow)]
+struct BlobMetadata {
+    length: i64,
+    compression: String,
+    created_at: Option<Timestamp>,
+}
+
+impl BlobMetadata {
+    fn into_model(
+        self,
+        project_id: models::ProjectId,
+        checksum: models::BlobChecksum,
+    ) -> models::Blob {
+        models::Blob {
+            project_id,
+            checksum,
+            length: self.length as u64,
+            compression: compression_from_str(&self.compression),
+            created_at: created_at(self.created_at),
         }
     }
 }
 
+#[derive(Debug, FromRow)]
+struct ListedBlob {
+    project_id: Uuid,
+    checksum: Vec<u8>,
+    length: i64,
+    compression: String,
+    created_at: Option<Timestamp>,
+}
+
+impl Into<models::Blob> for ListedBlob {
+    fn into(self) -> models::Blob {
+        models::Blob {
+            project_id: self.project_id.into(),
+            checksum: models::BlobChecksum::decode(&self.checksum).unwrap(),
+            length: self.length as u64,
+            compression: compression_from_str(&self.compression),
+            created_at: created_at(self.created_at),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct Blob {
+    compression: String,
+    key_version: i32,
+    /// Not set when the data is kept in the blob store.
+    data: Option<Vec<u8>>,
+}
+
+impl Blob {
+    /// Decrypts the blob, the data is still compressed.
+    fn decrypt(
+        &self,
+        project: &models::Project,
+        checksum: &models::BlobChecksum,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let key = project
+            .file_encryption_key(self.key_version as u32)
+            .ok_or_else(|| anyhow!("file encryption key {} not found", self.key_version))?;
+
+        let associated_data = models::FileEncryptionKey::blob_associated_data(project.id, checksum);
+        key.decrypt(&associated_data, ciphertext)
+    }
+}
+
+/// Key of a blob in the blob store. The key version is part of the key so that re-encrypting a
+/// blob never overwrites data that the stored metadata still refers to.
+fn blob_store_key(
+    project_id: models::ProjectId,
+    checksum: &models::BlobChecksum,
+    key_version: i32,
+) -> String {
+    format!(
+        "blobs/{}/{}/{key_version}",
+        project_id.into_uuid().as_hyphenated(),
+        checksum.to_hex()
+    )
+}
+
+#[derive(Debug, FromRow)]
+struct BlobKeyVersion {
+    checksum: Vec<u8>,
+    key_version: i32,
+}
+
+fn compression_to_str(compression: models::BlobCompression) -> &'static str {
+    match compression {
+        models::BlobCompression::Identity => "identity",
+        models::BlobCompression::Zstd => "zstd",
+    }
+}
+
+fn compression_from_str(compression: &str) -> models::BlobCompression {
+    match compression {
+        "identity" => models::BlobCompression::Identity,
+        "zstd" => models::BlobCompression::Zstd,
+        _ => unreachable!("unknown blob compression"),
+    }
+}
+
 pub(crate) struct Statements {
     get_file: PreparedStatement,
-    get_file_chunks: PreparedStatement,
-    create_chunk: PreparedStatement,
+    list_files: PreparedStatement,
+    get_file_blobs: PreparedStatement,
+    create_file_blob: PreparedStatement,
     finish_file: PreparedStatement,
+    index_file: PreparedStatement,
+    delete_file_bucket: PreparedStatement,
+    delete_file_index: PreparedStatement,
+    get_blob_metadata: PreparedStatement,
+    get_blob: PreparedStatement,
+    create_blob: PreparedStatement,
+    index_blob: PreparedStatement,
+    list_blobs: PreparedStatement,
+    delete_blob: PreparedStatement,
+    delete_blob_index: PreparedStatement,
+    list_blob_key_versions: PreparedStatement,
+    reencrypt_blob: PreparedStatement,
+    reindex_blob: PreparedStatement,
 }
 
 impl Statements {
@@ -55,7 +222,7 @@ impl Statements {
         let mut get_file = session
             .prepare(
                 r"
-                    SELECT project_id, checksum, length
+                    SELECT project_id, checksum, length, buckets, created_at
                     FROM files
                     WHERE project_id = ? AND checksum = ? AND bucket = ?;
                     ",
@@ -63,44 +230,214 @@ impl Statements {
             .await?;
         get_file.set_consistency(Consistency::LocalQuorum);
 
-        let mut get_file_chunks = session
+        let mut list_files = session
             .prepare(
                 r"
-                SELECT length, offset, data
+                SELECT project_id, checksum, length, buckets, created_at
+                FROM files_by_project
+                WHERE project_id = ? AND bucket = 0;
+                ",
+            )
+            .await?;
+        list_files.set_consistency(Consistency::LocalQuorum);
+
+        let mut get_file_blobs = session
+            .prepare(
+                r"
+                SELECT length, offset, blob_checksum, blob_length, data
                 FROM files
                 WHERE project_id = ? AND checksum = ? AND bucket = ?
                     AND offset >= ? AND offset < ?;
                 ",
             )
             .await?;
-        get_file_chunks.set_consistency(Consistency::LocalQuorum);
+        get_file_blobs.set_consistency(Consistency::LocalQuorum);
 
-        let mut create_chunk = session
+        let mut create_file_blob = session
             .prepare(
                 r"
-                INSERT INTO files (project_id, checksum, bucket, length, offset, data)
-                VALUES (?, ?, ?, ?, ?, ?);
+                INSERT INTO files (
+                    project_id, checksum, bucket, length, offset, blob_checksum, blob_length
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?);
                 ",
             )
             .await?;
-        create_chunk.set_consistency(Consistency::EachQuorum);
+        create_file_blob.set_consistency(Consistency::EachQuorum);
 
         let mut finish_file = session
             .prepare(
                 r"
                 UPDATE files
-                SET length = ?
+                SET length = ?, buckets = ?, created_at = ?
                 WHERE project_id = ? AND checksum = ? AND bucket = 0;
                 ",
             )
             .await?;
         finish_file.set_consistency(Consistency::EachQuorum);
 
+        let mut index_file = session
+            .prepare(
+                r"
+                INSERT INTO files_by_project (
+                    project_id, bucket, checksum, length, buckets, created_at
+                )
+                VALUES (?, 0, ?, ?, ?, ?);
+                ",
+            )
+            .await?;
+        index_file.set_consistency(Consistency::EachQuorum);
+
+        let mut delete_file_bucket = session
+            .prepare(
+                r"
+                DELETE FROM files
+                WHERE project_id = ? AND checksum = ? AND bucket = ?;
+                ",
+            )
+            .await?;
+        delete_file_bucket.set_consistency(Consistency::EachQuorum);
+
+        let mut delete_file_index = session
+            .prepare(
+                r"
+                DELETE FROM files_by_project
+                WHERE project_id = ? AND bucket = 0 AND checksum = ?;
+                ",
+            )
+            .await?;
+        delete_file_index.set_consistency(Consistency::EachQuorum);
+
+        let mut get_blob_metadata = session
+            .prepare(
+                r"
+                SELECT length, compression, created_at
+                FROM blobs
+                WHERE project_id = ? AND checksum = ?;
+                ",
+            )
+            .await?;
+        get_blob_metadata.set_consistency(Consistency::LocalQuorum);
+
+        let mut get_blob = session
+            .prepare(
+                r"
+                SELECT compression, key_version, data
+                FROM blobs
+                WHERE project_id = ? AND checksum = ?;
+                ",
+            )
+            .await?;
+        get_blob.set_consistency(Consistency::LocalQuorum);
+
+        let mut create_blob = session
+            .prepare(
+                r"
+                INSERT INTO blobs (
+                    project_id, checksum, length, compression, key_version, data, created_at
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?);
+                ",
+            )
+            .await?;
+        create_blob.set_consistency(Consistency::EachQuorum);
+
+        let mut index_blob = session
+            .prepare(
+                r"
+                INSERT INTO blobs_by_project (
+                    project_id, bucket, checksum, length, compression, key_version, created_at
+                )
+                VALUES (?, 0, ?, ?, ?, ?, ?);
+                ",
+            )
+            .await?;
+        index_blob.set_consistency(Consistency::EachQuorum);
+
+        let mut list_blobs = session
+            .prepare(
+                r"
+                SELECT project_id, checksum, length, compression, created_at
+                FROM blobs_by_project
+                WHERE project_id = ? AND bucket = 0;
+                ",
+            )
+            .await?;
+        list_blobs.set_consistency(Consistency::LocalQuorum);
+
+        let mut delete_blob = session
+            .prepare(
+                r"
+                DELETE FROM blobs
+                WHERE project_id = ? AND checksum = ?;
+                ",
+            )
+            .await?;
+        delete_blob.set_consistency(Consistency::EachQuorum);
+
+        let mut delete_blob_index = session
+            .prepare(
+                r"
+                DELETE FROM blobs_by_project
+                WHERE project_id = ? AND bucket = 0 AND checksum = ?;
+                ",
+            )
+            .await?;
+        delete_blob_index.set_consistency(Consistency::EachQuorum);
+
+        let mut list_blob_key_versions = session
+            .prepare(
+                r"
+                SELECT checksum, key_version
+                FROM blobs_by_project
+                WHERE project_id = ? AND bucket = 0;
+                ",
+            )
+            .await?;
+        list_blob_key_versions.set_consistency(Consistency::LocalQuorum);
+
+        let mut reencrypt_blob = session
+            .prepare(
+                r"
+                UPDATE blobs
+                SET key_version = ?, data = ?
+                WHERE project_id = ? AND checksum = ?
+                IF key_version = ?;
+                ",
+            )
+            .await?;
+        reencrypt_blob.set_serial_consistency(Some(SerialConsistency::Serial));
+
+        let mut reindex_blob = session
+            .prepare(
+                r"
+                UPDATE blobs_by_project
+                SET key_version = ?
+                WHERE project_id = ? AND bucket = 0 AND checksum = ?;
+                ",
+            )
+            .await?;
+        reindex_blob.set_consistency(Consistency::EachQuorum);
+
         Ok(Statements {
             get_file,
-            get_file_chunks,
-            create_chunk,
+            list_files,
+            get_file_blobs,
+            create_file_blob,
             finish_file,
+            index_file,
+            delete_file_bucket,
+            delete_file_index,
+            get_blob_metadata,
+            get_blob,
+            create_blob,
+            index_blob,
+            list_blobs,
+            delete_blob,
+            delete_blob_index,
+            list_blob_key_versions,
+            reencrypt_blob,
+            reindex_blob,
         })
     }
 }
@@ -120,66 +457,368 @@ impl FileRepository for ScyllaRepository {
             )
             .await?
             .maybe_first_row_typed::<File>()?
-            .and_then(|file| file.length.map(|_| file))
+            .filter(File::is_complete)
             .map(Into::into);
 
         Ok(file)
     }
 
-    async fn get_file_chunks(
+    async fn list_files(&self, project_id: models::ProjectId) -> Result<Vec<models::File>> {
+        let files = self
+            .session
+            .execute(&self.file_statements.list_files, (project_id.into_uuid(),))
+            .await?
+            .rows_typed::<File>()?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(File::is_complete)
+            .map(Into::into)
+            .collect();
+
+        Ok(files)
+    }
+
+    async fn create_file(
         &self,
         project_id: models::ProjectId,
-        checksum: models::FileChecksum,
-        (range_start, range_end): (u64, u64),
-    ) -> Result<Vec<models::FileChunk>> {
-        let chunks = self
-            .session
+        checksum: &models::FileChecksum,
+        length: u64,
+        blobs: &[models::FileBlob],
+    ) -> Result<()> {
+        let blobs_length = blobs.iter().map(|blob| blob.length).sum::<u64>();
+        ensure!(
+            blobs_length == length,
+            "file blobs must cover the whole file"
+        );
+
+        let encoded_checksum = checksum.encode();
+
+        for blob in blobs {
+            let bucket = bucket_for_offset(blob.offset);
+
+            // The length is a static column, it's set for the first bucket when the file is
+            // finished so that the file isn't visible until all blobs have been added.
+            let file_length = if bucket == 0 {
+                MaybeUnset::Unset
+            } else {
+                MaybeUnset::Set(length as i64)
+            };
+
+            self.session
+                .execute(
+                    &self.file_statements.create_file_blob,
+                    (
+                        project_id.into_uuid(),
+                        &encoded_checksum,
+                        bucket,
+                        file_length,
+                        blob.offset as i64,
+                        blob.checksum.encode(),
+                        blob.length as i64,
+                    ),
+                )
+                .await?;
+        }
+
+        let created_at = to_timestamp(&Utc::now());
+
+        self.session
             .execute(
-                &self.file_statements.get_file_chunks,
+                &self.file_statements.finish_file,
                 (
+                    length as i64,
+                    bucket_count(length),
+                    created_at,
                     project_id.into_uuid(),
-                    checksum.encode(),
-                    0i64,
-                    range_start as i64,
-                    range_end as i64,
+                    &encoded_checksum,
                 ),
             )
-            .await?
-            .rows_typed()?
-            .map(|row| {
-                let chunk: FileChunk = row?;
-                Ok(chunk.into())
-            })
-            .collect::<Result<Vec<_>>>()?;
+            .await?;
+
+        self.session
+            .execute(
+                &self.file_statements.index_file,
+                (
+                    project_id.into_uuid(),
+                    &encoded_checksum,
+                    length as i64,
+                    bucket_count(length),
+                    created_at,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_file_chunks(
+        &self,
+        project_id: models::ProjectId,
+        checksum: models::FileChecksum,
+        range: (u64, u64),
+    ) -> Result<Vec<models::FileChunk>> {
+        let (range_start, range_end) = range;
+        let (first_bucket, range_last_bucket) = bucket_range(range);
+
+        // Legacy chunks are stored in the bucket of their end, which can be one past the bucket
+        // of their offset.
+        let mut last_bucket = range_last_bucket + 1;
+
+        let project = self.get_project_for_files(project_id).await?;
+        let encoded_checksum = checksum.encode();
+        let mut chunks = vec![];
+
+        let mut bucket = first_bucket;
+        while bucket <= last_bucket {
+            let file_blobs = self
+                .session
+                .execute(
+                    &self.file_statements.get_file_blobs,
+                    (
+                        project_id.into_uuid(),
+                        &encoded_checksum,
+                        bucket,
+                        range_start as i64,
+                        range_end as i64,
+                    ),
+                )
+                .await?
+                .rows_typed::<FileBlob>()?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Don't look for blobs in buckets past the end of the file.
+            if let Some(file_blob) = file_blobs.first() {
+                let length = file_blob.length as u64;
+                last_bucket = match file_blob.blob_checksum {
+                    Some(_) => range_last_bucket.min(bucket_count(length) - 1),
+                    None => last_bucket.min(bucket_for_offset(length)),
+                };
+            }
+
+            for file_blob in file_blobs {
+                let (encoded_blob_checksum, blob_length) =
+                    match (file_blob.blob_checksum, file_blob.blob_length) {
+                        (Some(blob_checksum), Some(blob_length)) => (blob_checksum, blob_length),
+                        _ => {
+                            let data = file_blob
+                                .data
+                                .ok_or_else(|| anyhow!("file row has neither a blob nor data"))?;
+
+                            chunks.push(models::FileChunk {
+                                total_length: file_blob.length as u64,
+                                offset: file_blob.offset as u64,
+                                data,
+                            });
+                            continue;
+                        }
+                    };
+
+                let blob_checksum = models::BlobChecksum::decode(&encoded_blob_checksum)?;
+
+                let blob = self
+                    .session
+                    .execute(
+                        &self.file_statements.get_blob,
+                        (project_id.into_uuid(), &encoded_blob_checksum),
+                    )
+                    .await?
+                    .maybe_first_row_typed::<Blob>()?
+                    .ok_or_else(|| anyhow!("blob {} not found", blob_checksum.to_hex()))?;
+
+                let ciphertext = self
+                    .blob_ciphertext(project_id, &blob_checksum, &blob)
+                    .await?;
+                let data = blob.decrypt(&project, &blob_checksum, &ciphertext)?;
+                let data = compression_from_str(&blob.compression).decompress(data)?;
+
+                ensure!(
+                    data.len() as u64 == blob_length as u64,
+                    "blob {} has the wrong length",
+                    blob_checksum.to_hex()
+                );
+
+                chunks.push(models::FileChunk {
+                    total_length: file_blob.length as u64,
+                    offset: file_blob.offset as u64,
+                    data,
+                });
+            }
+
+            bucket += 1;
+        }
 
         Ok(chunks)
     }
 
-    async fn create_chunk(
+    async fn list_file_blobs(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Vec<models::FileBlob>> {
+        let file = match self.get_file(project_id, checksum).await? {
+            Some(file) => file,
+            None => return Ok(vec![]),
+        };
+
+        let encoded_checksum = checksum.encode();
+        let mut file_blobs = vec![];
+
+        for bucket in 0..bucket_count(file.length) {
+            let rows = self
+                .session
+                .execute(
+                    &self.file_statements.get_file_blobs,
+                    (
+                        project_id.into_uuid(),
+                        &encoded_checksum,
+                        bucket,
+                        0i64,
+                        i64::MAX,
+                    ),
+                )
+                .await?
+                .rows_typed::<FileBlob>()?;
+
+            for row in rows {
+                // Legacy chunks are kept inline and don't refer to any blobs.
+                if let FileBlob {
+                    offset,
+                    blob_checksum: Some(blob_checksum),
+                    blob_length: Some(blob_length),
+                    ..
+                } = row?
+                {
+                    file_blobs.push(models::FileBlob {
+                        offset: offset as u64,
+                        checksum: models::BlobChecksum::decode(&blob_checksum)?,
+                        length: blob_length as u64,
+                    });
+                }
+            }
+        }
+
+        Ok(file_blobs)
+    }
+
+    async fn delete_file(
         &self,
         project_id: models::ProjectId,
         checksum: &models::FileChecksum,
-        length: u64,
-        offset: u64,
-        data: Vec<u8>,
     ) -> Result<()> {
-        let bucket = (offset + data.len() as u64) as i64 / 67_108_864;
-        let length = if bucket == 0 {
-            MaybeUnset::Unset
-        } else {
-            MaybeUnset::Set(length as i64)
+        let encoded_checksum = checksum.encode();
+
+        let file = self
+            .session
+            .execute(
+                &self.file_statements.get_file,
+                (project_id.into_uuid(), &encoded_checksum, 0i64),
+            )
+            .await?
+            .maybe_first_row_typed::<File>()?;
+
+        let buckets = match file {
+            Some(File {
+                buckets: Some(buckets),
+                ..
+            }) => buckets,
+            // Legacy chunks are stored in the bucket of their end.
+            Some(File {
+                length: Some(length),
+                ..
+            }) => bucket_for_offset(length as u64) + 1,
+            _ => 1,
+        };
+
+        // The first bucket goes first, that's where the file is looked up.
+        for bucket in 0..buckets {
+            self.session
+                .execute(
+                    &self.file_statements.delete_file_bucket,
+                    (project_id.into_uuid(), &encoded_checksum, bucket),
+                )
+                .await?;
+        }
+
+        // Files are listed from the index, it goes last so that files that weren't fully
+        // deleted are listed again.
+        self.session
+            .execute(
+                &self.file_statements.delete_file_index,
+                (project_id.into_uuid(), &encoded_checksum),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<Option<models::Blob>> {
+        let blob = self
+            .session
+            .execute(
+                &self.file_statements.get_blob_metadata,
+                (project_id.into_uuid(), checksum.encode()),
+            )
+            .await?
+            .maybe_first_row_typed::<BlobMetadata>()?
+            .map(|blob| blob.into_model(project_id, *checksum));
+
+        Ok(blob)
+    }
+
+    async fn create_blob(
+        &self,
+        project_id: models::ProjectId,
+        blob: &models::CreateBlob,
+    ) -> Result<()> {
+        let project = self.get_project_for_files(project_id).await?;
+        let key = project
+            .current_file_encryption_key()
+            .ok_or_else(|| anyhow!("project has no file encryption key"))?;
+
+        let associated_data =
+            models::FileEncryptionKey::blob_associated_data(project_id, &blob.checksum);
+        let data = key.encrypt(&associated_data, &blob.data)?;
+
+        let data = match self.blob_store {
+            Some(blob_store) => {
+                let blob_key = blob_store_key(project_id, &blob.checksum, key.version as i32);
+                blob_store.put(&blob_key, data).await?;
+                None
+            }
+            None => Some(data),
         };
 
+        let created_at = to_timestamp(&Utc::now());
+
         self.session
             .execute(
-                &self.file_statements.create_chunk,
+                &self.file_statements.create_blob,
                 (
                     project_id.into_uuid(),
-                    checksum.encode(),
-                    bucket,
-                    length,
-                    offset as i64,
+                    blob.checksum.encode(),
+                    blob.length as i64,
+                    compression_to_str(blob.compression),
+                    key.version as i32,
                     data,
+                    created_at,
+                ),
+            )
+            .await?;
+
+        self.session
+            .execute(
+                &self.file_statements.index_blob,
+                (
+                    project_id.into_uuid(),
+                    blob.checksum.encode(),
+                    blob.length as i64,
+                    compression_to_str(blob.compression),
+                    key.version as i32,
+                    created_at,
                 ),
             )
             .await?;
@@ -187,23 +826,283 @@ impl FileRepository for ScyllaRepository {
         Ok(())
     }
 
-    async fn finish_file(
+    async fn list_blobs(&self, project_id: models::ProjectId) -> Result<Vec<models::Blob>> {
+        let blobs = self
+            .session
+            .execute(&self.file_statements.list_blobs, (project_id.into_uuid(),))
+            .await?
+            .rows_typed()?
+            .map(|row| {
+                let row: ListedBlob = row?;
+                Ok(row.into())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(blobs)
+    }
+
+    async fn delete_blob(
         &self,
         project_id: models::ProjectId,
-        checksum: &models::FileChecksum,
-        length: u64,
+        checksum: &models::BlobChecksum,
     ) -> Result<()> {
+        let encoded_checksum = checksum.encode();
+
+        let blob = self
+            .session
+            .execute(
+                &self.file_statements.get_blob,
+                (project_id.into_uuid(), &encoded_checksum),
+            )
+            .await?
+            .maybe_first_row_typed::<Blob>()?;
+
+        if let Some(blob) = blob {
+            self.session
+                .execute(
+                    &self.file_statements.delete_blob,
+                    (project_id.into_uuid(), &encoded_checksum),
+                )
+                .await?;
+
+            if let (Some(blob_store), None) = (self.blob_store, &blob.data) {
+                let blob_key = blob_store_key(project_id, checksum, blob.key_version);
+                blob_store.delete(&blob_key).await?;
+            }
+        }
+
+        // Blobs are listed from the index, it goes last so that blobs that weren't fully
+        // deleted are listed again.
         self.session
             .execute(
-                &self.file_statements.finish_file,
-                (
-                    length as i64,
-                    project_id.into_uuid(),
-                    checksum.encode().to_vec(),
-                ),
+                &self.file_statements.delete_blob_index,
+                (project_id.into_uuid(), &encoded_checksum),
             )
             .await?;
 
         Ok(())
     }
+
+    async fn reencrypt_blobs(&self, project_id: models::ProjectId, limit: usize) -> Result<usize> {
+        let project = self.get_project_for_files(project_id).await?;
+        let key = project
+            .current_file_encryption_key()
+            .ok_or_else(|| anyhow!("project has no file encryption key"))?;
+
+        let blobs = self
+            .session
+            .execute(
+                &self.file_statements.list_blob_key_versions,
+                (project_id.into_uuid(),),
+            )
+            .await?
+            .rows_typed::<BlobKeyVersion>()?
+            .filter(|row| match row {
+                Ok(blob) => blob.key_version != key.version as i32,
+                Err(_) => true,
+            })
+            .take(limit)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut reencrypted_blobs = 0;
+
+        for blob in blobs {
+            let checksum = models::BlobChecksum::decode(&blob.checksum)?;
+
+            let stored_blob = self
+                .session
+                .execute(
+                    &self.file_statements.get_blob,
+                    (project_id.into_uuid(), &blob.checksum),
+                )
+                .await?
+                .maybe_first_row_typed::<Blob>()?;
+
+            let stored_blob = match stored_blob {
+                Some(stored_blob) => stored_blob,
+                None => continue,
+            };
+
+            // The index is behind if a re-encryption was interrupted before it was updated.
+            if stored_blob.key_version == key.version as i32 {
+                self.session
+                    .execute(
+                        &self.file_statements.reindex_blob,
+                        (key.version as i32, project_id.into_uuid(), &blob.checksum),
+                    )
+                    .await?;
+
+                reencrypted_blobs += 1;
+                continue;
+            }
+
+            let ciphertext = self
+                .blob_ciphertext(project_id, &checksum, &stored_blob)
+                .await?;
+            let data = stored_blob.decrypt(&project, &checksum, &ciphertext)?;
+
+            let associated_data =
+                models::FileEncryptionKey::blob_associated_data(project_id, &checksum);
+            let data = key.encrypt(&associated_data, &data)?;
+
+            let data = match self.blob_store {
+                Some(blob_store) => {
+                    let blob_key = blob_store_key(project_id, &checksum, key.version as i32);
+                    blob_store.put(&blob_key, data).await?;
+                    None
+                }
+                None => Some(data),
+            };
+
+            let (applied, _key_version): (bool, Option<i32>) = self
+                .session
+                .execute(
+                    &self.file_statements.reencrypt_blob,
+                    (
+                        key.version as i32,
+                        data,
+                        project_id.into_uuid(),
+                        &blob.checksum,
+                        stored_blob.key_version,
+                    ),
+                )
+                .await?
+                .first_row_typed()?;
+
+            // Another worker got here first, which is fine as long as the blob was re-encrypted.
+            if applied {
+                self.session
+                    .execute(
+                        &self.file_statements.reindex_blob,
+                        (key.version as i32, project_id.into_uuid(), &blob.checksum),
+                    )
+                    .await?;
+
+                if let (Some(blob_store), None) = (self.blob_store, &stored_blob.data) {
+                    let blob_key = blob_store_key(project_id, &checksum, stored_blob.key_version);
+                    blob_store.delete(&blob_key).await?;
+                }
+
+                reencrypted_blobs += 1;
+            }
+        }
+
+        Ok(reencrypted_blobs)
+    }
+
+    async fn list_blob_key_versions(&self, project_id: models::ProjectId) -> Result<Vec<u32>> {
+        let mut key_versions = self
+            .session
+            .execute(
+                &self.file_statements.list_blob_key_versions,
+                (project_id.into_uuid(),),
+            )
+            .await?
+            .rows_typed::<BlobKeyVersion>()?
+            .map(|row| Ok(row?.key_version as u32))
+            .collect::<Result<Vec<_>>>()?;
+
+        key_versions.sort_unstable();
+        key_versions.dedup();
+
+        Ok(key_versions)
+    }
+}
+
+impl ScyllaRepository {
+    /// Returns the encrypted data of a blob, which is either stored inline or in the blob store.
+    async fn blob_ciphertext(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+        blob: &Blob,
+    ) -> Result<Vec<u8>> {
+        if let Some(data) = &blob.data {
+            return Ok(data.clone());
+        }
+
+        let blob_store = self
+            .blob_store
+            .ok_or_else(|| anyhow!("blob {} is kept in a blob store", checksum.to_hex()))?;
+
+        let blob_key = blob_store_key(project_id, checksum, blob.key_version);
+        blob_store
+            .get(&blob_key)
+            .await?
+            .ok_or_else(|| anyhow!("blob {} not found in blob store", checksum.to_hex()))
+    }
+
+    async fn get_project_for_files(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<models::Project> {
+        self.get_project(&project_id)
+            .await?
+            .ok_or_else(|| anyhow!("project not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_count_of_files() {
+        assert_eq!(bucket_count(0), 1);
+        assert_eq!(bucket_count(1), 1);
+        assert_eq!(bucket_count(BUCKET_SIZE), 1);
+        assert_eq!(bucket_count(BUCKET_SIZE + 1), 2);
+        assert_eq!(bucket_count(3 * BUCKET_SIZE + 1024), 4);
+    }
+
+    #[test]
+    fn files_without_bucket_count_are_legacy_files() {
+        let file = |length, buckets| File {
+            project_id: Uuid::nil(),
+            checksum: vec![],
+            length,
+            buckets,
+            created_at: None,
+        };
+
+        assert!(file(Some(1024), Some(1)).is_complete());
+        assert!(file(Some(1024), None).is_complete());
+        assert!(!file(Some(BUCKET_SIZE as i64 + 1), Some(1)).is_complete());
+        assert!(!file(None, None).is_complete());
+    }
+
+    #[test]
+    fn blobs_are_assigned_to_the_bucket_of_their_offset() {
+        let blob_size = 1 << 20;
+        let last_blob_in_first_bucket = BUCKET_SIZE - blob_size;
+
+        assert_eq!(bucket_for_offset(0), 0);
+        assert_eq!(bucket_for_offset(last_blob_in_first_bucket), 0);
+        assert_eq!(bucket_for_offset(BUCKET_SIZE), 1);
+        assert_eq!(bucket_for_offset(2 * BUCKET_SIZE + blob_size), 2);
+    }
+
+    #[test]
+    fn bucket_range_within_a_single_bucket() {
+        assert_eq!(bucket_range((0, 1)), (0, 0));
+        assert_eq!(bucket_range((0, 4 << 20)), (0, 0));
+        assert_eq!(bucket_range((BUCKET_SIZE - (4 << 20), BUCKET_SIZE)), (0, 0));
+        assert_eq!(bucket_range((BUCKET_SIZE, BUCKET_SIZE + 1)), (1, 1));
+    }
+
+    #[test]
+    fn bucket_range_across_multiple_buckets() {
+        assert_eq!(
+            bucket_range((BUCKET_SIZE - (2 << 20), BUCKET_SIZE + (2 << 20))),
+            (0, 1)
+        );
+        assert_eq!(bucket_range((0, 3 * BUCKET_SIZE + 1)), (0, 3));
+        assert_eq!(bucket_range((BUCKET_SIZE + 1, 2 * BUCKET_SIZE + 1)), (1, 2));
+    }
+
+    #[test]
+    fn bucket_range_of_empty_range() {
+        assert_eq!(bucket_range((0, 0)), (0, 0));
+        assert_eq!(bucket_range((BUCKET_SIZE, BUCKET_SIZE)), (1, 1));
+    }
 }
@@ -24,9 +24,16 @@ struct LayerSet {
 
     source_name: Option<String>,
     source_git_ref: Option<String>,
+    source_git_root: Option<String>,
+    source_git_paths: Option<Vec<String>>,
 
     build_current_layer_id: Uuid,
     build_last_layer_id: Uuid,
+
+    pinned_layer_id: Option<Uuid>,
+
+    template_name: Option<String>,
+    archived: Option<bool>,
 }
 
 impl Into<models::LayerSet> for LayerSet {
@@ -41,10 +48,16 @@ impl Into<models::LayerSet> for LayerSet {
             LayerSet {
                 source_name: Some(name),
                 source_git_ref: Some(ref_),
+                source_git_root,
+                source_git_paths,
                 ..
             } => Some(models::LayerSetSource {
                 name: name.parse().unwrap(),
-                kind: models::LayerSetSourceKind::Git { ref_ },
+                kind: models::LayerSetSourceKind::Git {
+                    ref_,
+                    root: source_git_root.unwrap_or_default(),
+                    paths: source_git_paths.unwrap_or_default(),
+                },
             }),
             LayerSet {
                 source_name: None, ..
@@ -65,6 +78,9 @@ impl Into<models::LayerSet> for LayerSet {
                     .filter(|id| !id.is_nil())
                     .map(Into::into),
             },
+            pinned_layer_id: self.pinned_layer_id.map(Into::into),
+            template_name: self.template_name.map(|name| name.parse().unwrap()),
+            archived: self.archived.unwrap_or(false),
         }
     }
 }
@@ -78,6 +94,8 @@ struct Layer {
     build_worker_id: Option<Uuid>,
     finalize_worker_id: Option<Uuid>,
     source_git_commit: Option<String>,
+    source_layer_set_name: Option<String>,
+    source_layer_id: Option<Uuid>,
 }
 
 impl Into<models::Layer> for Layer {
@@ -96,9 +114,14 @@ impl Into<models::Layer> for Layer {
                 ..
             } => Some(models::LayerSource::Git { commit }),
             Layer {
-                source_git_commit: None,
+                source_layer_set_name: Some(ref layer_set_name),
+                source_layer_id: Some(layer_id),
                 ..
-            } => None,
+            } => Some(models::LayerSource::Promotion {
+                layer_set_name: layer_set_name.parse().unwrap(),
+                layer_id: layer_id.into(),
+            }),
+            _ => None,
         };
 
         models::Layer {
@@ -141,6 +164,34 @@ impl Into<models::LayerChange> for LayerChange {
     }
 }
 
+#[derive(Debug, FromRow)]
+struct LayerMember {
+    project_id: Uuid,
+    layer_set_name: String,
+    layer_id: Uuid,
+    path: String,
+    checksum: Vec<u8>,
+    content_encoding_hint: i64,
+    headers: Option<BTreeMap<String, String>>,
+}
+
+impl Into<models::LayerMember> for LayerMember {
+    fn into(self) -> models::LayerMember {
+        models::LayerMember {
+            project_id: self.project_id.into(),
+            layer_set_name: self.layer_set_name.parse().unwrap(),
+            layer_id: self.layer_id.into(),
+            path: self.path,
+            checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
+            content_encoding_hint: models::ContentEncodingHint::decode(
+                &self.content_encoding_hint.to_le_bytes(),
+            )
+            .unwrap(),
+            headers: self.headers.unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Debug, FromRow)]
 struct LayerMemberSummary {
     path: String,
@@ -215,8 +266,9 @@ impl LayerRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, name, visibility, source_name, source_git_ref,
-                    build_current_layer_id, build_last_layer_id
+                SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                    source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                    template_name, archived
                 FROM layer_sets
                 WHERE project_id = ? AND bucket = ? AND name = ?;
                 ",
@@ -237,8 +289,9 @@ impl LayerRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, name, visibility, source_name, source_git_ref,
-                    build_current_layer_id, build_last_layer_id
+                SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                    source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                    template_name, archived
                 FROM layer_sets
                 WHERE project_id = ?;
                 ",
@@ -264,8 +317,9 @@ impl LayerRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, name, visibility, source_name, source_git_ref,
-                    build_current_layer_id, build_last_layer_id
+                SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                    source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                    template_name, archived
                 FROM layer_sets
                 WHERE project_id = ? AND bucket = ? AND source_name = ?
                 ALLOW FILTERING;
@@ -287,16 +341,22 @@ impl LayerRepository for ScyllaRepository {
         match layer_set.source {
             Some(models::LayerSetSource {
                 ref name,
-                kind: models::LayerSetSourceKind::Git { ref ref_ },
+                kind:
+                    models::LayerSetSourceKind::Git {
+                        ref ref_,
+                        ref root,
+                        ref paths,
+                    },
             }) => {
                 self.session
                     .query(
                         r"
                         INSERT INTO layer_sets (
                             project_id, bucket, name, visibility, source_name, source_git_ref,
-                            last_layer_id, build_current_layer_id, build_last_layer_id
+                            source_git_root, source_git_paths, last_layer_id,
+                            build_current_layer_id, build_last_layer_id, template_name, archived
                         )
-                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
                         IF NOT EXISTS;
                         ",
                         (
@@ -306,9 +366,16 @@ impl LayerRepository for ScyllaRepository {
                             visibility_to_str(layer_set.visibility),
                             name.as_str(),
                             ref_,
+                            root,
+                            paths,
                             Uuid::nil(),
                             Uuid::nil(),
                             Uuid::nil(),
+                            layer_set
+                                .template_name
+                                .as_ref()
+                                .map(models::LayerSetName::as_str),
+                            layer_set.archived,
                         ),
                     )
                     .await?;
@@ -341,6 +408,170 @@ impl LayerRepository for ScyllaRepository {
         Ok(())
     }
 
+    async fn get_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSetRetention>> {
+        let retention: Option<(i32,)> = self
+            .session
+            .query(
+                r"
+                SELECT keep_ready_layers
+                FROM layer_set_retention
+                WHERE project_id = ? AND layer_set_name = ?;
+                ",
+                (project_id.into_uuid(), layer_set_name.as_str()),
+            )
+            .await?
+            .maybe_first_row_typed()?;
+
+        let retention = retention.map(|(keep_ready_layers,)| models::LayerSetRetention {
+            keep_ready_layers: keep_ready_layers as u32,
+        });
+
+        Ok(retention)
+    }
+
+    async fn set_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        retention: &models::LayerSetRetention,
+    ) -> Result<()> {
+        self.session
+            .query(
+                r"
+                INSERT INTO layer_set_retention (
+                    project_id, layer_set_name, keep_ready_layers
+                )
+                VALUES (?, ?, ?);
+                ",
+                (
+                    project_id.into_uuid(),
+                    layer_set_name.as_str(),
+                    retention.keep_ready_layers as i32,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Option<models::RefLayerSetLimit>> {
+        let limit: Option<(i32,)> = self
+            .session
+            .query(
+                r"
+                SELECT max_layer_sets
+                FROM ref_layer_set_limits
+                WHERE project_id = ?;
+                ",
+                (project_id.into_uuid(),),
+            )
+            .await?
+            .maybe_first_row_typed()?;
+
+        let limit = limit.map(|(max_layer_sets,)| models::RefLayerSetLimit {
+            max_layer_sets: max_layer_sets as u32,
+        });
+
+        Ok(limit)
+    }
+
+    async fn set_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+        limit: &models::RefLayerSetLimit,
+    ) -> Result<()> {
+        self.session
+            .query(
+                r"
+                INSERT INTO ref_layer_set_limits (project_id, max_layer_sets)
+                VALUES (?, ?);
+                ",
+                (project_id.into_uuid(), limit.max_layer_sets as i32),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_layer_set_archived(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        archived: bool,
+    ) -> Result<()> {
+        let mut query = Query::new(
+            r"
+            UPDATE layer_sets
+            SET archived = ?
+            WHERE project_id = ? AND bucket = ? AND name = ?
+            IF EXISTS;
+            ",
+        );
+
+        query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+        let (applied,): (bool,) = self
+            .session
+            .query(
+                query,
+                (
+                    archived,
+                    project_id.into_uuid(),
+                    0i64,
+                    layer_set_name.as_str(),
+                ),
+            )
+            .await?
+            .first_row_typed()?;
+
+        ensure!(applied, "layer set not found");
+
+        Ok(())
+    }
+
+    async fn set_pinned_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: Option<models::LayerId>,
+    ) -> Result<()> {
+        let mut query = Query::new(
+            r"
+            UPDATE layer_sets
+            SET pinned_layer_id = ?
+            WHERE project_id = ? AND bucket = ? AND name = ?
+            IF EXISTS;
+            ",
+        );
+
+        query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+        let (applied,): (bool,) = self
+            .session
+            .query(
+                query,
+                (
+                    layer_id.map(|layer_id| layer_id.into_uuid()),
+                    project_id.into_uuid(),
+                    0i64,
+                    layer_set_name.as_str(),
+                ),
+            )
+            .await?
+            .first_row_typed()?;
+
+        ensure!(applied, "layer set not found");
+
+        Ok(())
+    }
+
     async fn set_last_layer_id(
         &self,
         project_id: models::ProjectId,
@@ -412,7 +643,9 @@ impl LayerRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, layer_set_name, bucket, id, status, source_git_commit
+                SELECT project_id, layer_set_name, id, status,
+                    build_worker_id, finalize_worker_id, source_git_commit,
+                    source_layer_set_name, source_layer_id
                 FROM layers
                 WHERE project_id = ? AND layer_set_name = ? AND bucket = ? AND id <= ?;
                 ",
@@ -453,6 +686,31 @@ impl LayerRepository for ScyllaRepository {
                     )
                     .await?;
             }
+            Some(models::LayerSource::Promotion {
+                layer_set_name,
+                layer_id,
+            }) => {
+                self.session
+                    .query(
+                        r"
+                        INSERT INTO layers (
+                            project_id, layer_set_name, bucket, id, status,
+                            source_layer_set_name, source_layer_id
+                        )
+                        VALUES (?, ?, ?, ?, ?, ?, ?);
+                        ",
+                        (
+                            layer.project_id.into_uuid(),
+                            layer.layer_set_name.as_str(),
+                            0i64,
+                            layer.id.into_uuid(),
+                            layer_status_to_str(layer.status),
+                            layer_set_name.as_str(),
+                            layer_id.into_uuid(),
+                        ),
+                    )
+                    .await?;
+            }
             None => {
                 self.session
                     .query(
@@ -477,6 +735,140 @@ impl LayerRepository for ScyllaRepository {
         Ok(())
     }
 
+    async fn list_layers(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Vec<models::Layer>> {
+        let layers = self
+            .session
+            .query(
+                r"
+                SELECT project_id, layer_set_name, id, status,
+                    build_worker_id, finalize_worker_id, source_git_commit,
+                    source_layer_set_name, source_layer_id
+                FROM layers
+                WHERE project_id = ? AND layer_set_name = ? AND bucket = ?;
+                ",
+                (project_id.into_uuid(), layer_set_name.as_str(), 0i64),
+            )
+            .await?
+            .rows_typed()?
+            .map(|row| {
+                let layer: Layer = row?;
+                Ok(layer.into())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(layers)
+    }
+
+    async fn delete_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let layer_members = self
+            .list_layer_members(project_id, layer_set_name, layer_id)
+            .await?;
+
+        for layer_member in layer_members {
+            self.session
+                .query(
+                    r"
+                    DELETE FROM layer_members
+                    WHERE project_id = ? AND layer_set_name = ? AND path = ? AND bucket = ?
+                        AND layer_id = ?;
+                    ",
+                    (
+                        project_id.into_uuid(),
+                        layer_set_name.as_str(),
+                        &layer_member.path,
+                        0i64,
+                        layer_id.into_uuid(),
+                    ),
+                )
+                .await?;
+        }
+
+        // The members are found through this table, so it goes last.
+        self.session
+            .query(
+                r"
+                DELETE FROM layer_members_by_layer
+                WHERE project_id = ? AND layer_set_name = ? AND layer_id = ? AND bucket = ?;
+                ",
+                (
+                    project_id.into_uuid(),
+                    layer_set_name.as_str(),
+                    layer_id.into_uuid(),
+                    0i64,
+                ),
+            )
+            .await?;
+
+        let mut worker_ids = self
+            .session
+            .query(
+                r"
+                SELECT worker_id
+                FROM layer_changes
+                WHERE project_id = ? AND layer_set_name = ? AND layer_id = ?
+                ALLOW FILTERING;
+                ",
+                (
+                    project_id.into_uuid(),
+                    layer_set_name.as_str(),
+                    layer_id.into_uuid(),
+                ),
+            )
+            .await?
+            .rows_typed::<(Uuid,)>()?
+            .map(|row| Ok(row?.0))
+            .collect::<Result<Vec<_>>>()?;
+
+        worker_ids.sort();
+        worker_ids.dedup();
+
+        for worker_id in worker_ids {
+            self.session
+                .query(
+                    r"
+                    DELETE FROM layer_changes
+                    WHERE project_id = ? AND layer_set_name = ? AND layer_id = ?
+                        AND worker_id = ? AND bucket = ?;
+                    ",
+                    (
+                        project_id.into_uuid(),
+                        layer_set_name.as_str(),
+                        layer_id.into_uuid(),
+                        worker_id,
+                        0i64,
+                    ),
+                )
+                .await?;
+        }
+
+        // The layer goes last, so that the members and changes are found again if this fails.
+        self.session
+            .query(
+                r"
+                DELETE FROM layers
+                WHERE project_id = ? AND layer_set_name = ? AND bucket = ? AND id = ?;
+                ",
+                (
+                    project_id.into_uuid(),
+                    layer_set_name.as_str(),
+                    0i64,
+                    layer_id.into_uuid(),
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
     async fn get_pending_layers(
         &self,
         filter: LayerPendingLayersFilter,
@@ -484,7 +876,8 @@ impl LayerRepository for ScyllaRepository {
         let query = Query::new(
             r"
             SELECT project_id, layer_set_name, id, status,
-                build_worker_id, finalize_worker_id, source_git_commit
+                build_worker_id, finalize_worker_id, source_git_commit,
+                source_layer_set_name, source_layer_id
             FROM layers
             WHERE status = ?
             ALLOW FILTERING
@@ -886,28 +1279,42 @@ impl LayerRepository for ScyllaRepository {
         let mut batch = Batch::default();
         let mut batch_values: Vec<_> = Vec::with_capacity(layer_members.len());
 
-        let query = Query::new(
-            r"
-            INSERT INTO layer_members (
-                project_id, layer_set_name, layer_id, bucket,
-                path, checksum, content_encoding_hint, headers
-            )
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?);
-            ",
-        );
+        // Members are looked up by path when serving, and listed by layer.
+        let queries = [
+            Query::new(
+                r"
+                INSERT INTO layer_members (
+                    project_id, layer_set_name, layer_id, bucket,
+                    path, checksum, content_encoding_hint, headers
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+                ",
+            ),
+            Query::new(
+                r"
+                INSERT INTO layer_members_by_layer (
+                    project_id, layer_set_name, layer_id, bucket,
+                    path, checksum, content_encoding_hint, headers
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+                ",
+            ),
+        ];
 
         for layer_member in layer_members {
-            batch.append_statement(query.clone());
-            batch_values.push((
-                layer_member.project_id.into_uuid(),
-                layer_member.layer_set_name.as_str(),
-                layer_member.layer_id.into_uuid(),
-                0i64,
-                &layer_member.path,
-                layer_member.checksum.encode(),
-                i64::from_le_bytes(layer_member.content_encoding_hint.encode()),
-                &layer_member.headers,
-            ));
+            for query in &queries {
+                batch.append_statement(query.clone());
+                batch_values.push((
+                    layer_member.project_id.into_uuid(),
+                    layer_member.layer_set_name.as_str(),
+                    layer_member.layer_id.into_uuid(),
+                    0i64,
+                    &layer_member.path,
+                    layer_member.checksum.encode(),
+                    i64::from_le_bytes(layer_member.content_encoding_hint.encode()),
+                    &layer_member.headers,
+                ));
+            }
         }
 
         self.session.batch(&batch, &batch_values).await?;
@@ -915,6 +1322,39 @@ impl LayerRepository for ScyllaRepository {
         Ok(())
     }
 
+    async fn list_layer_members(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<Vec<models::LayerMember>> {
+        let layer_members = self
+            .session
+            .query(
+                r"
+                SELECT project_id, layer_set_name, layer_id,
+                    path, checksum, content_encoding_hint, headers
+                FROM layer_members_by_layer
+                WHERE project_id = ? AND layer_set_name = ? AND layer_id = ? AND bucket = ?;
+                ",
+                (
+                    project_id.into_uuid(),
+                    layer_set_name.as_str(),
+                    layer_id.into_uuid(),
+                    0i64,
+                ),
+            )
+            .await?
+            .rows_typed()?
+            .map(|row| {
+                let layer_member: LayerMember = row?;
+                Ok(layer_member.into())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(layer_members)
+    }
+
     async fn get_layer_member_summary(
         &self,
         project_id: models::ProjectId,
@@ -1,19 +1,25 @@
-use anyhow::{Context as _, Result};
+use anyhow::Result;
 use scylla::{Session, SessionBuilder};
 
+use fairing_core2::repositories::BlobStore;
+
 mod domains;
 mod files;
 mod layers;
+mod migrations;
 mod projects;
 mod queue;
 mod sources;
 mod time;
 
+pub use migrations::Migration;
+
 pub struct ScyllaRepository {
     session: Session,
     domain_statements: domains::Statements,
     file_statements: files::Statements,
     layer_statements: layers::Statements,
+    blob_store: Option<&'static dyn BlobStore>,
 }
 
 impl ScyllaRepository {
@@ -21,13 +27,9 @@ impl ScyllaRepository {
         known_nodes: &[S],
         keyspace_name: &str,
     ) -> Result<ScyllaRepository> {
-        let session = SessionBuilder::new()
-            .known_nodes(known_nodes)
-            .use_keyspace(keyspace_name, false)
-            .build()
-            .await?;
+        let session = connect_session(known_nodes, keyspace_name).await?;
 
-        migrate(&session).await?;
+        migrations::up(&session).await?;
 
         let domain_statements = domains::Statements::prepare(&session).await?;
         let file_statements = files::Statements::prepare(&session).await?;
@@ -38,21 +40,50 @@ impl ScyllaRepository {
             domain_statements,
             file_statements,
             layer_statements,
+            blob_store: None,
         })
     }
+
+    /// Keeps the data of new blobs in `blob_store` instead of in ScyllaDB. Blobs that are already
+    /// stored in ScyllaDB can still be read.
+    pub fn with_blob_store(mut self, blob_store: &'static dyn BlobStore) -> ScyllaRepository {
+        self.blob_store = Some(blob_store);
+        self
+    }
+}
+
+/// Inspects and applies schema migrations without preparing any statements, which might depend on
+/// migrations that haven't been applied yet.
+pub struct ScyllaMigrations {
+    session: Session,
 }
 
-async fn migrate(session: &Session) -> Result<()> {
-    let initial = include_str!("../migrations/0001_initial.cql");
+impl ScyllaMigrations {
+    pub async fn connect<S: AsRef<str>>(
+        known_nodes: &[S],
+        keyspace_name: &str,
+    ) -> Result<ScyllaMigrations> {
+        let session = connect_session(known_nodes, keyspace_name).await?;
+        Ok(ScyllaMigrations { session })
+    }
 
-    let statements = initial
-        .split_inclusive(';')
-        .map(|statement| statement.trim())
-        .filter(|statement| !statement.is_empty());
+    pub async fn status(&self) -> Result<Vec<Migration>> {
+        migrations::status(&self.session).await
+    }
 
-    for statement in statements {
-        session.query(statement, ()).await.context(statement)?;
+    /// Applies pending migrations, waiting for other nodes that are applying migrations to finish
+    /// first. Returns the migrations that were applied.
+    pub async fn up(&self) -> Result<Vec<Migration>> {
+        migrations::up(&self.session).await
     }
+}
+
+async fn connect_session<S: AsRef<str>>(known_nodes: &[S], keyspace_name: &str) -> Result<Session> {
+    let session = SessionBuilder::new()
+        .known_nodes(known_nodes)
+        .use_keyspace(keyspace_name, false)
+        .build()
+        .await?;
 
-    Ok(())
+    Ok(session)
 }
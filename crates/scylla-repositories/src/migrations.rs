@@ -0,0 +1,605 @@
+use anyhow::{anyhow, ensure, Context as _, Result};
+use chrono::{DateTime, Utc};
+use scylla::{
+    frame::{response::result::Row, value::Timestamp},
+    query::Query,
+    statement::{Consistency, SerialConsistency},
+    Session,
+};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::time::{from_timestamp, to_timestamp};
+
+/// Schema migrations, applied in order. Statements are separated by `;` and every statement that
+/// succeeds is recorded in `schema_migration_statements`, so a migration that fails halfway
+/// through continues with the statement that failed when it's applied again.
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (1, "initial", include_str!("../migrations/0001_initial.cql")),
+    (
+        2,
+        "certificate_queue",
+        include_str!("../migrations/0002_certificate_queue.cql"),
+    ),
+    (
+        3,
+        "drop_domains",
+        include_str!("../migrations/0003_drop_domains.cql"),
+    ),
+    (
+        4,
+        "layer_set_retention",
+        include_str!("../migrations/0004_layer_set_retention.cql"),
+    ),
+    (
+        5,
+        "layer_set_pinned_layer",
+        include_str!("../migrations/0005_layer_set_pinned_layer.cql"),
+    ),
+    (
+        6,
+        "layer_promotions",
+        include_str!("../migrations/0006_layer_promotions.cql"),
+    ),
+    (
+        7,
+        "source_host_keys",
+        include_str!("../migrations/0007_source_host_keys.cql"),
+    ),
+    (
+        8,
+        "source_http_token",
+        include_str!("../migrations/0008_source_http_token.cql"),
+    ),
+    (
+        9,
+        "layer_set_source_root",
+        include_str!("../migrations/0009_layer_set_source_root.cql"),
+    ),
+    (
+        10,
+        "ref_layer_sets",
+        include_str!("../migrations/0010_ref_layer_sets.cql"),
+    ),
+    (
+        11,
+        "file_encryption_keys",
+        include_str!("../migrations/0011_file_encryption_keys.cql"),
+    ),
+    (
+        12,
+        "file_buckets",
+        include_str!("../migrations/0012_file_buckets.cql"),
+    ),
+    (13, "blobs", include_str!("../migrations/0013_blobs.cql")),
+    (
+        14,
+        "domain_routes",
+        include_str!("../migrations/0014_domain_routes.cql"),
+    ),
+    (
+        15,
+        "file_created_at",
+        include_str!("../migrations/0015_file_created_at.cql"),
+    ),
+    (
+        16,
+        "project_indexes",
+        include_str!("../migrations/0016_project_indexes.cql"),
+    ),
+];
+
+/// How long a node may hold the migration lock before someone else may take it over. The lock is
+/// refreshed while migrations are applied.
+const LOCK_TTL_SECONDS: i32 = 300;
+
+#[derive(Clone, Debug)]
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Returns all known migrations and when they were applied.
+pub(crate) async fn status(session: &Session) -> Result<Vec<Migration>> {
+    create_migration_tables(session).await?;
+
+    let mut query = Query::new(
+        r"
+        SELECT version, applied_at
+        FROM schema_migrations;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let applied = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(i32, Timestamp)>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let migrations = MIGRATIONS
+        .iter()
+        .map(|&(version, name, _cql)| Migration {
+            version,
+            name,
+            applied_at: applied
+                .iter()
+                .find(|(applied_version, _)| *applied_version == version)
+                .map(|(_, applied_at)| from_timestamp(applied_at)),
+        })
+        .collect();
+
+    Ok(migrations)
+}
+
+/// Applies all pending migrations while holding the migration lock, returns the migrations that
+/// were applied.
+pub(crate) async fn up(session: &Session) -> Result<Vec<Migration>> {
+    create_migration_tables(session).await?;
+
+    let owner = Uuid::new_v4();
+    while !try_lock(session, owner).await? {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    let res = tokio::select! {
+        res = apply_pending(session) => res,
+        err = keep_locked(session, owner) => Err(err),
+    };
+    let unlock_res = unlock(session, owner).await;
+
+    let applied = res?;
+    unlock_res?;
+
+    Ok(applied)
+}
+
+async fn apply_pending(session: &Session) -> Result<Vec<Migration>> {
+    let mut applied = vec![];
+
+    for migration in status(session).await? {
+        if migration.applied_at.is_some() {
+            continue;
+        }
+
+        let (_version, _name, cql) = MIGRATIONS
+            .iter()
+            .find(|(version, _name, _cql)| *version == migration.version)
+            .ok_or_else(|| anyhow!("migration {} not found", migration.version))?;
+
+        let applied_statements = applied_statements(session, migration.version).await?;
+
+        for (index, statement) in statements(cql).enumerate() {
+            let index = index as i32;
+            if applied_statements.contains(&index) {
+                continue;
+            }
+
+            session
+                .query(statement, ())
+                .await
+                .with_context(|| format!("migration {}: {statement}", migration.version))?;
+
+            let mut query = Query::new(
+                r"
+                INSERT INTO schema_migration_statements (version, statement)
+                VALUES (?, ?);
+                ",
+            );
+            query.set_consistency(Consistency::Quorum);
+
+            session.query(query, (migration.version, index)).await?;
+        }
+
+        session.await_schema_agreement().await?;
+
+        migrate_data(session, migration.version)
+            .await
+            .with_context(|| format!("migration {}: migrating data", migration.version))?;
+
+        let applied_at = Utc::now();
+
+        let mut query = Query::new(
+            r"
+            INSERT INTO schema_migrations (version, name, applied_at)
+            VALUES (?, ?, ?);
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session
+            .query(
+                query,
+                (migration.version, migration.name, to_timestamp(&applied_at)),
+            )
+            .await?;
+
+        applied.push(Migration {
+            applied_at: Some(applied_at),
+            ..migration
+        });
+    }
+
+    Ok(applied)
+}
+
+/// Returns the indexes of the statements of a migration that have already been applied.
+async fn applied_statements(session: &Session, version: i32) -> Result<Vec<i32>> {
+    let mut query = Query::new(
+        r"
+        SELECT statement
+        FROM schema_migration_statements
+        WHERE version = ?;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let statements = session
+        .query(query, (version,))
+        .await?
+        .rows_typed::<(i32,)>()?
+        .map(|row| row.map(|(statement,)| statement))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(statements)
+}
+
+/// Copies data for migrations that can't be expressed as statements, runs after the statements
+/// of the migration. These run again if the migration fails before it is recorded, so they have to
+/// be idempotent.
+async fn migrate_data(session: &Session, version: i32) -> Result<()> {
+    match version {
+        11 => copy_file_encryption_keys(session).await,
+        16 => copy_project_indexes(session).await,
+        _ => Ok(()),
+    }
+}
+
+/// Projects used to have a single file encryption key, which is kept as version 0 so that the
+/// files encrypted with it can still be read.
+async fn copy_file_encryption_keys(session: &Session) -> Result<()> {
+    let mut query = Query::new(
+        r"
+        SELECT id, file_encryption_key
+        FROM projects;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let projects = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(Uuid, Option<Vec<u8>>)>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (project_id, key) in projects {
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+
+        let mut query = Query::new(
+            r"
+            UPDATE projects
+            SET file_encryption_keys[0] = ?
+            WHERE id = ?;
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session.query(query, (key, project_id)).await?;
+    }
+
+    Ok(())
+}
+
+/// Fills the tables that layer members, domains, files and blobs are listed from, which used to
+/// be listed by scanning the tables they are stored in.
+async fn copy_project_indexes(session: &Session) -> Result<()> {
+    let mut query = Query::new(
+        r"
+        SELECT project_id, layer_set_name, layer_id, path, checksum, content_encoding_hint,
+            headers
+        FROM layer_members;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let layer_members = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(
+            Uuid,
+            String,
+            Uuid,
+            String,
+            Vec<u8>,
+            i64,
+            Option<BTreeMap<String, String>>,
+        )>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for layer_member in layer_members {
+        let mut query = Query::new(
+            r"
+            INSERT INTO layer_members_by_layer (
+                project_id, layer_set_name, layer_id, bucket,
+                path, checksum, content_encoding_hint, headers
+            )
+            VALUES (?, ?, ?, 0, ?, ?, ?, ?);
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session.query(query, layer_member).await?;
+    }
+
+    let mut query = Query::new(
+        r"
+        SELECT project_id, fqdn, kind, layer_set_name, layer_id
+        FROM domain_routes;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let domains = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(Uuid, String, String, Option<String>, Option<Uuid>)>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for domain in domains {
+        let mut query = Query::new(
+            r"
+            INSERT INTO domain_routes_by_project (
+                project_id, bucket, fqdn, kind, layer_set_name, layer_id
+            )
+            VALUES (?, 0, ?, ?, ?, ?);
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session.query(query, domain).await?;
+    }
+
+    // Only the partition key and static columns are selected, every file is listed once per
+    // bucket instead of once per blob.
+    let mut query = Query::new(
+        r"
+        SELECT DISTINCT project_id, checksum, bucket, length, buckets, created_at
+        FROM files;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let files = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(
+            Uuid,
+            Vec<u8>,
+            i64,
+            Option<i64>,
+            Option<i64>,
+            Option<Timestamp>,
+        )>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (project_id, checksum, bucket, length, buckets, created_at) in files {
+        // Files without a length haven't been finished, they are indexed once they are.
+        if bucket != 0 || length.is_none() {
+            continue;
+        }
+
+        let mut query = Query::new(
+            r"
+            INSERT INTO files_by_project (
+                project_id, bucket, checksum, length, buckets, created_at
+            )
+            VALUES (?, 0, ?, ?, ?, ?);
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session
+            .query(query, (project_id, checksum, length, buckets, created_at))
+            .await?;
+    }
+
+    let mut query = Query::new(
+        r"
+        SELECT project_id, checksum, length, compression, key_version, created_at
+        FROM blobs;
+        ",
+    );
+    query.set_consistency(Consistency::Quorum);
+
+    let blobs = session
+        .query(query, ())
+        .await?
+        .rows_typed::<(Uuid, Vec<u8>, i64, String, i32, Option<Timestamp>)>()?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for blob in blobs {
+        let mut query = Query::new(
+            r"
+            INSERT INTO blobs_by_project (
+                project_id, bucket, checksum, length, compression, key_version, created_at
+            )
+            VALUES (?, 0, ?, ?, ?, ?, ?);
+            ",
+        );
+        query.set_consistency(Consistency::Quorum);
+
+        session.query(query, blob).await?;
+    }
+
+    Ok(())
+}
+
+async fn create_migration_tables(session: &Session) -> Result<()> {
+    session
+        .query(
+            r"
+            CREATE TABLE IF NOT EXISTS schema_migrations (
+                version int,
+                name text,
+                applied_at timestamp,
+                PRIMARY KEY (version)
+            );
+            ",
+            (),
+        )
+        .await?;
+
+    session
+        .query(
+            r"
+            CREATE TABLE IF NOT EXISTS schema_migration_statements (
+                version int,
+                statement int,
+                PRIMARY KEY (version, statement)
+            );
+            ",
+            (),
+        )
+        .await?;
+
+    session
+        .query(
+            r"
+            CREATE TABLE IF NOT EXISTS schema_migrations_lock (
+                name text,
+                owner uuid,
+                PRIMARY KEY (name)
+            );
+            ",
+            (),
+        )
+        .await?;
+
+    session.await_schema_agreement().await?;
+
+    Ok(())
+}
+
+async fn try_lock(session: &Session, owner: Uuid) -> Result<bool> {
+    let mut query = Query::new(
+        r"
+        INSERT INTO schema_migrations_lock (name, owner)
+        VALUES ('schema', ?)
+        IF NOT EXISTS
+        USING TTL ?;
+        ",
+    );
+    query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+    let row = session
+        .query(query, (owner, LOCK_TTL_SECONDS))
+        .await?
+        .first_row()?;
+
+    is_applied(&row)
+}
+
+/// Refreshes the migration lock until that fails, which means that the lock expired and another
+/// node may have taken it over.
+async fn keep_locked(session: &Session, owner: Uuid) -> anyhow::Error {
+    let interval = std::time::Duration::from_secs(LOCK_TTL_SECONDS as u64 / 3);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match refresh_lock(session, owner).await {
+            Ok(true) => {}
+            Ok(false) => return anyhow!("migration lock expired before the migrations finished"),
+            Err(err) => return err.context("refreshing the migration lock"),
+        }
+    }
+}
+
+async fn refresh_lock(session: &Session, owner: Uuid) -> Result<bool> {
+    let mut query = Query::new(
+        r"
+        UPDATE schema_migrations_lock
+        USING TTL ?
+        SET owner = ?
+        WHERE name = 'schema'
+        IF owner = ?;
+        ",
+    );
+    query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+    let row = session
+        .query(query, (LOCK_TTL_SECONDS, owner, owner))
+        .await?
+        .first_row()?;
+
+    is_applied(&row)
+}
+
+async fn unlock(session: &Session, owner: Uuid) -> Result<()> {
+    let mut query = Query::new(
+        r"
+        DELETE FROM schema_migrations_lock
+        WHERE name = 'schema'
+        IF owner = ?;
+        ",
+    );
+    query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+    let row = session.query(query, (owner,)).await?.first_row()?;
+
+    ensure!(
+        is_applied(&row)?,
+        "migration lock expired before the migrations finished"
+    );
+
+    Ok(())
+}
+
+fn is_applied(row: &Row) -> Result<bool> {
+    // ScyllaDB returns the existing row next to `[applied]`, so the row can't be read as a tuple.
+    row.columns
+        .first()
+        .and_then(|applied| applied.as_ref())
+        .and_then(|applied| applied.as_boolean())
+        .ok_or_else(|| anyhow!("missing [applied] column"))
+}
+
+fn statements(cql: &str) -> impl Iterator<Item = &str> {
+    cql.split_inclusive(';')
+        .map(|statement| statement.trim())
+        .filter(|statement| !statement.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_numbered_in_order() {
+        for (index, (version, _name, cql)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version as usize, index + 1);
+            assert!(statements(cql).count() > 0);
+        }
+    }
+
+    #[test]
+    fn certificate_queue_selects_from_certificates() {
+        let all = MIGRATIONS
+            .iter()
+            .flat_map(|(_version, _name, cql)| statements(cql))
+            .collect::<Vec<_>>();
+
+        let certificate_queue = all
+            .iter()
+            .rev()
+            .find(|statement| statement.contains("VIEW IF NOT EXISTS certificate_queue"))
+            .unwrap();
+
+        assert!(certificate_queue.contains("FROM certificates"));
+        assert!(certificate_queue.contains("domains,"));
+    }
+}
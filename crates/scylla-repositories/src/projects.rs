@@ -1,5 +1,6 @@
-use anyhow::Result;
-use scylla::FromRow;
+use anyhow::{anyhow, ensure, Result};
+use scylla::{query::Query, statement::SerialConsistency, FromRow};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 use fairing_core2::{models, repositories::ProjectRepository};
@@ -10,15 +11,24 @@ use crate::ScyllaRepository;
 struct Project {
     id: Uuid,
     acme_dns_challenge_label: String,
-    file_encryption_key: Vec<u8>,
+    file_encryption_keys: Option<BTreeMap<i32, Vec<u8>>>,
 }
 
 impl Into<models::Project> for Project {
     fn into(self) -> models::Project {
+        let file_encryption_keys = self
+            .file_encryption_keys
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(version, key)| {
+                models::FileEncryptionKey::from_row(version as u32, &key).unwrap()
+            })
+            .collect();
+
         models::Project {
             id: self.id.into(),
             acme_dns_challenge_label: self.acme_dns_challenge_label,
-            file_encryption_key: self.file_encryption_key,
+            file_encryption_keys,
         }
     }
 }
@@ -30,7 +40,7 @@ impl ProjectRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT id, acme_dns_challenge_label, file_encryption_key
+                SELECT id, acme_dns_challenge_label, file_encryption_keys
                 FROM projects
                 WHERE id = ?;
                 ",
@@ -43,18 +53,42 @@ impl ProjectRepository for ScyllaRepository {
         Ok(project)
     }
 
+    async fn list_projects(&self) -> Result<Vec<models::Project>> {
+        let projects = self
+            .session
+            .query(
+                r"
+                SELECT id, acme_dns_challenge_label, file_encryption_keys
+                FROM projects;
+                ",
+                (),
+            )
+            .await?
+            .rows_typed()?
+            .map(|row| row.map(Project::into).map_err(|err| anyhow!("{:?}", err)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(projects)
+    }
+
     async fn create_or_update_project(&self, project: &models::Project) -> Result<()> {
+        let file_encryption_keys = project
+            .file_encryption_keys
+            .iter()
+            .map(|key| (key.version as i32, key.key_to_slice().to_vec()))
+            .collect::<BTreeMap<_, _>>();
+
         self.session
             .query(
                 r"
                 UPDATE projects
                 SET acme_dns_challenge_label = ?,
-                    file_encryption_key = ?
+                    file_encryption_keys = ?
                 WHERE id = ?;
                 ",
                 (
                     &project.acme_dns_challenge_label,
-                    &project.file_encryption_key,
+                    file_encryption_keys,
                     project.id.into_uuid(),
                 ),
             )
@@ -62,4 +96,66 @@ impl ProjectRepository for ScyllaRepository {
 
         Ok(())
     }
+
+    async fn create_file_encryption_key(
+        &self,
+        project_id: models::ProjectId,
+        key: &models::FileEncryptionKey,
+    ) -> Result<()> {
+        let mut query = Query::new(
+            r"
+            UPDATE projects
+            SET file_encryption_keys[?] = ?
+            WHERE id = ?
+            IF file_encryption_keys[?] = NULL;
+            ",
+        );
+
+        query.set_serial_consistency(Some(SerialConsistency::Serial));
+
+        let (applied, _key): (bool, Option<Vec<u8>>) = self
+            .session
+            .query(
+                query,
+                (
+                    key.version as i32,
+                    key.key_to_slice().to_vec(),
+                    project_id.into_uuid(),
+                    key.version as i32,
+                ),
+            )
+            .await?
+            .first_row_typed()?;
+
+        ensure!(
+            applied,
+            "a file encryption key with this version already exists"
+        );
+
+        Ok(())
+    }
+
+    async fn delete_file_encryption_keys(
+        &self,
+        project_id: models::ProjectId,
+        versions: &[u32],
+    ) -> Result<()> {
+        let versions = versions
+            .iter()
+            .map(|version| *version as i32)
+            .collect::<Vec<_>>();
+
+        self.session
+            .query(
+                r"
+                UPDATE projects
+                SET file_encryption_keys = file_encryption_keys - ?
+                WHERE id = ?;
+                ",
+                (versions, project_id.into_uuid()),
+            )
+            .await?;
+
+        Ok(())
+    }
 }
@@ -12,26 +12,43 @@ struct Source {
     name: String,
     git_repository_url: Option<String>,
     git_ed25519_secret_key: Option<Vec<u8>>,
+    git_known_host_keys: Option<Vec<String>>,
+    git_host_key_mismatch: Option<String>,
+    git_http_token: Option<String>,
 }
 
 impl Into<models::Source> for Source {
     fn into(self) -> models::Source {
-        let kind = match self {
-            Source {
-                git_repository_url: Some(repository_url),
-                git_ed25519_secret_key: Some(ed25519_secret_key),
-                ..
-            } => models::SourceKind::Git {
+        let known_host_keys = self.git_known_host_keys.unwrap_or_default();
+        let known_host_keys = known_host_keys
+            .into_iter()
+            .map(|host_key| host_key.parse().unwrap())
+            .collect();
+
+        let kind = match (self.git_repository_url, self.git_ed25519_secret_key) {
+            (Some(repository_url), Some(ed25519_secret_key)) => models::SourceKind::Git {
                 repository_url: repository_url.parse().unwrap(),
                 id_ed25519: models::Ed25519::from_row(ed25519_secret_key),
+                known_host_keys,
+                http_token: self
+                    .git_http_token
+                    .map(|http_token| http_token.parse().unwrap()),
             },
             _ => unreachable!("unknown source kind"),
         };
 
+        let status = match self.git_host_key_mismatch {
+            Some(host_key) => models::SourceStatus::HostKeyMismatch {
+                host_key: host_key.parse().unwrap(),
+            },
+            None => models::SourceStatus::Ok,
+        };
+
         models::Source {
             project_id: self.project_id.into(),
             name: self.name.parse().unwrap(),
             kind,
+            status,
         }
     }
 }
@@ -47,7 +64,8 @@ impl SourceRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, name, git_repository_url, git_ed25519_secret_key
+                SELECT project_id, name, git_repository_url, git_ed25519_secret_key,
+                    git_known_host_keys, git_host_key_mismatch, git_http_token
                 FROM sources
                 WHERE project_id = ? AND bucket = ? AND name = ?;
                 ",
@@ -65,7 +83,8 @@ impl SourceRepository for ScyllaRepository {
             .session
             .query(
                 r"
-                SELECT project_id, name, git_repository_url, git_ed25519_secret_key
+                SELECT project_id, name, git_repository_url, git_ed25519_secret_key,
+                    git_known_host_keys, git_host_key_mismatch, git_http_token
                 FROM sources
                 WHERE project_id = ? AND bucket = ?;
                 ",
@@ -84,18 +103,38 @@ impl SourceRepository for ScyllaRepository {
             models::SourceKind::Git {
                 ref repository_url,
                 ref id_ed25519,
+                ref known_host_keys,
+                ref http_token,
             } => {
+                let known_host_keys = known_host_keys
+                    .iter()
+                    .map(|host_key| host_key.to_string())
+                    .collect::<Vec<_>>();
+
+                let host_key_mismatch = match source.status {
+                    models::SourceStatus::Ok => None,
+                    models::SourceStatus::HostKeyMismatch { ref host_key } => {
+                        Some(host_key.as_str())
+                    }
+                };
+
                 self.session
                     .query(
                         r"
                         UPDATE sources
                         SET git_repository_url = ?,
-                            git_ed25519_secret_key = ?
+                            git_ed25519_secret_key = ?,
+                            git_known_host_keys = ?,
+                            git_host_key_mismatch = ?,
+                            git_http_token = ?
                         WHERE project_id = ? AND bucket = ? AND name = ?;
                         ",
                         (
                             repository_url.as_str(),
                             id_ed25519.secret_key_to_slice().to_vec(),
+                            known_host_keys,
+                            host_key_mismatch,
+                            http_token.as_ref().map(models::GitHttpToken::as_str),
                             source.project_id.into_uuid(),
                             0_i64,
                             source.name.as_str(),
@@ -0,0 +1,179 @@
+CREATE TABLE IF NOT EXISTS projects (
+    id BLOB NOT NULL,
+    acme_dns_challenge_label TEXT NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS file_encryption_keys (
+    project_id BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    key BLOB NOT NULL,
+    PRIMARY KEY (project_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS sources (
+    project_id BLOB NOT NULL,
+    name TEXT NOT NULL,
+
+    -- git source settings
+    git_repository_url TEXT,
+    git_ed25519_secret_key BLOB,
+
+    PRIMARY KEY (project_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS layer_sets (
+    project_id BLOB NOT NULL,
+    name TEXT NOT NULL,
+
+    visibility TEXT NOT NULL,
+
+    source_name TEXT,
+    source_git_ref TEXT,
+
+    last_layer_id BLOB,
+
+    build_current_layer_id BLOB,
+    build_last_layer_id BLOB,
+
+    PRIMARY KEY (project_id, name)
+);
+
+-- Worker ids expire so that layers are picked up again if a worker dies, the same way that the
+-- ScyllaDB backend uses TTLs.
+CREATE TABLE IF NOT EXISTS layers (
+    project_id BLOB NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    id BLOB NOT NULL,
+
+    status TEXT NOT NULL,
+
+    build_worker_id BLOB,
+    build_worker_expires_at INTEGER,
+    finalize_worker_id BLOB,
+    finalize_worker_expires_at INTEGER,
+
+    source_git_commit TEXT,
+
+    PRIMARY KEY (project_id, layer_set_name, id)
+);
+
+CREATE INDEX IF NOT EXISTS layers_status ON layers (status);
+
+CREATE TABLE IF NOT EXISTS layer_changes (
+    project_id BLOB NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    layer_id BLOB NOT NULL,
+    worker_id BLOB NOT NULL,
+
+    path TEXT NOT NULL,
+    checksum BLOB NOT NULL,
+    content_encoding_hint INTEGER NOT NULL,
+    headers BLOB NOT NULL,
+
+    PRIMARY KEY (project_id, layer_set_name, layer_id, worker_id, path)
+);
+
+CREATE TABLE IF NOT EXISTS layer_members (
+    project_id BLOB NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    layer_id BLOB NOT NULL,
+
+    checksum BLOB NOT NULL,
+    content_encoding_hint INTEGER NOT NULL,
+    headers BLOB NOT NULL,
+
+    PRIMARY KEY (project_id, layer_set_name, path, layer_id)
+);
+
+CREATE TABLE IF NOT EXISTS files (
+    project_id BLOB NOT NULL,
+    checksum BLOB NOT NULL,
+
+    length INTEGER NOT NULL,
+
+    PRIMARY KEY (project_id, checksum)
+);
+
+CREATE TABLE IF NOT EXISTS file_blobs (
+    project_id BLOB NOT NULL,
+    checksum BLOB NOT NULL,
+    offset INTEGER NOT NULL,
+
+    blob_checksum BLOB NOT NULL,
+    blob_length INTEGER NOT NULL,
+
+    PRIMARY KEY (project_id, checksum, offset)
+);
+
+-- The data of blobs is kept in the blob store.
+CREATE TABLE IF NOT EXISTS blobs (
+    project_id BLOB NOT NULL,
+    checksum BLOB NOT NULL,
+
+    length INTEGER NOT NULL,
+    compression TEXT NOT NULL,
+    key_version INTEGER NOT NULL,
+
+    PRIMARY KEY (project_id, checksum)
+);
+
+CREATE TABLE IF NOT EXISTS domains (
+    fqdn TEXT NOT NULL,
+    project_id BLOB NOT NULL,
+    kind TEXT NOT NULL,
+
+    layer_set_name TEXT,
+    layer_id BLOB,
+
+    PRIMARY KEY (fqdn)
+);
+
+CREATE TABLE IF NOT EXISTS certificates (
+    project_id BLOB NOT NULL,
+    name TEXT NOT NULL,
+
+    domains BLOB NOT NULL,
+
+    next_processing_time INTEGER,
+
+    keys BLOB,
+
+    acme_order_url TEXT,
+    csr BLOB,
+    csr_secret_key BLOB,
+
+    PRIMARY KEY (project_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS certificates_next_processing_time
+    ON certificates (next_processing_time);
+
+CREATE TABLE IF NOT EXISTS validated_domains (
+    fqdn TEXT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (fqdn)
+);
+
+CREATE TABLE IF NOT EXISTS acme_challenges (
+    acme_dns_challenge_label TEXT NOT NULL,
+    dns_01_token TEXT NOT NULL,
+    project_id BLOB NOT NULL,
+    certificate_name TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (acme_dns_challenge_label, dns_01_token)
+);
+
+CREATE TABLE IF NOT EXISTS build_queue_messages (
+    id BLOB NOT NULL,
+
+    worker_id BLOB,
+    worker_expires_at INTEGER,
+
+    project_id BLOB NOT NULL,
+    layer_set_name TEXT NOT NULL,
+    layer_id BLOB NOT NULL,
+
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE sources ADD COLUMN git_known_host_keys BLOB;
+ALTER TABLE sources ADD COLUMN git_host_key_mismatch TEXT;
@@ -0,0 +1 @@
+ALTER TABLE sources ADD COLUMN git_http_token TEXT;
@@ -0,0 +1,10 @@
+ALTER TABLE layer_sets ADD COLUMN template_name TEXT;
+ALTER TABLE layer_sets ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE ref_layer_set_limits (
+    project_id BLOB NOT NULL,
+
+    max_layer_sets INTEGER NOT NULL,
+
+    PRIMARY KEY (project_id)
+);
@@ -0,0 +1,4 @@
+-- Files and blobs that were stored before this migration count as stored long ago, garbage
+-- collection may delete them as soon as no layer refers to them.
+ALTER TABLE files ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE blobs ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
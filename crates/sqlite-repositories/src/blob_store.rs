@@ -0,0 +1,111 @@
+use anyhow::{ensure, Result};
+use std::{io::ErrorKind, path::PathBuf};
+use tokio::fs;
+
+use fairing_core2::repositories::BlobStore;
+
+/// Keeps blobs as files in a directory, one file per key.
+#[derive(Clone, Debug)]
+pub struct LocalBlobStore {
+    location: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub async fn open(location: impl Into<PathBuf>) -> Result<LocalBlobStore> {
+        let location = location.into();
+
+        fs::create_dir_all(&location).await?;
+
+        Ok(LocalBlobStore { location })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let valid_key = !key.is_empty()
+            && key.split('/').all(|part| {
+                !part.is_empty()
+                    && part != "."
+                    && part != ".."
+                    && part
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+            });
+
+        ensure!(valid_key, "invalid blob key: {key:?}");
+
+        Ok(self.location.join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Write to a temporary file first, so that readers never see a partially written blob.
+        let mut temp_path = path.clone();
+        temp_path.set_file_name(format!(".{}", uuid::Uuid::new_v4()));
+
+        fs::write(&temp_path, data).await?;
+        fs::rename(&temp_path, &path).await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn put_get_and_delete_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path()).await.unwrap();
+
+        assert_eq!(blob_store.get("blobs/a/1").await.unwrap(), None);
+
+        blob_store
+            .put("blobs/a/1", b"hello world".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            blob_store.get("blobs/a/1").await.unwrap(),
+            Some(b"hello world".to_vec())
+        );
+
+        blob_store.delete("blobs/a/1").await.unwrap();
+        assert_eq!(blob_store.get("blobs/a/1").await.unwrap(), None);
+        blob_store.delete("blobs/a/1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_cannot_escape_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = LocalBlobStore::open(dir.path().join("blobs"))
+            .await
+            .unwrap();
+
+        for key in ["../a", "blobs/../../a", "/a", "a//b", "a/./b", ""] {
+            assert!(blob_store.put(key, vec![]).await.is_err(), "{key}");
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::DomainRepository};
+
+use crate::{time, SqliteRepository};
+
+#[derive(Debug, FromRow)]
+struct Domain {
+    fqdn: String,
+    project_id: Uuid,
+    kind: String,
+    layer_set_name: Option<String>,
+    layer_id: Option<Uuid>,
+}
+
+impl Into<models::Domain> for Domain {
+    fn into(self) -> models::Domain {
+        let kind = match (self.kind.as_str(), self.layer_set_name, self.layer_id) {
+            ("layer", Some(layer_set_name), Some(layer_id)) => models::DomainKind::Layer {
+                layer_set_name: layer_set_name.parse().unwrap(),
+                layer_id: layer_id.into(),
+            },
+            ("layer_set", Some(layer_set_name), None) => models::DomainKind::LayerSet {
+                layer_set_name: layer_set_name.parse().unwrap(),
+            },
+            ("wildcard_private", _, _) => models::DomainKind::WildCard {
+                kind: models::WildCardKind::Private,
+            },
+            ("wildcard_public", _, _) => models::DomainKind::WildCard {
+                kind: models::WildCardKind::Public,
+            },
+            _ => unreachable!("unknown domain kind"),
+        };
+
+        models::Domain {
+            project_id: self.project_id.into(),
+            fqdn: self.fqdn,
+            kind,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct Certificate {
+    project_id: Uuid,
+    name: String,
+    domains: Vec<u8>,
+}
+
+impl Into<models::Certificate> for Certificate {
+    fn into(self) -> models::Certificate {
+        let (domain_names, _): (Vec<String>, _) =
+            bincode::decode_from_slice(&self.domains, bincode::config::standard()).unwrap();
+
+        let domain_names = domain_names
+            .into_iter()
+            .map(|domain_name| domain_name.parse().unwrap())
+            .collect();
+
+        models::Certificate {
+            project_id: self.project_id.into(),
+            name: self.name,
+            domain_names,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct CertificateRenewal {
+    acme_order_url: Option<String>,
+    csr: Option<Vec<u8>>,
+    csr_secret_key: Option<Vec<u8>>,
+}
+
+impl CertificateRenewal {
+    fn into_model(self) -> Option<models::CertificateRenewal> {
+        match self {
+            CertificateRenewal {
+                acme_order_url: Some(acme_order_url),
+                csr: Some(csr),
+                csr_secret_key: Some(csr_secret_key),
+            } => Some(models::CertificateRenewal {
+                acme_order_url,
+                csr,
+                csr_secret_key,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct QueuedCertificate {
+    project_id: Uuid,
+    name: String,
+}
+
+impl Into<models::QueuedCertificate> for QueuedCertificate {
+    fn into(self) -> models::QueuedCertificate {
+        models::QueuedCertificate {
+            project_id: self.project_id.into(),
+            name: self.name,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct ValidatedDomain {
+    fqdn: String,
+    data: Vec<u8>,
+}
+
+impl Into<models::ValidatedDomain> for ValidatedDomain {
+    fn into(self) -> models::ValidatedDomain {
+        let (data, _) =
+            bincode::decode_from_slice(&self.data, bincode::config::standard()).unwrap();
+
+        models::ValidatedDomain {
+            fqdn: self.fqdn,
+            data,
+        }
+    }
+}
+
+fn domain_kind_to_str(kind: &models::DomainKind) -> &'static str {
+    match kind {
+        models::DomainKind::Layer { .. } => "layer",
+        models::DomainKind::LayerSet { .. } => "layer_set",
+        models::DomainKind::WildCard {
+            kind: models::WildCardKind::Private,
+        } => "wildcard_private",
+        models::DomainKind::WildCard {
+            kind: models::WildCardKind::Public,
+        } => "wildcard_public",
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainRepository for SqliteRepository {
+    async fn create_domain(&self, domain: models::Domain) -> Result<()> {
+        let (layer_set_name, layer_id) = match domain.kind {
+            models::DomainKind::Layer {
+                ref layer_set_name,
+                layer_id,
+            } => (Some(layer_set_name.as_str()), Some(layer_id.into_uuid())),
+            models::DomainKind::LayerSet { ref layer_set_name } => {
+                (Some(layer_set_name.as_str()), None)
+            }
+            models::DomainKind::WildCard { .. } => (None, None),
+        };
+
+        sqlx::query(
+            r"
+            INSERT INTO domains (fqdn, project_id, kind, layer_set_name, layer_id)
+            VALUES (?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(&domain.fqdn)
+        .bind(domain.project_id.into_uuid())
+        .bind(domain_kind_to_str(&domain.kind))
+        .bind(layer_set_name)
+        .bind(layer_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_domain(&self, fqdn: &str) -> Result<Option<models::Domain>> {
+        let domain = sqlx::query_as::<_, Domain>(
+            r"
+            SELECT fqdn, project_id, kind, layer_set_name, layer_id
+            FROM domains
+            WHERE fqdn = ?;
+            ",
+        )
+        .bind(fqdn)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(domain)
+    }
+
+    async fn list_domains(&self, project_id: models::ProjectId) -> Result<Vec<models::Domain>> {
+        let domains = sqlx::query_as::<_, Domain>(
+            r"
+            SELECT fqdn, project_id, kind, layer_set_name, layer_id
+            FROM domains
+            WHERE project_id = ?
+            ORDER BY fqdn;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(domains)
+    }
+
+    async fn delete_domain(&self, fqdn: &str) -> Result<()> {
+        sqlx::query(
+            r"
+            DELETE FROM domains
+            WHERE fqdn = ?;
+            ",
+        )
+        .bind(fqdn)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn create_certificate(
+        &self,
+        certificate: &models::Certificate,
+        queue_timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let domain_names = certificate
+            .domain_names
+            .iter()
+            .map(|domain_name| domain_name.to_fqdn())
+            .collect::<Vec<_>>();
+
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO certificates (
+                project_id, name, domains, next_processing_time
+            )
+            VALUES (?, ?, ?, ?);
+            ",
+        )
+        .bind(certificate.project_id.into_uuid())
+        .bind(&certificate.name)
+        .bind(bincode::encode_to_vec(
+            &domain_names,
+            bincode::config::standard(),
+        )?)
+        .bind(time::to_timestamp(&queue_timestamp))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_certificate(
+        &self,
+        project_id: models::ProjectId,
+        name: &str,
+    ) -> Result<Option<models::Certificate>> {
+        let certificate = sqlx::query_as::<_, Certificate>(
+            r"
+            SELECT project_id, name, domains
+            FROM certificates
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(certificate)
+    }
+
+    async fn update_certificate(
+        &self,
+        project_id: models::ProjectId,
+        name: &str,
+        keys: &models::CertificateKeys,
+    ) -> Result<()> {
+        sqlx::query(
+            r"
+            UPDATE certificates
+            SET keys = ?
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(bincode::encode_to_vec(keys, bincode::config::standard())?)
+        .bind(project_id.into_uuid())
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn process_certificate(
+        &self,
+        project_id: models::ProjectId,
+        certificate_name: &str,
+        current_timestamp: DateTime<Utc>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<models::CertificateRenewal>> {
+        let certificate_renewal = sqlx::query_as::<_, CertificateRenewal>(
+            r"
+            UPDATE certificates
+            SET next_processing_time = ?
+            WHERE project_id = ? AND name = ? AND next_processing_time = ?
+            RETURNING acme_order_url, csr, csr_secret_key;
+            ",
+        )
+        .bind(time::to_timestamp(&timestamp))
+        .bind(project_id.into_uuid())
+        .bind(certificate_name)
+        .bind(time::to_timestamp(&current_timestamp))
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| anyhow!("certificate is being processed by someone else"))?;
+
+        Ok(certificate_renewal.into_model())
+    }
+
+    async fn update_certificate_renewal(
+        &self,
+        project_id: models::ProjectId,
+        certificate_name: &str,
+        certificate_renewal: Option<models::CertificateRenewal>,
+        current_timestamp: DateTime<Utc>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let (acme_order_url, csr, csr_secret_key) = match certificate_renewal {
+            Some(models::CertificateRenewal {
+                acme_order_url,
+                csr,
+                csr_secret_key,
+            }) => (Some(acme_order_url), Some(csr), Some(csr_secret_key)),
+            None => (None, None, None),
+        };
+
+        let result = sqlx::query(
+            r"
+            UPDATE certificates
+            SET acme_order_url = ?, csr = ?, csr_secret_key = ?, next_processing_time = ?
+            WHERE project_id = ? AND name = ? AND next_processing_time = ?;
+            ",
+        )
+        .bind(acme_order_url)
+        .bind(csr)
+        .bind(csr_secret_key)
+        .bind(time::to_timestamp(&timestamp))
+        .bind(project_id.into_uuid())
+        .bind(certificate_name)
+        .bind(time::to_timestamp(&current_timestamp))
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "certificate is being processed by someone else"
+        );
+
+        Ok(())
+    }
+
+    async fn get_queued_certificates(
+        &self,
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<Vec<models::QueuedCertificate>> {
+        let mut queued_certificates = vec![];
+
+        for timestamp in timestamps {
+            let certificates = sqlx::query_as::<_, QueuedCertificate>(
+                r"
+                SELECT project_id, name
+                FROM certificates
+                WHERE next_processing_time = ?;
+                ",
+            )
+            .bind(time::to_timestamp(timestamp))
+            .fetch_all(&self.pool)
+            .await?;
+
+            queued_certificates.extend(certificates.into_iter().map(Into::into));
+        }
+
+        Ok(queued_certificates)
+    }
+
+    async fn create_acme_challenge(&self, challenge: models::AcmeChallenge) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO acme_challenges (
+                acme_dns_challenge_label, dns_01_token, project_id, certificate_name, expires_at
+            )
+            VALUES (?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(&challenge.acme_dns_challenge_label)
+        .bind(&challenge.dns_01_token)
+        .bind(challenge.project_id.into_uuid())
+        .bind(&challenge.certificate_name)
+        .bind(time::expires_in(challenge.ttl.num_seconds()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_acme_dns_01_challenges(
+        &self,
+        acme_dns_challenge_label: &str,
+    ) -> Result<Vec<String>> {
+        let dns_01_tokens = sqlx::query_scalar::<_, String>(
+            r"
+            SELECT dns_01_token
+            FROM acme_challenges
+            WHERE acme_dns_challenge_label = ? AND expires_at > ?;
+            ",
+        )
+        .bind(acme_dns_challenge_label)
+        .bind(time::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(dns_01_tokens)
+    }
+
+    async fn create_validated_domain(
+        &self,
+        validated_domain: &models::ValidatedDomain,
+    ) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO validated_domains (fqdn, data)
+            VALUES (?, ?);
+            ",
+        )
+        .bind(&validated_domain.fqdn)
+        .bind(bincode::encode_to_vec(
+            &validated_domain.data,
+            bincode::config::standard(),
+        )?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_validated_domain(&self, fqdn: &str) -> Result<Option<models::ValidatedDomain>> {
+        let validated_domain = sqlx::query_as::<_, ValidatedDomain>(
+            r"
+            SELECT fqdn, data
+            FROM validated_domains
+            WHERE fqdn = ?;
+            ",
+        )
+        .bind(fqdn)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(validated_domain)
+    }
+}
@@ -0,0 +1,732 @@
+use anyhow::{anyhow, ensure, Result};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use fairing_core2::{
+    models,
+    repositories::{FileRepository, ProjectRepository},
+};
+
+use crate::{time, SqliteRepository};
+
+#[derive(Debug, FromRow)]
+struct File {
+    project_id: Uuid,
+    checksum: Vec<u8>,
+    length: i64,
+    created_at: i64,
+}
+
+impl Into<models::File> for File {
+    fn into(self) -> models::File {
+        models::File {
+            project_id: self.project_id.into(),
+            checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
+            length: self.length as u64,
+            created_at: time::from_timestamp(self.created_at),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct FileBlob {
+    length: i64,
+    offset: i64,
+    blob_checksum: Vec<u8>,
+    blob_length: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct StoredFileBlob {
+    offset: i64,
+    blob_checksum: Vec<u8>,
+    blob_length: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct Blob {
+    length: i64,
+    compression: String,
+    key_version: i64,
+    created_at: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct BlobWithChecksum {
+    checksum: Vec<u8>,
+    length: i64,
+    compression: String,
+    created_at: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct BlobKeyVersion {
+    checksum: Vec<u8>,
+    key_version: i64,
+}
+
+fn compression_to_str(compression: models::BlobCompression) -> &'static str {
+    match compression {
+        models::BlobCompression::Identity => "identity",
+        models::BlobCompression::Zstd => "zstd",
+    }
+}
+
+fn compression_from_str(compression: &str) -> models::BlobCompression {
+    match compression {
+        "identity" => models::BlobCompression::Identity,
+        "zstd" => models::BlobCompression::Zstd,
+        _ => unreachable!("unknown blob compression"),
+    }
+}
+
+/// Key of a blob in the blob store. The key version is part of the key so that re-encrypting a
+/// blob never overwrites data that the stored metadata still refers to.
+fn blob_store_key(
+    project_id: models::ProjectId,
+    checksum: &models::BlobChecksum,
+    key_version: i64,
+) -> String {
+    format!(
+        "blobs/{}/{}/{key_version}",
+        project_id.into_uuid().as_hyphenated(),
+        checksum.to_hex()
+    )
+}
+
+#[async_trait::async_trait]
+impl FileRepository for SqliteRepository {
+    async fn get_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Option<models::File>> {
+        let file = sqlx::query_as::<_, File>(
+            r"
+            SELECT project_id, checksum, length, created_at
+            FROM files
+            WHERE project_id = ? AND checksum = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(file)
+    }
+
+    async fn list_files(&self, project_id: models::ProjectId) -> Result<Vec<models::File>> {
+        let files = sqlx::query_as::<_, File>(
+            r"
+            SELECT project_id, checksum, length, created_at
+            FROM files
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(files)
+    }
+
+    async fn create_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+        length: u64,
+        blobs: &[models::FileBlob],
+    ) -> Result<()> {
+        let blobs_length = blobs.iter().map(|blob| blob.length).sum::<u64>();
+        ensure!(
+            blobs_length == length,
+            "file blobs must cover the whole file"
+        );
+
+        let encoded_checksum = checksum.encode();
+        let mut transaction = self.pool.begin().await?;
+
+        for blob in blobs {
+            sqlx::query(
+                r"
+                INSERT OR REPLACE INTO file_blobs (
+                    project_id, checksum, offset, blob_checksum, blob_length
+                )
+                VALUES (?, ?, ?, ?, ?);
+                ",
+            )
+            .bind(project_id.into_uuid())
+            .bind(&encoded_checksum)
+            .bind(blob.offset as i64)
+            .bind(blob.checksum.encode())
+            .bind(blob.length as i64)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO files (project_id, checksum, length, created_at)
+            VALUES (?, ?, ?, ?);
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(&encoded_checksum)
+        .bind(length as i64)
+        .bind(time::now())
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_file_chunks(
+        &self,
+        project_id: models::ProjectId,
+        checksum: models::FileChecksum,
+        (range_start, range_end): (u64, u64),
+    ) -> Result<Vec<models::FileChunk>> {
+        let project = self.get_project_for_files(project_id).await?;
+
+        let file_blobs = sqlx::query_as::<_, FileBlob>(
+            r"
+            SELECT files.length, file_blobs.offset, file_blobs.blob_checksum,
+                file_blobs.blob_length
+            FROM files
+            JOIN file_blobs ON file_blobs.project_id = files.project_id
+                AND file_blobs.checksum = files.checksum
+            WHERE files.project_id = ? AND files.checksum = ?
+                AND file_blobs.offset >= ? AND file_blobs.offset < ?
+            ORDER BY file_blobs.offset;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .bind(range_start as i64)
+        .bind(range_end as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut chunks = Vec::with_capacity(file_blobs.len());
+
+        for file_blob in file_blobs {
+            let blob_checksum = models::BlobChecksum::decode(&file_blob.blob_checksum)?;
+
+            let blob = sqlx::query_as::<_, Blob>(
+                r"
+                SELECT length, compression, key_version, created_at
+                FROM blobs
+                WHERE project_id = ? AND checksum = ?;
+                ",
+            )
+            .bind(project_id.into_uuid())
+            .bind(&file_blob.blob_checksum)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow!("blob {} not found", blob_checksum.to_hex()))?;
+
+            let data = self
+                .read_blob(&project, &blob_checksum, blob.key_version)
+                .await?;
+            let data = compression_from_str(&blob.compression).decompress(data)?;
+
+            ensure!(
+                data.len() as i64 == file_blob.blob_length,
+                "blob {} has the wrong length",
+                blob_checksum.to_hex()
+            );
+
+            chunks.push(models::FileChunk {
+                total_length: file_blob.length as u64,
+                offset: file_blob.offset as u64,
+                data,
+            });
+        }
+
+        Ok(chunks)
+    }
+
+    async fn list_file_blobs(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<Vec<models::FileBlob>> {
+        let file_blobs = sqlx::query_as::<_, StoredFileBlob>(
+            r"
+            SELECT offset, blob_checksum, blob_length
+            FROM file_blobs
+            WHERE project_id = ? AND checksum = ?
+            ORDER BY offset;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .fetch_all(&self.pool)
+        .await?;
+
+        file_blobs
+            .into_iter()
+            .map(|file_blob| {
+                Ok(models::FileBlob {
+                    offset: file_blob.offset as u64,
+                    checksum: models::BlobChecksum::decode(&file_blob.blob_checksum)?,
+                    length: file_blob.blob_length as u64,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_file(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::FileChecksum,
+    ) -> Result<()> {
+        let encoded_checksum = checksum.encode();
+        let mut transaction = self.pool.begin().await?;
+
+        for table in ["files", "file_blobs"] {
+            sqlx::query(&format!(
+                r"
+                DELETE FROM {table}
+                WHERE project_id = ? AND checksum = ?;
+                ",
+            ))
+            .bind(project_id.into_uuid())
+            .bind(&encoded_checksum)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<Option<models::Blob>> {
+        let blob = sqlx::query_as::<_, Blob>(
+            r"
+            SELECT length, compression, key_version, created_at
+            FROM blobs
+            WHERE project_id = ? AND checksum = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|blob| models::Blob {
+            project_id,
+            checksum: *checksum,
+            length: blob.length as u64,
+            compression: compression_from_str(&blob.compression),
+            created_at: time::from_timestamp(blob.created_at),
+        });
+
+        Ok(blob)
+    }
+
+    async fn create_blob(
+        &self,
+        project_id: models::ProjectId,
+        blob: &models::CreateBlob,
+    ) -> Result<()> {
+        let project = self.get_project_for_files(project_id).await?;
+        let key = project
+            .current_file_encryption_key()
+            .ok_or_else(|| anyhow!("project has no file encryption key"))?;
+
+        let associated_data =
+            models::FileEncryptionKey::blob_associated_data(project_id, &blob.checksum);
+        let data = key.encrypt(&associated_data, &blob.data)?;
+
+        // The data must be stored before the metadata, otherwise a reader could find a blob
+        // without any data.
+        let blob_key = blob_store_key(project_id, &blob.checksum, key.version as i64);
+        self.blob_store.put(&blob_key, data).await?;
+
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO blobs (
+                project_id, checksum, length, compression, key_version, created_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(blob.checksum.encode())
+        .bind(blob.length as i64)
+        .bind(compression_to_str(blob.compression))
+        .bind(key.version as i64)
+        .bind(time::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_blobs(&self, project_id: models::ProjectId) -> Result<Vec<models::Blob>> {
+        let blobs = sqlx::query_as::<_, BlobWithChecksum>(
+            r"
+            SELECT checksum, length, compression, created_at
+            FROM blobs
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?;
+
+        blobs
+            .into_iter()
+            .map(|blob| {
+                Ok(models::Blob {
+                    project_id,
+                    checksum: models::BlobChecksum::decode(&blob.checksum)?,
+                    length: blob.length as u64,
+                    compression: compression_from_str(&blob.compression),
+                    created_at: time::from_timestamp(blob.created_at),
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_blob(
+        &self,
+        project_id: models::ProjectId,
+        checksum: &models::BlobChecksum,
+    ) -> Result<()> {
+        let key_version = sqlx::query_scalar::<_, i64>(
+            r"
+            SELECT key_version
+            FROM blobs
+            WHERE project_id = ? AND checksum = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r"
+            DELETE FROM blobs
+            WHERE project_id = ? AND checksum = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(checksum.encode())
+        .execute(&self.pool)
+        .await?;
+
+        // The metadata is deleted first, so that no reader finds a blob without any data.
+        if let Some(key_version) = key_version {
+            let blob_key = blob_store_key(project_id, checksum, key_version);
+            self.blob_store.delete(&blob_key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reencrypt_blobs(&self, project_id: models::ProjectId, limit: usize) -> Result<usize> {
+        let project = self.get_project_for_files(project_id).await?;
+        let key = project
+            .current_file_encryption_key()
+            .ok_or_else(|| anyhow!("project has no file encryption key"))?;
+
+        let blobs = sqlx::query_as::<_, BlobKeyVersion>(
+            r"
+            SELECT checksum, key_version
+            FROM blobs
+            WHERE project_id = ? AND key_version != ?
+            LIMIT ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(key.version as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reencrypted_blobs = 0;
+
+        for blob in blobs {
+            let checksum = models::BlobChecksum::decode(&blob.checksum)?;
+
+            let data = self
+                .read_blob(&project, &checksum, blob.key_version)
+                .await?;
+
+            let associated_data =
+                models::FileEncryptionKey::blob_associated_data(project_id, &checksum);
+            let data = key.encrypt(&associated_data, &data)?;
+
+            let blob_key = blob_store_key(project_id, &checksum, key.version as i64);
+            self.blob_store.put(&blob_key, data).await?;
+
+            let result = sqlx::query(
+                r"
+                UPDATE blobs
+                SET key_version = ?
+                WHERE project_id = ? AND checksum = ? AND key_version = ?;
+                ",
+            )
+            .bind(key.version as i64)
+            .bind(project_id.into_uuid())
+            .bind(&blob.checksum)
+            .bind(blob.key_version)
+            .execute(&self.pool)
+            .await?;
+
+            // Another worker got here first, which is fine as long as the blob was re-encrypted.
+            if result.rows_affected() == 1 {
+                let blob_key = blob_store_key(project_id, &checksum, blob.key_version);
+                self.blob_store.delete(&blob_key).await?;
+
+                reencrypted_blobs += 1;
+            }
+        }
+
+        Ok(reencrypted_blobs)
+    }
+
+    async fn list_blob_key_versions(&self, project_id: models::ProjectId) -> Result<Vec<u32>> {
+        let key_versions = sqlx::query_scalar::<_, i64>(
+            r"
+            SELECT DISTINCT key_version
+            FROM blobs
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|key_version| key_version as u32)
+        .collect();
+
+        Ok(key_versions)
+    }
+}
+
+impl SqliteRepository {
+    /// Reads and decrypts a blob from the blob store, the data is still compressed.
+    async fn read_blob(
+        &self,
+        project: &models::Project,
+        checksum: &models::BlobChecksum,
+        key_version: i64,
+    ) -> Result<Vec<u8>> {
+        let key = project
+            .file_encryption_key(key_version as u32)
+            .ok_or_else(|| anyhow!("file encryption key {key_version} not found"))?;
+
+        let blob_key = blob_store_key(project.id, checksum, key_version);
+        let data = self
+            .blob_store
+            .get(&blob_key)
+            .await?
+            .ok_or_else(|| anyhow!("blob {} not found in blob store", checksum.to_hex()))?;
+
+        let associated_data = models::FileEncryptionKey::blob_associated_data(project.id, checksum);
+        key.decrypt(&associated_data, &data)
+    }
+
+    async fn get_project_for_files(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<models::Project> {
+        self.get_project(&project_id)
+            .await?
+            .ok_or_else(|| anyhow!("project not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn repository_with_project(
+        dir: &tempfile::TempDir,
+    ) -> (SqliteRepository, models::ProjectId) {
+        let repository = SqliteRepository::open(dir.path()).await.unwrap();
+
+        let project = models::Project {
+            id: Uuid::new_v4().into(),
+            acme_dns_challenge_label: "test".into(),
+            file_encryption_keys: vec![models::FileEncryptionKey::generate(1).unwrap()],
+        };
+        repository.create_or_update_project(&project).await.unwrap();
+
+        (repository, project.id)
+    }
+
+    async fn create_file(
+        repository: &SqliteRepository,
+        project_id: models::ProjectId,
+        parts: &[&[u8]],
+    ) -> models::FileChecksum {
+        let mut hasher = models::FileChecksum::blake2b_hasher(project_id);
+        let mut blobs = vec![];
+        let mut offset = 0;
+
+        for part in parts {
+            hasher.update(part);
+
+            let checksum = models::BlobChecksum::blake2b(project_id, part);
+            let (compression, data) = models::BlobCompression::compress(part).unwrap();
+
+            repository
+                .create_blob(
+                    project_id,
+                    &models::CreateBlob {
+                        checksum,
+                        length: part.len() as u64,
+                        compression,
+                        data,
+                    },
+                )
+                .await
+                .unwrap();
+
+            blobs.push(models::FileBlob {
+                offset,
+                checksum,
+                length: part.len() as u64,
+            });
+            offset += part.len() as u64;
+        }
+
+        let checksum = hasher.finalize();
+        repository
+            .create_file(project_id, &checksum, offset, &blobs)
+            .await
+            .unwrap();
+
+        checksum
+    }
+
+    async fn read_file(
+        repository: &SqliteRepository,
+        project_id: models::ProjectId,
+        checksum: models::FileChecksum,
+    ) -> Vec<u8> {
+        repository
+            .get_file_chunks(project_id, checksum, (0, u64::MAX >> 1))
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|chunk| chunk.data)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn create_and_read_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repository, project_id) = repository_with_project(&dir).await;
+
+        let repeated = b"hello world ".repeat(1024);
+        let checksum = create_file(&repository, project_id, &[b"first", &repeated]).await;
+
+        let file = repository
+            .get_file(project_id, &checksum)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.length, 5 + repeated.len() as u64);
+
+        let mut expected = b"first".to_vec();
+        expected.extend_from_slice(&repeated);
+        assert_eq!(read_file(&repository, project_id, checksum).await, expected);
+
+        let chunks = repository
+            .get_file_chunks(project_id, checksum, (5, 6))
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].offset, 5);
+        assert_eq!(chunks[0].total_length, file.length);
+    }
+
+    #[tokio::test]
+    async fn reencrypt_blobs_after_key_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repository, project_id) = repository_with_project(&dir).await;
+
+        let checksum = create_file(&repository, project_id, &[b"first", b"second"]).await;
+
+        repository
+            .create_file_encryption_key(
+                project_id,
+                &models::FileEncryptionKey::generate(2).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(repository.reencrypt_blobs(project_id, 1).await.unwrap(), 1);
+        assert_eq!(repository.reencrypt_blobs(project_id, 10).await.unwrap(), 1);
+        assert_eq!(repository.reencrypt_blobs(project_id, 10).await.unwrap(), 0);
+
+        repository
+            .delete_file_encryption_keys(project_id, &[1])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            read_file(&repository, project_id, checksum).await,
+            b"firstsecond"
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_files_and_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repository, project_id) = repository_with_project(&dir).await;
+
+        let checksum = create_file(&repository, project_id, &[b"first", b"second"]).await;
+
+        let file_blobs = repository
+            .list_file_blobs(project_id, &checksum)
+            .await
+            .unwrap();
+        assert_eq!(file_blobs.len(), 2);
+        assert_eq!(file_blobs[1].offset, 5);
+
+        repository.delete_file(project_id, &checksum).await.unwrap();
+        assert!(repository.list_files(project_id).await.unwrap().is_empty());
+        assert!(repository
+            .list_file_blobs(project_id, &checksum)
+            .await
+            .unwrap()
+            .is_empty());
+
+        for file_blob in &file_blobs {
+            repository
+                .delete_blob(project_id, &file_blob.checksum)
+                .await
+                .unwrap();
+        }
+
+        assert!(repository.list_blobs(project_id).await.unwrap().is_empty());
+
+        for file_blob in &file_blobs {
+            let blob_key = blob_store_key(project_id, &file_blob.checksum, 1);
+            assert!(repository
+                .blob_store
+                .get(&blob_key)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+}
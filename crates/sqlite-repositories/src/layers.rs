@@ -0,0 +1,1091 @@
+use anyhow::{anyhow, ensure, Result};
+use sqlx::FromRow;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use fairing_core2::{
+    models,
+    repositories::{LayerPendingLayersFilter, LayerRepository},
+};
+
+use crate::{time, SqliteRepository};
+
+/// How long a build worker may hold a layer before another worker can take over.
+const BUILD_TIMEOUT_SECONDS: i64 = 300;
+
+/// How long a finalizing worker may hold a layer before another worker can take over.
+const FINALIZE_TIMEOUT_SECONDS: i64 = 60;
+
+#[derive(Debug, FromRow)]
+struct LayerSet {
+    project_id: Uuid,
+    name: String,
+    visibility: String,
+
+    source_name: Option<String>,
+    source_git_ref: Option<String>,
+    source_git_root: Option<String>,
+    source_git_paths: Option<Vec<u8>>,
+
+    build_current_layer_id: Option<Uuid>,
+    build_last_layer_id: Option<Uuid>,
+
+    pinned_layer_id: Option<Uuid>,
+
+    template_name: Option<String>,
+    archived: bool,
+}
+
+impl Into<models::LayerSet> for LayerSet {
+    fn into(self) -> models::LayerSet {
+        let visibility = match self.visibility.as_str() {
+            "private" => models::LayerSetVisibility::Private,
+            "public" => models::LayerSetVisibility::Public,
+            _ => unreachable!("unknown visibility kind"),
+        };
+
+        let source = match self {
+            LayerSet {
+                source_name: Some(ref name),
+                source_git_ref: Some(ref ref_),
+                ref source_git_root,
+                ref source_git_paths,
+                ..
+            } => {
+                let (paths, _) = source_git_paths
+                    .as_ref()
+                    .map(|paths| {
+                        bincode::decode_from_slice(paths, bincode::config::standard()).unwrap()
+                    })
+                    .unwrap_or_default();
+
+                Some(models::LayerSetSource {
+                    name: name.parse().unwrap(),
+                    kind: models::LayerSetSourceKind::Git {
+                        ref_: ref_.clone(),
+                        root: source_git_root.clone().unwrap_or_default(),
+                        paths,
+                    },
+                })
+            }
+            LayerSet {
+                source_name: None, ..
+            } => None,
+            _ => unreachable!("unknown source kind"),
+        };
+
+        models::LayerSet {
+            project_id: self.project_id.into(),
+            name: self.name.parse().unwrap(),
+            visibility,
+            source,
+            build_status: models::LayerSetBuildStatus {
+                current_layer_id: self.build_current_layer_id.map(Into::into),
+                last_layer_id: self.build_last_layer_id.map(Into::into),
+            },
+            pinned_layer_id: self.pinned_layer_id.map(Into::into),
+            template_name: self.template_name.map(|name| name.parse().unwrap()),
+            archived: self.archived,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct Layer {
+    project_id: Uuid,
+    layer_set_name: String,
+    id: Uuid,
+    status: String,
+    source_git_commit: Option<String>,
+    source_layer_set_name: Option<String>,
+    source_layer_id: Option<Uuid>,
+}
+
+impl Into<models::Layer> for Layer {
+    fn into(self) -> models::Layer {
+        let status = match self.status.as_str() {
+            "building" => models::LayerStatus::Building,
+            "finalizing" => models::LayerStatus::Finalizing,
+            "ready" => models::LayerStatus::Ready,
+            "cancelled" => models::LayerStatus::Cancelled,
+            _ => unreachable!("unknown layer status"),
+        };
+
+        let source = match self {
+            Layer {
+                source_git_commit: Some(commit),
+                ..
+            } => Some(models::LayerSource::Git { commit }),
+            Layer {
+                source_layer_set_name: Some(ref layer_set_name),
+                source_layer_id: Some(layer_id),
+                ..
+            } => Some(models::LayerSource::Promotion {
+                layer_set_name: layer_set_name.parse().unwrap(),
+                layer_id: layer_id.into(),
+            }),
+            _ => None,
+        };
+
+        models::Layer {
+            project_id: self.project_id.into(),
+            layer_set_name: self.layer_set_name.parse().unwrap(),
+            id: self.id.into(),
+            status,
+            source,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct LayerChange {
+    project_id: Uuid,
+    layer_set_name: String,
+    layer_id: Uuid,
+    worker_id: Uuid,
+    path: String,
+    checksum: Vec<u8>,
+    content_encoding_hint: i64,
+    headers: Vec<u8>,
+}
+
+impl Into<models::LayerChange> for LayerChange {
+    fn into(self) -> models::LayerChange {
+        models::LayerChange {
+            project_id: self.project_id.into(),
+            layer_set_name: self.layer_set_name.parse().unwrap(),
+            layer_id: self.layer_id.into(),
+            worker_id: self.worker_id.into(),
+            path: self.path,
+            checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
+            content_encoding_hint: models::ContentEncodingHint::decode(
+                &self.content_encoding_hint.to_le_bytes(),
+            )
+            .unwrap(),
+            headers: decode_headers(&self.headers),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct LayerMember {
+    project_id: Uuid,
+    layer_set_name: String,
+    layer_id: Uuid,
+    path: String,
+    checksum: Vec<u8>,
+    content_encoding_hint: i64,
+    headers: Vec<u8>,
+}
+
+impl Into<models::LayerMember> for LayerMember {
+    fn into(self) -> models::LayerMember {
+        models::LayerMember {
+            project_id: self.project_id.into(),
+            layer_set_name: self.layer_set_name.parse().unwrap(),
+            layer_id: self.layer_id.into(),
+            path: self.path,
+            checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
+            content_encoding_hint: models::ContentEncodingHint::decode(
+                &self.content_encoding_hint.to_le_bytes(),
+            )
+            .unwrap(),
+            headers: decode_headers(&self.headers),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct LayerMemberSummary {
+    path: String,
+    checksum: Vec<u8>,
+    content_encoding_hint: i64,
+    headers: Vec<u8>,
+}
+
+impl Into<models::LayerMemberSummary> for LayerMemberSummary {
+    fn into(self) -> models::LayerMemberSummary {
+        models::LayerMemberSummary {
+            path: self.path,
+            checksum: models::FileChecksum::decode(&self.checksum).unwrap(),
+            content_encoding_hint: models::ContentEncodingHint::decode(
+                &self.content_encoding_hint.to_le_bytes(),
+            )
+            .unwrap(),
+            headers: decode_headers(&self.headers),
+        }
+    }
+}
+
+fn encode_headers(headers: &BTreeMap<String, String>) -> Vec<u8> {
+    bincode::encode_to_vec(headers, bincode::config::standard()).unwrap()
+}
+
+fn decode_headers(headers: &[u8]) -> BTreeMap<String, String> {
+    let (headers, _) = bincode::decode_from_slice(headers, bincode::config::standard()).unwrap();
+    headers
+}
+
+fn visibility_to_str(visibility: models::LayerSetVisibility) -> &'static str {
+    match visibility {
+        models::LayerSetVisibility::Private => "private",
+        models::LayerSetVisibility::Public => "public",
+    }
+}
+
+fn layer_status_to_str(status: models::LayerStatus) -> &'static str {
+    match status {
+        models::LayerStatus::Building => "building",
+        models::LayerStatus::Finalizing => "finalizing",
+        models::LayerStatus::Ready => "ready",
+        models::LayerStatus::Cancelled => "cancelled",
+    }
+}
+
+#[async_trait::async_trait]
+impl LayerRepository for SqliteRepository {
+    async fn get_layer_set(
+        &self,
+        project_id: models::ProjectId,
+        name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSet>> {
+        let layer_set = sqlx::query_as::<_, LayerSet>(
+            r"
+            SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                template_name, archived
+            FROM layer_sets
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(name.as_str())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(layer_set)
+    }
+
+    async fn list_layer_sets(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Vec<models::LayerSet>> {
+        let layer_sets = sqlx::query_as::<_, LayerSet>(
+            r"
+            SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                template_name, archived
+            FROM layer_sets
+            WHERE project_id = ?
+            ORDER BY name;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(layer_sets)
+    }
+
+    async fn list_layer_sets_for_source(
+        &self,
+        project_id: models::ProjectId,
+        name: &models::SourceName,
+    ) -> Result<Vec<models::LayerSet>> {
+        let layer_sets = sqlx::query_as::<_, LayerSet>(
+            r"
+            SELECT project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                source_git_paths, build_current_layer_id, build_last_layer_id, pinned_layer_id,
+                template_name, archived
+            FROM layer_sets
+            WHERE project_id = ? AND source_name = ?
+            ORDER BY name;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(name.as_str())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(layer_sets)
+    }
+
+    async fn create_layer_set(&self, layer_set: &models::LayerSet) -> Result<()> {
+        let (source_name, source_git_ref, source_git_root, source_git_paths) =
+            match layer_set.source {
+                Some(models::LayerSetSource {
+                    ref name,
+                    kind:
+                        models::LayerSetSourceKind::Git {
+                            ref ref_,
+                            ref root,
+                            ref paths,
+                        },
+                }) => (
+                    Some(name.as_str()),
+                    Some(ref_.as_str()),
+                    Some(root.as_str()),
+                    Some(bincode::encode_to_vec(paths, bincode::config::standard())?),
+                ),
+                None => (None, None, None, None),
+            };
+
+        sqlx::query(
+            r"
+            INSERT INTO layer_sets (
+                project_id, name, visibility, source_name, source_git_ref, source_git_root,
+                source_git_paths, template_name, archived
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT DO NOTHING;
+            ",
+        )
+        .bind(layer_set.project_id.into_uuid())
+        .bind(layer_set.name.as_str())
+        .bind(visibility_to_str(layer_set.visibility))
+        .bind(source_name)
+        .bind(source_git_ref)
+        .bind(source_git_root)
+        .bind(source_git_paths)
+        .bind(
+            layer_set
+                .template_name
+                .as_ref()
+                .map(models::LayerSetName::as_str),
+        )
+        .bind(layer_set.archived)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::LayerSetRetention>> {
+        let keep_ready_layers = sqlx::query_scalar::<_, i64>(
+            r"
+            SELECT keep_ready_layers
+            FROM layer_set_retention
+            WHERE project_id = ? AND layer_set_name = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let retention = keep_ready_layers.map(|keep_ready_layers| models::LayerSetRetention {
+            keep_ready_layers: keep_ready_layers as u32,
+        });
+
+        Ok(retention)
+    }
+
+    async fn set_layer_set_retention(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        retention: &models::LayerSetRetention,
+    ) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO layer_set_retention (
+                project_id, layer_set_name, keep_ready_layers
+            )
+            VALUES (?, ?, ?);
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(retention.keep_ready_layers as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+    ) -> Result<Option<models::RefLayerSetLimit>> {
+        let max_layer_sets = sqlx::query_scalar::<_, i64>(
+            r"
+            SELECT max_layer_sets
+            FROM ref_layer_set_limits
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let limit = max_layer_sets.map(|max_layer_sets| models::RefLayerSetLimit {
+            max_layer_sets: max_layer_sets as u32,
+        });
+
+        Ok(limit)
+    }
+
+    async fn set_ref_layer_set_limit(
+        &self,
+        project_id: models::ProjectId,
+        limit: &models::RefLayerSetLimit,
+    ) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO ref_layer_set_limits (project_id, max_layer_sets)
+            VALUES (?, ?);
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(limit.max_layer_sets as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_layer_set_archived(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        archived: bool,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET archived = ?
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(archived)
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(result.rows_affected() == 1, "layer set not found");
+
+        Ok(())
+    }
+
+    async fn set_pinned_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: Option<models::LayerId>,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET pinned_layer_id = ?
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(layer_id.map(|layer_id| layer_id.into_uuid()))
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(result.rows_affected() == 1, "layer set not found");
+
+        Ok(())
+    }
+
+    async fn set_last_layer_id(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET last_layer_id = ?1
+            WHERE project_id = ?2 AND name = ?3
+                AND (last_layer_id IS NULL OR last_layer_id < ?1);
+            ",
+        )
+        .bind(layer_id.into_uuid())
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "new layer id is older than previous layer id"
+        );
+
+        Ok(())
+    }
+
+    async fn get_last_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Option<models::Layer>> {
+        let layer = sqlx::query_as::<_, Layer>(
+            r"
+            SELECT layers.project_id, layers.layer_set_name, layers.id, layers.status,
+                layers.source_git_commit, layers.source_layer_set_name, layers.source_layer_id
+            FROM layer_sets
+            JOIN layers ON layers.project_id = layer_sets.project_id
+                AND layers.layer_set_name = layer_sets.name
+                AND layers.id <= layer_sets.last_layer_id
+            WHERE layer_sets.project_id = ? AND layer_sets.name = ?
+            ORDER BY layers.id DESC
+            LIMIT 1;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(layer)
+    }
+
+    async fn create_layer(&self, layer: &models::Layer) -> Result<()> {
+        let (source_git_commit, source_layer_set_name, source_layer_id) = match &layer.source {
+            Some(models::LayerSource::Git { commit }) => (Some(commit.as_str()), None, None),
+            Some(models::LayerSource::Promotion {
+                layer_set_name,
+                layer_id,
+            }) => (
+                None,
+                Some(layer_set_name.as_str()),
+                Some(layer_id.into_uuid()),
+            ),
+            None => (None, None, None),
+        };
+
+        sqlx::query(
+            r"
+            INSERT OR REPLACE INTO layers (
+                project_id, layer_set_name, id, status, source_git_commit,
+                source_layer_set_name, source_layer_id
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(layer.project_id.into_uuid())
+        .bind(layer.layer_set_name.as_str())
+        .bind(layer.id.into_uuid())
+        .bind(layer_status_to_str(layer.status))
+        .bind(source_git_commit)
+        .bind(source_layer_set_name)
+        .bind(source_layer_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_layers(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Vec<models::Layer>> {
+        let layers = sqlx::query_as::<_, Layer>(
+            r"
+            SELECT project_id, layer_set_name, id, status, source_git_commit,
+                source_layer_set_name, source_layer_id
+            FROM layers
+            WHERE project_id = ? AND layer_set_name = ?
+            ORDER BY id;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(layers)
+    }
+
+    async fn delete_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for table in ["layer_members", "layer_changes"] {
+            sqlx::query(&format!(
+                r"
+                DELETE FROM {table}
+                WHERE project_id = ? AND layer_set_name = ? AND layer_id = ?;
+                ",
+            ))
+            .bind(project_id.into_uuid())
+            .bind(layer_set_name.as_str())
+            .bind(layer_id.into_uuid())
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        sqlx::query(
+            r"
+            DELETE FROM layers
+            WHERE project_id = ? AND layer_set_name = ? AND id = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_pending_layers(
+        &self,
+        filter: LayerPendingLayersFilter,
+    ) -> Result<Vec<models::Layer>> {
+        let query = match filter {
+            LayerPendingLayersFilter::Building => {
+                r"
+                SELECT project_id, layer_set_name, id, status, source_git_commit,
+                    source_layer_set_name, source_layer_id
+                FROM layers
+                WHERE status = ? AND (build_worker_id IS NULL OR build_worker_expires_at < ?);
+                "
+            }
+            LayerPendingLayersFilter::Finalizing => {
+                r"
+                SELECT project_id, layer_set_name, id, status, source_git_commit,
+                    source_layer_set_name, source_layer_id
+                FROM layers
+                WHERE status = ?
+                    AND (finalize_worker_id IS NULL OR finalize_worker_expires_at < ?);
+                "
+            }
+        };
+
+        let status = match filter {
+            LayerPendingLayersFilter::Building => models::LayerStatus::Building,
+            LayerPendingLayersFilter::Finalizing => models::LayerStatus::Finalizing,
+        };
+
+        let layers = sqlx::query_as::<_, Layer>(query)
+            .bind(layer_status_to_str(status))
+            .bind(time::now())
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        Ok(layers)
+    }
+
+    async fn try_set_current_build(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET build_current_layer_id = ?1
+            WHERE project_id = ?2 AND name = ?3
+                AND build_current_layer_id IS NULL
+                AND (build_last_layer_id IS NULL OR build_last_layer_id < ?1);
+            ",
+        )
+        .bind(layer_id.into_uuid())
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .execute(&mut transaction)
+        .await?;
+
+        let (build_current_layer_id,): (Option<Uuid>,) = sqlx::query_as(
+            r"
+            SELECT build_current_layer_id
+            FROM layer_sets
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .fetch_one(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        match build_current_layer_id {
+            Some(build_current_layer_id) if build_current_layer_id == layer_id.into_uuid() => {
+                Ok(())
+            }
+            Some(_) => Err(anyhow!("layer set is already locked by a build")),
+            None => Err(anyhow!("layer set has already built a more recent layer, therefore this layer cannot be built")),
+        }
+    }
+
+    async fn build_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layers
+            SET build_worker_id = ?, build_worker_expires_at = ?
+            WHERE project_id = ? AND layer_set_name = ? AND id = ?
+                AND status = 'building'
+                AND (build_worker_id IS NULL OR build_worker_expires_at < ?);
+            ",
+        )
+        .bind(worker_id.into_uuid())
+        .bind(time::expires_in(BUILD_TIMEOUT_SECONDS))
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .bind(time::now())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "layer is already locked by another build worker"
+        );
+
+        Ok(())
+    }
+
+    async fn finish_build(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layers
+            SET status = 'finalizing', build_worker_expires_at = NULL
+            WHERE project_id = ? AND layer_set_name = ? AND id = ?
+                AND status = 'building'
+                AND build_worker_id = ? AND build_worker_expires_at >= ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .bind(worker_id.into_uuid())
+        .bind(time::now())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "build worker timed out and the build could not be finished"
+        );
+
+        Ok(())
+    }
+
+    async fn finalize_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            UPDATE layers
+            SET finalize_worker_id = ?, finalize_worker_expires_at = ?
+            WHERE project_id = ? AND layer_set_name = ? AND id = ?
+                AND status = 'finalizing'
+                AND (finalize_worker_id IS NULL OR finalize_worker_expires_at < ?);
+            ",
+        )
+        .bind(worker_id.into_uuid())
+        .bind(time::expires_in(FINALIZE_TIMEOUT_SECONDS))
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .bind(time::now())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "layer is already locked by another finalizing worker"
+        );
+
+        Ok(())
+    }
+
+    async fn finish_finalizing(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            r"
+            UPDATE layers
+            SET status = 'ready', finalize_worker_expires_at = NULL
+            WHERE project_id = ? AND layer_set_name = ? AND id = ?
+                AND status = 'finalizing'
+                AND finalize_worker_id = ? AND finalize_worker_expires_at >= ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .bind(worker_id.into_uuid())
+        .bind(time::now())
+        .execute(&mut transaction)
+        .await?;
+
+        ensure!(result.rows_affected() == 1);
+
+        let result = sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET build_current_layer_id = NULL, build_last_layer_id = ?1
+            WHERE project_id = ?2 AND name = ?3 AND build_current_layer_id = ?1;
+            ",
+        )
+        .bind(layer_id.into_uuid())
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .execute(&mut transaction)
+        .await?;
+
+        ensure!(result.rows_affected() == 1);
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn cancel_layer(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            r"
+            UPDATE layers
+            SET status = 'cancelled'
+            WHERE project_id = ? AND layer_set_name = ? AND id = ? AND status = 'building';
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .execute(&mut transaction)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "layer cannot be cancelled because of its status"
+        );
+
+        // Make sure this layer is not the current build.
+        sqlx::query(
+            r"
+            UPDATE layer_sets
+            SET build_current_layer_id = NULL
+            WHERE project_id = ?1 AND name = ?2 AND build_current_layer_id = ?3;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_layer_changes(&self, layer_changes: &[models::LayerChange]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for layer_change in layer_changes {
+            sqlx::query(
+                r"
+                INSERT OR REPLACE INTO layer_changes (
+                    project_id, layer_set_name, layer_id, worker_id,
+                    path, checksum, content_encoding_hint, headers
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+                ",
+            )
+            .bind(layer_change.project_id.into_uuid())
+            .bind(layer_change.layer_set_name.as_str())
+            .bind(layer_change.layer_id.into_uuid())
+            .bind(layer_change.worker_id.into_uuid())
+            .bind(&layer_change.path)
+            .bind(layer_change.checksum.encode())
+            .bind(i64::from_le_bytes(
+                layer_change.content_encoding_hint.encode(),
+            ))
+            .bind(encode_headers(&layer_change.headers))
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn list_layer_changes(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        worker_id: models::WorkerId,
+    ) -> Result<Vec<models::LayerChange>> {
+        let layer_changes = sqlx::query_as::<_, LayerChange>(
+            r"
+            SELECT project_id, layer_set_name, layer_id, worker_id,
+                path, checksum, content_encoding_hint, headers
+            FROM layer_changes
+            WHERE project_id = ? AND layer_set_name = ? AND layer_id = ? AND worker_id = ?
+            ORDER BY path;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .bind(worker_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(layer_changes)
+    }
+
+    async fn create_layer_members(&self, layer_members: &[models::LayerMember]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for layer_member in layer_members {
+            sqlx::query(
+                r"
+                INSERT OR REPLACE INTO layer_members (
+                    project_id, layer_set_name, path, layer_id,
+                    checksum, content_encoding_hint, headers
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?);
+                ",
+            )
+            .bind(layer_member.project_id.into_uuid())
+            .bind(layer_member.layer_set_name.as_str())
+            .bind(&layer_member.path)
+            .bind(layer_member.layer_id.into_uuid())
+            .bind(layer_member.checksum.encode())
+            .bind(i64::from_le_bytes(
+                layer_member.content_encoding_hint.encode(),
+            ))
+            .bind(encode_headers(&layer_member.headers))
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn list_layer_members(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+    ) -> Result<Vec<models::LayerMember>> {
+        let layer_members = sqlx::query_as::<_, LayerMember>(
+            r"
+            SELECT project_id, layer_set_name, layer_id, path, checksum, content_encoding_hint,
+                headers
+            FROM layer_members
+            WHERE project_id = ? AND layer_set_name = ? AND layer_id = ?
+            ORDER BY path;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(layer_set_name.as_str())
+        .bind(layer_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(layer_members)
+    }
+
+    async fn get_layer_member_summary(
+        &self,
+        project_id: models::ProjectId,
+        layer_set_name: &models::LayerSetName,
+        layer_id: models::LayerId,
+        paths: &[&str],
+    ) -> Result<Vec<models::LayerMemberSummary>> {
+        let mut layer_members = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            let layer_member = sqlx::query_as::<_, LayerMemberSummary>(
+                r"
+                SELECT path, checksum, content_encoding_hint, headers
+                FROM layer_members
+                WHERE project_id = ? AND layer_set_name = ? AND path = ? AND layer_id <= ?
+                ORDER BY layer_id DESC
+                LIMIT 1;
+                ",
+            )
+            .bind(project_id.into_uuid())
+            .bind(layer_set_name.as_str())
+            .bind(path)
+            .bind(layer_id.into_uuid())
+            .fetch_optional(&self.pool)
+            .await?;
+
+            if let Some(layer_member) = layer_member {
+                layer_members.push(layer_member.into());
+            }
+        }
+
+        Ok(layer_members)
+    }
+}
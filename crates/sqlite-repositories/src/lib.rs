@@ -0,0 +1,58 @@
+use anyhow::{Context as _, Result};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use std::path::PathBuf;
+
+use fairing_core2::repositories::BlobStore;
+
+mod blob_store;
+mod domains;
+mod files;
+mod layers;
+mod migrations;
+mod projects;
+mod queue;
+mod sources;
+mod time;
+
+pub use blob_store::LocalBlobStore;
+
+/// Repository for single node installations, metadata is kept in SQLite and file data in a
+/// directory next to the database.
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    blob_store: Box<dyn BlobStore>,
+}
+
+impl SqliteRepository {
+    pub async fn open(location: impl Into<PathBuf>) -> Result<SqliteRepository> {
+        let location = location.into();
+
+        tokio::fs::create_dir_all(&location).await?;
+
+        let options = SqliteConnectOptions::new()
+            .filename(location.join("fairing.db"))
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .foreign_keys(true);
+
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .context("opening sqlite database")?;
+
+        migrations::migrate(&pool).await?;
+
+        let blob_store = LocalBlobStore::open(location.join("blobs")).await?;
+
+        Ok(SqliteRepository {
+            pool,
+            blob_store: Box::new(blob_store),
+        })
+    }
+
+    /// Keeps the data of blobs in `blob_store` instead of on the local disk.
+    pub fn with_blob_store(mut self, blob_store: Box<dyn BlobStore>) -> SqliteRepository {
+        self.blob_store = blob_store;
+        self
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context as _, Result};
+use sqlx::sqlite::SqlitePool;
+
+/// Schema migrations, applied in order. Every migration runs in a transaction together with the
+/// row in `schema_migrations` that records it, so it's applied once or not at all.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("../migrations/0001_initial.sql")),
+    (
+        2,
+        "layer_set_retention",
+        include_str!("../migrations/0002_layer_set_retention.sql"),
+    ),
+    (
+        3,
+        "layer_set_pinned_layer",
+        include_str!("../migrations/0003_layer_set_pinned_layer.sql"),
+    ),
+    (
+        4,
+        "layer_promotions",
+        include_str!("../migrations/0004_layer_promotions.sql"),
+    ),
+    (
+        5,
+        "source_host_keys",
+        include_str!("../migrations/0005_source_host_keys.sql"),
+    ),
+    (
+        6,
+        "source_http_token",
+        include_str!("../migrations/0006_source_http_token.sql"),
+    ),
+    (
+        7,
+        "layer_set_source_root",
+        include_str!("../migrations/0007_layer_set_source_root.sql"),
+    ),
+    (
+        8,
+        "ref_layer_sets",
+        include_str!("../migrations/0008_ref_layer_sets.sql"),
+    ),
+    (
+        9,
+        "file_created_at",
+        include_str!("../migrations/0009_file_created_at.sql"),
+    ),
+];
+
+/// Applies all migrations that aren't recorded in `schema_migrations` yet.
+pub(crate) async fn migrate(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        r"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL,
+
+            PRIMARY KEY (version)
+        );
+        ",
+    )
+    .execute(pool)
+    .await
+    .context("creating schema_migrations")?;
+
+    for &(version, name, sql) in MIGRATIONS {
+        let mut transaction = pool.begin().await?;
+
+        let applied = sqlx::query("SELECT version FROM schema_migrations WHERE version = ?;")
+            .bind(version)
+            .fetch_optional(&mut transaction)
+            .await?
+            .is_some();
+        if applied {
+            continue;
+        }
+
+        let statements = sql
+            .split_inclusive(';')
+            .map(|statement| statement.trim())
+            .filter(|statement| !statement.is_empty());
+
+        for statement in statements {
+            sqlx::query(statement)
+                .execute(&mut transaction)
+                .await
+                .with_context(|| format!("migration {version:04} {name}: {statement}"))?;
+        }
+
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?);")
+            .bind(version)
+            .bind(name)
+            .bind(crate::time::now())
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row as _;
+
+    use super::*;
+    use crate::SqliteRepository;
+
+    #[tokio::test]
+    async fn apply_migrations_once() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let repository = SqliteRepository::open(dir.path()).await.unwrap();
+        migrate(&repository.pool).await.unwrap();
+
+        let versions = sqlx::query("SELECT version FROM schema_migrations ORDER BY version;")
+            .fetch_all(&repository.pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.get::<i64, _>("version"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            MIGRATIONS
+                .iter()
+                .map(|&(version, _, _)| version)
+                .collect::<Vec<_>>()
+        );
+
+        drop(repository);
+        SqliteRepository::open(dir.path()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn add_columns_to_existing_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = SqlitePool::connect(&format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("fairing.db").display()
+        ))
+        .await
+        .unwrap();
+
+        sqlx::query(
+            r"
+            CREATE TABLE schema_migrations (
+                version INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL,
+
+                PRIMARY KEY (version)
+            );
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for statement in MIGRATIONS[0].2.split_inclusive(';') {
+            if !statement.trim().is_empty() {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+        }
+        sqlx::query("INSERT INTO schema_migrations VALUES (1, 'initial', 0);")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO files (project_id, checksum, length) VALUES (x'00', x'01', 3);")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        migrate(&pool).await.unwrap();
+
+        let created_at: i64 = sqlx::query("SELECT created_at FROM files;")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("created_at");
+        assert_eq!(created_at, 0);
+    }
+}
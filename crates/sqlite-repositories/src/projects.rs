@@ -0,0 +1,185 @@
+use anyhow::{ensure, Result};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::ProjectRepository};
+
+use crate::SqliteRepository;
+
+#[derive(Debug, FromRow)]
+struct Project {
+    id: Uuid,
+    acme_dns_challenge_label: String,
+}
+
+#[derive(Debug, FromRow)]
+struct FileEncryptionKey {
+    version: i64,
+    key: Vec<u8>,
+}
+
+impl SqliteRepository {
+    async fn get_file_encryption_keys(
+        &self,
+        project_id: Uuid,
+    ) -> Result<Vec<models::FileEncryptionKey>> {
+        sqlx::query_as::<_, FileEncryptionKey>(
+            r"
+            SELECT version, key
+            FROM file_encryption_keys
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project_id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|key| models::FileEncryptionKey::from_row(key.version as u32, &key.key))
+        .collect()
+    }
+
+    async fn with_file_encryption_keys(&self, project: Project) -> Result<models::Project> {
+        let file_encryption_keys = self.get_file_encryption_keys(project.id).await?;
+
+        Ok(models::Project {
+            id: project.id.into(),
+            acme_dns_challenge_label: project.acme_dns_challenge_label,
+            file_encryption_keys,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ProjectRepository for SqliteRepository {
+    async fn get_project(&self, id: &models::ProjectId) -> Result<Option<models::Project>> {
+        let project = sqlx::query_as::<_, Project>(
+            r"
+            SELECT id, acme_dns_challenge_label
+            FROM projects
+            WHERE id = ?;
+            ",
+        )
+        .bind(id.into_uuid())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match project {
+            Some(project) => Ok(Some(self.with_file_encryption_keys(project).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_projects(&self) -> Result<Vec<models::Project>> {
+        let projects = sqlx::query_as::<_, Project>(
+            r"
+            SELECT id, acme_dns_challenge_label
+            FROM projects;
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut projects_with_keys = Vec::with_capacity(projects.len());
+        for project in projects {
+            projects_with_keys.push(self.with_file_encryption_keys(project).await?);
+        }
+
+        Ok(projects_with_keys)
+    }
+
+    async fn create_or_update_project(&self, project: &models::Project) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(
+            r"
+            INSERT INTO projects (id, acme_dns_challenge_label)
+            VALUES (?, ?)
+            ON CONFLICT (id) DO UPDATE
+            SET acme_dns_challenge_label = excluded.acme_dns_challenge_label;
+            ",
+        )
+        .bind(project.id.into_uuid())
+        .bind(&project.acme_dns_challenge_label)
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query(
+            r"
+            DELETE FROM file_encryption_keys
+            WHERE project_id = ?;
+            ",
+        )
+        .bind(project.id.into_uuid())
+        .execute(&mut transaction)
+        .await?;
+
+        for key in &project.file_encryption_keys {
+            sqlx::query(
+                r"
+                INSERT INTO file_encryption_keys (project_id, version, key)
+                VALUES (?, ?, ?);
+                ",
+            )
+            .bind(project.id.into_uuid())
+            .bind(key.version as i64)
+            .bind(key.key_to_slice())
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_file_encryption_key(
+        &self,
+        project_id: models::ProjectId,
+        key: &models::FileEncryptionKey,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            r"
+            INSERT INTO file_encryption_keys (project_id, version, key)
+            VALUES (?, ?, ?)
+            ON CONFLICT DO NOTHING;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(key.version as i64)
+        .bind(key.key_to_slice())
+        .execute(&self.pool)
+        .await?;
+
+        ensure!(
+            result.rows_affected() == 1,
+            "a file encryption key with this version already exists"
+        );
+
+        Ok(())
+    }
+
+    async fn delete_file_encryption_keys(
+        &self,
+        project_id: models::ProjectId,
+        versions: &[u32],
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for version in versions {
+            sqlx::query(
+                r"
+                DELETE FROM file_encryption_keys
+                WHERE project_id = ? AND version = ?;
+                ",
+            )
+            .bind(project_id.into_uuid())
+            .bind(*version as i64)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use fairing_core2::{models, repositories::SourceRepository};
+
+use crate::SqliteRepository;
+
+#[derive(Debug, FromRow)]
+struct Source {
+    project_id: Uuid,
+    name: String,
+    git_repository_url: Option<String>,
+    git_ed25519_secret_key: Option<Vec<u8>>,
+    git_known_host_keys: Option<Vec<u8>>,
+    git_host_key_mismatch: Option<String>,
+    git_http_token: Option<String>,
+}
+
+impl Into<models::Source> for Source {
+    fn into(self) -> models::Source {
+        let (known_host_keys, _): (Vec<String>, _) = self
+            .git_known_host_keys
+            .map(|known_host_keys| {
+                bincode::decode_from_slice(&known_host_keys, bincode::config::standard()).unwrap()
+            })
+            .unwrap_or_default();
+        let known_host_keys = known_host_keys
+            .into_iter()
+            .map(|host_key| host_key.parse().unwrap())
+            .collect();
+
+        let kind = match (self.git_repository_url, self.git_ed25519_secret_key) {
+            (Some(repository_url), Some(ed25519_secret_key)) => models::SourceKind::Git {
+                repository_url: repository_url.parse().unwrap(),
+                id_ed25519: models::Ed25519::from_row(ed25519_secret_key),
+                known_host_keys,
+                http_token: self
+                    .git_http_token
+                    .map(|http_token| http_token.parse().unwrap()),
+            },
+            _ => unreachable!("unknown source kind"),
+        };
+
+        let status = match self.git_host_key_mismatch {
+            Some(host_key) => models::SourceStatus::HostKeyMismatch {
+                host_key: host_key.parse().unwrap(),
+            },
+            None => models::SourceStatus::Ok,
+        };
+
+        models::Source {
+            project_id: self.project_id.into(),
+            name: self.name.parse().unwrap(),
+            kind,
+            status,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SourceRepository for SqliteRepository {
+    async fn get_source(
+        &self,
+        project_id: &models::ProjectId,
+        name: &models::SourceName,
+    ) -> Result<Option<models::Source>> {
+        let source = sqlx::query_as::<_, Source>(
+            r"
+            SELECT project_id, name, git_repository_url, git_ed25519_secret_key,
+                git_known_host_keys, git_host_key_mismatch, git_http_token
+            FROM sources
+            WHERE project_id = ? AND name = ?;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .bind(name.as_str())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(Into::into);
+
+        Ok(source)
+    }
+
+    async fn list_sources(&self, project_id: &models::ProjectId) -> Result<Vec<models::Source>> {
+        let sources = sqlx::query_as::<_, Source>(
+            r"
+            SELECT project_id, name, git_repository_url, git_ed25519_secret_key,
+                git_known_host_keys, git_host_key_mismatch, git_http_token
+            FROM sources
+            WHERE project_id = ?
+            ORDER BY name;
+            ",
+        )
+        .bind(project_id.into_uuid())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        Ok(sources)
+    }
+
+    async fn create_or_update_source(&self, source: &models::Source) -> Result<()> {
+        match source.kind {
+            models::SourceKind::Git {
+                ref repository_url,
+                ref id_ed25519,
+                ref known_host_keys,
+                ref http_token,
+            } => {
+                let known_host_keys = known_host_keys
+                    .iter()
+                    .map(|host_key| host_key.to_string())
+                    .collect::<Vec<_>>();
+
+                let host_key_mismatch = match source.status {
+                    models::SourceStatus::Ok => None,
+                    models::SourceStatus::HostKeyMismatch { ref host_key } => {
+                        Some(host_key.as_str())
+                    }
+                };
+
+                sqlx::query(
+                    r"
+                    INSERT INTO sources (
+                        project_id, name, git_repository_url, git_ed25519_secret_key,
+                        git_known_host_keys, git_host_key_mismatch, git_http_token
+                    )
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (project_id, name) DO UPDATE
+                    SET git_repository_url = excluded.git_repository_url,
+                        git_ed25519_secret_key = excluded.git_ed25519_secret_key,
+                        git_known_host_keys = excluded.git_known_host_keys,
+                        git_host_key_mismatch = excluded.git_host_key_mismatch,
+                        git_http_token = excluded.git_http_token;
+                    ",
+                )
+                .bind(source.project_id.into_uuid())
+                .bind(source.name.as_str())
+                .bind(repository_url.as_str())
+                .bind(id_ed25519.secret_key_to_slice())
+                .bind(bincode::encode_to_vec(
+                    known_host_keys,
+                    bincode::config::standard(),
+                )?)
+                .bind(host_key_mismatch)
+                .bind(http_token.as_ref().map(models::GitHttpToken::as_str))
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use chrono::{DateTime, TimeZone, Utc};
+
+pub(crate) fn to_timestamp(date_time: &DateTime<Utc>) -> i64 {
+    date_time.timestamp_millis()
+}
+
+pub(crate) fn from_timestamp(timestamp: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(timestamp).unwrap()
+}
+
+/// Returns the time in milliseconds after `seconds` from now, used instead of TTLs.
+pub(crate) fn expires_in(seconds: i64) -> i64 {
+    to_timestamp(&(Utc::now() + chrono::Duration::seconds(seconds)))
+}
+
+pub(crate) fn now() -> i64 {
+    to_timestamp(&Utc::now())
+}
@@ -1,8 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     const PROTO_FILES: &[&str] = &[
         "../proto/domains/v1beta1/domains.proto",
+        "../proto/layers/v1beta1/layers.proto",
         "../proto/sites/v1beta1/sites.proto",
         "../proto/sources/v1beta1/sources.proto",
+        "../proto/sources/v1beta2/sources.proto",
         "../proto/teams/v1beta1/teams.proto",
         "../proto/users/v1beta1/users.proto",
     ];
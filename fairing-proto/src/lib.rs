@@ -4,6 +4,12 @@ pub mod domains {
     }
 }
 
+pub mod layers {
+    pub mod v1beta1 {
+        tonic::include_proto!("fairing.layers.v1beta1");
+    }
+}
+
 pub mod sites {
     pub mod v1beta1 {
         tonic::include_proto!("fairing.sites.v1beta1");
@@ -14,6 +20,10 @@ pub mod sources {
     pub mod v1beta1 {
         tonic::include_proto!("fairing.sources.v1beta1");
     }
+
+    pub mod v1beta2 {
+        tonic::include_proto!("fairing.sources.v1beta2");
+    }
 }
 
 pub mod teams {
@@ -3,6 +3,10 @@ use fairing_core::{
     models::{self, prelude::*},
     services::{AcmeService, BuildServiceBuilder, Storage},
 };
+use fairing_core2::repositories::{
+    BlobStore, DomainRepository, FileRepository, LayerRepository, ProjectRepository,
+    SourceRepository,
+};
 use std::net::SocketAddr;
 use tokio::task;
 use tracing_subscriber::prelude::*;
@@ -25,11 +29,38 @@ struct Args {
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
     /// Start the server.
-    Server {
+    Server,
+    /// Move teams, sources, layer sets and files from the legacy postgres database into the
+    /// configured database.
+    MigrateLegacy {
+        /// Url of the legacy postgres database.
         #[clap(long)]
-        scylla_known_nodes: Vec<String>,
+        legacy_database_url: String,
+
+        /// Directory where the legacy server kept its files.
+        #[clap(long, default_value = ".data")]
+        legacy_file_storage: String,
+    },
+    /// Show or apply schema migrations of the configured database.
+    Migrate {
+        #[clap(subcommand)]
+        command: MigrateCommands,
+    },
+    /// Manage projects.
+    Project {
+        #[clap(subcommand)]
+        command: ProjectCommands,
+    },
+    /// Export layers to archives or import them.
+    Layer {
+        #[clap(subcommand)]
+        command: LayerCommands,
+    },
+    /// Delete expired layers and the files that no layer refers to anymore.
+    Gc {
+        /// Report what would be deleted without deleting anything.
         #[clap(long)]
-        scylla_keyspace: String,
+        dry_run: bool,
     },
     Acme {
         #[clap(subcommand)]
@@ -37,6 +68,52 @@ enum Commands {
     },
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum MigrateCommands {
+    /// List all migrations and when they were applied.
+    Status,
+    /// Apply pending migrations.
+    Up,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ProjectCommands {
+    /// Create a new file encryption key and re-encrypt existing blobs with it, the old keys are
+    /// deleted once no blob uses them anymore.
+    RotateFileEncryptionKey {
+        #[clap(long)]
+        project: fairing_core2::models::ProjectId,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum LayerCommands {
+    /// Write a layer to a tar archive, the archive is compressed if the path ends with `.zst`.
+    Export {
+        #[clap(long)]
+        project: fairing_core2::models::ProjectId,
+
+        #[clap(long)]
+        layer_set: fairing_core2::models::LayerSetName,
+
+        /// Defaults to the last layer that was built.
+        #[clap(long)]
+        layer: Option<fairing_core2::models::LayerId>,
+
+        path: std::path::PathBuf,
+    },
+    /// Create a new layer from a tar archive.
+    Import {
+        #[clap(long)]
+        project: fairing_core2::models::ProjectId,
+
+        #[clap(long)]
+        layer_set: fairing_core2::models::LayerSetName,
+
+        path: std::path::PathBuf,
+    },
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum AcmeCommands {
     Create {
@@ -51,6 +128,8 @@ enum AcmeCommands {
 #[derive(Debug, serde::Deserialize)]
 struct Config {
     database: DatabaseConfig,
+    #[serde(default)]
+    blob_store: Option<BlobStoreConfig>,
     acme: AcmeConfig,
     http: HttpConfig,
     https: HttpsConfig,
@@ -60,7 +139,37 @@ struct Config {
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "lowercase", tag = "type")]
 enum DatabaseConfig {
-    Postgres { url: String },
+    /// Keeps its tables in `schema`, so it can share a database with the legacy server. Use
+    /// `migrate-legacy` to move data from the legacy tables.
+    Postgres {
+        url: String,
+        #[serde(default = "default_postgres_schema")]
+        schema: String,
+    },
+    ScyllaDb {
+        known_nodes: Vec<String>,
+        keyspace_name: String,
+    },
+    /// Single node installations, keeps everything in a directory on the local disk.
+    Sqlite { path: String },
+}
+
+/// Where to keep the data of file blobs. If this isn't configured they're kept in the database, or
+/// next to it on the local disk for sqlite.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+enum BlobStoreConfig {
+    S3 {
+        endpoint: String,
+        region: String,
+        bucket: String,
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        path_style: bool,
+        #[serde(default)]
+        prefix: String,
+    },
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -100,12 +209,132 @@ struct HttpsConfig {
 #[derive(Debug, serde::Deserialize)]
 struct ApiConfig {
     host: String,
+    #[serde(default)]
+    tokens: Vec<ApiTokenConfig>,
+}
+
+/// Bearer token for the api, grants administrator access to a single project.
+#[derive(Debug, serde::Deserialize)]
+struct ApiTokenConfig {
+    token: String,
+    project: String,
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn default_postgres_schema() -> String {
+    "fairing".to_string()
+}
+
+struct Repositories {
+    project: &'static dyn ProjectRepository,
+    source: &'static dyn SourceRepository,
+    layer: &'static dyn LayerRepository,
+    file: &'static dyn FileRepository,
+    domain: &'static dyn DomainRepository,
+}
+
+impl Repositories {
+    fn leak<R>(database: R) -> Repositories
+    where
+        R: ProjectRepository
+            + SourceRepository
+            + LayerRepository
+            + FileRepository
+            + DomainRepository
+            + 'static,
+    {
+        let database: &'static R = Box::leak(Box::new(database));
+
+        Repositories {
+            project: database,
+            source: database,
+            layer: database,
+            file: database,
+            domain: database,
+        }
+    }
+
+    async fn connect(
+        database: &DatabaseConfig,
+        blob_store: Option<BlobStoreConfig>,
+    ) -> Result<Repositories> {
+        let repositories = match database {
+            DatabaseConfig::ScyllaDb {
+                known_nodes,
+                keyspace_name,
+            } => {
+                let mut database =
+                    scylla_repositories::ScyllaRepository::connect(known_nodes, keyspace_name)
+                        .await
+                        .context("connecting to scylladb")?;
+
+                if let Some(blob_store) = blob_store {
+                    let blob_store = Box::leak(blob_store.connect()?);
+                    database = database.with_blob_store(blob_store);
+                }
+
+                Repositories::leak(database)
+            }
+            DatabaseConfig::Sqlite { path } => {
+                let mut database = sqlite_repositories::SqliteRepository::open(path)
+                    .await
+                    .context("opening sqlite database")?;
+
+                if let Some(blob_store) = blob_store {
+                    database = database.with_blob_store(blob_store.connect()?);
+                }
+
+                Repositories::leak(database)
+            }
+            DatabaseConfig::Postgres { url, schema } => {
+                let mut database = postgres_repositories::PostgresRepository::connect(url, schema)
+                    .await
+                    .context("connecting to postgres")?;
+
+                if let Some(blob_store) = blob_store {
+                    database = database.with_blob_store(blob_store.connect()?);
+                }
+
+                Repositories::leak(database)
+            }
+        };
+
+        Ok(repositories)
+    }
+}
+
+impl BlobStoreConfig {
+    fn connect(self) -> Result<Box<dyn BlobStore>> {
+        match self {
+            BlobStoreConfig::S3 {
+                endpoint,
+                region,
+                bucket,
+                access_key_id,
+                secret_access_key,
+                path_style,
+                prefix,
+            } => {
+                let blob_store = s3_repositories::S3BlobStore::new(s3_repositories::S3Options {
+                    endpoint,
+                    region,
+                    bucket,
+                    access_key_id,
+                    secret_access_key,
+                    path_style,
+                    prefix,
+                })
+                .context("configuring s3 blob store")?;
+
+                Ok(Box::new(blob_store))
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Args = clap::Parser::parse();
@@ -120,6 +349,22 @@ async fn main() -> Result<()> {
         const ENV_MAP: &[(&str, &str)] = &[
             ("FAIRING_DATABASE_TYPE", "database.type"),
             ("FAIRING_DATABASE_URL", "database.url"),
+            ("FAIRING_DATABASE_PATH", "database.path"),
+            ("FAIRING_DATABASE_KEYSPACE_NAME", "database.keyspace_name"),
+            ("FAIRING_BLOB_STORE_TYPE", "blob_store.type"),
+            ("FAIRING_BLOB_STORE_ENDPOINT", "blob_store.endpoint"),
+            ("FAIRING_BLOB_STORE_REGION", "blob_store.region"),
+            ("FAIRING_BLOB_STORE_BUCKET", "blob_store.bucket"),
+            (
+                "FAIRING_BLOB_STORE_ACCESS_KEY_ID",
+                "blob_store.access_key_id",
+            ),
+            (
+                "FAIRING_BLOB_STORE_SECRET_ACCESS_KEY",
+                "blob_store.secret_access_key",
+            ),
+            ("FAIRING_BLOB_STORE_PATH_STYLE", "blob_store.path_style"),
+            ("FAIRING_BLOB_STORE_PREFIX", "blob_store.prefix"),
             ("FAIRING_ACME_SERVER", "acme.server"),
             ("FAIRING_ACME_DNS_TYPE", "acme.dns.type"),
             ("FAIRING_ACME_DNS_ZONE", "acme.dns.zone"),
@@ -133,6 +378,7 @@ async fn main() -> Result<()> {
         }
 
         const ENV_MAP_LIST: &[(&str, &str)] = &[
+            ("FAIRING_DATABASE_KNOWN_NODES", "database.known_nodes"),
             ("FAIRING_ACME_UDP_BIND", "acme.dns.udp_bind"),
             ("FAIRING_ACME_TCP_BIND", "acme.dns.tcp_bind"),
             ("FAIRING_HTTP_BIND", "http.bind"),
@@ -160,34 +406,107 @@ async fn main() -> Result<()> {
         .with(console_subscriber::spawn())
         .init();
 
-    if let Commands::Server {
-        scylla_known_nodes,
-        scylla_keyspace,
-    } = args.command
-    {
+    if let Commands::Server = args.command {
         use fairing_core2::services::{
-            Authentication, DomainService, LayerService, ProjectService, SourceService,
+            Authentication, DomainService, LayerMemberService, LayerPromotionService, LayerService,
+            ProjectService, SourceService, UploadService,
         };
 
-        let database =
-            scylla_repositories::ScyllaRepository::connect(&scylla_known_nodes, &scylla_keyspace)
-                .await
-                .context("connecting to scylladb")?;
-        let database = Box::leak(Box::new(database));
+        let repositories = Repositories::connect(&config.database, config.blob_store).await?;
 
         let git_source = git_repositories::LocalGitSource;
         let git_source = Box::leak(Box::new(git_source));
 
         let auth = Authentication::System { project_id: None };
 
-        let domain_service = DomainService::new(database);
-        let project_service = ProjectService::new(database);
-        let source_service = SourceService::new(database, git_source, database);
-        let layer_service = LayerService::new(database);
+        let domain_service = DomainService::new(repositories.domain);
+        let project_service = ProjectService::new(repositories.project);
+        let source_service = SourceService::new(
+            repositories.source,
+            git_source,
+            repositories.layer,
+            repositories.domain,
+        );
+        let layer_service = LayerService::new(repositories.layer);
         let build_service = fairing_core2::services::BuildService::new(
-            database, database, git_source, database, database,
+            repositories.layer,
+            repositories.source,
+            git_source,
+            repositories.file,
+            repositories.domain,
+        );
+        let http_service = fairing_core2::services::HttpService::new(
+            repositories.layer,
+            repositories.file,
+            repositories.domain,
+        );
+        let upload_service =
+            UploadService::new(repositories.layer, repositories.file, repositories.domain);
+
+        let api_tokens = config
+            .api
+            .tokens
+            .iter()
+            .map(|api_token| {
+                let project_id = api_token
+                    .project
+                    .parse()
+                    .context("parsing project of api token")?;
+                Ok((api_token.token.clone(), project_id))
+            })
+            .collect::<Result<_>>()?;
+        let api_tokens = Box::leak(Box::new(server::ApiTokens::new(api_tokens)));
+
+        let member_service = LayerMemberService::new(repositories.layer, repositories.file);
+        let promotion_service = LayerPromotionService::new(repositories.layer, repositories.domain);
+
+        let layers_service = server::LayersService::new(
+            layer_service,
+            upload_service,
+            member_service,
+            promotion_service,
+            api_tokens,
+        );
+
+        let sources_service = server::ProjectSourcesService::new(source_service, api_tokens);
+
+        let file_encryption_service = fairing_core2::services::FileEncryptionService::new(
+            repositories.project,
+            repositories.file,
+        );
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+
+            loop {
+                interval.tick().await;
+
+                let res = file_encryption_service.reencrypt_files().await;
+                if let Err(err) = res {
+                    tracing::error!("file encryption: {err:?}");
+                }
+            }
+        });
+
+        let garbage_collection_service = fairing_core2::services::GarbageCollectionService::new(
+            repositories.project,
+            repositories.layer,
+            repositories.file,
+            repositories.domain,
         );
-        let http_service = fairing_core2::services::HttpService::new(database, database, database);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(86400));
+
+            loop {
+                interval.tick().await;
+
+                let res = garbage_collection_service.collect_garbage(false).await;
+                if let Err(err) = res {
+                    tracing::error!("garbage collection: {err:?}");
+                }
+            }
+        });
 
         build_service.build().await?;
 
@@ -209,6 +528,7 @@ async fn main() -> Result<()> {
                     name: "test".parse()?,
                     kind: fairing_core2::models::CreateSourceKind::Git {
                         repository_url: "git@github.com:martinrlilja/web-test.git".parse()?,
+                        http_token: None,
                     },
                 },
             )
@@ -226,6 +546,8 @@ async fn main() -> Result<()> {
                         source: source.clone(),
                         kind: fairing_core2::models::CreateLayerSetSourceKind::Git {
                             ref_: "refs/heads/master".into(),
+                            root: String::new(),
+                            paths: vec![],
                         },
                     }),
                 },
@@ -259,6 +581,8 @@ async fn main() -> Result<()> {
 
         server::serve(
             http_service,
+            layers_service,
+            sources_service,
             config.http.bind,
             config.http.redirect_https,
             config.http.redirect_https_port,
@@ -269,7 +593,10 @@ async fn main() -> Result<()> {
 
         return Ok(());
 
-        let DatabaseConfig::Postgres { url: database_url } = config.database;
+        let database_url = match config.database {
+            DatabaseConfig::Postgres { url, .. } => url,
+            _ => anyhow::bail!("the legacy server requires a postgres database"),
+        };
         let database = backends::PostgresDatabase::connect(&database_url).await?;
         database.migrate().await?;
 
@@ -389,6 +716,174 @@ async fn main() -> Result<()> {
         } else {
             tracing::info!("not starting acme dns server because private_key is not set");
         }
+    } else if let Commands::MigrateLegacy {
+        legacy_database_url,
+        legacy_file_storage,
+    } = args.command
+    {
+        let repositories = Repositories::connect(&config.database, config.blob_store).await?;
+
+        let migration = postgres_repositories::legacy::LegacyMigration::connect(
+            &legacy_database_url,
+            legacy_file_storage,
+            repositories.project,
+            repositories.source,
+            repositories.layer,
+            repositories.file,
+            repositories.domain,
+        )
+        .await?;
+
+        for team in migration.migrate().await? {
+            println!(
+                "team {} is now project {}",
+                team.name,
+                team.project_id.into_uuid()
+            );
+        }
+    } else if let Commands::Migrate { command } = args.command {
+        let (known_nodes, keyspace_name) = match config.database {
+            DatabaseConfig::ScyllaDb {
+                known_nodes,
+                keyspace_name,
+            } => (known_nodes, keyspace_name),
+            _ => anyhow::bail!("postgres and sqlite databases are migrated when they are opened"),
+        };
+
+        let migrations =
+            scylla_repositories::ScyllaMigrations::connect(&known_nodes, &keyspace_name)
+                .await
+                .context("connecting to scylladb")?;
+
+        let migrations = match command {
+            MigrateCommands::Status => migrations.status().await?,
+            MigrateCommands::Up => migrations.up().await?,
+        };
+
+        for migration in migrations {
+            match migration.applied_at {
+                Some(applied_at) => println!(
+                    "{:04} {} applied at {}",
+                    migration.version,
+                    migration.name,
+                    applied_at.to_rfc3339()
+                ),
+                None => println!("{:04} {} pending", migration.version, migration.name),
+            }
+        }
+    } else if let Commands::Project { command } = args.command {
+        use fairing_core2::services::{Authentication, FileEncryptionService, ProjectService};
+
+        let repositories = Repositories::connect(&config.database, config.blob_store).await?;
+
+        let project_service = ProjectService::new(repositories.project);
+        let file_encryption_service =
+            FileEncryptionService::new(repositories.project, repositories.file);
+
+        match command {
+            ProjectCommands::RotateFileEncryptionKey { project } => {
+                let auth = Authentication::System {
+                    project_id: Some(project),
+                };
+
+                let project = project_service.rotate_file_encryption_key(&auth).await?;
+                let version = project
+                    .current_file_encryption_key()
+                    .map(|key| key.version)
+                    .unwrap_or_default();
+
+                println!("created file encryption key {version}");
+
+                file_encryption_service.reencrypt_files().await?;
+
+                println!("re-encrypted files");
+            }
+        }
+    } else if let Commands::Layer { command } = args.command {
+        use fairing_core2::services::{Authentication, LayerArchiveFormat, LayerArchiveService};
+
+        let repositories = Repositories::connect(&config.database, config.blob_store).await?;
+
+        let archive_service =
+            LayerArchiveService::new(repositories.layer, repositories.file, repositories.domain);
+
+        match command {
+            LayerCommands::Export {
+                project,
+                layer_set,
+                layer,
+                path,
+            } => {
+                let auth = Authentication::System {
+                    project_id: Some(project),
+                };
+
+                let layer_id = match layer {
+                    Some(layer_id) => layer_id,
+                    None => repositories
+                        .layer
+                        .get_layer_set(project, &layer_set)
+                        .await?
+                        .and_then(|layer_set| layer_set.build_status.last_layer_id)
+                        .ok_or_else(|| anyhow::anyhow!("layer set has no built layers"))?,
+                };
+
+                let format = LayerArchiveFormat::from_file_name(&path.to_string_lossy());
+                let file = std::fs::File::create(&path)
+                    .with_context(|| format!("creating {}", path.display()))?;
+
+                archive_service
+                    .export_layer(&auth, &layer_set, layer_id, file, format)
+                    .await?;
+
+                println!(
+                    "exported layer {} to {}",
+                    layer_id.into_uuid(),
+                    path.display()
+                );
+            }
+            LayerCommands::Import {
+                project,
+                layer_set,
+                path,
+            } => {
+                let auth = Authentication::System {
+                    project_id: Some(project),
+                };
+
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("opening {}", path.display()))?;
+
+                let layer = archive_service
+                    .import_layer(&auth, &layer_set, file)
+                    .await?;
+
+                println!(
+                    "imported layer {}, preview it at {}",
+                    layer.id.into_uuid(),
+                    fairing_core2::models::Domain::layer_preview_fqdn(layer.id)
+                );
+            }
+        }
+    } else if let Commands::Gc { dry_run } = args.command {
+        let repositories = Repositories::connect(&config.database, config.blob_store).await?;
+
+        let garbage_collection_service = fairing_core2::services::GarbageCollectionService::new(
+            repositories.project,
+            repositories.layer,
+            repositories.file,
+            repositories.domain,
+        );
+
+        let report = garbage_collection_service.collect_garbage(dry_run).await?;
+
+        let verb = if dry_run { "would delete" } else { "deleted" };
+        println!(
+            "{verb} {} layers with {} members",
+            report.layers, report.layer_members
+        );
+        println!("{verb} {} files, {} bytes", report.files, report.file_bytes);
+        println!("{verb} {} blobs, {} bytes", report.blobs, report.blob_bytes);
     } else if let Commands::Acme { command } = args.command {
         let AcmeCommands::Create {
             mail_contact,
@@ -0,0 +1,542 @@
+use tonic::{Request, Response, Status, Streaming};
+
+use fairing_core2::{
+    models,
+    services::{
+        Authentication, LayerMemberService, LayerPromotionService, LayerService, UploadService,
+    },
+};
+use fairing_proto::layers::v1beta1::{
+    layer, layer_diff_entry, layers_server::Layers, upload_file_request, CreateUploadRequest,
+    CreateUploadResponse, DiffLayersRequest, DiffLayersResponse, FinalizeUploadRequest,
+    GetLayerRequest, GetLayerSetRequest, GetLayerSetRetentionRequest, Layer, LayerDiffEntry,
+    LayerMember, LayerSet, LayerSetRetention, ListLayerMembersRequest, ListLayerMembersResponse,
+    PinLayerSetRequest, PromoteLayerRequest, SetLayerSetRetentionRequest, UnpinLayerSetRequest,
+    UploadFileRequest, UploadFileResponse,
+};
+
+use super::{service_status, ApiTokens};
+
+#[derive(Copy, Clone)]
+pub struct LayersService {
+    layer_service: LayerService,
+    upload_service: UploadService,
+    member_service: LayerMemberService,
+    promotion_service: LayerPromotionService,
+    api_tokens: &'static ApiTokens,
+}
+
+impl LayersService {
+    pub fn new(
+        layer_service: LayerService,
+        upload_service: UploadService,
+        member_service: LayerMemberService,
+        promotion_service: LayerPromotionService,
+        api_tokens: &'static ApiTokens,
+    ) -> LayersService {
+        LayersService {
+            layer_service,
+            upload_service,
+            member_service,
+            promotion_service,
+            api_tokens,
+        }
+    }
+
+    async fn layer_set_response(
+        &self,
+        auth: &Authentication,
+        layer_set_name: &models::LayerSetName,
+    ) -> Result<Response<LayerSet>, Status> {
+        let layer_set = self
+            .layer_service
+            .get_layer_set(auth, layer_set_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting layer set")
+            })?
+            .ok_or_else(|| Status::not_found("layer set not found"))?;
+
+        Ok(Response::new(layer_set_to_proto(&layer_set)))
+    }
+}
+
+#[tonic::async_trait]
+impl Layers for LayersService {
+    async fn get_layer_set(
+        &self,
+        request: Request<GetLayerSetRequest>,
+    ) -> Result<Response<LayerSet>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let layer_set_name = parse_layer_set_name(&auth, &request.get_ref().name)?;
+
+        self.layer_set_response(&auth, &layer_set_name).await
+    }
+
+    async fn pin_layer_set(
+        &self,
+        request: Request<PinLayerSetRequest>,
+    ) -> Result<Response<LayerSet>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let layer_set_name = parse_layer_set_name(&auth, &request.get_ref().name)?;
+        let (pinned_layer_set_name, layer_id) = parse_layer_name(&auth, &request.get_ref().layer)?;
+
+        if pinned_layer_set_name != layer_set_name {
+            return Err(Status::invalid_argument(
+                "layer must belong to the layer set",
+            ));
+        }
+
+        self.layer_service
+            .pin_layer(&auth, &layer_set_name, layer_id)
+            .await
+            .map_err(|err| service_status(err, "error when pinning layer set"))?;
+
+        self.layer_set_response(&auth, &layer_set_name).await
+    }
+
+    async fn unpin_layer_set(
+        &self,
+        request: Request<UnpinLayerSetRequest>,
+    ) -> Result<Response<LayerSet>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let layer_set_name = parse_layer_set_name(&auth, &request.get_ref().name)?;
+
+        self.layer_service
+            .unpin_layer_set(&auth, &layer_set_name)
+            .await
+            .map_err(|err| service_status(err, "error when unpinning layer set"))?;
+
+        self.layer_set_response(&auth, &layer_set_name).await
+    }
+
+    async fn get_layer_set_retention(
+        &self,
+        request: Request<GetLayerSetRetentionRequest>,
+    ) -> Result<Response<LayerSetRetention>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let layer_set_name = parse_layer_set_retention_name(&auth, &request.get_ref().name)?;
+
+        let layer_set = self
+            .layer_service
+            .get_layer_set(&auth, &layer_set_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting layer set")
+            })?
+            .ok_or_else(|| Status::not_found("layer set not found"))?;
+
+        let retention = self
+            .layer_service
+            .get_layer_set_retention(&auth, &layer_set_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting layer set retention")
+            })?;
+
+        let retention = layer_set_retention_to_proto(&layer_set, &retention);
+
+        Ok(Response::new(retention))
+    }
+
+    async fn set_layer_set_retention(
+        &self,
+        request: Request<SetLayerSetRetentionRequest>,
+    ) -> Result<Response<LayerSetRetention>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let retention = request
+            .into_inner()
+            .retention
+            .ok_or_else(|| Status::invalid_argument("missing retention"))?;
+
+        let layer_set_name = parse_layer_set_retention_name(&auth, &retention.name)?;
+
+        if retention.keep_ready_layers < 1 {
+            return Err(Status::invalid_argument(
+                "at least one ready layer must be kept",
+            ));
+        }
+
+        let layer_set = self
+            .layer_service
+            .get_layer_set(&auth, &layer_set_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting layer set")
+            })?
+            .ok_or_else(|| Status::not_found("layer set not found"))?;
+
+        let retention = models::LayerSetRetention {
+            keep_ready_layers: retention.keep_ready_layers,
+        };
+
+        self.layer_service
+            .set_layer_set_retention(&auth, &layer_set_name, &retention)
+            .await
+            .map_err(|err| service_status(err, "error when setting layer set retention"))?;
+
+        let retention = layer_set_retention_to_proto(&layer_set, &retention);
+
+        Ok(Response::new(retention))
+    }
+
+    async fn get_layer(
+        &self,
+        request: Request<GetLayerRequest>,
+    ) -> Result<Response<Layer>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let (layer_set_name, layer_id) = parse_layer_name(&auth, &request.get_ref().name)?;
+
+        let layer = self
+            .layer_service
+            .get_layer(&auth, &layer_set_name, layer_id)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting layer")
+            })?
+            .ok_or_else(|| Status::not_found("layer not found"))?;
+
+        Ok(Response::new(layer_to_proto(&layer)))
+    }
+
+    async fn create_upload(
+        &self,
+        request: Request<CreateUploadRequest>,
+    ) -> Result<Response<CreateUploadResponse>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let create_upload = request.into_inner();
+
+        let layer_set_name = parse_layer_set_name(&auth, &create_upload.parent)?;
+
+        let files = create_upload
+            .files
+            .into_iter()
+            .map(|file| {
+                let checksum = models::FileChecksum::from_blake2b(&file.checksum)
+                    .map_err(|_err| Status::invalid_argument("invalid file checksum"))?;
+
+                Ok(models::UploadManifestFile {
+                    path: file.path,
+                    checksum,
+                    length: file.length,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+
+        let manifest = models::UploadManifest { files };
+
+        let (layer, missing_checksums) = self
+            .upload_service
+            .create_upload(&auth, &layer_set_name, &manifest)
+            .await
+            .map_err(|err| service_status(err, "error when creating upload"))?;
+
+        let missing_checksums = missing_checksums
+            .iter()
+            .filter_map(|checksum| checksum.as_blake2b().map(|checksum| checksum.to_vec()))
+            .collect();
+
+        Ok(Response::new(CreateUploadResponse {
+            layer: Some(layer_to_proto(&layer)),
+            missing_checksums,
+        }))
+    }
+
+    async fn upload_file(
+        &self,
+        request: Request<Streaming<UploadFileRequest>>,
+    ) -> Result<Response<UploadFileResponse>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let mut stream = request.into_inner();
+
+        let header = match stream.message().await?.and_then(|message| message.kind) {
+            Some(upload_file_request::Kind::Header(header)) => header,
+            _ => return Err(Status::invalid_argument("expected a header first")),
+        };
+
+        let (layer_set_name, layer_id) = parse_layer_name(&auth, &header.layer)?;
+
+        let checksum = models::FileChecksum::from_blake2b(&header.checksum)
+            .map_err(|_err| Status::invalid_argument("invalid file checksum"))?;
+
+        let mut upload = self
+            .upload_service
+            .upload_file(&auth, &layer_set_name, layer_id, checksum, header.length)
+            .await
+            .map_err(|err| service_status(err, "error when uploading file"))?;
+
+        while let Some(message) = stream.message().await? {
+            match message.kind {
+                Some(upload_file_request::Kind::Data(data)) => upload
+                    .write(&data)
+                    .await
+                    .map_err(|err| service_status(err, "error when uploading file"))?,
+                _ => return Err(Status::invalid_argument("expected file data")),
+            }
+        }
+
+        upload
+            .finish()
+            .await
+            .map_err(|err| service_status(err, "error when uploading file"))?;
+
+        Ok(Response::new(UploadFileResponse {}))
+    }
+
+    async fn finalize_upload(
+        &self,
+        request: Request<FinalizeUploadRequest>,
+    ) -> Result<Response<Layer>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let (layer_set_name, layer_id) = parse_layer_name(&auth, &request.get_ref().name)?;
+
+        let layer = self
+            .upload_service
+            .finalize_upload(&auth, &layer_set_name, layer_id)
+            .await
+            .map_err(|err| service_status(err, "error when finalizing upload"))?;
+
+        Ok(Response::new(layer_to_proto(&layer)))
+    }
+
+    async fn list_layer_members(
+        &self,
+        request: Request<ListLayerMembersRequest>,
+    ) -> Result<Response<ListLayerMembersResponse>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let list_request = request.into_inner();
+
+        let (layer_set_name, layer_id) = parse_layer_name(&auth, &list_request.parent)?;
+
+        let list = models::ListLayerMembers {
+            prefix: list_request.prefix,
+            page_token: Some(list_request.page_token).filter(|page_token| !page_token.is_empty()),
+            page_size: list_request.page_size as usize,
+        };
+
+        let (members, next_page_token) = self
+            .member_service
+            .list_layer_members(&auth, &layer_set_name, layer_id, &list)
+            .await
+            .map_err(|err| service_status(err, "error when listing layer members"))?;
+
+        let resources = members
+            .into_iter()
+            .map(|member| LayerMember {
+                path: member.path,
+                checksum: member
+                    .checksum
+                    .as_blake2b()
+                    .map(|checksum| checksum.to_vec())
+                    .unwrap_or_default(),
+                length: member.length,
+                headers: member.headers.into_iter().collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ListLayerMembersResponse {
+            resources,
+            next_page_token: next_page_token.unwrap_or_default(),
+        }))
+    }
+
+    async fn diff_layers(
+        &self,
+        request: Request<DiffLayersRequest>,
+    ) -> Result<Response<DiffLayersResponse>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let (layer_set_name, layer_id) = parse_layer_name(&auth, &request.get_ref().name)?;
+        let (base_layer_set_name, base_layer_id) =
+            parse_layer_name(&auth, &request.get_ref().base_layer)?;
+
+        if base_layer_set_name != layer_set_name {
+            return Err(Status::invalid_argument(
+                "layers must belong to the same layer set",
+            ));
+        }
+
+        let diff = self
+            .member_service
+            .diff_layers(&auth, &layer_set_name, base_layer_id, layer_id)
+            .await
+            .map_err(|err| service_status(err, "error when comparing layers"))?;
+
+        let entries = diff
+            .into_iter()
+            .map(|entry| {
+                let kind = match entry.kind {
+                    models::LayerDiffKind::Added => layer_diff_entry::Kind::Added,
+                    models::LayerDiffKind::Modified => layer_diff_entry::Kind::Modified,
+                    models::LayerDiffKind::Deleted => layer_diff_entry::Kind::Deleted,
+                };
+
+                LayerDiffEntry {
+                    path: entry.path,
+                    kind: kind.into(),
+                    old_length: entry.old_length.unwrap_or_default(),
+                    new_length: entry.new_length.unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        Ok(Response::new(DiffLayersResponse { entries }))
+    }
+
+    async fn promote_layer(
+        &self,
+        request: Request<PromoteLayerRequest>,
+    ) -> Result<Response<Layer>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let (source_layer_set_name, source_layer_id) =
+            parse_layer_name(&auth, &request.get_ref().name)?;
+        let layer_set_name = parse_layer_set_name(&auth, &request.get_ref().target_layer_set)?;
+
+        let layer = self
+            .promotion_service
+            .promote_layer(
+                &auth,
+                &source_layer_set_name,
+                source_layer_id,
+                &layer_set_name,
+            )
+            .await
+            .map_err(|err| service_status(err, "error when promoting layer"))?;
+
+        Ok(Response::new(layer_to_proto(&layer)))
+    }
+}
+
+fn layer_set_to_proto(layer_set: &models::LayerSet) -> LayerSet {
+    let name = format!(
+        "projects/{}/layerSets/{}",
+        layer_set.project_id.into_uuid(),
+        layer_set.name.as_str(),
+    );
+
+    let layer_name = |layer_id: Option<models::LayerId>| {
+        layer_id
+            .map(|layer_id| format!("{}/layers/{}", name, layer_id.into_uuid()))
+            .unwrap_or_default()
+    };
+
+    LayerSet {
+        last_layer: layer_name(layer_set.build_status.last_layer_id),
+        pinned_layer: layer_name(layer_set.pinned_layer_id),
+        name,
+    }
+}
+
+fn layer_set_retention_to_proto(
+    layer_set: &models::LayerSet,
+    retention: &models::LayerSetRetention,
+) -> LayerSetRetention {
+    LayerSetRetention {
+        name: format!(
+            "projects/{}/layerSets/{}/retention",
+            layer_set.project_id.into_uuid(),
+            layer_set.name.as_str(),
+        ),
+        keep_ready_layers: retention.keep_ready_layers,
+    }
+}
+
+fn layer_to_proto(layer: &models::Layer) -> Layer {
+    let status = match layer.status {
+        models::LayerStatus::Building => layer::Status::Building,
+        models::LayerStatus::Finalizing => layer::Status::Finalizing,
+        models::LayerStatus::Ready => layer::Status::Ready,
+        models::LayerStatus::Cancelled => layer::Status::Cancelled,
+    };
+
+    let promoted_from = match &layer.source {
+        Some(models::LayerSource::Promotion {
+            layer_set_name,
+            layer_id,
+        }) => format!(
+            "projects/{}/layerSets/{}/layers/{}",
+            layer.project_id.into_uuid(),
+            layer_set_name.as_str(),
+            layer_id.into_uuid(),
+        ),
+        _ => String::new(),
+    };
+
+    Layer {
+        name: format!(
+            "projects/{}/layerSets/{}/layers/{}",
+            layer.project_id.into_uuid(),
+            layer.layer_set_name.as_str(),
+            layer.id.into_uuid(),
+        ),
+        status: status.into(),
+        preview_host: models::Domain::layer_preview_fqdn(layer.id),
+        promoted_from,
+    }
+}
+
+/// Parses `projects/{project}/layerSets/{layer_set}`, the project has to be the one that the
+/// request is authenticated for.
+fn parse_layer_set_name(auth: &Authentication, name: &str) -> Result<models::LayerSetName, Status> {
+    let invalid_name = || Status::invalid_argument("invalid layer set name");
+
+    match name.split('/').collect::<Vec<_>>()[..] {
+        ["projects", project_id, "layerSets", layer_set_name] => {
+            let project_id = project_id
+                .parse::<models::ProjectId>()
+                .map_err(|_err| invalid_name())?;
+
+            if auth.project_id().ok() != Some(project_id) {
+                return Err(Status::permission_denied("not allowed"));
+            }
+
+            layer_set_name.parse().map_err(|_err| invalid_name())
+        }
+        _ => Err(invalid_name()),
+    }
+}
+
+/// Parses `projects/{project}/layerSets/{layer_set}/retention`.
+fn parse_layer_set_retention_name(
+    auth: &Authentication,
+    name: &str,
+) -> Result<models::LayerSetName, Status> {
+    let layer_set_name = name
+        .strip_suffix("/retention")
+        .ok_or_else(|| Status::invalid_argument("invalid layer set retention name"))?;
+
+    parse_layer_set_name(auth, layer_set_name)
+}
+
+/// Parses `projects/{project}/layerSets/{layer_set}/layers/{layer}`.
+fn parse_layer_name(
+    auth: &Authentication,
+    name: &str,
+) -> Result<(models::LayerSetName, models::LayerId), Status> {
+    let (layer_set_name, layer_id) = name
+        .rsplit_once("/layers/")
+        .ok_or_else(|| Status::invalid_argument("invalid layer name"))?;
+
+    let layer_set_name = parse_layer_set_name(auth, layer_set_name)?;
+    let layer_id = layer_id
+        .parse()
+        .map_err(|_err| Status::invalid_argument("invalid layer name"))?;
+
+    Ok((layer_set_name, layer_id))
+}
@@ -1,12 +1,14 @@
 use anyhow::{anyhow, Error, Result};
+use bytes::{Buf, Bytes};
 use fairing_core::{
     backends::{Database, FileMetadata, FileStorage},
     models::{self, prelude::*},
 };
 use fairing_proto::{
-    domains::v1beta1::domains_server::DomainsServer, sites::v1beta1::sites_server::SitesServer,
-    sources::v1beta1::sources_server::SourcesServer, teams::v1beta1::teams_server::TeamsServer,
-    users::v1beta1::users_server::UsersServer,
+    domains::v1beta1::domains_server::DomainsServer, layers::v1beta1::layers_server::LayersServer,
+    sites::v1beta1::sites_server::SitesServer, sources::v1beta1::sources_server::SourcesServer,
+    sources::v1beta2::sources_server::SourcesServer as ProjectSourcesServer,
+    teams::v1beta1::teams_server::TeamsServer, users::v1beta1::users_server::UsersServer,
 };
 use futures::future::{self, Either, TryFutureExt};
 use hyper::{service::make_service_fn, Server};
@@ -23,14 +25,21 @@ use tower::Service;
 
 mod certificate_resolver;
 mod domains;
+mod layers;
+mod project_sources;
 mod sites;
 mod sources;
 mod teams;
 mod users;
 mod web;
 
+pub use layers::LayersService;
+pub use project_sources::ProjectSourcesService;
+
 pub async fn serve(
     http_service: fairing_core2::services::HttpService,
+    layers_service: LayersService,
+    sources_service: ProjectSourcesService,
     http_addr: Vec<SocketAddr>,
     https_redirect: bool,
     https_redirect_port: Option<u16>,
@@ -54,7 +63,14 @@ pub async fn serve(
             }));
         } else {
             task_set.push(tokio::spawn(async move {
-                server(http_service, http_acceptor, api_host).await
+                server(
+                    http_service,
+                    layers_service,
+                    sources_service,
+                    http_acceptor,
+                    api_host,
+                )
+                .await
             }));
         }
 
@@ -69,7 +85,14 @@ pub async fn serve(
         let https_acceptor = hyper::server::accept::from_stream(incoming_tls_stream);
 
         task_set.push(tokio::spawn(async move {
-            server(http_service, https_acceptor, api_host).await
+            server(
+                http_service,
+                layers_service,
+                sources_service,
+                https_acceptor,
+                api_host,
+            )
+            .await
         }));
 
         tracing::info!("https listening on {https_addr}");
@@ -85,6 +108,8 @@ pub async fn serve(
 
 async fn server<Accept>(
     http_service: fairing_core2::services::HttpService,
+    layers_service: LayersService,
+    sources_service: ProjectSourcesService,
     acceptor: Accept,
     api_host: &'static str,
 ) -> Result<()>
@@ -128,8 +153,14 @@ where
         .into_service();
     */
 
+    let tonic = TonicServer::builder()
+        .add_service(LayersServer::new(layers_service))
+        .add_service(ProjectSourcesServer::new(sources_service))
+        .into_service();
+
     Server::builder(acceptor)
         .serve(make_service_fn(move |s: &Accept::Conn| {
+            let tonic = tonic.clone();
             let connection = http_service.handle_connection(
                 fairing_core2::services::ConnectionMeta::new(s.remote_addr(), s.sni_hostname()),
             );
@@ -139,6 +170,7 @@ where
 
             future::ok::<_, Infallible>(tower::service_fn(
                 move |req: hyper::Request<hyper::Body>| {
+                    let mut tonic = tonic.clone();
                     let sni_hostname = sni_hostname.clone();
 
                     let authority = req.uri().authority().cloned().or_else(|| {
@@ -169,7 +201,19 @@ where
                         host = %host.unwrap_or("None"),
                     );
 
-                    connection.handle_request(req)
+                    match (req.version(), host) {
+                        (http::Version::HTTP_2, Some(host)) if host == api_host => Either::Left(
+                            tonic
+                                .call(req)
+                                .map_ok(|res| res.map(EitherBody::Left))
+                                .map_err(|err| anyhow!("tonic error: {:?}", err)),
+                        ),
+                        _ => Either::Right(
+                            connection
+                                .handle_request(req)
+                                .map_ok(|res| res.map(EitherBody::Right)),
+                        ),
+                    }
 
                     /*
                     match (req.version(), host) {
@@ -303,6 +347,59 @@ async fn auth<T>(
     }
 }
 
+/// Bearer tokens of the api, each token is an administrator of a single project.
+pub struct ApiTokens(Vec<(String, fairing_core2::models::ProjectId)>);
+
+impl ApiTokens {
+    pub fn new(tokens: Vec<(String, fairing_core2::models::ProjectId)>) -> ApiTokens {
+        ApiTokens(tokens)
+    }
+
+    fn authenticate<T>(
+        &self,
+        req: &Request<T>,
+    ) -> Result<fairing_core2::services::Authentication, Status> {
+        let token = req
+            .metadata()
+            .get("authorization")
+            .and_then(|token| token.to_str().ok())
+            .and_then(|token| token.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("invalid authorization token"))?;
+
+        self.0
+            .iter()
+            .find(|(api_token, _)| constant_time_eq(api_token.as_bytes(), token.as_bytes()))
+            .map(
+                |(_, project_id)| fairing_core2::services::Authentication::Role {
+                    project_id: *project_id,
+                    role: fairing_core2::services::AuthenticationRole::Administrator,
+                },
+            )
+            .ok_or_else(|| Status::unauthenticated("invalid authorization token"))
+    }
+}
+
+/// Maps errors of the services to a status. Errors that aren't caused by the request are logged
+/// and only `message` is returned, their details might not be meant for the client.
+fn service_status(err: Error, message: &'static str) -> Status {
+    use fairing_core2::services::RequestError;
+
+    match err.downcast_ref::<RequestError>() {
+        Some(RequestError::NotAllowed) => Status::permission_denied("not allowed"),
+        Some(err @ RequestError::NotFound(_)) => Status::not_found(err.to_string()),
+        Some(RequestError::InvalidArgument(err)) => Status::invalid_argument(err),
+        Some(RequestError::FailedPrecondition(err)) => Status::failed_precondition(err),
+        None => {
+            tracing::error!("error: {:?}", err);
+            Status::internal(message)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 struct AuthInterceptor {
     database: Database,
 }
@@ -327,14 +424,16 @@ enum EitherBody<A, B> {
 }
 
 // From: https://github.com/hyperium/tonic/blob/master/examples/src/hyper_warp_multiplex/server.rs
+// The data of both bodies is copied into `Bytes`, since tonic and the web server use different
+// buffer types.
 impl<A, B> http_body::Body for EitherBody<A, B>
 where
     A: http_body::Body + Send + Unpin,
-    B: http_body::Body<Data = A::Data> + Send + Unpin,
+    B: http_body::Body + Send + Unpin,
     A::Error: Into<Error>,
     B::Error: Into<Error>,
 {
-    type Data = A::Data;
+    type Data = Bytes;
     type Error = anyhow::Error;
 
     fn is_end_stream(&self) -> bool {
@@ -349,8 +448,8 @@ where
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
         match self.get_mut() {
-            EitherBody::Left(b) => Pin::new(b).poll_data(cx).map(map_option_err),
-            EitherBody::Right(b) => Pin::new(b).poll_data(cx).map(map_option_err),
+            EitherBody::Left(b) => Pin::new(b).poll_data(cx).map(map_option_data),
+            EitherBody::Right(b) => Pin::new(b).poll_data(cx).map(map_option_data),
         }
     }
 
@@ -365,8 +464,13 @@ where
     }
 }
 
-fn map_option_err<T, U: Into<Error>>(err: Option<Result<T, U>>) -> Option<Result<T, Error>> {
-    err.map(|e| e.map_err(Into::into))
+fn map_option_data<T: Buf, U: Into<Error>>(
+    data: Option<Result<T, U>>,
+) -> Option<Result<Bytes, Error>> {
+    data.map(|data| {
+        data.map(|mut data| data.copy_to_bytes(data.remaining()))
+            .map_err(Into::into)
+    })
 }
 
 trait ConnectionInfo {
@@ -0,0 +1,158 @@
+use tonic::{Request, Response, Status};
+
+use fairing_core2::{
+    models,
+    services::{Authentication, SourceService},
+};
+use fairing_proto::sources::v1beta2::{
+    source, sources_server::Sources, GetSourceRequest, PinHostKeysRequest, Source,
+};
+
+use super::ApiTokens;
+
+#[derive(Copy, Clone)]
+pub struct ProjectSourcesService {
+    source_service: SourceService,
+    api_tokens: &'static ApiTokens,
+}
+
+impl ProjectSourcesService {
+    pub fn new(
+        source_service: SourceService,
+        api_tokens: &'static ApiTokens,
+    ) -> ProjectSourcesService {
+        ProjectSourcesService {
+            source_service,
+            api_tokens,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Sources for ProjectSourcesService {
+    async fn get_source(
+        &self,
+        request: Request<GetSourceRequest>,
+    ) -> Result<Response<Source>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let source_name = parse_source_name(&auth, &request.get_ref().name)?;
+
+        let source = self
+            .source_service
+            .get_source(&auth, &source_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting source")
+            })?
+            .ok_or_else(|| Status::not_found("source not found"))?;
+
+        Ok(Response::new(source_to_proto(&source)))
+    }
+
+    async fn pin_host_keys(
+        &self,
+        request: Request<PinHostKeysRequest>,
+    ) -> Result<Response<Source>, Status> {
+        let auth = self.api_tokens.authenticate(&request)?;
+
+        let pin_host_keys = request.into_inner();
+
+        let source_name = parse_source_name(&auth, &pin_host_keys.name)?;
+
+        if pin_host_keys.host_keys.is_empty() {
+            return Err(Status::invalid_argument(
+                "at least one host key must be pinned",
+            ));
+        }
+
+        let host_keys = pin_host_keys
+            .host_keys
+            .iter()
+            .map(|host_key| {
+                host_key
+                    .parse::<models::GitHostKey>()
+                    .map_err(|err| Status::invalid_argument(err.to_string()))
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        self.source_service
+            .get_source(&auth, &source_name)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when getting source")
+            })?
+            .ok_or_else(|| Status::not_found("source not found"))?;
+
+        let source = self
+            .source_service
+            .pin_host_keys(&auth, &source_name, host_keys)
+            .await
+            .map_err(|err| {
+                tracing::error!("error: {:?}", err);
+                Status::internal("error when pinning host keys")
+            })?;
+
+        Ok(Response::new(source_to_proto(&source)))
+    }
+}
+
+fn source_to_proto(source: &models::Source) -> Source {
+    let (status, mismatched_host_key) = match &source.status {
+        models::SourceStatus::Ok => (source::Status::Ok, String::new()),
+        models::SourceStatus::HostKeyMismatch { host_key } => {
+            (source::Status::HostKeyMismatch, host_key.to_string())
+        }
+    };
+
+    let kind = match &source.kind {
+        models::SourceKind::Git {
+            repository_url,
+            id_ed25519,
+            known_host_keys,
+            ..
+        } => source::Kind::GitSource(source::GitSource {
+            repository_url: repository_url.as_str().to_owned(),
+            id_ed25519_pub: models::GitHostKey::from_public_key(&id_ed25519.public_key())
+                .to_string(),
+            known_host_keys: known_host_keys
+                .iter()
+                .map(|host_key| host_key.to_string())
+                .collect(),
+        }),
+    };
+
+    Source {
+        name: format!(
+            "projects/{}/sources/{}",
+            source.project_id.into_uuid(),
+            source.name.as_str(),
+        ),
+        status: status.into(),
+        mismatched_host_key,
+        kind: Some(kind),
+    }
+}
+
+/// Parses `projects/{project}/sources/{source}`, the project has to be the one that the request
+/// is authenticated for.
+fn parse_source_name(auth: &Authentication, name: &str) -> Result<models::SourceName, Status> {
+    let invalid_name = || Status::invalid_argument("invalid source name");
+
+    match name.split('/').collect::<Vec<_>>()[..] {
+        ["projects", project_id, "sources", source_name] => {
+            let project_id = project_id
+                .parse::<models::ProjectId>()
+                .map_err(|_err| invalid_name())?;
+
+            if auth.project_id().ok() != Some(project_id) {
+                return Err(Status::permission_denied("not allowed"));
+            }
+
+            source_name.parse().map_err(|_err| invalid_name())
+        }
+        _ => Err(invalid_name()),
+    }
+}
@@ -0,0 +1,304 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use fairing_core2::models;
+use fairing_proto::layers::v1beta1::{
+    layer, layers_client::LayersClient, upload_file_request, CreateUploadRequest,
+    FinalizeUploadRequest, GetLayerRequest, UploadFileRequest, UploadManifestFile,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::io::AsyncReadExt as _;
+use tonic::{
+    service::interceptor::InterceptedService,
+    transport::{Channel, Uri},
+};
+
+use crate::TokenAuth;
+
+const CONCURRENT_UPLOADS: usize = 8;
+
+const UPLOAD_CHUNK_SIZE: usize = 1 << 20;
+
+type Client = LayersClient<InterceptedService<Channel, TokenAuth>>;
+
+struct LocalFile {
+    path: String,
+    local_path: PathBuf,
+    checksum: Vec<u8>,
+    length: u64,
+}
+
+/// Uploads all files in a directory as a new layer, only the files that the project doesn't have
+/// already are sent.
+pub async fn deploy(
+    channel: Channel,
+    web_url: &str,
+    token: &str,
+    dir: &Path,
+    layer_set_name: &str,
+    wait: bool,
+) -> Result<()> {
+    let project_id = match layer_set_name.split('/').collect::<Vec<_>>()[..] {
+        ["projects", project_id, "layerSets", _] => project_id
+            .parse::<models::ProjectId>()
+            .context("parsing project id")?,
+        _ => {
+            return Err(anyhow!(
+                "layer set must be formatted as projects/<project>/layerSets/<layer-set>"
+            ))
+        }
+    };
+
+    let mut client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+    let files = read_dir(project_id, dir)?;
+
+    let response = client
+        .create_upload(CreateUploadRequest {
+            parent: layer_set_name.into(),
+            files: files
+                .iter()
+                .map(|file| UploadManifestFile {
+                    path: file.path.clone(),
+                    checksum: file.checksum.clone(),
+                    length: file.length,
+                })
+                .collect(),
+        })
+        .await?
+        .into_inner();
+
+    let layer = response
+        .layer
+        .ok_or_else(|| anyhow!("server did not return a layer"))?;
+
+    println!("Created layer: {}", layer.name);
+
+    let files_by_checksum = files
+        .iter()
+        .map(|file| (file.checksum.as_slice(), file))
+        .collect::<HashMap<_, _>>();
+
+    let missing_files = response
+        .missing_checksums
+        .iter()
+        .map(|checksum| {
+            files_by_checksum
+                .get(checksum.as_slice())
+                .copied()
+                .ok_or_else(|| anyhow!("server asked for a file that is not in the manifest"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    println!(
+        "Uploading {} of {} files",
+        missing_files.len(),
+        files_by_checksum.len()
+    );
+
+    let progress = ProgressBar::new(missing_files.iter().map(|file| file.length).sum());
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} ({bytes_per_sec})")
+            .expect("progress bar template is valid"),
+    );
+
+    stream::iter(missing_files)
+        .map(|file| upload_file(client.clone(), &layer.name, file, progress.clone()))
+        .buffer_unordered(CONCURRENT_UPLOADS)
+        .try_collect::<()>()
+        .await?;
+
+    progress.finish_and_clear();
+
+    let mut layer = client
+        .finalize_upload(FinalizeUploadRequest {
+            name: layer.name.clone(),
+        })
+        .await?
+        .into_inner();
+
+    println!("Preview: {}", preview_url(web_url, &layer.preview_host)?);
+
+    if wait {
+        loop {
+            match layer.status() {
+                layer::Status::Ready => break,
+                layer::Status::Cancelled => return Err(anyhow!("layer was cancelled")),
+                _ => (),
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            layer = client
+                .get_layer(GetLayerRequest {
+                    name: layer.name.clone(),
+                })
+                .await?
+                .into_inner();
+        }
+
+        println!("Layer is ready");
+    }
+
+    Ok(())
+}
+
+async fn upload_file(
+    mut client: Client,
+    layer_name: &str,
+    file: &LocalFile,
+    progress: ProgressBar,
+) -> Result<()> {
+    let local_file = tokio::fs::File::open(&file.local_path)
+        .await
+        .with_context(|| format!("reading {}", file.local_path.display()))?;
+
+    let header = UploadFileRequest {
+        kind: Some(upload_file_request::Kind::Header(
+            upload_file_request::Header {
+                layer: layer_name.into(),
+                checksum: file.checksum.clone(),
+                length: file.length,
+            },
+        )),
+    };
+
+    // Requests can't fail, an error while reading the file ends them early and is returned once
+    // the upload is over.
+    let read_error = Arc::new(Mutex::new(None));
+
+    let chunks = stream::unfold(
+        (local_file, file.length, read_error.clone()),
+        |(mut local_file, remaining, read_error)| async move {
+            if remaining == 0 {
+                return None;
+            }
+
+            match read_chunk(&mut local_file, remaining).await {
+                Ok(chunk) => {
+                    let remaining = remaining - chunk.len() as u64;
+                    let request = UploadFileRequest {
+                        kind: Some(upload_file_request::Kind::Data(chunk)),
+                    };
+
+                    Some((request, (local_file, remaining, read_error)))
+                }
+                Err(err) => {
+                    *read_error.lock().unwrap() = Some(err);
+                    None
+                }
+            }
+        },
+    );
+
+    let requests = stream::iter([header])
+        .chain(chunks)
+        .inspect(move |request| {
+            if let Some(upload_file_request::Kind::Data(ref data)) = request.kind {
+                progress.inc(data.len() as u64);
+            }
+        });
+
+    let res = client.upload_file(requests).await;
+
+    if let Some(err) = read_error.lock().unwrap().take() {
+        return Err(err.context(format!("reading {}", file.local_path.display())));
+    }
+
+    res.with_context(|| format!("uploading {}", file.path))?;
+
+    Ok(())
+}
+
+/// Reads the next chunk of a file that has `remaining` bytes left to upload.
+async fn read_chunk(local_file: &mut tokio::fs::File, remaining: u64) -> Result<Vec<u8>> {
+    let length = remaining.min(UPLOAD_CHUNK_SIZE as u64);
+
+    let mut chunk = Vec::with_capacity(length as usize);
+    local_file.take(length).read_to_end(&mut chunk).await?;
+
+    ensure!(!chunk.is_empty(), "file changed while deploying");
+
+    Ok(chunk)
+}
+
+/// Lists the regular files in a directory, symbolic links are skipped.
+fn read_dir(project_id: models::ProjectId, dir: &Path) -> Result<Vec<LocalFile>> {
+    let mut files = vec![];
+
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(dir)?;
+
+        let mut path = String::new();
+        for component in relative_path.components() {
+            let component = component
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| anyhow!("{} is not valid UTF-8", relative_path.display()))?;
+
+            path.push('/');
+            path.push_str(component);
+        }
+
+        let (checksum, length) = hash_file(project_id, entry.path())
+            .with_context(|| format!("reading {}", entry.path().display()))?;
+
+        files.push(LocalFile {
+            path,
+            local_path: entry.into_path(),
+            checksum,
+            length,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Computes the same keyed checksum as the server, which depends on the project.
+fn hash_file(project_id: models::ProjectId, path: &Path) -> Result<(Vec<u8>, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = models::FileChecksum::blake2b_hasher(project_id);
+    let mut buffer = vec![0u8; 64 << 10];
+    let mut length = 0;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        length += read as u64;
+    }
+
+    let checksum = hasher.finalize();
+    let checksum = checksum
+        .as_blake2b()
+        .expect("files are hashed with blake2b")
+        .to_vec();
+
+    Ok((checksum, length))
+}
+
+/// Url of the preview host on the web server, which may listen on another port than the api.
+fn preview_url(web_url: &str, preview_host: &str) -> Result<String> {
+    let uri = web_url.parse::<Uri>().context("parsing web url")?;
+    let scheme = uri.scheme_str().unwrap_or("https");
+
+    match uri.port_u16() {
+        Some(port) => Ok(format!("{scheme}://{preview_host}:{port}")),
+        None => Ok(format!("{scheme}://{preview_host}")),
+    }
+}
@@ -7,6 +7,7 @@ use tonic::{
 };
 
 mod config;
+mod deploy;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -78,6 +79,32 @@ async fn main() -> Result<()> {
                 .about("Refresh source")
                 .arg(Arg::with_name("source")),
             )
+            .subcommand(
+                SubCommand::with_name("get")
+                    .about("Show a source and whether its repository can be fetched")
+                    .arg(
+                        Arg::with_name("source")
+                            .required(true)
+                            .help("Format: projects/<project>/sources/<source>"),
+                    )
+                    .arg(token_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("pin-host-keys")
+                    .about("Replace the host keys that the repository of a source must present")
+                    .arg(
+                        Arg::with_name("source")
+                            .required(true)
+                            .help("Format: projects/<project>/sources/<source>"),
+                    )
+                    .arg(
+                        Arg::with_name("host-key")
+                            .required(true)
+                            .multiple(true)
+                            .help("Format: \"ssh-ed25519 AAAA...\""),
+                    )
+                    .arg(token_arg()),
+            )
         )
         .subcommand(
             SubCommand::with_name("domains")
@@ -106,6 +133,127 @@ async fn main() -> Result<()> {
                         )
                 )
         )
+        .subcommand(
+            SubCommand::with_name("layers")
+                .about("Layer inspection")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("List the files of a layer")
+                        .arg(
+                            Arg::with_name("layer")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>/layers/<layer>"),
+                        )
+                        .arg(
+                            Arg::with_name("prefix")
+                                .long("prefix")
+                                .takes_value(true)
+                                .help("Only list paths starting with this prefix."),
+                        )
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("diff")
+                        .about("Compare a layer with an earlier layer")
+                        .arg(
+                            Arg::with_name("base-layer")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>/layers/<layer>"),
+                        )
+                        .arg(
+                            Arg::with_name("layer")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>/layers/<layer>"),
+                        )
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("promote")
+                        .about("Promote a ready layer to another layer set without rebuilding it")
+                        .arg(
+                            Arg::with_name("layer")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>/layers/<layer>"),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .value_name("layer-set-name")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Layer set to promote to. Format: projects/<project>/layerSets/<layer-set>"),
+                        )
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("pin")
+                        .about("Serve a ready layer until the layer set is unpinned")
+                        .arg(
+                            Arg::with_name("layer")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>/layers/<layer>"),
+                        )
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("unpin")
+                        .about("Serve the last ready layer of a layer set again")
+                        .arg(
+                            Arg::with_name("layer-set")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>"),
+                        )
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("retention")
+                        .about("Show or change which layers of a layer set are kept by garbage collection")
+                        .arg(
+                            Arg::with_name("layer-set")
+                                .required(true)
+                                .help("Format: projects/<project>/layerSets/<layer-set>"),
+                        )
+                        .arg(
+                            Arg::with_name("keep-ready-layers")
+                                .long("keep-ready-layers")
+                                .takes_value(true)
+                                .help("Number of the most recent ready layers to keep."),
+                        )
+                        .arg(token_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("deploy")
+                .about("Upload a directory as a new layer")
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("Directory with the files of the layer."),
+                )
+                .arg(
+                    Arg::with_name("layer-set")
+                        .long("layer-set")
+                        .value_name("layer-set-name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Layer set to upload to. Format: projects/<project>/layerSets/<layer-set>"),
+                )
+                .arg(token_arg())
+                .arg(
+                    Arg::with_name("web-url")
+                        .long("web-url")
+                        .env("FAIRING_WEB_URL")
+                        .takes_value(true)
+                        .default_value("https://localhost")
+                        .help("Address that sites are served from, the preview url uses its scheme and port."),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .long("wait")
+                        .help("Wait until the layer is ready, fails if the layer is cancelled."),
+                ),
+        )
         .get_matches();
 
     let subscriber = tracing_subscriber::FmtSubscriber::builder()
@@ -120,9 +268,9 @@ async fn main() -> Result<()> {
         .to_owned();
 
     let channel = if host.starts_with("http://") {
-        Channel::from_shared(host)?.connect().await?
+        Channel::from_shared(host.clone())?.connect().await?
     } else {
-        Channel::from_shared(host)?
+        Channel::from_shared(host.clone())?
             .tls_config(ClientTlsConfig::new())?
             .connect()
             .await?
@@ -138,6 +286,25 @@ async fn main() -> Result<()> {
         command_sources(&matches, channel).await?;
     } else if let Some(matches) = matches.subcommand_matches("domains") {
         command_domains(&matches, channel).await?;
+    } else if let Some(matches) = matches.subcommand_matches("layers") {
+        command_layers(matches, channel).await?;
+    } else if let Some(matches) = matches.subcommand_matches("deploy") {
+        let dir = matches.value_of("dir").expect("dir must be set");
+        let layer_set = matches
+            .value_of("layer-set")
+            .expect("layer set must be set");
+        let token = matches.value_of("token").expect("token must be set");
+        let web_url = matches.value_of("web-url").expect("web url must be set");
+
+        deploy::deploy(
+            channel,
+            web_url,
+            token,
+            dir.as_ref(),
+            layer_set,
+            matches.is_present("wait"),
+        )
+        .await?;
     }
 
     Ok(())
@@ -353,13 +520,19 @@ async fn command_sites(matches: &ArgMatches<'_>, channel: Channel) -> Result<()>
 }
 
 async fn command_sources(matches: &ArgMatches<'_>, channel: Channel) -> Result<()> {
-    use fairing_proto::sources::v1beta1::{sources_client::SourcesClient, RefreshSourceRequest};
+    use fairing_proto::sources::{
+        v1beta1::{sources_client::SourcesClient, RefreshSourceRequest},
+        v1beta2::{
+            source, sources_client::SourcesClient as ProjectSourcesClient, GetSourceRequest,
+            PinHostKeysRequest,
+        },
+    };
 
-    let auth = ConfigAuth::read().await?;
+    if let Some(matches) = matches.subcommand_matches("refresh") {
+        let auth = ConfigAuth::read().await?;
 
-    let mut sources_client = SourcesClient::with_interceptor(channel, auth);
+        let mut sources_client = SourcesClient::with_interceptor(channel, auth);
 
-    if let Some(matches) = matches.subcommand_matches("refresh") {
         let name = matches.value_of("source").expect("source name must be set");
 
         let _response = sources_client
@@ -367,6 +540,58 @@ async fn command_sources(matches: &ArgMatches<'_>, channel: Channel) -> Result<(
             .await?;
 
         println!("Refreshed source");
+    } else if let Some(matches) = matches.subcommand_matches("get") {
+        let name = matches.value_of("source").expect("source name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut sources_client =
+            ProjectSourcesClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = sources_client
+            .get_source(GetSourceRequest { name: name.into() })
+            .await?
+            .into_inner();
+
+        println!("{}", response.name);
+
+        match response.status() {
+            source::Status::Ok => println!("status: ok"),
+            source::Status::HostKeyMismatch => {
+                println!("status: host key mismatch");
+                println!("presented host key: {}", response.mismatched_host_key);
+            }
+            source::Status::Unspecified => println!("status: unknown"),
+        }
+
+        if let Some(source::Kind::GitSource(git_source)) = response.kind {
+            println!("repository: {}", git_source.repository_url);
+            println!("deploy key: {}", git_source.id_ed25519_pub);
+
+            for host_key in git_source.known_host_keys {
+                println!("host key: {host_key}");
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("pin-host-keys") {
+        let name = matches.value_of("source").expect("source name must be set");
+        let host_keys = matches
+            .values_of("host-key")
+            .expect("host keys must be set")
+            .map(str::to_owned)
+            .collect();
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut sources_client =
+            ProjectSourcesClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = sources_client
+            .pin_host_keys(PinHostKeysRequest {
+                name: name.into(),
+                host_keys,
+            })
+            .await?
+            .into_inner();
+
+        println!("pinned host keys of {}", response.name);
     }
 
     Ok(())
@@ -418,6 +643,191 @@ async fn command_domains(matches: &ArgMatches<'_>, channel: Channel) -> Result<(
     Ok(())
 }
 
+async fn command_layers(matches: &ArgMatches<'_>, channel: Channel) -> Result<()> {
+    use fairing_proto::layers::v1beta1::{
+        layer_diff_entry, layers_client::LayersClient, DiffLayersRequest,
+        GetLayerSetRetentionRequest, LayerSetRetention, ListLayerMembersRequest,
+        PinLayerSetRequest, PromoteLayerRequest, SetLayerSetRetentionRequest, UnpinLayerSetRequest,
+    };
+
+    if let Some(matches) = matches.subcommand_matches("ls") {
+        let layer = matches.value_of("layer").expect("layer name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let mut page_token = String::new();
+        loop {
+            let response = layers_client
+                .list_layer_members(ListLayerMembersRequest {
+                    parent: layer.into(),
+                    prefix: matches.value_of("prefix").unwrap_or_default().into(),
+                    page_size: 1000,
+                    page_token,
+                })
+                .await?
+                .into_inner();
+
+            for member in response.resources {
+                let checksum = member
+                    .checksum
+                    .iter()
+                    .take(8)
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>();
+
+                println!("{:>10}  {checksum}  {}", member.length, member.path);
+            }
+
+            if response.next_page_token.is_empty() {
+                break;
+            }
+
+            page_token = response.next_page_token;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("diff") {
+        let base_layer = matches
+            .value_of("base-layer")
+            .expect("base layer name must be set");
+        let layer = matches.value_of("layer").expect("layer name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = layers_client
+            .diff_layers(DiffLayersRequest {
+                name: layer.into(),
+                base_layer: base_layer.into(),
+            })
+            .await?
+            .into_inner();
+
+        if response.entries.is_empty() {
+            println!("No changes");
+        }
+
+        for entry in response.entries {
+            let kind = match entry.kind() {
+                layer_diff_entry::Kind::Added => "A",
+                layer_diff_entry::Kind::Modified => "M",
+                layer_diff_entry::Kind::Deleted => "D",
+                layer_diff_entry::Kind::Unspecified => "?",
+            };
+
+            let delta = entry.new_length as i64 - entry.old_length as i64;
+
+            println!("{kind}  {delta:>+10}  {}", entry.path);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("promote") {
+        let layer = matches.value_of("layer").expect("layer name must be set");
+        let layer_set = matches.value_of("to").expect("layer set name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = layers_client
+            .promote_layer(PromoteLayerRequest {
+                name: layer.into(),
+                target_layer_set: layer_set.into(),
+            })
+            .await?
+            .into_inner();
+
+        println!("promoted {} to {}", response.promoted_from, response.name);
+        println!("preview it at {}", response.preview_host);
+    } else if let Some(matches) = matches.subcommand_matches("pin") {
+        let layer = matches.value_of("layer").expect("layer name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let layer_set = layer
+            .rsplit_once("/layers/")
+            .map(|(layer_set, _layer_id)| layer_set)
+            .ok_or_else(|| anyhow!("invalid layer name"))?;
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = layers_client
+            .pin_layer_set(PinLayerSetRequest {
+                name: layer_set.into(),
+                layer: layer.into(),
+            })
+            .await?
+            .into_inner();
+
+        println!("{} is pinned to {}", response.name, response.pinned_layer);
+    } else if let Some(matches) = matches.subcommand_matches("unpin") {
+        let layer_set = matches
+            .value_of("layer-set")
+            .expect("layer set name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = layers_client
+            .unpin_layer_set(UnpinLayerSetRequest {
+                name: layer_set.into(),
+            })
+            .await?
+            .into_inner();
+
+        if response.last_layer.is_empty() {
+            println!("{} is unpinned, it has no ready layers", response.name);
+        } else {
+            println!(
+                "{} is unpinned, serving {}",
+                response.name, response.last_layer
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches("retention") {
+        let layer_set = matches
+            .value_of("layer-set")
+            .expect("layer set name must be set");
+        let token = matches.value_of("token").expect("token must be set");
+
+        let name = format!("{layer_set}/retention");
+
+        let mut layers_client = LayersClient::with_interceptor(channel, TokenAuth::new(token)?);
+
+        let response = match matches.value_of("keep-ready-layers") {
+            Some(keep_ready_layers) => {
+                let keep_ready_layers = keep_ready_layers
+                    .parse()
+                    .map_err(|_err| anyhow!("invalid number of ready layers to keep"))?;
+
+                layers_client
+                    .set_layer_set_retention(SetLayerSetRetentionRequest {
+                        retention: Some(LayerSetRetention {
+                            name,
+                            keep_ready_layers,
+                        }),
+                    })
+                    .await?
+                    .into_inner()
+            }
+            None => layers_client
+                .get_layer_set_retention(GetLayerSetRetentionRequest { name })
+                .await?
+                .into_inner(),
+        };
+
+        println!(
+            "{} keeps the {} most recent ready layers",
+            layer_set, response.keep_ready_layers
+        );
+    }
+
+    Ok(())
+}
+
+fn token_arg() -> Arg<'static, 'static> {
+    Arg::with_name("token")
+        .env("FAIRING_TOKEN")
+        .long("token")
+        .takes_value(true)
+        .required(true)
+        .help("API token of the project.")
+}
+
 #[derive(Clone)]
 struct ConfigAuth {
     token: AsciiMetadataValue,
@@ -444,3 +854,26 @@ impl tonic::service::Interceptor for ConfigAuth {
         Ok(request)
     }
 }
+
+#[derive(Clone)]
+struct TokenAuth {
+    token: AsciiMetadataValue,
+}
+
+impl TokenAuth {
+    fn new(token: &str) -> Result<TokenAuth> {
+        let token = MetadataValue::from_str(&format!("Bearer {token}"))
+            .map_err(|_err| anyhow!("invalid api token"))?;
+
+        Ok(TokenAuth { token })
+    }
+}
+
+impl tonic::service::Interceptor for TokenAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, tonic::Status> {
+        request
+            .metadata_mut()
+            .insert("authorization", self.token.clone());
+        Ok(request)
+    }
+}